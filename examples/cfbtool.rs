@@ -86,7 +86,7 @@ fn main() {
         if let Some(paths) = submatches.values_of("path") {
             for path in paths {
                 let (comp_path, inner_path) = split(path);
-                let mut comp = cfb::open(&comp_path).unwrap();
+                let comp = cfb::open(&comp_path).unwrap();
                 let mut stream = comp.open_stream(inner_path).unwrap();
                 io::copy(&mut stream, &mut io::stdout()).unwrap();
             }
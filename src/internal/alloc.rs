@@ -1,22 +1,62 @@
+use crate::internal::IdSet;
 use crate::internal::{
-    consts, Chain, Sector, SectorInit, Sectors, Validation, Version,
+    consts, is_free, Chain, ChainCache, FreeList, Header, Journal, ReadAt,
+    Sector, SectorInit, Sectors, SetLen, Validator, Version, WriteMode,
 };
-use byteorder::{LittleEndian, WriteBytesExt};
-use fnv::FnvHashSet;
-use std::io::{self, Seek, Write};
-use std::mem::size_of;
+use crate::io::{self, Read, Seek, Write, WriteBytesExt};
+use alloc::collections::BTreeSet;
+use alloc::format;
+use alloc::vec;
+use alloc::vec::Vec;
+use byteorder::LittleEndian;
+use core::mem::{self, size_of};
 
 //===========================================================================//
 
-macro_rules! malformed {
-    ($e:expr) => { invalid_data!("Malformed FAT ({})", $e) };
-    ($fmt:expr, $($arg:tt)+) => {
-        invalid_data!("Malformed FAT ({})", format!($fmt, $($arg)+))
+macro_rules! malformation {
+    ($sector_id:expr; $($arg:tt)+) => {
+        corruption!(
+            Fat { sector_id: Some($sector_id) },
+            "Malformed FAT ({})",
+            format!($($arg)+)
+        )
+    };
+    ($($arg:tt)+) => {
+        corruption!(
+            Fat { sector_id: None },
+            "Malformed FAT ({})",
+            format!($($arg)+)
+        )
     };
 }
 
 //===========================================================================//
 
+type CopyFn<F> = fn(&mut Sectors<F>, u32, u32) -> io::Result<()>;
+
+/// The DIFAT and FAT as they were when a transaction began, which remain the
+/// ones recorded in the file until the transaction is committed.
+struct Snapshot<F> {
+    difat_sector_ids: Vec<u32>,
+    difat: Vec<u32>,
+    fat: Vec<u32>,
+    /// A function that copies one sector to another.  This is stored here
+    /// because copying needs `F: Read`, which writing otherwise doesn't.
+    copy_sector: CopyFn<F>,
+}
+
+impl<F> Snapshot<F> {
+    /// Returns true if the given sector was allocated when the transaction
+    /// began, in which case it must not be overwritten or reused until the
+    /// transaction is committed.
+    fn is_protected(&self, sector_id: u32) -> bool {
+        matches!(self.fat.get(sector_id as usize),
+                 Some(&entry) if entry != consts::FREE_SECTOR)
+    }
+}
+
+//===========================================================================//
+
 /// A wrapper around the sectors of a compound file, providing sector
 /// allocation via the FAT and DIFAT.
 pub struct Allocator<F> {
@@ -24,6 +64,21 @@ pub struct Allocator<F> {
     difat_sector_ids: Vec<u32>,
     difat: Vec<u32>,
     fat: Vec<u32>,
+    free_sectors: FreeList,
+    /// The sector IDs of recently used chains.
+    chains: ChainCache,
+    write_mode: WriteMode,
+    /// In write-back mode, the indices (within the DIFAT) of FAT sectors that
+    /// have been changed in memory but not yet written to the file.
+    dirty_fat_sectors: BTreeSet<usize>,
+    /// While a transaction is in progress, the tables as of its start.
+    /// Sectors allocated in that snapshot are never written to or reused
+    /// (and so are left out of `free_sectors` when freed) until the
+    /// transaction is committed.
+    transaction: Option<Snapshot<F>>,
+    /// In journaling mode, the journal that records each new header before
+    /// a commit writes it to the file.
+    journal: Option<Journal>,
 }
 
 impl<F> Allocator<F> {
@@ -32,10 +87,22 @@ impl<F> Allocator<F> {
         difat_sector_ids: Vec<u32>,
         difat: Vec<u32>,
         fat: Vec<u32>,
-        validation: Validation,
+        validator: &mut Validator,
     ) -> io::Result<Allocator<F>> {
-        let mut alloc = Allocator { sectors, difat_sector_ids, difat, fat };
-        alloc.validate(validation)?;
+        let free_sectors = FreeList::new(is_free(&fat));
+        let mut alloc = Allocator {
+            sectors,
+            difat_sector_ids,
+            difat,
+            fat,
+            free_sectors,
+            chains: ChainCache::default(),
+            write_mode: WriteMode::WriteThrough,
+            dirty_fat_sectors: BTreeSet::new(),
+            transaction: None,
+            journal: None,
+        };
+        alloc.validate(validator)?;
         Ok(alloc)
     }
 
@@ -47,10 +114,59 @@ impl<F> Allocator<F> {
         self.sectors.sector_len()
     }
 
+    pub fn write_mode(&self) -> WriteMode {
+        self.write_mode
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.transaction.is_some()
+    }
+
+    /// Returns true if changes to the FAT, MiniFAT, and directory should only
+    /// be marked as dirty rather than written to the file immediately, either
+    /// because of write-back mode or because a transaction is in progress.
+    pub fn defers_writes(&self) -> bool {
+        self.write_mode.is_write_back() || self.transaction.is_some()
+    }
+
+    pub fn is_journaling(&self) -> bool {
+        self.journal.is_some()
+    }
+
+    /// Switches to journaling mode, in which every commit first records the
+    /// new header in the given journal.
+    pub fn set_journal(&mut self, journal: Journal) {
+        self.journal = Some(journal);
+    }
+
+    pub fn num_sectors(&self) -> u32 {
+        self.sectors.num_sectors()
+    }
+
+    /// Replaces the in-memory DIFAT and FAT, e.g. after the file has been
+    /// rewritten with a new layout.
+    pub fn replace_tables(
+        &mut self,
+        difat_sector_ids: Vec<u32>,
+        difat: Vec<u32>,
+        mut fat: Vec<u32>,
+    ) {
+        while fat.last() == Some(&consts::FREE_SECTOR) {
+            fat.pop();
+        }
+        self.free_sectors = FreeList::new(is_free(&fat));
+        self.chains.clear();
+        self.dirty_fat_sectors.clear();
+        self.difat_sector_ids = difat_sector_ids;
+        self.difat = difat;
+        self.fat = fat;
+    }
+
     pub fn next(&self, sector_id: u32) -> io::Result<u32> {
         let index = sector_id as usize;
         if index >= self.fat.len() {
-            invalid_data!(
+            corrupted!(
+                Fat { sector_id: Some(sector_id) },
                 "Found reference to sector {}, but FAT has only {} entries",
                 index,
                 self.fat.len()
@@ -61,15 +177,125 @@ impl<F> Allocator<F> {
             && (next_id > consts::MAX_REGULAR_SECTOR
                 || next_id as usize >= self.fat.len())
         {
-            invalid_data!("next_id ({}) is invalid", next_id);
+            corrupted!(
+                Fat { sector_id: Some(sector_id) },
+                "next_id ({}) is invalid",
+                next_id
+            );
         }
         Ok(next_id)
     }
 
+    pub fn inner(&self) -> &F {
+        self.sectors.inner()
+    }
+
+    #[cfg(feature = "async")]
+    pub fn inner_mut(&mut self) -> &mut F {
+        self.sectors.inner_mut()
+    }
+
+    /// Returns the in-memory FAT, which may omit free entries at the end.
+    pub fn fat(&self) -> &[u32] {
+        &self.fat
+    }
+
+    /// Returns the in-memory DIFAT, i.e. the IDs of the FAT sectors, in
+    /// order.
+    pub fn difat(&self) -> &[u32] {
+        &self.difat
+    }
+
+    /// Returns the IDs of the sectors that hold the part of the DIFAT that
+    /// doesn't fit in the header, in chain order.
+    pub fn difat_sector_ids(&self) -> &[u32] {
+        &self.difat_sector_ids
+    }
+
+    /// Checks the index of free sectors against the FAT.  During a
+    /// transaction, sectors freed since it began that were allocated before
+    /// it began can't be reused yet, and so mustn't be indexed.
+    #[cfg(test)]
+    pub fn verify_free_sectors(&self) -> io::Result<()> {
+        let snapshot = self.transaction.as_ref();
+        let is_reusable =
+            self.fat.iter().enumerate().map(|(index, &entry)| {
+                entry == consts::FREE_SECTOR
+                    && !snapshot.is_some_and(|snapshot| {
+                        snapshot.is_protected(index as u32)
+                    })
+            });
+        self.free_sectors.verify(is_reusable, |sector_id, message| {
+            crate::Error::Fat { sector_id: Some(sector_id), message }
+        })
+    }
+
+    /// Returns the IDs of the sectors that hold the DIFAT and the FAT.
+    pub fn table_sector_ids(&self) -> Vec<u32> {
+        self.difat_sector_ids.iter().chain(&self.difat).copied().collect()
+    }
+
     pub fn into_inner(self) -> F {
         self.sectors.into_inner()
     }
 
+    pub fn sector_offset(
+        &self,
+        sector_id: u32,
+        offset_within_sector: u64,
+    ) -> io::Result<u64> {
+        self.sectors.sector_offset(sector_id, offset_within_sector)
+    }
+
+    /// Walks the FAT to find the sector IDs of the chain starting at the
+    /// given sector, unless they are already cached.
+    pub fn load_chain(&mut self, start_sector_id: u32) -> io::Result<()> {
+        if start_sector_id == consts::END_OF_CHAIN {
+            return Ok(());
+        }
+        if self.chains.contains(start_sector_id) {
+            self.chains.touch(start_sector_id);
+            return Ok(());
+        }
+        let mut sector_ids = Vec::<u32>::new();
+        let mut current_sector_id = start_sector_id;
+        while current_sector_id != consts::END_OF_CHAIN {
+            sector_ids.push(current_sector_id);
+            current_sector_id = self.next(current_sector_id)?;
+            if current_sector_id == start_sector_id {
+                corrupted!(
+                    ChainLoop { sector_id: current_sector_id },
+                    "Chain contained duplicate sector id {}",
+                    current_sector_id
+                );
+            }
+        }
+        self.chains.insert(start_sector_id, sector_ids);
+        Ok(())
+    }
+
+    /// Returns the sector IDs of the chain starting at the given sector,
+    /// which must already have been loaded with `load_chain`.
+    pub fn cached_chain(&self, start_sector_id: u32) -> &[u32] {
+        match self.chains.get(start_sector_id) {
+            Some(sector_ids) => sector_ids,
+            None => {
+                debug_assert_eq!(start_sector_id, consts::END_OF_CHAIN);
+                &[]
+            }
+        }
+    }
+
+    /// Returns the sector IDs of the chain starting at the given sector, if
+    /// they are cached.  Unlike `load_chain`, this needs only a shared
+    /// reference.
+    pub fn try_cached_chain(&self, start_sector_id: u32) -> Option<&[u32]> {
+        if start_sector_id == consts::END_OF_CHAIN {
+            return Some(&[]);
+        }
+        self.chains.get(start_sector_id)
+    }
+
     pub fn open_chain(
         &mut self,
         start_sector_id: u32,
@@ -78,77 +304,113 @@ impl<F> Allocator<F> {
         Chain::new(self, start_sector_id, init)
     }
 
-    fn validate(&mut self, validation: Validation) -> io::Result<()> {
+    /// Discards all changes made since the current transaction began,
+    /// restoring the DIFAT and FAT from that time.
+    pub fn revert_transaction(&mut self) {
+        if let Some(snapshot) = self.transaction.take() {
+            self.replace_tables(
+                snapshot.difat_sector_ids,
+                snapshot.difat,
+                snapshot.fat,
+            );
+        }
+    }
+
+    fn validate(&mut self, validator: &mut Validator) -> io::Result<()> {
         if self.fat.len() > self.sectors.num_sectors() as usize {
-            malformed!(
+            validator.reject(malformation!(
                 "FAT has {} entries, but file has only {} sectors",
                 self.fat.len(),
                 self.sectors.num_sectors()
-            );
+            ))?;
         }
         for &difat_sector in self.difat_sector_ids.iter() {
             let difat_sector_index = difat_sector as usize;
             if difat_sector_index >= self.fat.len() {
-                malformed!(
+                validator.reject(malformation!(
+                    difat_sector;
                     "FAT has {} entries, but DIFAT lists {} as a DIFAT sector",
                     self.fat.len(),
                     difat_sector
-                );
+                ))?;
+                continue;
             }
             if self.fat[difat_sector_index] != consts::DIFAT_SECTOR {
-                if validation.is_strict() {
-                    malformed!(
+                validator.tolerate(
+                    validator.policy().allow_unmarked_fat_sectors,
+                    malformation!(
+                        difat_sector;
                         "DIFAT sector {} is not marked as such in the FAT",
                         difat_sector
-                    );
-                } else {
-                    self.fat[difat_sector_index] = consts::DIFAT_SECTOR;
-                }
+                    ),
+                )?;
+                self.fat[difat_sector_index] = consts::DIFAT_SECTOR;
+                self.free_sectors.set_free(difat_sector, false);
             }
         }
         for &fat_sector in self.difat.iter() {
             let fat_sector_index = fat_sector as usize;
             if fat_sector_index >= self.fat.len() {
-                malformed!(
+                validator.reject(malformation!(
+                    fat_sector;
                     "FAT has {} entries, but DIFAT lists {} as a FAT sector",
                     self.fat.len(),
                     fat_sector
-                );
+                ))?;
+                continue;
             }
             if self.fat[fat_sector_index] != consts::FAT_SECTOR {
-                if validation.is_strict() {
-                    malformed!(
+                validator.tolerate(
+                    validator.policy().allow_unmarked_fat_sectors,
+                    malformation!(
+                        fat_sector;
                         "FAT sector {} is not marked as such in the FAT",
                         fat_sector
-                    );
-                } else {
-                    self.fat[fat_sector_index] = consts::FAT_SECTOR;
-                }
+                    ),
+                )?;
+                self.fat[fat_sector_index] = consts::FAT_SECTOR;
+                self.free_sectors.set_free(fat_sector, false);
             }
         }
-        let mut pointees = FnvHashSet::default();
+        let mut pointees = IdSet::default();
         for (from_sector, &to_sector) in self.fat.iter().enumerate() {
             if to_sector <= consts::MAX_REGULAR_SECTOR {
                 if to_sector as usize >= self.fat.len() {
-                    malformed!(
+                    validator.reject(malformation!(
+                        from_sector as u32;
                         "FAT has {} entries, but sector {} points to {}",
                         self.fat.len(),
                         from_sector,
                         to_sector
-                    );
+                    ))?;
                 }
                 if pointees.contains(&to_sector) {
-                    malformed!("sector {} pointed to twice", to_sector);
+                    validator.reject(malformation!(
+                        to_sector;
+                        "sector {} pointed to twice",
+                        to_sector
+                    ))?;
                 }
                 pointees.insert(to_sector);
             } else if to_sector == consts::INVALID_SECTOR {
-                malformed!("0x{:08X} is not a valid FAT entry", to_sector);
+                validator.reject(malformation!(
+                    from_sector as u32;
+                    "0x{:08X} is not a valid FAT entry",
+                    to_sector
+                ))?;
             }
         }
+        validator.finish_stage()?;
         Ok(())
     }
 }
 
+impl<F: SetLen> Allocator<F> {
+    pub fn set_num_sectors(&mut self, num_sectors: u32) -> io::Result<()> {
+        self.sectors.set_num_sectors(num_sectors)
+    }
+}
+
 impl<F: Seek> Allocator<F> {
     pub fn seek_within_header(
         &mut self,
@@ -187,11 +449,26 @@ impl<F: Seek> Allocator<F> {
     }
 }
 
+impl<F: ReadAt> Allocator<F> {
+    /// Reads from the given offset within a sector, stopping at the end of
+    /// that sector.  This needs only a shared reference.
+    pub fn read_at_sector(
+        &self,
+        sector_id: u32,
+        offset_within_sector: u64,
+        buf: &mut [u8],
+    ) -> io::Result<usize> {
+        self.sectors.read_at_sector(sector_id, offset_within_sector, buf)
+    }
+}
+
 impl<F: Write + Seek> Allocator<F> {
     /// Allocates a new chain with one sector, and returns the starting sector
     /// number.
     pub fn begin_chain(&mut self, init: SectorInit) -> io::Result<u32> {
-        self.allocate_sector(init)
+        let sector_id = self.allocate_sector(init)?;
+        self.chains.insert(sector_id, vec![sector_id]);
+        Ok(sector_id)
     }
 
     /// Given the starting sector (or any internal sector) of a chain, extends
@@ -203,16 +480,30 @@ impl<F: Write + Seek> Allocator<F> {
         init: SectorInit,
     ) -> io::Result<u32> {
         debug_assert_ne!(start_sector_id, consts::END_OF_CHAIN);
-        let mut last_sector_id = start_sector_id;
-        loop {
-            let next = self.fat[last_sector_id as usize];
-            if next == consts::END_OF_CHAIN {
-                break;
+        let last_sector_id = match self.chains.get(start_sector_id) {
+            Some(sector_ids) => *sector_ids.last().unwrap(),
+            None => {
+                let mut last_sector_id = start_sector_id;
+                loop {
+                    let next = self.fat[last_sector_id as usize];
+                    if next == consts::END_OF_CHAIN {
+                        break;
+                    }
+                    last_sector_id = next;
+                }
+                last_sector_id
             }
-            last_sector_id = next;
-        }
+        };
         let new_sector_id = self.allocate_sector(init)?;
         self.set_fat(last_sector_id, new_sector_id)?;
+        if !self.chains.push(start_sector_id, new_sector_id) {
+            // We were given an internal sector of the chain (or the chain was
+            // evicted from the cache), so we don't know which cached chain (if
+            // any) we just extended; drop it.
+            self.chains.retain(|sector_ids| {
+                sector_ids.last() != Some(&last_sector_id)
+            });
+        }
         Ok(new_sector_id)
     }
 
@@ -220,19 +511,16 @@ impl<F: Write + Seek> Allocator<F> {
     /// returns the new sector number.
     fn allocate_sector(&mut self, init: SectorInit) -> io::Result<u32> {
         // If there's an existing free sector, use that.
-        for sector_id in 0..self.fat.len() {
-            if self.fat[sector_id] == consts::FREE_SECTOR {
-                let sector_id = sector_id as u32;
-                self.set_fat(sector_id, consts::END_OF_CHAIN)?;
-                self.sectors.init_sector(sector_id, init)?;
-                return Ok(sector_id);
-            }
+        if let Some(sector_id) = self.free_sectors.first() {
+            self.set_fat(sector_id, consts::END_OF_CHAIN)?;
+            self.sectors.init_sector(sector_id, init)?;
+            return Ok(sector_id);
         }
         // Otherwise, we need a new sector; if there's not room in the FAT to
         // add it, then first we need to allocate a new FAT sector.
         let fat_entries_per_sector =
             self.sectors.sector_len() / size_of::<u32>();
-        if self.fat.len() % fat_entries_per_sector == 0 {
+        if self.fat.len() >= self.difat.len() * fat_entries_per_sector {
             self.append_fat_sector()?;
         }
         // Add a new sector to the end of the file and return it.
@@ -259,8 +547,7 @@ impl<F: Write + Seek> Allocator<F> {
         if difat_index < consts::NUM_DIFAT_ENTRIES_IN_HEADER {
             // This DIFAT entry goes in the file header.
             let offset = 76 + 4 * difat_index as u64;
-            let mut header = self.sectors.seek_within_header(offset)?;
-            header.write_u32::<LittleEndian>(new_fat_sector_id)?;
+            self.write_header_fields(offset, &[new_fat_sector_id])?;
         } else {
             // This DIFAT entry goes in a DIFAT sector.
             let difat_entries_per_sector = (self.sector_len() - 4) / 4;
@@ -274,51 +561,73 @@ impl<F: Write + Seek> Allocator<F> {
                     .init_sector(new_difat_sector_id, SectorInit::Difat)?;
                 // Record this new DIFAT sector in the FAT.
                 self.set_fat(new_difat_sector_id, consts::DIFAT_SECTOR)?;
-                // Add this sector to the end of the DIFAT chain.
+                // Add this sector to the end of the DIFAT chain.  (During a
+                // transaction, DIFAT sectors are instead all rewritten when
+                // the transaction is committed.)
                 if let Some(&last_sector_id) = self.difat_sector_ids.last() {
-                    let offset = self.sector_len() as u64 - 4;
-                    let mut sector = self
-                        .sectors
-                        .seek_within_sector(last_sector_id, offset)?;
-                    sector.write_u32::<LittleEndian>(new_difat_sector_id)?;
+                    if self.transaction.is_none() {
+                        let offset = self.sector_len() as u64 - 4;
+                        let mut sector = self
+                            .sectors
+                            .seek_within_sector(last_sector_id, offset)?;
+                        sector
+                            .write_u32::<LittleEndian>(new_difat_sector_id)?;
+                    }
                 }
                 self.difat_sector_ids.push(new_difat_sector_id);
                 // Update DIFAT chain fields in header.
-                let mut header = self.sectors.seek_within_header(68)?;
-                header.write_u32::<LittleEndian>(self.difat_sector_ids[0])?;
-                header.write_u32::<LittleEndian>(
+                let difat_chain_fields = [
+                    self.difat_sector_ids[0],
                     self.difat_sector_ids.len() as u32,
-                )?;
+                ];
+                self.write_header_fields(68, &difat_chain_fields)?;
             }
             // Write the new entry into the DIFAT sector.
-            let difat_sector_id = self.difat_sector_ids[difat_sector_index];
-            let index_within_difat_sector = difat_index
-                - consts::NUM_DIFAT_ENTRIES_IN_HEADER
-                - difat_sector_index * difat_entries_per_sector;
-            let mut sector = self.sectors.seek_within_sector(
-                difat_sector_id,
-                4 * index_within_difat_sector as u64,
-            )?;
-            sector.write_u32::<LittleEndian>(new_fat_sector_id)?;
+            if self.transaction.is_none() {
+                let difat_sector_id =
+                    self.difat_sector_ids[difat_sector_index];
+                let index_within_difat_sector = difat_index
+                    - consts::NUM_DIFAT_ENTRIES_IN_HEADER
+                    - difat_sector_index * difat_entries_per_sector;
+                let mut sector = self.sectors.seek_within_sector(
+                    difat_sector_id,
+                    4 * index_within_difat_sector as u64,
+                )?;
+                sector.write_u32::<LittleEndian>(new_fat_sector_id)?;
+            }
         }
 
         // Update length of FAT chain in header.
-        let mut header = self.sectors.seek_within_header(44)?;
-        header.write_u32::<LittleEndian>(self.difat.len() as u32)?;
-        Ok(())
+        self.write_header_fields(44, &[self.difat.len() as u32])
     }
 
-    /// Sets the given sector to point to `END_OF_CHAIN`, and deallocates all
+    /// Truncates the chain starting at the given sector so that it has only
+    /// `num_sectors` sectors (which must be nonzero), and deallocates all
     /// subsequent sectors in the chain.
-    pub fn free_chain_after(&mut self, sector_id: u32) -> io::Result<()> {
-        let next = self.next(sector_id)?;
-        self.set_fat(sector_id, consts::END_OF_CHAIN)?;
-        self.free_chain(next)?;
+    pub fn truncate_chain(
+        &mut self,
+        start_sector_id: u32,
+        num_sectors: usize,
+    ) -> io::Result<()> {
+        debug_assert!(num_sectors > 0);
+        self.load_chain(start_sector_id)?;
+        let freed_sector_ids =
+            self.chains.split_off(start_sector_id, num_sectors).unwrap();
+        if freed_sector_ids.is_empty() {
+            return Ok(());
+        }
+        let last_sector_id =
+            self.cached_chain(start_sector_id)[num_sectors - 1];
+        self.set_fat(last_sector_id, consts::END_OF_CHAIN)?;
+        for sector_id in freed_sector_ids {
+            self.free_sector(sector_id)?;
+        }
         Ok(())
     }
 
     /// Given the start sector of a chain, deallocates the entire chain.
     pub fn free_chain(&mut self, start_sector_id: u32) -> io::Result<()> {
+        self.chains.remove(start_sector_id);
         let mut sector_id = start_sector_id;
         while sector_id != consts::END_OF_CHAIN {
             let next = self.next(sector_id)?;
@@ -331,44 +640,451 @@ impl<F: Write + Seek> Allocator<F> {
     /// Deallocates the specified sector.
     fn free_sector(&mut self, sector_id: u32) -> io::Result<()> {
         self.set_fat(sector_id, consts::FREE_SECTOR)?;
-        // TODO: Truncate FAT if last FAT sector is now all free.
-        Ok(())
+        if let Some(ref snapshot) = self.transaction {
+            // The last committed state may still refer to this sector, so it
+            // can't be reused (or removed from the FAT) until the transaction
+            // is committed.
+            if snapshot.is_protected(sector_id) {
+                self.free_sectors.set_free(sector_id, false);
+            }
+            return Ok(());
+        }
+        self.truncate_fat()
+    }
+
+    /// Drops trailing free entries from the in-memory FAT, then releases any
+    /// FAT sectors (and DIFAT sectors) at the end of the DIFAT that no longer
+    /// cover any allocated sectors other than themselves.
+    fn truncate_fat(&mut self) -> io::Result<()> {
+        let fat_entries_per_sector =
+            self.sectors.sector_len() / size_of::<u32>();
+        loop {
+            while self.fat.last() == Some(&consts::FREE_SECTOR) {
+                self.fat.pop();
+            }
+            self.free_sectors.truncate(self.fat.len());
+            if self.difat.len() <= 1 {
+                return Ok(());
+            }
+            // Determine how long the FAT would be without the last FAT sector
+            // (and the last DIFAT sector, if it would become empty).
+            let (fat_sector_id, difat_sector_id) = self.last_fat_sectors();
+            let mut new_fat_len = self.fat.len();
+            while new_fat_len > 0 {
+                let sector_id = (new_fat_len - 1) as u32;
+                if self.fat[new_fat_len - 1] != consts::FREE_SECTOR
+                    && sector_id != fat_sector_id
+                    && Some(sector_id) != difat_sector_id
+                {
+                    break;
+                }
+                new_fat_len -= 1;
+            }
+            let remaining_capacity =
+                (self.difat.len() - 1) * fat_entries_per_sector;
+            if new_fat_len > remaining_capacity {
+                return Ok(());
+            }
+            self.remove_last_fat_sector()?;
+        }
+    }
+
+    /// Returns the ID of the last FAT sector, along with the ID of the last
+    /// DIFAT sector if removing that FAT sector would leave the last DIFAT
+    /// sector empty.
+    fn last_fat_sectors(&self) -> (u32, Option<u32>) {
+        let difat_entries_per_sector = (self.sector_len() - 4) / 4;
+        let difat_index = self.difat.len() - 1;
+        let difat_sector_id = if difat_index
+            >= consts::NUM_DIFAT_ENTRIES_IN_HEADER
+            && (difat_index - consts::NUM_DIFAT_ENTRIES_IN_HEADER)
+                % difat_entries_per_sector
+                == 0
+        {
+            self.difat_sector_ids.last().copied()
+        } else {
+            None
+        };
+        (self.difat[difat_index], difat_sector_id)
+    }
+
+    /// Removes the last FAT sector from the DIFAT and deallocates it, along
+    /// with the last DIFAT sector if that sector is now empty.  The FAT
+    /// sector being removed must not cover any allocated sectors other than
+    /// itself and that DIFAT sector.
+    fn remove_last_fat_sector(&mut self) -> io::Result<()> {
+        // Mark the sectors being removed as free in the FAT.  This must
+        // happen first, since their own FAT entries may be in the FAT sector
+        // that is being removed.
+        debug_assert!(self.transaction.is_none());
+        let (fat_sector_id, difat_sector_id) = self.last_fat_sectors();
+        for sector_id in Some(fat_sector_id).into_iter().chain(difat_sector_id)
+        {
+            if (sector_id as usize) < self.fat.len() {
+                self.set_fat(sector_id, consts::FREE_SECTOR)?;
+            }
+        }
+        self.difat.pop();
+        let difat_index = self.difat.len();
+
+        // Clear the DIFAT entry in the file.
+        if difat_index < consts::NUM_DIFAT_ENTRIES_IN_HEADER {
+            // This DIFAT entry is in the file header.
+            let offset = 76 + 4 * difat_index as u64;
+            self.write_header_fields(offset, &[consts::FREE_SECTOR])?;
+        } else if difat_sector_id.is_some() {
+            // That was the only entry in the last DIFAT sector, so remove that
+            // sector from the end of the DIFAT chain.
+            self.difat_sector_ids.pop();
+            if let Some(&last_sector_id) = self.difat_sector_ids.last() {
+                let offset = self.sector_len() as u64 - 4;
+                let mut sector =
+                    self.sectors.seek_within_sector(last_sector_id, offset)?;
+                sector.write_u32::<LittleEndian>(consts::END_OF_CHAIN)?;
+            }
+            // Update DIFAT chain fields in header.
+            let difat_chain_fields = [
+                self.difat_sector_ids
+                    .first()
+                    .copied()
+                    .unwrap_or(consts::END_OF_CHAIN),
+                self.difat_sector_ids.len() as u32,
+            ];
+            self.write_header_fields(68, &difat_chain_fields)?;
+        } else {
+            // This DIFAT entry is in a DIFAT sector that is still needed.
+            let difat_entries_per_sector = (self.sector_len() - 4) / 4;
+            let index = difat_index - consts::NUM_DIFAT_ENTRIES_IN_HEADER;
+            let difat_sector_id =
+                self.difat_sector_ids[index / difat_entries_per_sector];
+            let mut sector = self.sectors.seek_within_sector(
+                difat_sector_id,
+                4 * (index % difat_entries_per_sector) as u64,
+            )?;
+            sector.write_u32::<LittleEndian>(consts::FREE_SECTOR)?;
+        }
+
+        // Update length of FAT chain in header.
+        self.write_header_fields(44, &[self.difat.len() as u32])
     }
 
     /// Sets `self.fat[index] = value`, and also writes that change to the
-    /// underlying file.  The `index` must be <= `self.fat.len()`.
+    /// underlying file (or, in write-back mode, marks the FAT sector as
+    /// dirty).
+    /// The `index` must be <= `self.fat.len()`.
     fn set_fat(&mut self, index: u32, value: u32) -> io::Result<()> {
         let index = index as usize;
         debug_assert!(index <= self.fat.len());
         let fat_entries_per_sector =
             self.sectors.sector_len() / size_of::<u32>();
-        let fat_sector_id = self.difat[index / fat_entries_per_sector];
-        let offset_within_sector = 4 * (index % fat_entries_per_sector) as u64;
-        let mut sector = self
-            .sectors
-            .seek_within_sector(fat_sector_id, offset_within_sector)?;
-        sector.write_u32::<LittleEndian>(value)?;
+        if self.defers_writes() {
+            self.dirty_fat_sectors.insert(index / fat_entries_per_sector);
+        } else {
+            let fat_sector_id = self.difat[index / fat_entries_per_sector];
+            let offset_within_sector =
+                4 * (index % fat_entries_per_sector) as u64;
+            let mut sector = self
+                .sectors
+                .seek_within_sector(fat_sector_id, offset_within_sector)?;
+            sector.write_u32::<LittleEndian>(value)?;
+        }
         if index == self.fat.len() {
             self.fat.push(value);
         } else {
             self.fat[index] = value;
         }
+        self.free_sectors.set_free(index as u32, value == consts::FREE_SECTOR);
+        Ok(())
+    }
+
+    /// Writes each dirty FAT sector to the underlying file.
+    fn write_dirty_fat_sectors(&mut self) -> io::Result<()> {
+        let fat_entries_per_sector =
+            self.sectors.sector_len() / size_of::<u32>();
+        let mut buffer = Vec::with_capacity(self.sectors.sector_len());
+        for index in mem::take(&mut self.dirty_fat_sectors) {
+            // FAT sectors that have since been removed are skipped.
+            let fat_sector_id = match self.difat.get(index) {
+                Some(&sector_id) => sector_id,
+                None => continue,
+            };
+            buffer.clear();
+            let start = index * fat_entries_per_sector;
+            for entry_index in start..(start + fat_entries_per_sector) {
+                let entry = self
+                    .fat
+                    .get(entry_index)
+                    .copied()
+                    .unwrap_or(consts::FREE_SECTOR);
+                buffer.write_u32::<LittleEndian>(entry)?;
+            }
+            self.sectors.seek_to_sector(fat_sector_id)?.write_all(&buffer)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the whole DIFAT chain to the underlying file.
+    fn write_difat_sectors(&mut self) -> io::Result<()> {
+        let difat_entries_per_sector = (self.sector_len() - 4) / 4;
+        let mut buffer = Vec::with_capacity(self.sectors.sector_len());
+        for (index, &sector_id) in self.difat_sector_ids.iter().enumerate() {
+            buffer.clear();
+            let start = consts::NUM_DIFAT_ENTRIES_IN_HEADER
+                + index * difat_entries_per_sector;
+            for entry_index in start..(start + difat_entries_per_sector) {
+                let entry = self
+                    .difat
+                    .get(entry_index)
+                    .copied()
+                    .unwrap_or(consts::FREE_SECTOR);
+                buffer.write_u32::<LittleEndian>(entry)?;
+            }
+            let next = self
+                .difat_sector_ids
+                .get(index + 1)
+                .copied()
+                .unwrap_or(consts::END_OF_CHAIN);
+            buffer.write_u32::<LittleEndian>(next)?;
+            self.sectors.seek_to_sector(sector_id)?.write_all(&buffer)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the given values to consecutive fields of the file header,
+    /// starting at the given offset.  During a transaction this does nothing,
+    /// since the whole header is rewritten when the transaction is committed.
+    pub fn write_header_fields(
+        &mut self,
+        offset: u64,
+        values: &[u32],
+    ) -> io::Result<()> {
+        if self.transaction.is_some() {
+            return Ok(());
+        }
+        let mut header = self.sectors.seek_within_header(offset)?;
+        for &value in values {
+            header.write_u32::<LittleEndian>(value)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the ID of the sector at the given index within the chain
+    /// starting at the given sector, so that it can be written to.  During a
+    /// transaction, if that sector is still part of the last committed state,
+    /// it is first replaced within the chain by a fresh copy, and the ID of
+    /// the copy is returned instead (so if `index` is zero, the chain will
+    /// now start at the returned sector).
+    pub fn copy_on_write(
+        &mut self,
+        start_sector_id: u32,
+        index: usize,
+    ) -> io::Result<u32> {
+        self.load_chain(start_sector_id)?;
+        let old_sector_id = self.cached_chain(start_sector_id)[index];
+        let copy_sector = match self.transaction {
+            Some(ref snapshot) if snapshot.is_protected(old_sector_id) => {
+                snapshot.copy_sector
+            }
+            _ => return Ok(old_sector_id),
+        };
+        let new_sector_id = self.allocate_sector(SectorInit::Zero)?;
+        copy_sector(&mut self.sectors, old_sector_id, new_sector_id)?;
+        let next_sector_id = self.fat[old_sector_id as usize];
+        self.set_fat(new_sector_id, next_sector_id)?;
+        let mut sector_ids = self.chains.remove(start_sector_id).unwrap();
+        if index > 0 {
+            self.set_fat(sector_ids[index - 1], new_sector_id)?;
+        }
+        sector_ids[index] = new_sector_id;
+        self.chains.insert(sector_ids[0], sector_ids);
+        self.free_sector(old_sector_id)?;
+        Ok(new_sector_id)
+    }
+
+    /// Moves each FAT or DIFAT sector that needs rewriting, but that is still
+    /// part of the last committed state, to a fresh sector.  Moving a sector
+    /// changes FAT entries (and possibly the DIFAT), so this repeats until
+    /// everything left to rewrite is in fresh sectors.
+    fn relocate_fat_sectors(&mut self) -> io::Result<()> {
+        loop {
+            let snapshot = self.transaction.as_ref().unwrap();
+            let difat_changed = self.difat != snapshot.difat
+                || self.difat_sector_ids != snapshot.difat_sector_ids;
+            let difat_indices: Vec<usize> = if difat_changed {
+                (0..self.difat_sector_ids.len())
+                    .filter(|&index| {
+                        snapshot.is_protected(self.difat_sector_ids[index])
+                    })
+                    .collect()
+            } else {
+                Vec::new()
+            };
+            let fat_indices: Vec<usize> = self
+                .dirty_fat_sectors
+                .iter()
+                .copied()
+                .filter(|&index| {
+                    index < self.difat.len()
+                        && snapshot.is_protected(self.difat[index])
+                })
+                .collect();
+            if difat_indices.is_empty() && fat_indices.is_empty() {
+                return Ok(());
+            }
+            for index in difat_indices {
+                let new_sector_id = self.allocate_sector(SectorInit::Difat)?;
+                self.set_fat(new_sector_id, consts::DIFAT_SECTOR)?;
+                let old_sector_id = mem::replace(
+                    &mut self.difat_sector_ids[index],
+                    new_sector_id,
+                );
+                self.free_sector(old_sector_id)?;
+            }
+            for index in fat_indices {
+                let new_sector_id = self.allocate_sector(SectorInit::Fat)?;
+                self.set_fat(new_sector_id, consts::FAT_SECTOR)?;
+                let old_sector_id =
+                    mem::replace(&mut self.difat[index], new_sector_id);
+                self.free_sector(old_sector_id)?;
+            }
+        }
+    }
+
+    /// Commits the current transaction: writes the FAT and DIFAT (to fresh
+    /// sectors where necessary), and then switches the file over to the new
+    /// state by writing the header, whose directory and MiniFAT fields must
+    /// already be filled in.  Any sectors that were freed during the
+    /// transaction become available for reuse.  If this returns an error,
+    /// the transaction is still in progress (and can be reverted).
+    ///
+    /// Writes are ordered so that a crash at any point leaves the file
+    /// holding either the old state or the new one: everything but the
+    /// header is written and flushed first, and the header is then written
+    /// in one piece.  In journaling mode, the new header is also recorded in
+    /// the journal beforehand, so that if writing the header is interrupted,
+    /// it can be completed when the file is next opened.
+    pub fn commit_transaction(
+        &mut self,
+        mut header: Header,
+    ) -> io::Result<()> {
+        debug_assert!(self.transaction.is_some());
+        self.relocate_fat_sectors()?;
+        let difat_changed = {
+            let snapshot = self.transaction.as_ref().unwrap();
+            self.difat != snapshot.difat
+                || self.difat_sector_ids != snapshot.difat_sector_ids
+        };
+        self.write_dirty_fat_sectors()?;
+        if difat_changed {
+            self.write_difat_sectors()?;
+        }
+        // Make sure everything else is in place before switching over.
+        self.sectors.flush()?;
+        header.num_fat_sectors = self.difat.len() as u32;
+        header.first_difat_sector = self
+            .difat_sector_ids
+            .first()
+            .copied()
+            .unwrap_or(consts::END_OF_CHAIN);
+        header.num_difat_sectors = self.difat_sector_ids.len() as u32;
+        header.initial_difat_entries =
+            [consts::FREE_SECTOR; consts::NUM_DIFAT_ENTRIES_IN_HEADER];
+        for (entry, &sector_id) in
+            header.initial_difat_entries.iter_mut().zip(self.difat.iter())
+        {
+            *entry = sector_id;
+        }
+        if self.journal.is_some() {
+            header.transaction_signature = consts::JOURNALING_SIGNATURE;
+        }
+        let mut header_data = Vec::with_capacity(consts::HEADER_LEN);
+        header.write_to(&mut header_data)?;
+        if let Some(ref mut journal) = self.journal {
+            journal.record(&header_data)?;
+        }
+        self.sectors.seek_within_header(0)?.write_all(&header_data)?;
+        self.sectors.flush()?;
+        if let Some(ref mut journal) = self.journal {
+            journal.clear()?;
+        }
+        self.transaction = None;
+        self.free_sectors = FreeList::new(is_free(&self.fat));
+        Ok(())
+    }
+
+    /// Changes when FAT updates are written to the underlying file.  Any
+    /// pending changes are written out when switching to write-through mode
+    /// (unless a transaction is in progress).
+    pub fn set_write_mode(&mut self, write_mode: WriteMode) -> io::Result<()> {
+        if !write_mode.is_write_back() && self.transaction.is_none() {
+            self.write_dirty_fat_sectors()?;
+        }
+        self.write_mode = write_mode;
         Ok(())
     }
 
-    /// Flushes all changes to the underlying file.
+    /// Flushes all changes to the underlying file.  During a transaction,
+    /// only stream data is flushed; FAT changes wait until the transaction is
+    /// committed.
     pub fn flush(&mut self) -> io::Result<()> {
+        if self.transaction.is_none() {
+            self.write_dirty_fat_sectors()?;
+        }
         self.sectors.flush()
     }
 }
 
+impl<F: Read + Write + Seek> Allocator<F> {
+    /// Begins a transaction.  Until it is committed or reverted, no sector
+    /// that is currently allocated will be written to, and the header, FAT,
+    /// and DIFAT will not be updated in the file.
+    pub fn begin_transaction(&mut self) -> io::Result<()> {
+        if self.transaction.is_some() {
+            invalid_input!("A transaction is already in progress");
+        }
+        self.write_dirty_fat_sectors()?;
+        self.transaction = Some(Snapshot {
+            difat_sector_ids: self.difat_sector_ids.clone(),
+            difat: self.difat.clone(),
+            fat: self.fat.clone(),
+            copy_sector: Sectors::copy_sector,
+        });
+        Ok(())
+    }
+}
+
+impl<F: Write + Seek + SetLen> Allocator<F> {
+    /// Truncates the underlying file to remove any free sectors at the end.
+    pub fn shrink_to_fit(&mut self) -> io::Result<()> {
+        if self.journal.is_some() {
+            invalid_input!(
+                "Cannot shrink a compound file while in journaling mode"
+            );
+        }
+        if self.transaction.is_some() {
+            invalid_input!(
+                "Cannot shrink a compound file while a transaction is in \
+                 progress"
+            );
+        }
+        self.truncate_fat()?;
+        let num_sectors = self.fat.len() as u32;
+        if num_sectors < self.sectors.num_sectors() {
+            self.sectors.set_num_sectors(num_sectors)?;
+        }
+        Ok(())
+    }
+}
+
 //===========================================================================//
 
 #[cfg(test)]
 mod tests {
     use super::Allocator;
-    use crate::internal::{consts, Sectors, Validation, Version};
-    use std::io::Cursor;
+    use crate::internal::{
+        consts, SectorInit, Sectors, ValidationPolicy, Version,
+    };
+    use std::io::{self, Cursor};
 
     fn make_sectors(
         version: Version,
@@ -381,14 +1097,14 @@ mod tests {
     fn make_allocator(
         difat: Vec<u32>,
         fat: Vec<u32>,
-        validation: Validation,
+        policy: ValidationPolicy,
     ) -> Allocator<Cursor<Vec<u8>>> {
         Allocator::new(
             make_sectors(Version::V3, fat.len()),
             vec![],
             difat,
             fat,
-            validation,
+            &mut policy.into(),
         )
         .unwrap()
     }
@@ -402,8 +1118,14 @@ mod tests {
         let difat = vec![0];
         let fat = vec![consts::FAT_SECTOR, 2, consts::END_OF_CHAIN];
         let sectors = make_sectors(Version::V3, 2);
-        Allocator::new(sectors, vec![], difat, fat, Validation::Strict)
-            .unwrap();
+        Allocator::new(
+            sectors,
+            vec![],
+            difat,
+            fat,
+            &mut ValidationPolicy::strict().into(),
+        )
+        .unwrap();
     }
 
     #[test]
@@ -416,8 +1138,14 @@ mod tests {
         let difat = vec![0];
         let fat = vec![consts::FAT_SECTOR, consts::END_OF_CHAIN];
         let sectors = make_sectors(Version::V3, fat.len());
-        Allocator::new(sectors, difat_sectors, difat, fat, Validation::Strict)
-            .unwrap();
+        Allocator::new(
+            sectors,
+            difat_sectors,
+            difat,
+            fat,
+            &mut ValidationPolicy::strict().into(),
+        )
+        .unwrap();
     }
 
     #[test]
@@ -430,8 +1158,14 @@ mod tests {
         let difat = vec![0];
         let fat = vec![consts::FAT_SECTOR, consts::END_OF_CHAIN];
         let sectors = make_sectors(Version::V3, fat.len());
-        Allocator::new(sectors, difat_sectors, difat, fat, Validation::Strict)
-            .unwrap();
+        Allocator::new(
+            sectors,
+            difat_sectors,
+            difat,
+            fat,
+            &mut ValidationPolicy::strict().into(),
+        )
+        .unwrap();
     }
 
     #[test]
@@ -447,13 +1181,13 @@ mod tests {
             difat_sectors,
             difat,
             fat,
-            Validation::Permissive,
+            &mut ValidationPolicy::permissive().into(),
         )
         .unwrap();
         // We should repair the FAT entry, and the resulting Allocator should
         // now pass Strict validation.
         assert_eq!(allocator.fat[1], consts::DIFAT_SECTOR);
-        allocator.validate(Validation::Strict).unwrap();
+        allocator.validate(&mut ValidationPolicy::strict().into()).unwrap();
     }
 
     #[test]
@@ -464,7 +1198,7 @@ mod tests {
     fn fat_sector_out_of_range() {
         let difat = vec![0, 3];
         let fat = vec![consts::FAT_SECTOR, consts::END_OF_CHAIN];
-        make_allocator(difat, fat, Validation::Permissive);
+        make_allocator(difat, fat, ValidationPolicy::permissive());
     }
 
     #[test]
@@ -475,7 +1209,7 @@ mod tests {
     fn fat_sector_not_marked_in_fat_strict() {
         let difat = vec![0, 1];
         let fat = vec![consts::FAT_SECTOR, consts::END_OF_CHAIN];
-        make_allocator(difat, fat, Validation::Strict);
+        make_allocator(difat, fat, ValidationPolicy::strict());
     }
 
     // Regression test for https://github.com/mdsteele/rust-cfb/issues/30
@@ -485,11 +1219,12 @@ mod tests {
         let fat = vec![consts::FAT_SECTOR, consts::END_OF_CHAIN];
         // Marking the second FAT sector as END_OF_CHAIN instead of FAT_SECTOR
         // is a spec violation, but is tolerated under Permissive validation.
-        let mut allocator = make_allocator(difat, fat, Validation::Permissive);
+        let mut allocator =
+            make_allocator(difat, fat, ValidationPolicy::permissive());
         // We should repair the FAT entry, and the resulting Allocator should
         // now pass Strict validation.
         assert_eq!(allocator.fat[1], consts::FAT_SECTOR);
-        allocator.validate(Validation::Strict).unwrap();
+        allocator.validate(&mut ValidationPolicy::strict().into()).unwrap();
     }
 
     #[test]
@@ -500,7 +1235,7 @@ mod tests {
     fn pointee_out_of_range() {
         let difat = vec![0];
         let fat = vec![consts::FAT_SECTOR, 2];
-        make_allocator(difat, fat, Validation::Permissive);
+        make_allocator(difat, fat, ValidationPolicy::permissive());
     }
 
     #[test]
@@ -508,7 +1243,7 @@ mod tests {
     fn double_pointee() {
         let difat = vec![0];
         let fat = vec![consts::FAT_SECTOR, 3, 3, consts::END_OF_CHAIN];
-        make_allocator(difat, fat, Validation::Permissive);
+        make_allocator(difat, fat, ValidationPolicy::permissive());
     }
 
     #[test]
@@ -518,7 +1253,125 @@ mod tests {
     fn invalid_pointee() {
         let difat = vec![0];
         let fat = vec![consts::FAT_SECTOR, consts::INVALID_SECTOR];
-        make_allocator(difat, fat, Validation::Permissive);
+        make_allocator(difat, fat, ValidationPolicy::permissive());
+    }
+
+    /// Walks the FAT directly (bypassing the chain cache) to list the sectors
+    /// in a chain.
+    fn walk_fat(
+        allocator: &Allocator<Cursor<Vec<u8>>>,
+        start: u32,
+    ) -> Vec<u32> {
+        let mut sector_ids = Vec::new();
+        let mut sector_id = start;
+        while sector_id != consts::END_OF_CHAIN {
+            sector_ids.push(sector_id);
+            sector_id = allocator.fat[sector_id as usize];
+        }
+        sector_ids
+    }
+
+    #[test]
+    fn chain_cache_tracks_fat() {
+        let difat = vec![0];
+        let fat = vec![consts::FAT_SECTOR];
+        let mut allocator =
+            make_allocator(difat, fat, ValidationPolicy::strict());
+        let start1 = allocator.begin_chain(SectorInit::Zero).unwrap();
+        let start2 = allocator.begin_chain(SectorInit::Zero).unwrap();
+        for _ in 0..4 {
+            allocator.extend_chain(start1, SectorInit::Zero).unwrap();
+            allocator.extend_chain(start2, SectorInit::Zero).unwrap();
+        }
+        for &start in &[start1, start2] {
+            let chain = allocator.open_chain(start, SectorInit::Zero).unwrap();
+            assert_eq!(chain.num_sectors(), 5);
+            let sector_ids = chain.sector_ids().to_vec();
+            assert_eq!(sector_ids, walk_fat(&allocator, start));
+        }
+
+        // Truncating or freeing a chain should update the cache.
+        allocator.truncate_chain(start1, 2).unwrap();
+        allocator.free_chain(start2).unwrap();
+        assert_eq!(
+            allocator.cached_chain(start1),
+            &walk_fat(&allocator, start1)[..]
+        );
+        assert_eq!(allocator.cached_chain(start1).len(), 2);
+
+        // A new chain that reuses freed sectors (including the old start
+        // sector of the freed chain) should not see stale cached data.
+        let start3 = allocator.begin_chain(SectorInit::Zero).unwrap();
+        assert_eq!(start3, start2);
+        allocator.extend_chain(start3, SectorInit::Zero).unwrap();
+        let chain = allocator.open_chain(start3, SectorInit::Zero).unwrap();
+        let sector_ids = chain.sector_ids().to_vec();
+        assert_eq!(sector_ids, walk_fat(&allocator, start3));
+        assert_eq!(sector_ids.len(), 2);
+    }
+
+    #[test]
+    fn extend_chain_from_internal_sector() {
+        let difat = vec![0];
+        let fat = vec![consts::FAT_SECTOR];
+        let mut allocator =
+            make_allocator(difat, fat, ValidationPolicy::strict());
+        let start = allocator.begin_chain(SectorInit::Zero).unwrap();
+        let last = allocator.extend_chain(start, SectorInit::Zero).unwrap();
+        allocator.extend_chain(last, SectorInit::Zero).unwrap();
+        let chain = allocator.open_chain(start, SectorInit::Zero).unwrap();
+        let sector_ids = chain.sector_ids().to_vec();
+        assert_eq!(sector_ids, walk_fat(&allocator, start));
+        assert_eq!(sector_ids.len(), 3);
+    }
+
+    #[test]
+    fn nested_transaction_is_an_error() {
+        let difat = vec![0];
+        let fat = vec![consts::FAT_SECTOR];
+        let mut allocator =
+            make_allocator(difat, fat, ValidationPolicy::strict());
+        allocator.begin_transaction().unwrap();
+        let error = allocator.begin_transaction().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(allocator.in_transaction());
+    }
+
+    #[test]
+    fn free_list_tracks_fat() {
+        let difat = vec![0];
+        let fat = vec![consts::FAT_SECTOR];
+        let mut allocator =
+            make_allocator(difat, fat, ValidationPolicy::strict());
+        let start1 = allocator.begin_chain(SectorInit::Zero).unwrap();
+        let start2 = allocator.begin_chain(SectorInit::Zero).unwrap();
+        for _ in 0..4 {
+            allocator.extend_chain(start1, SectorInit::Zero).unwrap();
+            allocator.extend_chain(start2, SectorInit::Zero).unwrap();
+            allocator.verify_free_sectors().unwrap();
+        }
+        let freed = walk_fat(&allocator, start1)[2..].to_vec();
+        allocator.truncate_chain(start1, 2).unwrap();
+        allocator.verify_free_sectors().unwrap();
+        // Freed sectors are reused, lowest first.
+        let start3 = allocator.begin_chain(SectorInit::Zero).unwrap();
+        assert_eq!(start3, freed[0]);
+        allocator.verify_free_sectors().unwrap();
+        allocator.free_chain(start2).unwrap();
+        allocator.verify_free_sectors().unwrap();
+
+        // Sectors freed during a transaction aren't reused until it's
+        // committed, but sectors allocated and freed within it are.
+        allocator.begin_transaction().unwrap();
+        allocator.free_chain(start3).unwrap();
+        allocator.verify_free_sectors().unwrap();
+        let start4 = allocator.begin_chain(SectorInit::Zero).unwrap();
+        assert_ne!(start4, start3);
+        allocator.verify_free_sectors().unwrap();
+        allocator.free_chain(start4).unwrap();
+        allocator.verify_free_sectors().unwrap();
+        assert_eq!(allocator.begin_chain(SectorInit::Zero).unwrap(), start4);
+        allocator.verify_free_sectors().unwrap();
     }
 }
 
@@ -0,0 +1,775 @@
+use crate::internal::consts;
+use crate::internal::path::ObjectPath;
+use crate::internal::stream::write_data_to_stream;
+use crate::internal::{
+    seek_within, Entries, Entry, Header, NotCached, SectorCache, SectorInit,
+    ValidationPolicy, Version, Violation,
+};
+use crate::CompoundFile;
+use byteorder::{LittleEndian, ReadBytesExt};
+use fnv::FnvHashSet;
+use futures_io::{AsyncRead, AsyncSeek, AsyncWrite};
+use std::future::poll_fn;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+//===========================================================================//
+
+/// A compound file whose underlying file is accessed asynchronously, through
+/// the `futures-io` traits `AsyncRead`, `AsyncSeek`, and (for writing)
+/// `AsyncWrite`.  Files implementing tokio's I/O traits can be used by way of
+/// the `compat` adapters in the `tokio-util` crate.
+///
+/// Opening the file loads its header, allocation tables, and directory (and
+/// the mini stream) into memory.  After that, listing entries never touches
+/// the underlying file, and streams are read with
+/// [`AsyncStream`](struct.AsyncStream.html).  Changes (including new
+/// storages and streams) are held in memory until they are written to the
+/// underlying file by [`flush`](#method.flush).  Unlike `CompoundFile`, an
+/// `AsyncCompoundFile` cannot flush its changes when dropped, so any changes
+/// that haven't been flushed are discarded.
+pub struct AsyncCompoundFile<F> {
+    inner: F,
+    comp: CompoundFile<SectorCache>,
+    write_back: Option<WriteBack>,
+}
+
+impl<F> AsyncCompoundFile<F> {
+    /// Returns the CFB format version used for this compound file.
+    pub fn version(&self) -> Version {
+        self.comp.version()
+    }
+
+    /// Returns every deviation from the CFB spec that the validation policy
+    /// tolerated when this compound file was opened.
+    pub fn warnings(&self) -> &[Violation] {
+        self.comp.warnings()
+    }
+
+    /// Returns information about the root storage object.
+    pub fn root_entry(&self) -> Entry {
+        self.comp.root_entry()
+    }
+
+    /// Given a path within the compound file, get information about that
+    /// stream or storage object.
+    pub fn entry<P: ObjectPath>(&self, path: P) -> io::Result<Entry> {
+        self.comp.entry(path)
+    }
+
+    /// Returns an iterator over the entries within the root storage object.
+    pub fn read_root_storage(&self) -> Entries<SectorCache> {
+        self.comp.read_root_storage()
+    }
+
+    /// Returns an iterator over the entries within a storage object.
+    pub fn read_storage<P: ObjectPath>(
+        &self,
+        path: P,
+    ) -> io::Result<Entries<SectorCache>> {
+        self.comp.read_storage(path)
+    }
+
+    /// Returns an iterator over all entries within the compound file, in
+    /// preorder.
+    pub fn walk(&self) -> Entries<SectorCache> {
+        self.comp.walk()
+    }
+
+    /// Returns an iterator over all entries under a storage subtree, in
+    /// preorder.
+    pub fn walk_storage<P: ObjectPath>(
+        &self,
+        path: P,
+    ) -> io::Result<Entries<SectorCache>> {
+        self.comp.walk_storage(path)
+    }
+
+    /// Returns true if there is an existing stream or storage at the given
+    /// path, or false if there is nothing at that path.
+    pub fn exists<P: ObjectPath>(&self, path: P) -> bool {
+        self.comp.exists(path)
+    }
+
+    /// Returns true if there is an existing stream at the given path, or
+    /// false if there is a storage or nothing at that path.
+    pub fn is_stream<P: ObjectPath>(&self, path: P) -> bool {
+        self.comp.is_stream(path)
+    }
+
+    /// Returns true if there is an existing storage at the given path, or
+    /// false if there is a stream or nothing at that path.
+    pub fn is_storage<P: ObjectPath>(&self, path: P) -> bool {
+        self.comp.is_storage(path)
+    }
+
+    /// Opens an existing stream in the compound file for reading and/or
+    /// writing (depending on what the underlying file supports).
+    pub fn open_stream<P: ObjectPath>(
+        &mut self,
+        path: P,
+    ) -> io::Result<AsyncStream<F>> {
+        let stream_id = self.comp.stream_id_for_path(&path)?;
+        Ok(AsyncStream::new(self, stream_id))
+    }
+
+    /// Consumes the `AsyncCompoundFile`, returning the underlying file.  Any
+    /// changes that haven't been flushed are discarded.
+    pub fn into_inner(self) -> F {
+        self.inner
+    }
+
+    /// Drops cached data that has been written back to the underlying file,
+    /// other than the file's header, allocation tables, directory, and mini
+    /// stream.
+    fn evict(&mut self) -> io::Result<()> {
+        let mut minialloc = self.comp.minialloc_mut();
+        let sector_len = minialloc.sector_len() as u64;
+        let keep: FnvHashSet<u32> =
+            minialloc.metadata_sector_ids()?.into_iter().collect();
+        minialloc.inner_mut().evict(|offset| {
+            let index = offset / sector_len;
+            index == 0 || keep.contains(&((index - 1) as u32))
+        });
+        Ok(())
+    }
+}
+
+impl<F: AsyncRead + AsyncSeek + Unpin> AsyncCompoundFile<F> {
+    /// Opens an existing compound file, using the underlying reader.  If the
+    /// underlying reader also supports the `AsyncWrite` trait, then the
+    /// `AsyncCompoundFile` object will be writable as well.
+    pub async fn open(inner: F) -> io::Result<AsyncCompoundFile<F>> {
+        AsyncCompoundFile::open_with_policy(
+            inner,
+            ValidationPolicy::permissive(),
+        )
+        .await
+    }
+
+    /// Like `open()`, but is stricter when parsing and will return an error if
+    /// the file violates the CFB spec in any way.
+    pub async fn open_strict(inner: F) -> io::Result<AsyncCompoundFile<F>> {
+        AsyncCompoundFile::open_with_policy(inner, ValidationPolicy::strict())
+            .await
+    }
+
+    /// Like `open()`, but uses the given policy to decide which deviations
+    /// from the CFB spec to tolerate when parsing.
+    pub async fn open_with_policy(
+        mut inner: F,
+        policy: ValidationPolicy,
+    ) -> io::Result<AsyncCompoundFile<F>> {
+        let inner_len = seek(&mut inner, SeekFrom::End(0)).await?;
+        let mut cache = SectorCache::new(inner_len);
+        load(&mut inner, &mut cache, 0, consts::HEADER_LEN as u64).await?;
+        cache.seek(SeekFrom::Start(0))?;
+        let mut sector_len = consts::HEADER_LEN as u64;
+        if let Ok(header) = Header::read_from(&mut cache, &mut policy.into()) {
+            sector_len = header.version.sector_len() as u64;
+            prefetch_tables(&mut inner, &mut cache, &header).await?;
+        }
+        // Parse the file from the cache.  The prefetch above normally loads
+        // everything that parsing needs, but if it missed anything (e.g.
+        // because the file is malformed in some way that the parser
+        // tolerates), load the missing sector and try again.
+        let mini_stream_sector_ids = loop {
+            let mut validator = policy.into();
+            let error = match CompoundFile::open_internal(
+                &mut cache,
+                &mut validator,
+            ) {
+                Ok(mut comp) => break mini_stream_sector_ids(&mut comp)?,
+                Err(error) => error,
+            };
+            match NotCached::offset_of(&error) {
+                Some(offset) => {
+                    let start = offset - offset % sector_len;
+                    let loaded =
+                        load(&mut inner, &mut cache, start, sector_len)
+                            .await?;
+                    if !loaded {
+                        return Err(error);
+                    }
+                }
+                None => return Err(error),
+            }
+        };
+        let ranges = sector_ranges(mini_stream_sector_ids, sector_len);
+        for (offset, len) in ranges {
+            load(&mut inner, &mut cache, offset, len).await?;
+        }
+        let comp = CompoundFile::open_internal(cache, &mut policy.into())?;
+        Ok(AsyncCompoundFile { inner, comp, write_back: None })
+    }
+}
+
+impl<F: AsyncRead + AsyncWrite + AsyncSeek + Unpin> AsyncCompoundFile<F> {
+    /// Creates a new compound file with no contents, using the underlying
+    /// reader/writer.  The reader/writer should be initially empty.
+    pub async fn create(inner: F) -> io::Result<AsyncCompoundFile<F>> {
+        AsyncCompoundFile::create_with_version(Version::V4, inner).await
+    }
+
+    /// Creates a new compound file of the given version with no contents,
+    /// using the underlying writer.  The writer should be initially empty.
+    pub async fn create_with_version(
+        version: Version,
+        inner: F,
+    ) -> io::Result<AsyncCompoundFile<F>> {
+        let comp =
+            CompoundFile::create_with_version(version, SectorCache::new(0))?;
+        let mut comp = AsyncCompoundFile { inner, comp, write_back: None };
+        comp.flush().await?;
+        Ok(comp)
+    }
+
+    /// Creates a new, empty storage object (i.e. "directory") at the provided
+    /// path.  The parent storage object must already exist.  Like all
+    /// changes, this is written to the underlying file by `flush`.
+    pub fn create_storage<P: ObjectPath>(
+        &mut self,
+        path: P,
+    ) -> io::Result<()> {
+        self.comp.create_storage(path)
+    }
+
+    /// Recursively creates a storage and all of its parent storages if they
+    /// are missing.
+    pub fn create_storage_all<P: ObjectPath>(
+        &mut self,
+        path: P,
+    ) -> io::Result<()> {
+        self.comp.create_storage_all(path)
+    }
+
+    /// Removes the storage object at the provided path.  The storage object
+    /// must exist and have no children.
+    pub fn remove_storage<P: ObjectPath>(
+        &mut self,
+        path: P,
+    ) -> io::Result<()> {
+        self.comp.remove_storage(path)
+    }
+
+    /// Recursively removes a storage and all of its children.  If called on
+    /// the root storage, recursively removes all of its children but not the
+    /// root storage itself (which cannot be removed).
+    pub fn remove_storage_all<P: ObjectPath>(
+        &mut self,
+        path: P,
+    ) -> io::Result<()> {
+        self.comp.remove_storage_all(path)
+    }
+
+    /// Creates and returns a new, empty stream object at the provided path.
+    /// If a stream already exists at that path, it will be replaced by the
+    /// new stream.  The parent storage object must already exist.
+    pub fn create_stream<P: ObjectPath>(
+        &mut self,
+        path: P,
+    ) -> io::Result<AsyncStream<F>> {
+        self.comp.create_stream_with_path(&path, true)?;
+        self.open_stream(path)
+    }
+
+    /// Creates and returns a new, empty stream object at the provided path.
+    /// Returns an error if a stream already exists at that path.  The parent
+    /// storage object must already exist.
+    pub fn create_new_stream<P: ObjectPath>(
+        &mut self,
+        path: P,
+    ) -> io::Result<AsyncStream<F>> {
+        self.comp.create_stream_with_path(&path, false)?;
+        self.open_stream(path)
+    }
+
+    /// Removes the stream object at the provided path.
+    pub fn remove_stream<P: ObjectPath>(&mut self, path: P) -> io::Result<()> {
+        self.comp.remove_stream(path)
+    }
+
+    /// Renames (or moves) a stream or storage object from one path to
+    /// another.
+    pub fn rename<P: ObjectPath, Q: ObjectPath>(
+        &mut self,
+        from: P,
+        to: Q,
+    ) -> io::Result<()> {
+        self.comp.rename(from, to)
+    }
+
+    /// Writes all changes to the underlying file, and flushes it.
+    pub async fn flush(&mut self) -> io::Result<()> {
+        poll_fn(|cx| self.poll_flush(cx)).await
+    }
+
+    fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            // If an earlier flush was abandoned partway through, finish it,
+            // and then start another to pick up any changes made since.
+            let is_fresh = self.write_back.is_none();
+            ready!(self.poll_write_back(cx))?;
+            if is_fresh {
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+
+    fn poll_write_back(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.write_back.is_none() {
+            self.comp.flush()?;
+            let runs = self.comp.minialloc_mut().inner_mut().take_dirty_runs();
+            self.write_back = Some(WriteBack::new(runs));
+        }
+        let write_back = self.write_back.as_mut().unwrap();
+        let result = ready!(write_back.poll(&mut self.inner, cx));
+        let write_back = self.write_back.take().unwrap();
+        match result {
+            Ok(()) => Poll::Ready(self.evict()),
+            Err(error) => {
+                let mut minialloc = self.comp.minialloc_mut();
+                write_back.restore(minialloc.inner_mut());
+                Poll::Ready(Err(error))
+            }
+        }
+    }
+}
+
+//===========================================================================//
+
+/// The state of writing cached changes back to the underlying file.
+struct WriteBack {
+    runs: Vec<(u64, Vec<u8>)>,
+    next_run: usize,
+    written: usize,
+    seeked: bool,
+}
+
+impl WriteBack {
+    fn new(runs: Vec<(u64, Vec<u8>)>) -> WriteBack {
+        WriteBack { runs, next_run: 0, written: 0, seeked: false }
+    }
+
+    fn poll<F: AsyncWrite + AsyncSeek + Unpin>(
+        &mut self,
+        inner: &mut F,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        while let Some((offset, data)) = self.runs.get(self.next_run) {
+            if !self.seeked {
+                let position = SeekFrom::Start(offset + self.written as u64);
+                ready!(Pin::new(&mut *inner).poll_seek(cx, position))?;
+                self.seeked = true;
+            }
+            let result =
+                ready!(Pin::new(&mut *inner)
+                    .poll_write(cx, &data[self.written..]));
+            let num_bytes = match result {
+                Ok(0) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "failed to write whole buffer",
+                    )));
+                }
+                Ok(num_bytes) => num_bytes,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {
+                    continue;
+                }
+                Err(error) => return Poll::Ready(Err(error)),
+            };
+            self.written += num_bytes;
+            if self.written == data.len() {
+                self.next_run += 1;
+                self.written = 0;
+                self.seeked = false;
+            }
+        }
+        Pin::new(inner).poll_flush(cx)
+    }
+
+    /// Marks all of the runs as needing to be written back again, after the
+    /// write back failed.
+    fn restore(self, cache: &mut SectorCache) {
+        for (offset, data) in self.runs {
+            cache.mark_dirty(offset, data.len() as u64);
+        }
+    }
+}
+
+//===========================================================================//
+
+/// A stream entry in an [`AsyncCompoundFile`](struct.AsyncCompoundFile.html),
+/// which implements the `futures-io` traits `AsyncRead`, `AsyncSeek`, and
+/// (if the compound file is writable) `AsyncWrite`.
+///
+/// Reads of data in the mini stream are served from memory; other reads go
+/// to the underlying file.  Writes are held in memory until they are flushed,
+/// either with `AsyncWriteExt::flush` on the stream or with
+/// `AsyncCompoundFile::flush`.
+pub struct AsyncStream<'a, F> {
+    file: &'a mut AsyncCompoundFile<F>,
+    stream_id: u32,
+    /// The file offsets and length of the stream's sectors, or `None` if the
+    /// stream has been written to since they were last computed.
+    layout: Option<(Vec<u64>, u64)>,
+    total_len: u64,
+    position: u64,
+    /// True if the underlying file has been seeked to the start of the read
+    /// currently in progress.
+    seeked: bool,
+}
+
+impl<'a, F> AsyncStream<'a, F> {
+    fn new(
+        file: &'a mut AsyncCompoundFile<F>,
+        stream_id: u32,
+    ) -> AsyncStream<'a, F> {
+        let total_len = file.comp.minialloc().dir_entry(stream_id).stream_len;
+        AsyncStream {
+            file,
+            stream_id,
+            layout: None,
+            total_len,
+            position: 0,
+            seeked: false,
+        }
+    }
+
+    /// Returns the current length of the stream, in bytes.
+    pub fn len(&self) -> u64 {
+        self.total_len
+    }
+
+    /// Returns true if the stream is empty.
+    pub fn is_empty(&self) -> bool {
+        self.total_len == 0
+    }
+
+    /// Returns the file offset and length of the longest contiguous run of
+    /// stream data (up to `max_len` bytes) starting at the current position.
+    fn next_run(&mut self, max_len: usize) -> io::Result<(u64, usize)> {
+        if self.layout.is_none() {
+            let (sector_offsets, sector_len, _) =
+                self.file.comp.stream_sector_offsets_for_id(self.stream_id)?;
+            self.layout = Some((sector_offsets, sector_len as u64));
+        }
+        let (sector_offsets, sector_len) = self.layout.as_ref().unwrap();
+        let sector_len = *sector_len;
+        let max_len = (max_len as u64).min(self.total_len - self.position);
+        let mut index = (self.position / sector_len) as usize;
+        let offset = sector_offsets[index] + self.position % sector_len;
+        let mut run_len = sector_len - self.position % sector_len;
+        index += 1;
+        while run_len < max_len
+            && index < sector_offsets.len()
+            && sector_offsets[index] == offset + run_len
+        {
+            run_len += sector_len;
+            index += 1;
+        }
+        Ok((offset, run_len.min(max_len) as usize))
+    }
+
+    /// Reads the given run from the cache, if all of it is cached.
+    fn read_cached(
+        &mut self,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> io::Result<bool> {
+        let mut minialloc = self.file.comp.minialloc_mut();
+        let cache = minialloc.inner_mut();
+        if !cache.contains(offset, buf.len() as u64) {
+            return Ok(false);
+        }
+        cache.seek(SeekFrom::Start(offset))?;
+        cache.read_exact(buf)?;
+        Ok(true)
+    }
+
+    fn seek_to(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = seek_within(pos, self.position, self.total_len)?;
+        self.seeked = false;
+        Ok(self.position)
+    }
+}
+
+impl<'a, F: AsyncRead + AsyncSeek + Unpin> AsyncRead for AsyncStream<'a, F> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let stream = self.get_mut();
+        if buf.is_empty() || stream.position >= stream.total_len {
+            return Poll::Ready(Ok(0));
+        }
+        let (offset, run_len) = stream.next_run(buf.len())?;
+        let buf = &mut buf[..run_len];
+        if stream.read_cached(offset, buf)? {
+            stream.position += run_len as u64;
+            return Poll::Ready(Ok(run_len));
+        }
+        let inner = &mut stream.file.inner;
+        if !stream.seeked {
+            ready!(
+                Pin::new(&mut *inner).poll_seek(cx, SeekFrom::Start(offset))
+            )?;
+            stream.seeked = true;
+        }
+        let result = ready!(Pin::new(inner).poll_read(cx, buf));
+        stream.seeked = false;
+        let num_bytes = result?;
+        if num_bytes == 0 {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Stream data at offset {} is past end of file",
+                    offset
+                ),
+            )));
+        }
+        let mut minialloc = stream.file.comp.minialloc_mut();
+        minialloc.inner_mut().overlay_dirty(offset, &mut buf[..num_bytes]);
+        stream.position += num_bytes as u64;
+        Poll::Ready(Ok(num_bytes))
+    }
+}
+
+impl<'a, F> AsyncSeek for AsyncStream<'a, F> {
+    fn poll_seek(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        pos: SeekFrom,
+    ) -> Poll<io::Result<u64>> {
+        Poll::Ready(self.get_mut().seek_to(pos))
+    }
+}
+
+impl<'a, F: AsyncRead + AsyncWrite + AsyncSeek + Unpin> AsyncWrite
+    for AsyncStream<'a, F>
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let stream = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        write_data_to_stream(
+            &mut stream.file.comp.minialloc_mut(),
+            stream.stream_id,
+            stream.position,
+            buf,
+        )?;
+        stream.position += buf.len() as u64;
+        stream.total_len = stream.total_len.max(stream.position);
+        stream.layout = None;
+        stream.seeked = false;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let stream = self.get_mut();
+        stream.seeked = false;
+        stream.file.poll_flush(cx)
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+//===========================================================================//
+
+async fn seek<F: AsyncSeek + Unpin>(
+    inner: &mut F,
+    pos: SeekFrom,
+) -> io::Result<u64> {
+    poll_fn(|cx| Pin::new(&mut *inner).poll_seek(cx, pos)).await
+}
+
+async fn read_exact_at<F: AsyncRead + AsyncSeek + Unpin>(
+    inner: &mut F,
+    offset: u64,
+    buf: &mut [u8],
+) -> io::Result<()> {
+    seek(inner, SeekFrom::Start(offset)).await?;
+    let mut filled = 0;
+    while filled < buf.len() {
+        let remaining = &mut buf[filled..];
+        match poll_fn(|cx| Pin::new(&mut *inner).poll_read(cx, remaining))
+            .await
+        {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "failed to fill whole buffer",
+                ));
+            }
+            Ok(num_bytes) => filled += num_bytes,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(())
+}
+
+/// Loads whatever parts of the given range of the underlying file aren't
+/// already in the cache.  Returns true if anything was loaded.
+async fn load<F: AsyncRead + AsyncSeek + Unpin>(
+    inner: &mut F,
+    cache: &mut SectorCache,
+    offset: u64,
+    len: u64,
+) -> io::Result<bool> {
+    let ranges = cache.missing_ranges(offset, len);
+    for &(start, range_len) in ranges.iter() {
+        let mut buffer = vec![0u8; range_len as usize];
+        read_exact_at(inner, start, &mut buffer).await?;
+        cache.fill(start, &buffer);
+    }
+    Ok(!ranges.is_empty())
+}
+
+/// Loads the DIFAT, FAT, directory, and MiniFAT sectors of the file
+/// described by `header` into the cache, fetching runs of adjacent sectors
+/// together.  Anything that doesn't make sense (such as an invalid sector ID)
+/// is skipped, and left for the parser to report.
+async fn prefetch_tables<F: AsyncRead + AsyncSeek + Unpin>(
+    inner: &mut F,
+    cache: &mut SectorCache,
+    header: &Header,
+) -> io::Result<()> {
+    let sector_len = header.version.sector_len() as u64;
+    let num_sectors =
+        ((cache.len() + sector_len - 1) / sector_len).saturating_sub(1);
+    let is_valid = |sector_id: u32| {
+        sector_id <= consts::MAX_REGULAR_SECTOR
+            && (sector_id as u64) < num_sectors
+    };
+    let offset_of = |sector_id: u32| (sector_id as u64 + 1) * sector_len;
+    let num_entries = (sector_len / 4) as usize;
+
+    // Each DIFAT sector gives the location of the next, so they have to be
+    // fetched one at a time.
+    let mut fat_sector_ids: Vec<u32> = header
+        .initial_difat_entries
+        .iter()
+        .copied()
+        .filter(|&sector_id| is_valid(sector_id))
+        .collect();
+    let mut seen_sector_ids = FnvHashSet::default();
+    let mut current_difat_sector = header.first_difat_sector;
+    while is_valid(current_difat_sector)
+        && seen_sector_ids.insert(current_difat_sector)
+    {
+        let offset = offset_of(current_difat_sector);
+        load(inner, cache, offset, sector_len).await?;
+        let entries = match read_u32s(cache, offset, num_entries) {
+            Some(entries) => entries,
+            None => break,
+        };
+        fat_sector_ids.extend(
+            entries[..num_entries - 1]
+                .iter()
+                .copied()
+                .filter(|&sector_id| is_valid(sector_id)),
+        );
+        current_difat_sector = entries[num_entries - 1];
+    }
+
+    for (offset, len) in sector_ranges(fat_sector_ids.clone(), sector_len) {
+        load(inner, cache, offset, len).await?;
+    }
+    let mut fat = Vec::new();
+    for &sector_id in fat_sector_ids.iter() {
+        match read_u32s(cache, offset_of(sector_id), num_entries) {
+            Some(entries) => fat.extend(entries),
+            None => break,
+        }
+    }
+
+    let mut chain_sector_ids = Vec::new();
+    for start_sector_id in
+        [header.first_dir_sector, header.first_minifat_sector]
+    {
+        let mut seen_sector_ids = FnvHashSet::default();
+        let mut current_sector = start_sector_id;
+        while is_valid(current_sector)
+            && seen_sector_ids.insert(current_sector)
+        {
+            chain_sector_ids.push(current_sector);
+            current_sector = fat
+                .get(current_sector as usize)
+                .copied()
+                .unwrap_or(consts::END_OF_CHAIN);
+        }
+    }
+    for (offset, len) in sector_ranges(chain_sector_ids, sector_len) {
+        load(inner, cache, offset, len).await?;
+    }
+    Ok(())
+}
+
+/// Reads `count` little-endian integers from the cache, or returns `None` if
+/// they aren't all cached.
+fn read_u32s(
+    cache: &mut SectorCache,
+    offset: u64,
+    count: usize,
+) -> Option<Vec<u32>> {
+    if !cache.contains(offset, 4 * count as u64) {
+        return None;
+    }
+    let mut values = vec![0u32; count];
+    cache.seek(SeekFrom::Start(offset)).ok()?;
+    cache.read_u32_into::<LittleEndian>(&mut values).ok()?;
+    Some(values)
+}
+
+/// Returns the IDs of the sectors in the mini stream of the given compound
+/// file.
+fn mini_stream_sector_ids<G>(
+    comp: &mut CompoundFile<G>,
+) -> io::Result<Vec<u32>> {
+    let mut minialloc = comp.minialloc_mut();
+    let start_sector_id = minialloc.root_dir_entry().start_sector;
+    let chain = minialloc.open_chain(start_sector_id, SectorInit::Fat)?;
+    Ok(chain.sector_ids().to_vec())
+}
+
+/// Sorts the given sector IDs, and returns the file ranges, as `(offset,
+/// len)` pairs, of the runs of adjacent sectors among them.
+fn sector_ranges(
+    mut sector_ids: Vec<u32>,
+    sector_len: u64,
+) -> Vec<(u64, u64)> {
+    sector_ids.sort_unstable();
+    sector_ids.dedup();
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for sector_id in sector_ids {
+        let offset = (sector_id as u64 + 1) * sector_len;
+        match ranges.last_mut() {
+            Some((start, len)) if *start + *len == offset => {
+                *len += sector_len
+            }
+            _ => ranges.push((offset, sector_len)),
+        }
+    }
+    ranges
+}
+
+//===========================================================================//
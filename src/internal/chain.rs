@@ -1,13 +1,14 @@
 use crate::internal::{consts, Allocator, Sector, SectorInit};
-use std::cmp;
-use std::io::{self, Read, Seek, SeekFrom, Write};
+use crate::io::{self, Read, Seek, SeekFrom, Write};
+use alloc::vec::Vec;
+use core::cmp;
 
 //===========================================================================//
 
 pub struct Chain<'a, F: 'a> {
     allocator: &'a mut Allocator<F>,
     init: SectorInit,
-    sector_ids: Vec<u32>,
+    start_sector_id: u32,
     offset_from_start: u64,
 }
 
@@ -17,32 +18,68 @@ impl<'a, F> Chain<'a, F> {
         start_sector_id: u32,
         init: SectorInit,
     ) -> io::Result<Chain<'a, F>> {
-        let mut sector_ids = Vec::<u32>::new();
-        let mut current_sector_id = start_sector_id;
-        let first_sector_id = start_sector_id;
-        while current_sector_id != consts::END_OF_CHAIN {
-            sector_ids.push(current_sector_id);
-            current_sector_id = allocator.next(current_sector_id)?;
-            if current_sector_id == first_sector_id {
-                invalid_data!(
-                    "Chain contained duplicate sector id {}",
-                    current_sector_id
-                );
-            }
-        }
-        Ok(Chain { allocator, init, sector_ids, offset_from_start: 0 })
+        allocator.load_chain(start_sector_id)?;
+        Ok(Chain { allocator, init, start_sector_id, offset_from_start: 0 })
     }
 
     pub fn start_sector_id(&self) -> u32 {
-        self.sector_ids.first().copied().unwrap_or(consts::END_OF_CHAIN)
+        self.start_sector_id
     }
 
     pub fn num_sectors(&self) -> usize {
-        self.sector_ids.len()
+        self.sector_ids().len()
+    }
+
+    pub fn sector_ids(&self) -> &[u32] {
+        self.allocator.cached_chain(self.start_sector_id)
     }
 
     pub fn len(&self) -> u64 {
-        (self.allocator.sector_len() as u64) * (self.sector_ids.len() as u64)
+        (self.allocator.sector_len() as u64) * (self.num_sectors() as u64)
+    }
+
+    /// Returns the offset within the underlying file of the start of each
+    /// sector in the chain.
+    pub fn sector_offsets(&self) -> io::Result<Vec<u64>> {
+        self.sector_ids()
+            .iter()
+            .map(|&sector_id| self.allocator.sector_offset(sector_id, 0))
+            .collect()
+    }
+
+    /// Treating the chain as a sequence of subsectors of the given length
+    /// (e.g. the mini stream), returns the offset within the underlying file
+    /// of the start of each of the specified subsectors.
+    pub fn subsector_offsets(
+        &self,
+        subsector_indices: &[u32],
+        subsector_len: usize,
+    ) -> io::Result<Vec<u64>> {
+        debug_assert_eq!(self.allocator.sector_len() % subsector_len, 0);
+        let subsectors_per_sector =
+            self.allocator.sector_len() / subsector_len;
+        let sector_ids = self.sector_ids();
+        subsector_indices
+            .iter()
+            .map(|&subsector_index| {
+                let sector_index_within_chain =
+                    subsector_index as usize / subsectors_per_sector;
+                let subsector_index_within_sector =
+                    subsector_index as usize % subsectors_per_sector;
+                let sector_id = match sector_ids.get(sector_index_within_chain)
+                {
+                    Some(&sector_id) => sector_id,
+                    None => corrupted!(
+                        MiniFat { sector_id: Some(subsector_index) },
+                        "invalid sector id"
+                    ),
+                };
+                self.allocator.sector_offset(
+                    sector_id,
+                    (subsector_index_within_sector * subsector_len) as u64,
+                )
+            })
+            .collect()
     }
 }
 
@@ -61,12 +98,14 @@ impl<'a, F: Seek> Chain<'a, F> {
             subsector_index as usize / subsectors_per_sector;
         let subsector_index_within_sector =
             subsector_index % (subsectors_per_sector as u32);
-        let sector_id = *self
-            .sector_ids
-            .get(sector_index_within_chain)
-            .ok_or_else(|| {
-                io::Error::new(io::ErrorKind::InvalidData, "invalid sector id")
-            })?;
+        let sector_id = match self.sector_ids().get(sector_index_within_chain)
+        {
+            Some(&sector_id) => sector_id,
+            None => corrupted!(
+                MiniFat { sector_id: Some(subsector_index) },
+                "invalid sector id"
+            ),
+        };
         self.allocator.seek_within_subsector(
             sector_id,
             subsector_index_within_sector,
@@ -83,31 +122,37 @@ impl<'a, F: Write + Seek> Chain<'a, F> {
         let sector_len = self.allocator.sector_len() as u64;
         let new_num_sectors =
             ((sector_len + new_len - 1) / sector_len) as usize;
+        let old_num_sectors = self.num_sectors();
         if new_num_sectors == 0 {
-            if let Some(&start_sector) = self.sector_ids.first() {
-                self.allocator.free_chain(start_sector)?;
+            if self.start_sector_id != consts::END_OF_CHAIN {
+                self.allocator.free_chain(self.start_sector_id)?;
+                self.start_sector_id = consts::END_OF_CHAIN;
             }
-        } else if new_num_sectors <= self.sector_ids.len() {
-            if new_num_sectors < self.sector_ids.len() {
+        } else if new_num_sectors <= old_num_sectors {
+            if new_num_sectors < old_num_sectors {
                 self.allocator
-                    .free_chain_after(self.sector_ids[new_num_sectors - 1])?;
+                    .truncate_chain(self.start_sector_id, new_num_sectors)?;
             }
             // TODO: init remainder of final sector
         } else {
-            for _ in self.sector_ids.len()..new_num_sectors {
-                let new_sector_id = if let Some(&last_sector_id) =
-                    self.sector_ids.last()
-                {
-                    self.allocator.extend_chain(last_sector_id, self.init)?
-                } else {
-                    self.allocator.begin_chain(self.init)?
-                };
-                self.sector_ids.push(new_sector_id);
+            for _ in old_num_sectors..new_num_sectors {
+                self.push_sector()?;
             }
         }
         Ok(())
     }
 
+    /// Adds a new sector to the end of the chain (starting the chain if it
+    /// was empty).
+    fn push_sector(&mut self) -> io::Result<u32> {
+        if self.start_sector_id == consts::END_OF_CHAIN {
+            self.start_sector_id = self.allocator.begin_chain(self.init)?;
+            Ok(self.start_sector_id)
+        } else {
+            self.allocator.extend_chain(self.start_sector_id, self.init)
+        }
+    }
+
     pub fn free(self) -> io::Result<()> {
         self.allocator.free_chain(self.start_sector_id())
     }
@@ -145,8 +190,7 @@ impl<'a, F: Read + Seek> Read for Chain<'a, F> {
         let sector_len = self.allocator.sector_len() as u64;
         let current_sector_index =
             (self.offset_from_start / sector_len) as usize;
-        debug_assert!(current_sector_index < self.sector_ids.len());
-        let current_sector_id = self.sector_ids[current_sector_index];
+        let current_sector_id = self.sector_ids()[current_sector_index];
         let offset_within_sector = self.offset_from_start % sector_len;
         let mut sector = self
             .allocator
@@ -166,20 +210,18 @@ impl<'a, F: Write + Seek> Write for Chain<'a, F> {
         let mut total_len = self.len();
         let sector_len = self.allocator.sector_len() as u64;
         if self.offset_from_start == total_len {
-            let new_sector_id =
-                if let Some(&last_sector_id) = self.sector_ids.last() {
-                    self.allocator.extend_chain(last_sector_id, self.init)?
-                } else {
-                    self.allocator.begin_chain(self.init)?
-                };
-            self.sector_ids.push(new_sector_id);
+            self.push_sector()?;
             total_len += sector_len;
             debug_assert_eq!(total_len, self.len());
         }
         let current_sector_index =
             (self.offset_from_start / sector_len) as usize;
-        debug_assert!(current_sector_index < self.sector_ids.len());
-        let current_sector_id = self.sector_ids[current_sector_index];
+        let current_sector_id = self
+            .allocator
+            .copy_on_write(self.start_sector_id, current_sector_index)?;
+        if current_sector_index == 0 {
+            self.start_sector_id = current_sector_id;
+        }
         let offset_within_sector = self.offset_from_start % sector_len;
         let mut sector = self
             .allocator
@@ -0,0 +1,204 @@
+use crate::internal::IdMap;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+//===========================================================================//
+
+/// The most sector IDs that a `ChainCache` holds by default (4 MiB worth),
+/// after which the least recently used chains are dropped from it.
+const DEFAULT_CAPACITY: usize = 1 << 20;
+
+//===========================================================================//
+
+/// A cache of the sector IDs of chains in a FAT or MiniFAT, keyed by each
+/// chain's starting sector, so that chains don't have to be re-walked every
+/// time they are opened or extended.  The total number of cached sector IDs
+/// is bounded; when it's exceeded, the least recently used chains are
+/// evicted (and will be re-walked if they are needed again).
+pub struct ChainCache {
+    /// For each cached chain, the stamp of its last use and its sector IDs.
+    chains: IdMap<u32, (u64, Vec<u32>)>,
+    /// The starting sector of each cached chain, ordered by last use.
+    recency: BTreeMap<u64, u32>,
+    next_stamp: u64,
+    num_sector_ids: usize,
+    capacity: usize,
+}
+
+impl ChainCache {
+    /// Creates an empty cache that holds at most `capacity` sector IDs
+    /// (except that the most recently used chain is always kept, however
+    /// long it is).
+    pub fn with_capacity(capacity: usize) -> ChainCache {
+        ChainCache {
+            chains: IdMap::default(),
+            recency: BTreeMap::new(),
+            next_stamp: 0,
+            num_sector_ids: 0,
+            capacity,
+        }
+    }
+
+    /// Returns the total number of sector IDs in the cache.
+    #[cfg(test)]
+    pub fn num_sector_ids(&self) -> usize {
+        self.num_sector_ids
+    }
+
+    /// Returns true if the chain starting at the given sector is cached.
+    pub fn contains(&self, start_sector_id: u32) -> bool {
+        self.chains.contains_key(&start_sector_id)
+    }
+
+    /// Returns the cached sector IDs of the chain starting at the given
+    /// sector, if any.
+    pub fn get(&self, start_sector_id: u32) -> Option<&[u32]> {
+        self.chains
+            .get(&start_sector_id)
+            .map(|(_, sector_ids)| sector_ids.as_slice())
+    }
+
+    /// Marks the chain starting at the given sector (if cached) as the most
+    /// recently used one.
+    pub fn touch(&mut self, start_sector_id: u32) {
+        if let Some((stamp, _)) = self.chains.get_mut(&start_sector_id) {
+            self.recency.remove(stamp);
+            *stamp = self.next_stamp;
+            self.recency.insert(self.next_stamp, start_sector_id);
+            self.next_stamp += 1;
+        }
+    }
+
+    /// Caches the sector IDs of the chain starting at the given sector,
+    /// replacing any that were cached before, and marks it as the most
+    /// recently used chain.
+    pub fn insert(&mut self, start_sector_id: u32, sector_ids: Vec<u32>) {
+        self.remove(start_sector_id);
+        self.num_sector_ids += sector_ids.len();
+        self.chains.insert(start_sector_id, (self.next_stamp, sector_ids));
+        self.recency.insert(self.next_stamp, start_sector_id);
+        self.next_stamp += 1;
+        self.evict();
+    }
+
+    /// Appends a sector ID to the chain starting at the given sector.
+    /// Returns false (and does nothing) if that chain isn't cached.
+    pub fn push(&mut self, start_sector_id: u32, sector_id: u32) -> bool {
+        match self.chains.get_mut(&start_sector_id) {
+            Some((_, sector_ids)) => sector_ids.push(sector_id),
+            None => return false,
+        }
+        self.num_sector_ids += 1;
+        self.touch(start_sector_id);
+        self.evict();
+        true
+    }
+
+    /// Shortens the cached chain starting at the given sector to `len`
+    /// sectors, and returns the sector IDs that were removed from its end
+    /// (or `None` if that chain isn't cached).
+    pub fn split_off(
+        &mut self,
+        start_sector_id: u32,
+        len: usize,
+    ) -> Option<Vec<u32>> {
+        let (_, sector_ids) = self.chains.get_mut(&start_sector_id)?;
+        let removed = sector_ids.split_off(len.min(sector_ids.len()));
+        self.num_sector_ids -= removed.len();
+        Some(removed)
+    }
+
+    /// Removes the chain starting at the given sector from the cache, and
+    /// returns its sector IDs (if it was cached).
+    pub fn remove(&mut self, start_sector_id: u32) -> Option<Vec<u32>> {
+        let (stamp, sector_ids) = self.chains.remove(&start_sector_id)?;
+        self.recency.remove(&stamp);
+        self.num_sector_ids -= sector_ids.len();
+        Some(sector_ids)
+    }
+
+    /// Removes every cached chain for which the predicate returns false.
+    pub fn retain<P: FnMut(&[u32]) -> bool>(&mut self, mut predicate: P) {
+        let removed: Vec<u32> = self
+            .chains
+            .iter()
+            .filter(|(_, (_, sector_ids))| !predicate(sector_ids))
+            .map(|(&start_sector_id, _)| start_sector_id)
+            .collect();
+        for start_sector_id in removed {
+            self.remove(start_sector_id);
+        }
+    }
+
+    /// Removes every chain from the cache.
+    pub fn clear(&mut self) {
+        self.chains.clear();
+        self.recency.clear();
+        self.num_sector_ids = 0;
+    }
+
+    /// Evicts the least recently used chains until the cache is within its
+    /// capacity, never evicting the most recently used one.
+    fn evict(&mut self) {
+        while self.num_sector_ids > self.capacity && self.recency.len() > 1 {
+            let (_, start_sector_id) = self.recency.pop_first().unwrap();
+            let (_, sector_ids) =
+                self.chains.remove(&start_sector_id).unwrap();
+            self.num_sector_ids -= sector_ids.len();
+        }
+    }
+}
+
+impl Default for ChainCache {
+    fn default() -> ChainCache {
+        ChainCache::with_capacity(DEFAULT_CAPACITY)
+    }
+}
+
+//===========================================================================//
+
+#[cfg(test)]
+mod tests {
+    use super::ChainCache;
+    use alloc::vec;
+
+    #[test]
+    fn evicts_least_recently_used_chains() {
+        let mut cache = ChainCache::with_capacity(6);
+        cache.insert(1, vec![1, 2]);
+        cache.insert(3, vec![3, 4]);
+        cache.insert(5, vec![5, 6]);
+        assert_eq!(cache.num_sector_ids(), 6);
+        // Using chain 1 makes chain 3 the least recently used one.
+        cache.touch(1);
+        cache.insert(7, vec![7]);
+        assert!(cache.contains(1));
+        assert!(!cache.contains(3));
+        assert!(cache.contains(5));
+        assert!(cache.contains(7));
+        assert_eq!(cache.num_sector_ids(), 5);
+        // Growing a chain can also evict others, but never that chain.
+        for sector_id in 8..12 {
+            assert!(cache.push(7, sector_id));
+        }
+        assert_eq!(cache.get(7), Some(&[7, 8, 9, 10, 11][..]));
+        assert!(!cache.contains(1));
+        assert!(!cache.contains(5));
+        assert_eq!(cache.num_sector_ids(), 5);
+    }
+
+    #[test]
+    fn keeps_most_recent_chain_even_if_over_capacity() {
+        let mut cache = ChainCache::with_capacity(2);
+        cache.insert(1, vec![1]);
+        cache.insert(2, vec![2, 3, 4]);
+        assert!(!cache.contains(1));
+        assert_eq!(cache.get(2), Some(&[2, 3, 4][..]));
+        assert_eq!(cache.split_off(2, 1), Some(vec![3, 4]));
+        assert_eq!(cache.num_sector_ids(), 1);
+        assert_eq!(cache.remove(2), Some(vec![2]));
+        assert_eq!(cache.num_sector_ids(), 0);
+    }
+}
+
+//===========================================================================//
@@ -0,0 +1,151 @@
+use crate::internal::path::{path_from_name_chain, PathBuf};
+use crate::internal::{consts, IdSet, ObjType, Report, SectorInit, Validator};
+use crate::io::{self, Read, Seek};
+use crate::CompoundFile;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+//===========================================================================//
+
+/// Checks a compound file against the CFB spec, and reports every violation
+/// found, rather than just the first one.
+///
+/// This checks everything that `CompoundFile::open_strict` does, and
+/// additionally checks that the chain of every stream is intact and that no
+/// two chains share a sector.  Checking stops early only when the file is too
+/// damaged to continue, in which case the report says that the file can't be
+/// opened.  An error is returned only if reading from `inner` fails.
+pub fn check<F: Read + Seek>(inner: F) -> io::Result<Report> {
+    let mut validator = Validator::exhaustive();
+    let mut comp = match CompoundFile::open_internal(inner, &mut validator) {
+        Ok(comp) => comp,
+        Err(error) => {
+            // The error will already have been recorded if it was returned
+            // at the end of a stage of parsing; otherwise, record it now.
+            let recorded = validator.first_error().is_some_and(|first| {
+                crate::Error::from_io(&error) == Some(first)
+            });
+            if !recorded {
+                validator.reject_io(error)?;
+            }
+            return Ok(Report {
+                violations: validator.into_violations(),
+                can_open_permissive: false,
+            });
+        }
+    };
+    check_chains(&mut comp, &mut validator)?;
+    Ok(Report {
+        violations: validator.into_violations(),
+        can_open_permissive: true,
+    })
+}
+
+/// Checks that the chain of every stream reachable from the root is intact
+/// and long enough for the stream, and that no sector (or mini sector)
+/// belongs to more than one chain.
+fn check_chains<F: Read + Seek>(
+    comp: &mut CompoundFile<F>,
+    validator: &mut Validator,
+) -> io::Result<()> {
+    // Each stream's ID and path, found by walking the tree with the names of
+    // each entry's ancestors.
+    let mut streams = Vec::<(u32, PathBuf)>::new();
+    let root_child = comp.minialloc().root_dir_entry().child;
+    let mut stack = vec![(root_child, Vec::<String>::new())];
+    while let Some((stream_id, parent_names)) = stack.pop() {
+        if stream_id == consts::NO_STREAM {
+            continue;
+        }
+        let minialloc = comp.minialloc();
+        let dir_entry = minialloc.dir_entry(stream_id);
+        let mut names = parent_names.clone();
+        names.push(dir_entry.name.clone());
+        if dir_entry.obj_type == ObjType::Stream {
+            let name_chain: Vec<&str> =
+                names.iter().map(String::as_str).collect();
+            streams.push((stream_id, path_from_name_chain(&name_chain)));
+        }
+        stack.push((dir_entry.left_sibling, parent_names.clone()));
+        stack.push((dir_entry.right_sibling, parent_names));
+        stack.push((dir_entry.child, names));
+    }
+    streams.sort_unstable_by_key(|&(stream_id, _)| stream_id);
+
+    let mut minialloc = comp.minialloc_mut();
+    let mut claimed = IdSet::default();
+    match minialloc.metadata_sector_ids() {
+        Ok(sector_ids) => claim(&mut claimed, &sector_ids, validator)?,
+        Err(error) => validator.reject_io(error)?,
+    }
+    let root_entry = minialloc.root_dir_entry();
+    let (root_start_sector, root_stream_len) =
+        (root_entry.start_sector, root_entry.stream_len);
+    match minialloc.open_chain(root_start_sector, SectorInit::Fat) {
+        Ok(chain) if chain.len() < root_stream_len => {
+            validator.reject(corruption!(
+                Directory {
+                    stream_id: Some(consts::ROOT_STREAM_ID),
+                    path: Some(PathBuf::from("/")),
+                },
+                "Mini stream is {} bytes long, but its chain has only {} \
+                 sectors",
+                root_stream_len,
+                chain.num_sectors()
+            ))?;
+        }
+        Ok(_) => {}
+        Err(error) => validator.reject_io(error)?,
+    }
+    drop(minialloc);
+
+    let mut claimed_mini = IdSet::default();
+    for (stream_id, path) in streams {
+        if let Err(error) = comp.stream_sector_offsets_for_id(stream_id) {
+            validator.reject_io(crate::Error::io_at_path(error, &path))?;
+            continue;
+        }
+        let mut minialloc = comp.minialloc_mut();
+        let dir_entry = minialloc.dir_entry(stream_id);
+        let start_sector = dir_entry.start_sector;
+        if dir_entry.stream_len < consts::MINI_STREAM_CUTOFF as u64 {
+            let chain = minialloc.open_mini_chain(start_sector)?;
+            for &sector_id in chain.sector_ids() {
+                if !claimed_mini.insert(sector_id) {
+                    validator.reject(corruption!(
+                        MiniFat { sector_id: Some(sector_id) },
+                        "Mini sector {} belongs to more than one chain",
+                        sector_id
+                    ))?;
+                }
+            }
+        } else {
+            let chain =
+                minialloc.open_chain(start_sector, SectorInit::Zero)?;
+            claim(&mut claimed, chain.sector_ids(), validator)?;
+        }
+    }
+    Ok(())
+}
+
+/// Marks the given sectors as belonging to a chain, reporting any that
+/// already belong to another one.
+fn claim(
+    claimed: &mut IdSet<u32>,
+    sector_ids: &[u32],
+    validator: &mut Validator,
+) -> io::Result<()> {
+    for &sector_id in sector_ids {
+        if !claimed.insert(sector_id) {
+            validator.reject(corruption!(
+                Fat { sector_id: Some(sector_id) },
+                "Sector {} belongs to more than one chain",
+                sector_id
+            ))?;
+        }
+    }
+    Ok(())
+}
+
+//===========================================================================//
@@ -0,0 +1,483 @@
+use crate::internal::IdMap;
+use crate::internal::{
+    consts, DirEntry, Header, MiniAllocator, ObjType, SectorInit, SetLen,
+    Version,
+};
+use crate::io::{self, Read, Seek, Write, WriteBytesExt};
+use alloc::vec;
+use alloc::vec::Vec;
+use byteorder::LittleEndian;
+use core::mem::{self, size_of};
+
+//===========================================================================//
+
+/// A fully compacted arrangement of the contents of a compound file.  The FAT
+/// sectors come first, followed by the DIFAT sectors, the directory, the
+/// MiniFAT, and the mini stream, and then the sectors of each regular stream
+/// in stream ID order.  Every chain is contiguous, and there are no free
+/// sectors or mini sectors anywhere.
+///
+/// Stream IDs are preserved (so that any open `Stream` objects remain valid),
+/// but directory entries that are not reachable from the root are dropped,
+/// as are trailing unallocated directory sectors.
+pub struct Layout {
+    pub num_sectors: u32,
+    pub difat_sector_ids: Vec<u32>,
+    pub difat: Vec<u32>,
+    pub fat: Vec<u32>,
+    pub dir_start_sector: u32,
+    pub dir_entries: Vec<DirEntry>,
+    pub minifat_start_sector: u32,
+    pub minifat: Vec<u32>,
+    version: Version,
+    num_dir_sectors: u32,
+    num_minifat_sectors: u32,
+    num_mini_stream_sectors: u32,
+    first_stream_sector: u32,
+    /// The stream ID, old starting mini sector, and length of each stream
+    /// that lives in the mini stream.
+    small_streams: Vec<(u32, u32, u64)>,
+    /// The old starting sector, new starting sector, and length of each
+    /// stream that lives in a regular chain.
+    large_streams: Vec<(u32, u32, u64)>,
+}
+
+impl Layout {
+    pub fn new<F>(minialloc: &MiniAllocator<F>) -> Layout {
+        let version = minialloc.version();
+        let sector_len = version.sector_len() as u64;
+        let fat_entries_per_sector = version.sector_len() / size_of::<u32>();
+        let mini_sector_len = consts::MINI_SECTOR_LEN as u64;
+
+        // Copy all directory entries that are reachable from the root.
+        let mut reachable = Vec::<u32>::new();
+        let mut stack = vec![consts::ROOT_STREAM_ID];
+        while let Some(stream_id) = stack.pop() {
+            if stream_id == consts::NO_STREAM {
+                continue;
+            }
+            reachable.push(stream_id);
+            let dir_entry = minialloc.dir_entry(stream_id);
+            stack.push(dir_entry.left_sibling);
+            stack.push(dir_entry.right_sibling);
+            stack.push(dir_entry.child);
+        }
+        reachable.sort_unstable();
+        let dir_entries_per_sector = version.dir_entries_per_sector();
+        let num_dir_entries = *reachable.last().unwrap() as usize + 1;
+        let num_dir_sectors = (num_dir_entries + dir_entries_per_sector - 1)
+            / dir_entries_per_sector;
+        let mut dir_entries = vec![
+            DirEntry::unallocated();
+            num_dir_sectors * dir_entries_per_sector
+        ];
+        for &stream_id in reachable.iter() {
+            dir_entries[stream_id as usize] =
+                minialloc.dir_entry(stream_id).clone();
+        }
+
+        // Sort streams into those that belong in the mini stream and those
+        // that need regular chains.
+        let mut small_streams = Vec::new();
+        let mut large_streams = Vec::new();
+        let mut num_mini_sectors = 0;
+        let mut num_stream_sectors = 0;
+        for &stream_id in reachable.iter() {
+            let dir_entry = &mut dir_entries[stream_id as usize];
+            if dir_entry.obj_type != ObjType::Stream {
+                continue;
+            }
+            let stream_len = dir_entry.stream_len;
+            if stream_len == 0 {
+                dir_entry.start_sector = consts::END_OF_CHAIN;
+            } else if stream_len < consts::MINI_STREAM_CUTOFF as u64 {
+                small_streams.push((
+                    stream_id,
+                    dir_entry.start_sector,
+                    stream_len,
+                ));
+                num_mini_sectors +=
+                    (stream_len + mini_sector_len - 1) / mini_sector_len;
+            } else {
+                large_streams.push((
+                    dir_entry.start_sector,
+                    consts::END_OF_CHAIN,
+                    stream_len,
+                ));
+                num_stream_sectors +=
+                    (stream_len + sector_len - 1) / sector_len;
+            }
+        }
+        let num_minifat_sectors =
+            (num_mini_sectors + (fat_entries_per_sector as u64) - 1)
+                / (fat_entries_per_sector as u64);
+        let num_mini_stream_sectors =
+            ((num_mini_sectors * mini_sector_len) + sector_len - 1)
+                / sector_len;
+        let num_data_sectors = num_dir_sectors as u64
+            + num_minifat_sectors
+            + num_mini_stream_sectors
+            + num_stream_sectors;
+
+        // Find the smallest FAT that can cover every sector of the file,
+        // including its own sectors and the DIFAT sectors needed to list
+        // them.
+        let difat_entries_per_sector = fat_entries_per_sector as u64 - 1;
+        let mut num_fat_sectors: u64 = 1;
+        let num_difat_sectors = loop {
+            let num_extra_fat_sectors = num_fat_sectors
+                .saturating_sub(consts::NUM_DIFAT_ENTRIES_IN_HEADER as u64);
+            let num_difat_sectors =
+                (num_extra_fat_sectors + difat_entries_per_sector - 1)
+                    / difat_entries_per_sector;
+            if num_fat_sectors * fat_entries_per_sector as u64
+                >= num_data_sectors + num_fat_sectors + num_difat_sectors
+            {
+                break num_difat_sectors;
+            }
+            num_fat_sectors += 1;
+        };
+
+        // Lay out the FAT, DIFAT, directory, MiniFAT, and mini stream.
+        let mut fat = Vec::<u32>::new();
+        let difat: Vec<u32> = (0..num_fat_sectors as u32).collect();
+        fat.resize(num_fat_sectors as usize, consts::FAT_SECTOR);
+        let difat_sector_ids: Vec<u32> = (fat.len() as u32
+            ..(fat.len() as u64 + num_difat_sectors) as u32)
+            .collect();
+        fat.resize(
+            (num_fat_sectors + num_difat_sectors) as usize,
+            consts::DIFAT_SECTOR,
+        );
+        let dir_start_sector = append_chain(&mut fat, num_dir_sectors as u64);
+        let minifat_start_sector = append_chain(&mut fat, num_minifat_sectors);
+        let mini_stream_start_sector =
+            append_chain(&mut fat, num_mini_stream_sectors);
+        let first_stream_sector = fat.len() as u32;
+
+        // Lay out the mini chains within the mini stream.
+        let mut minifat = Vec::<u32>::new();
+        for &(stream_id, _, stream_len) in small_streams.iter() {
+            dir_entries[stream_id as usize].start_sector = append_chain(
+                &mut minifat,
+                (stream_len + mini_sector_len - 1) / mini_sector_len,
+            );
+        }
+        debug_assert_eq!(minifat.len() as u64, num_mini_sectors);
+        {
+            let root_entry = &mut dir_entries[consts::ROOT_STREAM_ID as usize];
+            root_entry.start_sector = mini_stream_start_sector;
+            root_entry.stream_len = num_mini_sectors * mini_sector_len;
+        }
+
+        // Lay out the regular stream chains.
+        let mut large_index = 0;
+        for &stream_id in reachable.iter() {
+            let dir_entry = &mut dir_entries[stream_id as usize];
+            if dir_entry.obj_type == ObjType::Stream
+                && dir_entry.stream_len >= consts::MINI_STREAM_CUTOFF as u64
+            {
+                let new_start_sector = append_chain(
+                    &mut fat,
+                    (dir_entry.stream_len + sector_len - 1) / sector_len,
+                );
+                dir_entry.start_sector = new_start_sector;
+                large_streams[large_index].1 = new_start_sector;
+                large_index += 1;
+            }
+        }
+        debug_assert_eq!(
+            fat.len() as u64,
+            num_data_sectors + num_fat_sectors + num_difat_sectors
+        );
+
+        Layout {
+            num_sectors: fat.len() as u32,
+            difat_sector_ids,
+            difat,
+            fat,
+            dir_start_sector,
+            dir_entries,
+            minifat_start_sector,
+            minifat,
+            version,
+            num_dir_sectors: num_dir_sectors as u32,
+            num_minifat_sectors: num_minifat_sectors as u32,
+            num_mini_stream_sectors: num_mini_stream_sectors as u32,
+            first_stream_sector,
+            small_streams,
+            large_streams,
+        }
+    }
+
+    fn header(&self) -> Header {
+        let mut header = Header {
+            version: self.version,
+            // 2.2 requires this to be zero in V3
+            num_dir_sectors: if self.version == Version::V3 {
+                0
+            } else {
+                self.num_dir_sectors
+            },
+            num_fat_sectors: self.difat.len() as u32,
+            first_dir_sector: self.dir_start_sector,
+            transaction_signature: 0,
+            first_minifat_sector: self.minifat_start_sector,
+            num_minifat_sectors: self.num_minifat_sectors,
+            first_difat_sector: self
+                .difat_sector_ids
+                .first()
+                .copied()
+                .unwrap_or(consts::END_OF_CHAIN),
+            num_difat_sectors: self.difat_sector_ids.len() as u32,
+            initial_difat_entries: [consts::FREE_SECTOR;
+                consts::NUM_DIFAT_ENTRIES_IN_HEADER],
+        };
+        for (entry, &sector_id) in
+            header.initial_difat_entries.iter_mut().zip(self.difat.iter())
+        {
+            *entry = sector_id;
+        }
+        header
+    }
+
+    /// Reads the data of every stream that lives in the mini stream, and
+    /// returns the contents of the new mini stream.
+    fn read_mini_stream<F: Read + Seek>(
+        &self,
+        minialloc: &mut MiniAllocator<F>,
+    ) -> io::Result<Vec<u8>> {
+        let mut data = vec![0u8; self.minifat.len() * consts::MINI_SECTOR_LEN];
+        for &(stream_id, old_start_sector, stream_len) in
+            self.small_streams.iter()
+        {
+            let new_start_sector =
+                self.dir_entries[stream_id as usize].start_sector;
+            let offset = new_start_sector as usize * consts::MINI_SECTOR_LEN;
+            let mut chain = minialloc.open_mini_chain(old_start_sector)?;
+            chain.read_exact(&mut data[offset..][..stream_len as usize])?;
+        }
+        Ok(data)
+    }
+
+    /// Writes the header, padded to the length of a sector.
+    fn write_header<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.header().write_to(writer)?;
+        let sector_len = self.version.sector_len();
+        if sector_len > consts::HEADER_LEN {
+            writer.write_all(&vec![0; sector_len - consts::HEADER_LEN])?;
+        }
+        Ok(())
+    }
+
+    /// Writes the FAT, DIFAT, directory, MiniFAT, and mini stream sectors
+    /// (i.e. every sector before the first regular stream sector).
+    fn write_metadata_sectors<W: Write>(
+        &self,
+        writer: &mut W,
+        mini_stream: &[u8],
+    ) -> io::Result<()> {
+        let sector_len = self.version.sector_len();
+        let fat_entries_per_sector = sector_len / size_of::<u32>();
+        for index in 0..(self.difat.len() * fat_entries_per_sector) {
+            let entry =
+                self.fat.get(index).copied().unwrap_or(consts::FREE_SECTOR);
+            writer.write_u32::<LittleEndian>(entry)?;
+        }
+        let difat_entries_per_sector = fat_entries_per_sector - 1;
+        let mut remaining_difat = self
+            .difat
+            .iter()
+            .skip(consts::NUM_DIFAT_ENTRIES_IN_HEADER)
+            .copied();
+        for index in 0..self.difat_sector_ids.len() {
+            for _ in 0..difat_entries_per_sector {
+                let entry =
+                    remaining_difat.next().unwrap_or(consts::FREE_SECTOR);
+                writer.write_u32::<LittleEndian>(entry)?;
+            }
+            let next = self
+                .difat_sector_ids
+                .get(index + 1)
+                .copied()
+                .unwrap_or(consts::END_OF_CHAIN);
+            writer.write_u32::<LittleEndian>(next)?;
+        }
+        for dir_entry in self.dir_entries.iter() {
+            dir_entry.write_to(writer)?;
+        }
+        for index in
+            0..(self.num_minifat_sectors as usize * fat_entries_per_sector)
+        {
+            let entry = self
+                .minifat
+                .get(index)
+                .copied()
+                .unwrap_or(consts::FREE_SECTOR);
+            writer.write_u32::<LittleEndian>(entry)?;
+        }
+        writer.write_all(mini_stream)?;
+        let mini_stream_capacity =
+            self.num_mini_stream_sectors as usize * sector_len;
+        writer
+            .write_all(&vec![0; mini_stream_capacity - mini_stream.len()])?;
+        Ok(())
+    }
+}
+
+/// Appends a contiguous chain of the given length to the end of a FAT or
+/// MiniFAT, and returns its starting sector (or `END_OF_CHAIN` if the length
+/// is zero).
+fn append_chain(table: &mut Vec<u32>, length: u64) -> u32 {
+    if length == 0 {
+        return consts::END_OF_CHAIN;
+    }
+    let start = table.len() as u32;
+    let end = start + length as u32;
+    table.extend((start + 1)..end);
+    table.push(consts::END_OF_CHAIN);
+    start
+}
+
+//===========================================================================//
+
+/// Writes a compacted copy of the compound file to `writer`, without
+/// modifying the original.
+pub fn compact_into<F: Read + Seek, W: Write>(
+    minialloc: &mut MiniAllocator<F>,
+    writer: &mut W,
+) -> io::Result<()> {
+    let layout = Layout::new(minialloc);
+    let mini_stream = layout.read_mini_stream(minialloc)?;
+    layout.write_header(writer)?;
+    layout.write_metadata_sectors(writer, &mini_stream)?;
+    let sector_len = layout.version.sector_len();
+    let mut buffer = vec![0u8; sector_len];
+    for &(old_start_sector, _, stream_len) in layout.large_streams.iter() {
+        let mut chain =
+            minialloc.open_chain(old_start_sector, SectorInit::Zero)?;
+        let mut remaining = stream_len;
+        while remaining > 0 {
+            let num_bytes = remaining.min(sector_len as u64) as usize;
+            chain.read_exact(&mut buffer[..num_bytes])?;
+            for byte in &mut buffer[num_bytes..] {
+                *byte = 0;
+            }
+            writer.write_all(&buffer)?;
+            remaining -= num_bytes as u64;
+        }
+    }
+    writer.flush()
+}
+
+/// Compacts the compound file in place, relocating sectors within the
+/// underlying file, then truncates the file and replaces the in-memory
+/// allocation tables to match.
+pub fn compact_in_place<F: Read + Write + Seek + SetLen>(
+    minialloc: &mut MiniAllocator<F>,
+) -> io::Result<()> {
+    if minialloc.is_journaling() {
+        invalid_input!(
+            "Cannot compact a compound file while in journaling mode"
+        );
+    }
+    if minialloc.in_transaction() {
+        invalid_input!(
+            "Cannot compact a compound file while a transaction is in progress"
+        );
+    }
+    let layout = Layout::new(minialloc);
+    let sector_len = layout.version.sector_len();
+
+    // Read everything we need out of the old layout before we start
+    // overwriting sectors: the mini stream data, and the current location of
+    // every regular stream sector.
+    let mini_stream = layout.read_mini_stream(minialloc)?;
+    let mut moves = Vec::<(u32, u32)>::new();
+    for &(old_start_sector, new_start_sector, stream_len) in
+        layout.large_streams.iter()
+    {
+        let num_sectors = ((stream_len + (sector_len as u64) - 1)
+            / (sector_len as u64)) as usize;
+        let chain =
+            minialloc.open_chain(old_start_sector, SectorInit::Zero)?;
+        if chain.num_sectors() < num_sectors {
+            corrupted!(
+                Fat { sector_id: Some(old_start_sector) },
+                "Chain starting at sector {} has only {} sectors, but stream \
+                 length requires {}",
+                old_start_sector,
+                chain.num_sectors(),
+                num_sectors
+            );
+        }
+        for (index, &sector_id) in
+            chain.sector_ids()[..num_sectors].iter().enumerate()
+        {
+            moves.push((sector_id, new_start_sector + index as u32));
+        }
+    }
+    let mut destinations = IdMap::<u32, u32>::default();
+    for &(from, to) in moves.iter() {
+        if destinations.insert(from, to).is_some() {
+            corrupted!(
+                Fat { sector_id: Some(from) },
+                "Sector {} is used by more than one stream",
+                from
+            );
+        }
+    }
+    destinations.retain(|from, to| from != to);
+
+    // Make sure the file is long enough to hold the new layout.  (This will
+    // only happen for files that were malformed to begin with.)
+    if layout.num_sectors > minialloc.num_sectors() {
+        minialloc.set_num_sectors(layout.num_sectors)?;
+    }
+
+    // Move each regular stream sector to its new location.  The sector
+    // mapping is a partial permutation, so we follow each path (or cycle) of
+    // moves, picking up the data in each destination sector before
+    // overwriting it.
+    let mut buffer = vec![0u8; sector_len];
+    let mut next_buffer = vec![0u8; sector_len];
+    for &(from, _) in moves.iter() {
+        let mut to = match destinations.remove(&from) {
+            Some(to) => to,
+            None => continue,
+        };
+        minialloc.seek_to_sector(from)?.read_exact(&mut buffer)?;
+        loop {
+            let next_to = destinations.remove(&to);
+            if next_to.is_some() {
+                minialloc.seek_to_sector(to)?.read_exact(&mut next_buffer)?;
+            }
+            minialloc.seek_to_sector(to)?.write_all(&buffer)?;
+            match next_to {
+                Some(next_to) => {
+                    mem::swap(&mut buffer, &mut next_buffer);
+                    to = next_to;
+                }
+                None => break,
+            }
+        }
+    }
+
+    // Write out the new metadata sectors and header, then shrink the file.
+    let mut metadata =
+        Vec::with_capacity(layout.first_stream_sector as usize * sector_len);
+    layout.write_metadata_sectors(&mut metadata, &mini_stream)?;
+    debug_assert_eq!(
+        metadata.len(),
+        layout.first_stream_sector as usize * sector_len
+    );
+    for (sector_id, data) in metadata.chunks(sector_len).enumerate() {
+        minialloc.seek_to_sector(sector_id as u32)?.write_all(data)?;
+    }
+    layout.header().write_to(&mut minialloc.seek_within_header(0)?)?;
+    minialloc.set_num_sectors(layout.num_sectors)?;
+    minialloc.replace_layout(layout);
+    minialloc.flush()
+}
+
+//===========================================================================//
@@ -12,6 +12,8 @@ pub const BYTE_ORDER_MARK: u16 = 0xfffe;
 pub const MINI_SECTOR_SHIFT: u16 = 6; // 64-byte mini sectors
 pub const MINI_SECTOR_LEN: usize = 1 << (MINI_SECTOR_SHIFT as usize);
 pub const MINI_STREAM_CUTOFF: u32 = 4096;
+// Transaction signature ("JRNL") marking a file open in journaling mode:
+pub const JOURNALING_SIGNATURE: u32 = 0x4c4e524a;
 
 // Constants for FAT entries:
 pub const MAX_REGULAR_SECTOR: u32 = 0xfffffffa;
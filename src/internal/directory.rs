@@ -1,18 +1,40 @@
+use crate::internal::IdSet;
 use crate::internal::{
-    self, consts, Allocator, Chain, Color, DirEntry, ObjType, Sector,
-    SectorInit, Timestamp, Validation, Version,
+    self, consts, Allocator, Chain, Color, DirEntry, FreeList, Header,
+    Journal, ObjType, ReadAt, Sector, SectorInit, SetLen, Timestamp,
+    Validator, Version, WriteMode,
 };
-use byteorder::{LittleEndian, WriteBytesExt};
-use fnv::FnvHashSet;
-use std::cmp::Ordering;
-use std::io::{self, Seek, SeekFrom, Write};
+use crate::io::{self, Read, Seek, SeekFrom, Write};
+use alloc::collections::BTreeSet;
+use alloc::format;
+use alloc::string::ToString;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+use core::mem;
 
 //===========================================================================//
 
+macro_rules! malformation {
+    ($stream_id:expr; $($arg:tt)+) => {
+        corruption!(
+            Directory { stream_id: Some($stream_id), path: None },
+            "Malformed directory ({})",
+            format!($($arg)+)
+        )
+    };
+    ($($arg:tt)+) => {
+        corruption!(
+            Directory { stream_id: None, path: None },
+            "Malformed directory ({})",
+            format!($($arg)+)
+        )
+    };
+}
+
 macro_rules! malformed {
-    ($e:expr) => { invalid_data!("Malformed directory ({})", $e) };
-    ($fmt:expr, $($arg:tt)+) => {
-        invalid_data!("Malformed directory ({})", format!($fmt, $($arg)+))
+    ($($arg:tt)+) => {
+        return Err(malformation!($($arg)+).into())
     };
 }
 
@@ -24,6 +46,14 @@ pub struct Directory<F> {
     allocator: Allocator<F>,
     dir_entries: Vec<DirEntry>,
     dir_start_sector: u32,
+    free_dir_entries: FreeList,
+    /// In write-back mode, the indices (within the directory chain) of
+    /// directory sectors that have been changed in memory but not yet written
+    /// to the file.
+    dirty_dir_sectors: BTreeSet<usize>,
+    /// While a transaction is in progress, the directory entries and the
+    /// directory's start sector as of its start.
+    transaction: Option<(Vec<DirEntry>, u32)>,
 }
 
 impl<F> Directory<F> {
@@ -31,10 +61,18 @@ impl<F> Directory<F> {
         allocator: Allocator<F>,
         dir_entries: Vec<DirEntry>,
         dir_start_sector: u32,
-        validation: Validation,
+        validator: &mut Validator,
     ) -> io::Result<Directory<F>> {
-        let directory = Directory { allocator, dir_entries, dir_start_sector };
-        directory.validate(validation)?;
+        let free_dir_entries = FreeList::new(is_unallocated(&dir_entries));
+        let directory = Directory {
+            allocator,
+            dir_entries,
+            dir_start_sector,
+            free_dir_entries,
+            dirty_dir_sectors: BTreeSet::new(),
+            transaction: None,
+        };
+        directory.validate(validator)?;
         Ok(directory)
     }
 
@@ -46,6 +84,56 @@ impl<F> Directory<F> {
         self.allocator.sector_len()
     }
 
+    pub fn write_mode(&self) -> WriteMode {
+        self.allocator.write_mode()
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.allocator.in_transaction()
+    }
+
+    pub fn defers_writes(&self) -> bool {
+        self.allocator.defers_writes()
+    }
+
+    pub fn is_journaling(&self) -> bool {
+        self.allocator.is_journaling()
+    }
+
+    pub fn set_journal(&mut self, journal: Journal) {
+        self.allocator.set_journal(journal)
+    }
+
+    pub fn inner(&self) -> &F {
+        self.allocator.inner()
+    }
+
+    #[cfg(feature = "async")]
+    pub fn inner_mut(&mut self) -> &mut F {
+        self.allocator.inner_mut()
+    }
+
+    pub fn fat(&self) -> &[u32] {
+        self.allocator.fat()
+    }
+
+    pub fn difat(&self) -> &[u32] {
+        self.allocator.difat()
+    }
+
+    pub fn difat_sector_ids(&self) -> &[u32] {
+        self.allocator.difat_sector_ids()
+    }
+
+    /// Returns the IDs of the sectors that hold the DIFAT, the FAT, and the
+    /// directory.
+    pub fn table_sector_ids(&mut self) -> io::Result<Vec<u32>> {
+        let mut sector_ids = self.allocator.table_sector_ids();
+        let chain = self.open_chain(self.dir_start_sector, SectorInit::Dir)?;
+        sector_ids.extend_from_slice(chain.sector_ids());
+        Ok(sector_ids)
+    }
+
     pub fn into_inner(self) -> F {
         self.allocator.into_inner()
     }
@@ -69,6 +157,26 @@ impl<F> Directory<F> {
         Some(stream_id)
     }
 
+    pub fn num_sectors(&self) -> u32 {
+        self.allocator.num_sectors()
+    }
+
+    pub fn sector_offset(
+        &self,
+        sector_id: u32,
+        offset_within_sector: u64,
+    ) -> io::Result<u64> {
+        self.allocator.sector_offset(sector_id, offset_within_sector)
+    }
+
+    pub fn load_chain(&mut self, start_sector_id: u32) -> io::Result<()> {
+        self.allocator.load_chain(start_sector_id)
+    }
+
+    pub fn try_cached_chain(&self, start_sector_id: u32) -> Option<&[u32]> {
+        self.allocator.try_cached_chain(start_sector_id)
+    }
+
     pub fn open_chain(
         &mut self,
         start_sector_id: u32,
@@ -77,6 +185,59 @@ impl<F> Directory<F> {
         self.allocator.open_chain(start_sector_id, init)
     }
 
+    /// Replaces the in-memory directory, DIFAT, and FAT, e.g. after the file
+    /// has been rewritten with a new layout.
+    pub fn replace_tables(
+        &mut self,
+        dir_entries: Vec<DirEntry>,
+        dir_start_sector: u32,
+        difat_sector_ids: Vec<u32>,
+        difat: Vec<u32>,
+        fat: Vec<u32>,
+    ) {
+        self.free_dir_entries = FreeList::new(is_unallocated(&dir_entries));
+        self.dirty_dir_sectors.clear();
+        self.dir_entries = dir_entries;
+        self.dir_start_sector = dir_start_sector;
+        self.allocator.replace_tables(difat_sector_ids, difat, fat);
+    }
+
+    /// Discards all changes made since the current transaction began,
+    /// restoring the directory, DIFAT, and FAT from that time.
+    pub fn revert_transaction(&mut self) {
+        if let Some((dir_entries, dir_start_sector)) = self.transaction.take()
+        {
+            self.free_dir_entries =
+                FreeList::new(is_unallocated(&dir_entries));
+            self.dirty_dir_sectors.clear();
+            self.dir_entries = dir_entries;
+            self.dir_start_sector = dir_start_sector;
+        }
+        self.allocator.revert_transaction();
+    }
+
+    pub fn dir_start_sector(&self) -> u32 {
+        self.dir_start_sector
+    }
+
+    pub fn num_dir_entries(&self) -> u32 {
+        self.dir_entries.len() as u32
+    }
+
+    /// Checks the index of unallocated directory entries against the
+    /// entries themselves.
+    #[cfg(test)]
+    pub fn verify_free_dir_entries(&self) -> io::Result<()> {
+        self.free_dir_entries.verify(
+            is_unallocated(&self.dir_entries),
+            |stream_id, message| crate::Error::Directory {
+                stream_id: Some(stream_id),
+                path: None,
+                message,
+            },
+        )
+    }
+
     pub fn root_dir_entry(&self) -> &DirEntry {
         self.dir_entry(consts::ROOT_STREAM_ID)
     }
@@ -89,40 +250,587 @@ impl<F> Directory<F> {
         &mut self.dir_entries[stream_id as usize]
     }
 
-    fn validate(&self, validation: Validation) -> io::Result<()> {
+    /// Links an allocated (but currently unlinked) directory entry into the
+    /// red-black sibling tree under the specified parent entry, rebalancing
+    /// the tree as needed.  The stream IDs of all entries that were changed
+    /// are added to `touched`.
+    fn link_dir_entry(
+        &mut self,
+        parent_id: u32,
+        stream_id: u32,
+        touched: &mut Vec<u32>,
+    ) {
+        {
+            let dir_entry = self.dir_entry_mut(stream_id);
+            dir_entry.left_sibling = consts::NO_STREAM;
+            dir_entry.right_sibling = consts::NO_STREAM;
+            dir_entry.color = Color::Red;
+        }
+        touched.push(stream_id);
+        // Find the insertion point, recording the path from the top of the
+        // tree down to the new entry's parent.
+        let mut path = Vec::<u32>::new();
+        let mut sibling_id = self.dir_entry(parent_id).child;
+        let mut ordering = Ordering::Equal;
+        while sibling_id != consts::NO_STREAM {
+            let sibling = self.dir_entry(sibling_id);
+            path.push(sibling_id);
+            ordering = internal::path::compare_names(
+                &self.dir_entry(stream_id).name,
+                &sibling.name,
+            );
+            sibling_id = match ordering {
+                Ordering::Less => sibling.left_sibling,
+                Ordering::Greater => sibling.right_sibling,
+                Ordering::Equal => panic!("internal error: insert duplicate"),
+            };
+        }
+        match (path.last(), ordering) {
+            (Some(&prev_id), Ordering::Less) => {
+                self.dir_entry_mut(prev_id).left_sibling = stream_id;
+                touched.push(prev_id);
+            }
+            (Some(&prev_id), _) => {
+                self.dir_entry_mut(prev_id).right_sibling = stream_id;
+                touched.push(prev_id);
+            }
+            (None, _) => {
+                self.dir_entry_mut(parent_id).child = stream_id;
+                touched.push(parent_id);
+            }
+        }
+
+        // Restore the red-black invariants, walking back up the path.
+        let mut node_id = stream_id;
+        while let Some(tree_parent_id) = path.pop() {
+            if !self.is_red(tree_parent_id) {
+                break;
+            }
+            // Since the tree parent is red, it cannot be the top of the tree,
+            // so there must be a grandparent.
+            let grandparent_id = match path.pop() {
+                Some(id) => id,
+                None => break,
+            };
+            let parent_is_left =
+                self.dir_entry(grandparent_id).left_sibling == tree_parent_id;
+            let uncle_id = if parent_is_left {
+                self.dir_entry(grandparent_id).right_sibling
+            } else {
+                self.dir_entry(grandparent_id).left_sibling
+            };
+            if self.is_red(uncle_id) {
+                self.set_color(tree_parent_id, Color::Black, touched);
+                self.set_color(uncle_id, Color::Black, touched);
+                self.set_color(grandparent_id, Color::Red, touched);
+                node_id = grandparent_id;
+                continue;
+            }
+            let great_id = path.last().copied().unwrap_or(consts::NO_STREAM);
+            let mut top_id = tree_parent_id;
+            if parent_is_left {
+                if self.dir_entry(tree_parent_id).right_sibling == node_id {
+                    self.rotate_left(
+                        parent_id,
+                        grandparent_id,
+                        tree_parent_id,
+                        touched,
+                    );
+                    top_id = node_id;
+                }
+                self.rotate_right(
+                    parent_id,
+                    great_id,
+                    grandparent_id,
+                    touched,
+                );
+            } else {
+                if self.dir_entry(tree_parent_id).left_sibling == node_id {
+                    self.rotate_right(
+                        parent_id,
+                        grandparent_id,
+                        tree_parent_id,
+                        touched,
+                    );
+                    top_id = node_id;
+                }
+                self.rotate_left(parent_id, great_id, grandparent_id, touched);
+            }
+            self.set_color(top_id, Color::Black, touched);
+            self.set_color(grandparent_id, Color::Red, touched);
+            break;
+        }
+        let top_id = self.dir_entry(parent_id).child;
+        self.set_color(top_id, Color::Black, touched);
+    }
+
+    /// Unlinks the directory entry with the given name from the red-black
+    /// sibling tree under the specified parent entry, without deallocating
+    /// it, and returns its stream ID.  Other entries in the tree keep their
+    /// stream IDs.  The stream IDs of all entries that were changed are added
+    /// to `touched`.
+    fn unlink_dir_entry(
+        &mut self,
+        parent_id: u32,
+        name: &str,
+        touched: &mut Vec<u32>,
+    ) -> u32 {
+        // Find the directory entry with the given name below the parent,
+        // recording the path from the top of the tree down to it.
+        let mut path = Vec::<u32>::new();
+        let mut stream_id = self.dir_entry(parent_id).child;
+        loop {
+            debug_assert_ne!(stream_id, consts::NO_STREAM);
+            let dir_entry = self.dir_entry(stream_id);
+            let next_id =
+                match internal::path::compare_names(name, &dir_entry.name) {
+                    Ordering::Equal => break,
+                    Ordering::Less => dir_entry.left_sibling,
+                    Ordering::Greater => dir_entry.right_sibling,
+                };
+            path.push(stream_id);
+            stream_id = next_id;
+        }
+        touched.push(stream_id);
+
+        // Restructure the tree, keeping track of the entry that moves into
+        // the vacated position (which may be NO_STREAM), the path down to
+        // that position, which side of its tree parent it is on, and the
+        // color that was removed from the tree.
+        let left_sibling = self.dir_entry(stream_id).left_sibling;
+        let right_sibling = self.dir_entry(stream_id).right_sibling;
+        let tree_parent_id = path.last().copied().unwrap_or(consts::NO_STREAM);
+        let (node_id, node_is_left, removed_color) = if left_sibling
+            == consts::NO_STREAM
+            || right_sibling == consts::NO_STREAM
+        {
+            let replacement_id = if left_sibling == consts::NO_STREAM {
+                right_sibling
+            } else {
+                left_sibling
+            };
+            let is_left = tree_parent_id != consts::NO_STREAM
+                && self.dir_entry(tree_parent_id).left_sibling == stream_id;
+            self.replace_link(
+                parent_id,
+                tree_parent_id,
+                stream_id,
+                replacement_id,
+                touched,
+            );
+            (replacement_id, is_left, self.dir_entry(stream_id).color)
+        } else {
+            // The entry has two children, so replace it with its in-order
+            // predecessor, which has no right sibling.
+            let mut pred_path = Vec::<u32>::new();
+            let mut predecessor_id = left_sibling;
+            loop {
+                let next_id = self.dir_entry(predecessor_id).right_sibling;
+                if next_id == consts::NO_STREAM {
+                    break;
+                }
+                pred_path.push(predecessor_id);
+                predecessor_id = next_id;
+            }
+            let pred_left = self.dir_entry(predecessor_id).left_sibling;
+            let removed_color = self.dir_entry(predecessor_id).color;
+            let is_left = if let Some(&pred_parent_id) = pred_path.last() {
+                self.dir_entry_mut(pred_parent_id).right_sibling = pred_left;
+                self.dir_entry_mut(predecessor_id).left_sibling = left_sibling;
+                touched.push(pred_parent_id);
+                false
+            } else {
+                true
+            };
+            let color = self.dir_entry(stream_id).color;
+            {
+                let pred_entry = self.dir_entry_mut(predecessor_id);
+                pred_entry.right_sibling = right_sibling;
+                pred_entry.color = color;
+            }
+            touched.push(predecessor_id);
+            self.replace_link(
+                parent_id,
+                tree_parent_id,
+                stream_id,
+                predecessor_id,
+                touched,
+            );
+            path.push(predecessor_id);
+            path.extend(pred_path);
+            (pred_left, is_left, removed_color)
+        };
+        let dir_entry = self.dir_entry_mut(stream_id);
+        dir_entry.left_sibling = consts::NO_STREAM;
+        dir_entry.right_sibling = consts::NO_STREAM;
+
+        // If a black entry was removed, restore the red-black invariants,
+        // walking back up the path.
+        if removed_color == Color::Black {
+            self.fix_after_unlink(
+                parent_id,
+                node_id,
+                node_is_left,
+                path,
+                touched,
+            );
+        }
+        stream_id
+    }
+
+    /// Restores the red-black invariants after a black entry has been
+    /// removed from the sibling tree under `parent_id`.  `node_id` is the
+    /// entry (possibly NO_STREAM) now occupying the removed position, and
+    /// `path` is the list of its ancestors from the top of the tree down.
+    fn fix_after_unlink(
+        &mut self,
+        parent_id: u32,
+        mut node_id: u32,
+        mut node_is_left: bool,
+        mut path: Vec<u32>,
+        touched: &mut Vec<u32>,
+    ) {
+        while !self.is_red(node_id) {
+            let tree_parent_id = match path.last() {
+                Some(&id) => id,
+                None => break,
+            };
+            let grandparent_id = if path.len() >= 2 {
+                path[path.len() - 2]
+            } else {
+                consts::NO_STREAM
+            };
+            let mut sibling_id = if node_is_left {
+                self.dir_entry(tree_parent_id).right_sibling
+            } else {
+                self.dir_entry(tree_parent_id).left_sibling
+            };
+            if self.is_red(sibling_id) {
+                self.set_color(sibling_id, Color::Black, touched);
+                self.set_color(tree_parent_id, Color::Red, touched);
+                // After rotating, the old sibling sits between the tree
+                // parent and the grandparent.
+                let index = path.len() - 1;
+                path.insert(index, sibling_id);
+                if node_is_left {
+                    self.rotate_left(
+                        parent_id,
+                        grandparent_id,
+                        tree_parent_id,
+                        touched,
+                    );
+                    sibling_id = self.dir_entry(tree_parent_id).right_sibling;
+                } else {
+                    self.rotate_right(
+                        parent_id,
+                        grandparent_id,
+                        tree_parent_id,
+                        touched,
+                    );
+                    sibling_id = self.dir_entry(tree_parent_id).left_sibling;
+                }
+            }
+            if sibling_id == consts::NO_STREAM {
+                // This can only happen if the tree was not a valid red-black
+                // tree to begin with; just move up and hope for the best.
+                node_id = tree_parent_id;
+                path.pop();
+                node_is_left = self.is_left_of_path_end(&path, node_id);
+                continue;
+            }
+            let (near_id, far_id) = if node_is_left {
+                let sibling = self.dir_entry(sibling_id);
+                (sibling.left_sibling, sibling.right_sibling)
+            } else {
+                let sibling = self.dir_entry(sibling_id);
+                (sibling.right_sibling, sibling.left_sibling)
+            };
+            if !self.is_red(near_id) && !self.is_red(far_id) {
+                self.set_color(sibling_id, Color::Red, touched);
+                node_id = tree_parent_id;
+                path.pop();
+                node_is_left = self.is_left_of_path_end(&path, node_id);
+                continue;
+            }
+            if !self.is_red(far_id) {
+                self.set_color(near_id, Color::Black, touched);
+                self.set_color(sibling_id, Color::Red, touched);
+                if node_is_left {
+                    self.rotate_right(
+                        parent_id,
+                        tree_parent_id,
+                        sibling_id,
+                        touched,
+                    );
+                } else {
+                    self.rotate_left(
+                        parent_id,
+                        tree_parent_id,
+                        sibling_id,
+                        touched,
+                    );
+                }
+                sibling_id = near_id;
+            }
+            let grandparent_id = if path.len() >= 2 {
+                path[path.len() - 2]
+            } else {
+                consts::NO_STREAM
+            };
+            let color = self.dir_entry(tree_parent_id).color;
+            self.set_color(sibling_id, color, touched);
+            self.set_color(tree_parent_id, Color::Black, touched);
+            if node_is_left {
+                let far_id = self.dir_entry(sibling_id).right_sibling;
+                self.set_color(far_id, Color::Black, touched);
+                self.rotate_left(
+                    parent_id,
+                    grandparent_id,
+                    tree_parent_id,
+                    touched,
+                );
+            } else {
+                let far_id = self.dir_entry(sibling_id).left_sibling;
+                self.set_color(far_id, Color::Black, touched);
+                self.rotate_right(
+                    parent_id,
+                    grandparent_id,
+                    tree_parent_id,
+                    touched,
+                );
+            }
+            node_id = self.dir_entry(parent_id).child;
+            break;
+        }
+        self.set_color(node_id, Color::Black, touched);
+    }
+
+    fn is_red(&self, stream_id: u32) -> bool {
+        stream_id != consts::NO_STREAM
+            && self.dir_entry(stream_id).color == Color::Red
+    }
+
+    fn set_color(
+        &mut self,
+        stream_id: u32,
+        color: Color,
+        touched: &mut Vec<u32>,
+    ) {
+        if stream_id != consts::NO_STREAM
+            && self.dir_entry(stream_id).color != color
+        {
+            self.dir_entry_mut(stream_id).color = color;
+            touched.push(stream_id);
+        }
+    }
+
+    /// Returns true if the given entry is the left sibling of the last entry
+    /// in the path.
+    fn is_left_of_path_end(&self, path: &[u32], stream_id: u32) -> bool {
+        match path.last() {
+            Some(&id) => self.dir_entry(id).left_sibling == stream_id,
+            None => false,
+        }
+    }
+
+    /// Makes whichever link pointed to `old_id` (either a sibling link of
+    /// `tree_parent_id`, or the child link of the storage `parent_id` if
+    /// `tree_parent_id` is NO_STREAM) point to `new_id` instead.
+    fn replace_link(
+        &mut self,
+        parent_id: u32,
+        tree_parent_id: u32,
+        old_id: u32,
+        new_id: u32,
+        touched: &mut Vec<u32>,
+    ) {
+        if tree_parent_id == consts::NO_STREAM {
+            debug_assert_eq!(self.dir_entry(parent_id).child, old_id);
+            self.dir_entry_mut(parent_id).child = new_id;
+            touched.push(parent_id);
+        } else {
+            let tree_parent = self.dir_entry_mut(tree_parent_id);
+            if tree_parent.left_sibling == old_id {
+                tree_parent.left_sibling = new_id;
+            } else {
+                debug_assert_eq!(tree_parent.right_sibling, old_id);
+                tree_parent.right_sibling = new_id;
+            }
+            touched.push(tree_parent_id);
+        }
+    }
+
+    fn rotate_left(
+        &mut self,
+        parent_id: u32,
+        tree_parent_id: u32,
+        stream_id: u32,
+        touched: &mut Vec<u32>,
+    ) {
+        let right_id = self.dir_entry(stream_id).right_sibling;
+        debug_assert_ne!(right_id, consts::NO_STREAM);
+        let inner_id = self.dir_entry(right_id).left_sibling;
+        self.dir_entry_mut(stream_id).right_sibling = inner_id;
+        self.dir_entry_mut(right_id).left_sibling = stream_id;
+        touched.push(stream_id);
+        touched.push(right_id);
+        self.replace_link(
+            parent_id,
+            tree_parent_id,
+            stream_id,
+            right_id,
+            touched,
+        );
+    }
+
+    fn rotate_right(
+        &mut self,
+        parent_id: u32,
+        tree_parent_id: u32,
+        stream_id: u32,
+        touched: &mut Vec<u32>,
+    ) {
+        let left_id = self.dir_entry(stream_id).left_sibling;
+        debug_assert_ne!(left_id, consts::NO_STREAM);
+        let inner_id = self.dir_entry(left_id).right_sibling;
+        self.dir_entry_mut(stream_id).left_sibling = inner_id;
+        self.dir_entry_mut(left_id).right_sibling = stream_id;
+        touched.push(stream_id);
+        touched.push(left_id);
+        self.replace_link(
+            parent_id,
+            tree_parent_id,
+            stream_id,
+            left_id,
+            touched,
+        );
+    }
+
+    /// Returns the stream IDs of all entries in the sibling tree under the
+    /// specified parent entry, in name order.
+    fn sibling_tree_in_order(&self, parent_id: u32) -> Vec<u32> {
+        let mut members = Vec::new();
+        let mut stack = Vec::new();
+        let mut current_id = self.dir_entry(parent_id).child;
+        loop {
+            while current_id != consts::NO_STREAM {
+                stack.push(current_id);
+                current_id = self.dir_entry(current_id).left_sibling;
+            }
+            match stack.pop() {
+                Some(stream_id) => {
+                    members.push(stream_id);
+                    current_id = self.dir_entry(stream_id).right_sibling;
+                }
+                None => break,
+            }
+        }
+        members
+    }
+
+    /// Relinks the given entries (which must be sorted by name) into a
+    /// balanced red-black tree under the specified parent entry.  Entries on
+    /// the deepest level of an incomplete tree are colored red, and all
+    /// others black, so that every path has the same number of black entries.
+    fn rebuild_sibling_tree(
+        &mut self,
+        parent_id: u32,
+        members: &[u32],
+        touched: &mut Vec<u32>,
+    ) {
+        fn build<F>(
+            directory: &mut Directory<F>,
+            members: &[u32],
+            depth: usize,
+            max_depth: usize,
+            touched: &mut Vec<u32>,
+        ) -> u32 {
+            if members.is_empty() {
+                return consts::NO_STREAM;
+            }
+            let middle = members.len() / 2;
+            let left_id = build(
+                directory,
+                &members[..middle],
+                depth + 1,
+                max_depth,
+                touched,
+            );
+            let right_id = build(
+                directory,
+                &members[(middle + 1)..],
+                depth + 1,
+                max_depth,
+                touched,
+            );
+            let stream_id = members[middle];
+            let color = if depth == max_depth && depth > 0 {
+                Color::Red
+            } else {
+                Color::Black
+            };
+            let dir_entry = directory.dir_entry_mut(stream_id);
+            if dir_entry.left_sibling != left_id
+                || dir_entry.right_sibling != right_id
+                || dir_entry.color != color
+            {
+                dir_entry.left_sibling = left_id;
+                dir_entry.right_sibling = right_id;
+                dir_entry.color = color;
+                touched.push(stream_id);
+            }
+            stream_id
+        }
+
+        let mut max_depth = 0;
+        while (2 << max_depth) <= members.len() {
+            max_depth += 1;
+        }
+        let top_id = build(self, members, 0, max_depth, touched);
+        if self.dir_entry(parent_id).child != top_id {
+            self.dir_entry_mut(parent_id).child = top_id;
+            touched.push(parent_id);
+        }
+    }
+
+    fn validate(&self, validator: &mut Validator) -> io::Result<()> {
         if self.dir_entries.is_empty() {
             malformed!("root entry is missing");
         }
         let root_entry = self.root_dir_entry();
-        if root_entry.stream_len % consts::MINI_SECTOR_LEN as u64 != 0 {
-            malformed!(
+        if root_entry.stream_len % (consts::MINI_SECTOR_LEN as u64) != 0 {
+            validator.reject(malformation!(
+                consts::ROOT_STREAM_ID;
                 "root stream len is {}, but should be multiple of {}",
                 root_entry.stream_len,
                 consts::MINI_SECTOR_LEN
-            );
+            ))?;
         }
-        let mut visited = FnvHashSet::default();
+        let mut visited = IdSet::default();
         let mut stack = vec![(consts::ROOT_STREAM_ID, false)];
         while let Some((stream_id, parent_is_red)) = stack.pop() {
             if visited.contains(&stream_id) {
-                malformed!("loop in tree");
+                validator.reject(malformation!(stream_id; "loop in tree"))?;
+                continue;
             }
             visited.insert(stream_id);
             let dir_entry = self.dir_entry(stream_id);
             if stream_id == consts::ROOT_STREAM_ID {
                 if dir_entry.obj_type != ObjType::Root {
-                    malformed!(
+                    validator.reject(malformation!(
+                        stream_id;
                         "root entry has object type {:?}",
                         dir_entry.obj_type
-                    );
+                    ))?;
                 }
             } else if dir_entry.obj_type != ObjType::Storage
                 && dir_entry.obj_type != ObjType::Stream
             {
-                malformed!(
+                validator.reject(malformation!(
+                    stream_id;
                     "non-root entry with object type {:?}",
                     dir_entry.obj_type
-                );
+                ))?;
             }
             let node_is_red = dir_entry.color == Color::Red;
             // The MS-CFB spec section 2.6.4 says that two consecutive nodes in
@@ -130,68 +838,122 @@ impl<F> Directory<F> {
             // both be red, but apparently some implementations don't obey this
             // (see https://github.com/mdsteele/rust-cfb/issues/10).  We still
             // want to be able to read these files, so we only consider this an
-            // error under Strict validation.
-            if parent_is_red && node_is_red && validation.is_strict() {
-                malformed!("RB tree has adjacent red nodes");
+            // error if the validation policy doesn't allow it.
+            if parent_is_red && node_is_red {
+                validator.tolerate(
+                    validator.policy().allow_adjacent_red_nodes,
+                    malformation!(
+                        stream_id;
+                        "RB tree has adjacent red nodes"
+                    ),
+                )?;
             }
             let left_sibling = dir_entry.left_sibling;
             if left_sibling != consts::NO_STREAM {
                 if left_sibling as usize >= self.dir_entries.len() {
-                    malformed!(
+                    validator.reject(malformation!(
+                        stream_id;
                         "left sibling index is {}, but directory entry count \
                          is {}",
                         left_sibling,
                         self.dir_entries.len()
-                    );
-                }
-                let entry = &self.dir_entry(left_sibling);
-                if internal::path::compare_names(&entry.name, &dir_entry.name)
-                    != Ordering::Less
-                {
-                    malformed!(
-                        "name ordering, {:?} vs {:?}",
-                        dir_entry.name,
-                        entry.name
-                    );
+                    ))?;
+                } else {
+                    let entry = &self.dir_entry(left_sibling);
+                    if internal::path::compare_names(
+                        &entry.name,
+                        &dir_entry.name,
+                    ) != Ordering::Less
+                    {
+                        validator.reject(malformation!(
+                            stream_id;
+                            "name ordering, {:?} vs {:?}",
+                            dir_entry.name,
+                            entry.name
+                        ))?;
+                    }
+                    stack.push((left_sibling, node_is_red));
                 }
-                stack.push((left_sibling, node_is_red));
             }
             let right_sibling = dir_entry.right_sibling;
             if right_sibling != consts::NO_STREAM {
                 if right_sibling as usize >= self.dir_entries.len() {
-                    malformed!(
+                    validator.reject(malformation!(
+                        stream_id;
                         "right sibling index is {}, but directory entry count \
                          is {}",
-                        right_sibling, self.dir_entries.len());
-                }
-                let entry = &self.dir_entry(right_sibling);
-                if internal::path::compare_names(&dir_entry.name, &entry.name)
-                    != Ordering::Less
-                {
-                    malformed!(
-                        "name ordering, {:?} vs {:?}",
-                        dir_entry.name,
-                        entry.name
-                    );
+                        right_sibling,
+                        self.dir_entries.len()
+                    ))?;
+                } else {
+                    let entry = &self.dir_entry(right_sibling);
+                    if internal::path::compare_names(
+                        &dir_entry.name,
+                        &entry.name,
+                    ) != Ordering::Less
+                    {
+                        validator.reject(malformation!(
+                            stream_id;
+                            "name ordering, {:?} vs {:?}",
+                            dir_entry.name,
+                            entry.name
+                        ))?;
+                    }
+                    stack.push((right_sibling, node_is_red));
                 }
-                stack.push((right_sibling, node_is_red));
             }
             let child = dir_entry.child;
             if child != consts::NO_STREAM {
                 if child as usize >= self.dir_entries.len() {
-                    malformed!(
+                    validator.reject(malformation!(
+                        stream_id;
                         "child index is {}, but directory entry count is {}",
                         child,
                         self.dir_entries.len()
-                    );
+                    ))?;
+                } else {
+                    stack.push((child, false));
                 }
-                stack.push((child, false));
             }
         }
+        validator.finish_stage()?;
         Ok(())
     }
 }
 
+/// Returns, for each directory entry, whether that entry is unallocated.
+fn is_unallocated(
+    dir_entries: &[DirEntry],
+) -> impl Iterator<Item = bool> + '_ {
+    dir_entries
+        .iter()
+        .map(|dir_entry| dir_entry.obj_type == ObjType::Unallocated)
+}
+
+impl<F: SetLen> Directory<F> {
+    pub fn set_num_sectors(&mut self, num_sectors: u32) -> io::Result<()> {
+        self.allocator.set_num_sectors(num_sectors)
+    }
+}
+
+impl<F: Write + Seek + SetLen> Directory<F> {
+    /// Truncates the underlying file to remove any free sectors at the end.
+    pub fn shrink_to_fit(&mut self) -> io::Result<()> {
+        self.allocator.shrink_to_fit()
+    }
+}
+
+impl<F: ReadAt> Directory<F> {
+    pub fn read_at_sector(
+        &self,
+        sector_id: u32,
+        offset_within_sector: u64,
+        buf: &mut [u8],
+    ) -> io::Result<usize> {
+        self.allocator.read_at_sector(sector_id, offset_within_sector, buf)
+    }
+}
+
 impl<F: Seek> Directory<F> {
     pub fn seek_within_header(
         &mut self,
@@ -200,6 +962,18 @@ impl<F: Seek> Directory<F> {
         self.allocator.seek_within_header(offset_within_header)
     }
 
+    pub fn seek_to_sector(&mut self, sector_id: u32) -> io::Result<Sector<F>> {
+        self.allocator.seek_to_sector(sector_id)
+    }
+
+    pub fn seek_within_sector(
+        &mut self,
+        sector_id: u32,
+        offset_within_sector: u64,
+    ) -> io::Result<Sector<F>> {
+        self.allocator.seek_within_sector(sector_id, offset_within_sector)
+    }
+
     fn seek_to_dir_entry(&mut self, stream_id: u32) -> io::Result<Sector<F>> {
         self.seek_within_dir_entry(stream_id, 0)
     }
@@ -212,11 +986,21 @@ impl<F: Seek> Directory<F> {
         let dir_entries_per_sector =
             self.version().dir_entries_per_sector() as u32;
         let index_within_sector = stream_id % dir_entries_per_sector;
-        let mut directory_sector = self.dir_start_sector;
-        for _ in 0..(stream_id / dir_entries_per_sector) {
-            debug_assert_ne!(directory_sector, consts::END_OF_CHAIN);
-            directory_sector = self.allocator.next(directory_sector)?;
-        }
+        let index_within_chain = (stream_id / dir_entries_per_sector) as usize;
+        let directory_sector = {
+            let chain = self
+                .allocator
+                .open_chain(self.dir_start_sector, SectorInit::Dir)?;
+            match chain.sector_ids().get(index_within_chain) {
+                Some(&sector_id) => sector_id,
+                None => corrupted!(
+                    Directory { stream_id: Some(stream_id), path: None },
+                    "Directory entry {} is beyond the end of the directory \
+                     chain",
+                    stream_id
+                ),
+            }
+        };
         self.allocator.seek_within_subsector(
             directory_sector,
             index_within_sector,
@@ -249,6 +1033,17 @@ impl<F: Write + Seek> Directory<F> {
         self.allocator.free_chain(start_sector_id)
     }
 
+    /// Truncates the chain starting at the given sector so that it has only
+    /// `num_sectors` sectors (which must be nonzero), and deallocates all
+    /// subsequent sectors in the chain.
+    pub fn truncate_chain(
+        &mut self,
+        start_sector_id: u32,
+        num_sectors: usize,
+    ) -> io::Result<()> {
+        self.allocator.truncate_chain(start_sector_id, num_sectors)
+    }
+
     /// Inserts a new directory entry into the tree under the specified parent
     /// entry, then returns the new stream ID.
     pub fn insert_dir_entry(
@@ -269,44 +1064,11 @@ impl<F: Write + Seek> Directory<F> {
         }
         *self.dir_entry_mut(stream_id) = DirEntry::new(name, obj_type, ts);
 
-        // Insert the new entry into the tree.
-        let mut sibling_id = self.dir_entry(parent_id).child;
-        let mut prev_sibling_id = parent_id;
-        let mut ordering = Ordering::Equal;
-        while sibling_id != consts::NO_STREAM {
-            let sibling = self.dir_entry(sibling_id);
-            prev_sibling_id = sibling_id;
-            ordering = internal::path::compare_names(name, &sibling.name);
-            sibling_id = match ordering {
-                Ordering::Less => sibling.left_sibling,
-                Ordering::Greater => sibling.right_sibling,
-                Ordering::Equal => panic!("internal error: insert duplicate"),
-            };
-        }
-        match ordering {
-            Ordering::Less => {
-                self.dir_entry_mut(prev_sibling_id).left_sibling = stream_id;
-                let mut sector =
-                    self.seek_within_dir_entry(prev_sibling_id, 68)?;
-                sector.write_u32::<LittleEndian>(stream_id)?;
-            }
-            Ordering::Greater => {
-                self.dir_entry_mut(prev_sibling_id).right_sibling = stream_id;
-                let mut sector =
-                    self.seek_within_dir_entry(prev_sibling_id, 72)?;
-                sector.write_u32::<LittleEndian>(stream_id)?;
-            }
-            Ordering::Equal => {
-                debug_assert_eq!(prev_sibling_id, parent_id);
-                self.dir_entry_mut(parent_id).child = stream_id;
-                let mut sector = self.seek_within_dir_entry(parent_id, 76)?;
-                sector.write_u32::<LittleEndian>(stream_id)?;
-            }
-        }
-        // TODO: rebalance tree
-
-        // Write new entry to underyling file.
-        self.write_dir_entry(stream_id)?;
+        // Insert the new entry into the tree, then write the new entry (and
+        // any entries that rebalancing changed) to the underlying file.
+        let mut touched = Vec::new();
+        self.link_dir_entry(parent_id, stream_id, &mut touched);
+        self.write_dir_entries(touched)?;
         Ok(stream_id)
     }
 
@@ -316,91 +1078,60 @@ impl<F: Write + Seek> Directory<F> {
         parent_id: u32,
         name: &str,
     ) -> io::Result<()> {
-        // Find the directory entry with the given name below the parent.
-        let mut stream_ids = Vec::new();
-        let mut stream_id = self.dir_entry(parent_id).child;
-        loop {
-            debug_assert_ne!(stream_id, consts::NO_STREAM);
-            debug_assert!(!stream_ids.contains(&stream_id));
-            stream_ids.push(stream_id);
-            let dir_entry = self.dir_entry(stream_id);
-            match internal::path::compare_names(name, &dir_entry.name) {
-                Ordering::Equal => break,
-                Ordering::Less => stream_id = dir_entry.left_sibling,
-                Ordering::Greater => stream_id = dir_entry.right_sibling,
-            }
-        }
+        let mut touched = Vec::new();
+        let stream_id = self.unlink_dir_entry(parent_id, name, &mut touched);
         debug_assert_eq!(self.dir_entry(stream_id).child, consts::NO_STREAM);
+        touched.retain(|&id| id != stream_id);
+        self.write_dir_entries(touched)?;
+        self.free_dir_entry(stream_id)?;
+        Ok(())
+    }
 
-        // Restructure the tree.
-        let mut replacement_id = consts::NO_STREAM;
-        loop {
-            let left_sibling = self.dir_entry(stream_id).left_sibling;
-            let right_sibling = self.dir_entry(stream_id).right_sibling;
-            if left_sibling == consts::NO_STREAM
-                && right_sibling == consts::NO_STREAM
-            {
-                break;
-            } else if left_sibling == consts::NO_STREAM {
-                replacement_id = right_sibling;
-                break;
-            } else if right_sibling == consts::NO_STREAM {
-                replacement_id = left_sibling;
-                break;
-            }
-            let mut predecessor_id = left_sibling;
-            loop {
-                stream_ids.push(predecessor_id);
-                let next_id = self.dir_entry(predecessor_id).right_sibling;
-                if next_id == consts::NO_STREAM {
-                    break;
+    /// Moves a directory entry from one parent to another (or to the same
+    /// parent), giving it a new name.  The entry keeps its stream ID, and all
+    /// of its other fields (including its child pointer) are left unchanged.
+    pub fn move_dir_entry(
+        &mut self,
+        old_parent_id: u32,
+        old_name: &str,
+        new_parent_id: u32,
+        new_name: &str,
+    ) -> io::Result<u32> {
+        let mut touched = Vec::new();
+        let stream_id =
+            self.unlink_dir_entry(old_parent_id, old_name, &mut touched);
+        self.dir_entry_mut(stream_id).name = new_name.to_string();
+        self.link_dir_entry(new_parent_id, stream_id, &mut touched);
+        self.write_dir_entries(touched)?;
+        Ok(stream_id)
+    }
+
+    /// Rebuilds every sibling tree in the directory as a balanced, validly
+    /// colored red-black tree, writing any changed entries to the underlying
+    /// file.  This is useful for trees that were written by implementations
+    /// that don't keep them balanced.
+    pub fn rebalance_all(&mut self) -> io::Result<()> {
+        let mut touched = Vec::new();
+        let mut stack = vec![consts::ROOT_STREAM_ID];
+        while let Some(parent_id) = stack.pop() {
+            let members = self.sibling_tree_in_order(parent_id);
+            for &stream_id in members.iter() {
+                if self.dir_entry(stream_id).obj_type != ObjType::Stream {
+                    stack.push(stream_id);
                 }
-                predecessor_id = next_id;
-            }
-            let mut pred_entry = self.dir_entry(predecessor_id).clone();
-            debug_assert_eq!(pred_entry.right_sibling, consts::NO_STREAM);
-            pred_entry.left_sibling = left_sibling;
-            pred_entry.right_sibling = right_sibling;
-            pred_entry.write_to(&mut self.seek_to_dir_entry(stream_id)?)?;
-            *self.dir_entry_mut(stream_id) = pred_entry;
-            stream_id = predecessor_id;
-        }
-        // TODO: recolor nodes
-
-        // Remove the entry.
-        debug_assert_eq!(stream_ids.last(), Some(&stream_id));
-        stream_ids.pop();
-        if let Some(&sibling_id) = stream_ids.last() {
-            if self.dir_entry(sibling_id).left_sibling == stream_id {
-                self.dir_entry_mut(sibling_id).left_sibling = replacement_id;
-                let mut sector = self.seek_within_dir_entry(sibling_id, 68)?;
-                sector.write_u32::<LittleEndian>(replacement_id)?;
-            } else {
-                debug_assert_eq!(
-                    self.dir_entry(sibling_id).right_sibling,
-                    stream_id
-                );
-                self.dir_entry_mut(sibling_id).right_sibling = replacement_id;
-                let mut sector = self.seek_within_dir_entry(sibling_id, 72)?;
-                sector.write_u32::<LittleEndian>(replacement_id)?;
             }
-        } else {
-            self.dir_entry_mut(parent_id).child = replacement_id;
-            let mut sector = self.seek_within_dir_entry(parent_id, 76)?;
-            sector.write_u32::<LittleEndian>(replacement_id)?;
+            self.rebuild_sibling_tree(parent_id, &members, &mut touched);
         }
-        self.free_dir_entry(stream_id)?;
-        Ok(())
+        self.write_dir_entries(touched)
     }
 
     /// Adds a new (uninitialized) entry to the directory and returns the new
     /// stream ID.
     fn allocate_dir_entry(&mut self) -> io::Result<u32> {
         // If there's an existing unalloated directory entry, use that.
-        for (stream_id, entry) in self.dir_entries.iter().enumerate() {
-            if entry.obj_type == ObjType::Unallocated {
-                return Ok(stream_id as u32);
-            }
+        if let Some(stream_id) = self.free_dir_entries.first() {
+            self.free_dir_entries.set_free(stream_id, false);
+            return Ok(stream_id);
         }
         // Otherwise, we need a new entry; if there's not room in the directory
         // chain to add it, then first we need to add a new directory sector.
@@ -409,6 +1140,9 @@ impl<F: Write + Seek> Directory<F> {
         if self.dir_entries.len() % dir_entries_per_sector == 0 {
             let start_sector = self.dir_start_sector;
             self.allocator.extend_chain(start_sector, SectorInit::Dir)?;
+            let num_dir_sectors =
+                self.dir_entries.len() / dir_entries_per_sector + 1;
+            self.write_num_dir_sectors(num_dir_sectors as u32)?;
         }
         // Add a new entry to the end of the directory and return it.
         let stream_id = self.dir_entries.len() as u32;
@@ -420,10 +1154,52 @@ impl<F: Write + Seek> Directory<F> {
     fn free_dir_entry(&mut self, stream_id: u32) -> io::Result<()> {
         debug_assert_ne!(stream_id, consts::ROOT_STREAM_ID);
         let dir_entry = DirEntry::unallocated();
-        dir_entry.write_to(&mut self.seek_to_dir_entry(stream_id)?)?;
+        if self.defers_writes() {
+            self.mark_dir_entry_dirty(stream_id);
+        } else {
+            dir_entry.write_to(&mut self.seek_to_dir_entry(stream_id)?)?;
+        }
         *self.dir_entry_mut(stream_id) = dir_entry;
-        // TODO: Truncate directory chain if last directory sector is now all
-        //       unallocated.
+        self.free_dir_entries.set_free(stream_id, true);
+        self.truncate_dir_chain()
+    }
+
+    /// Releases any sectors at the end of the directory chain that contain
+    /// only unallocated entries.
+    fn truncate_dir_chain(&mut self) -> io::Result<()> {
+        let dir_entries_per_sector = self.version().dir_entries_per_sector();
+        let old_num_dir_sectors =
+            (self.dir_entries.len() + dir_entries_per_sector - 1)
+                / dir_entries_per_sector;
+        let mut num_dir_sectors = old_num_dir_sectors;
+        while num_dir_sectors > 1
+            && self.dir_entries
+                [(num_dir_sectors - 1) * dir_entries_per_sector..]
+                .iter()
+                .all(|dir_entry| dir_entry.obj_type == ObjType::Unallocated)
+        {
+            num_dir_sectors -= 1;
+            self.dir_entries
+                .truncate(num_dir_sectors * dir_entries_per_sector);
+            self.free_dir_entries.truncate(self.dir_entries.len());
+        }
+        if num_dir_sectors < old_num_dir_sectors {
+            self.allocator
+                .truncate_chain(self.dir_start_sector, num_dir_sectors)?;
+            self.write_num_dir_sectors(num_dir_sectors as u32)?;
+        }
+        Ok(())
+    }
+
+    /// Updates the number of directory sectors recorded in the header.  (In
+    /// V3 files this field is required to be zero, so it is left alone.)
+    fn write_num_dir_sectors(
+        &mut self,
+        num_dir_sectors: u32,
+    ) -> io::Result<()> {
+        if self.version() == Version::V4 {
+            self.allocator.write_header_fields(40, &[num_dir_sectors])?;
+        }
         Ok(())
     }
 
@@ -452,7 +1228,23 @@ impl<F: Write + Seek> Directory<F> {
         self.with_dir_entry_mut(consts::ROOT_STREAM_ID, func)
     }
 
+    fn write_dir_entries(
+        &mut self,
+        mut stream_ids: Vec<u32>,
+    ) -> io::Result<()> {
+        stream_ids.sort_unstable();
+        stream_ids.dedup();
+        for stream_id in stream_ids {
+            self.write_dir_entry(stream_id)?;
+        }
+        Ok(())
+    }
+
     fn write_dir_entry(&mut self, stream_id: u32) -> io::Result<()> {
+        if self.defers_writes() {
+            self.mark_dir_entry_dirty(stream_id);
+            return Ok(());
+        }
         let mut chain = self
             .allocator
             .open_chain(self.dir_start_sector, SectorInit::Dir)?;
@@ -461,26 +1253,147 @@ impl<F: Write + Seek> Directory<F> {
         self.dir_entries[stream_id as usize].write_to(&mut chain)
     }
 
-    /// Flushes all changes to the underlying file.
+    /// Records that the directory sector containing the specified entry needs
+    /// to be written out on the next flush.
+    fn mark_dir_entry_dirty(&mut self, stream_id: u32) {
+        let dir_entries_per_sector = self.version().dir_entries_per_sector();
+        self.dirty_dir_sectors
+            .insert(stream_id as usize / dir_entries_per_sector);
+    }
+
+    /// Writes each dirty directory sector to the underlying file.
+    fn write_dirty_dir_sectors(&mut self) -> io::Result<()> {
+        let dir_entries_per_sector = self.version().dir_entries_per_sector();
+        let mut buffer = Vec::with_capacity(self.sector_len());
+        for index in mem::take(&mut self.dirty_dir_sectors) {
+            // Directory sectors that have since been removed are skipped.
+            let start = index * dir_entries_per_sector;
+            if start >= self.dir_entries.len() {
+                continue;
+            }
+            buffer.clear();
+            for stream_id in start..(start + dir_entries_per_sector) {
+                match self.dir_entries.get(stream_id) {
+                    Some(dir_entry) => dir_entry.write_to(&mut buffer)?,
+                    None => DirEntry::unallocated().write_to(&mut buffer)?,
+                }
+            }
+            let mut chain = self
+                .allocator
+                .open_chain(self.dir_start_sector, SectorInit::Dir)?;
+            let offset = (index * buffer.len()) as u64;
+            chain.seek(SeekFrom::Start(offset))?;
+            chain.write_all(&buffer)?;
+            // During a transaction, writing may have moved the first sector.
+            self.dir_start_sector = chain.start_sector_id();
+        }
+        Ok(())
+    }
+
+    /// Given the starting sector of a chain, returns the ID of the sector at
+    /// the given index within it, copying that sector first if it is still
+    /// part of the last committed state.  See `Allocator::copy_on_write`.
+    pub fn copy_on_write(
+        &mut self,
+        start_sector_id: u32,
+        index: usize,
+    ) -> io::Result<u32> {
+        self.allocator.copy_on_write(start_sector_id, index)
+    }
+
+    /// Writes the given values to consecutive fields of the file header,
+    /// unless a transaction is in progress.
+    pub fn write_header_fields(
+        &mut self,
+        offset: u64,
+        values: &[u32],
+    ) -> io::Result<()> {
+        self.allocator.write_header_fields(offset, values)
+    }
+
+    /// Commits the current transaction: writes the changed directory sectors
+    /// (to fresh sectors where necessary), and then the FAT, DIFAT, and
+    /// header.
+    pub fn commit_transaction(
+        &mut self,
+        first_minifat_sector: u32,
+        num_minifat_sectors: u32,
+    ) -> io::Result<()> {
+        self.write_dirty_dir_sectors()?;
+        let num_dir_sectors = match self.version() {
+            Version::V3 => 0,
+            Version::V4 => self
+                .allocator
+                .open_chain(self.dir_start_sector, SectorInit::Dir)?
+                .num_sectors() as u32,
+        };
+        let header = Header {
+            version: self.version(),
+            num_dir_sectors,
+            num_fat_sectors: 0,
+            first_dir_sector: self.dir_start_sector,
+            transaction_signature: 0,
+            first_minifat_sector,
+            num_minifat_sectors,
+            first_difat_sector: consts::END_OF_CHAIN,
+            num_difat_sectors: 0,
+            initial_difat_entries: [consts::FREE_SECTOR;
+                consts::NUM_DIFAT_ENTRIES_IN_HEADER],
+        };
+        self.allocator.commit_transaction(header)?;
+        self.transaction = None;
+        Ok(())
+    }
+
+    /// Changes when FAT and directory updates are written to the underlying
+    /// file.  Any pending changes are written out when switching to
+    /// write-through mode (unless a transaction is in progress).
+    pub fn set_write_mode(&mut self, write_mode: WriteMode) -> io::Result<()> {
+        if !write_mode.is_write_back() && !self.in_transaction() {
+            self.write_dirty_dir_sectors()?;
+        }
+        self.allocator.set_write_mode(write_mode)
+    }
+
+    /// Flushes all changes to the underlying file.  During a transaction,
+    /// directory changes wait until the transaction is committed.
     pub fn flush(&mut self) -> io::Result<()> {
+        if !self.in_transaction() {
+            self.write_dirty_dir_sectors()?;
+        }
         self.allocator.flush()
     }
 }
 
+impl<F: Read + Write + Seek> Directory<F> {
+    /// Begins a transaction.  Until it is committed or reverted, no sector
+    /// that is currently allocated will be written to, and the header and
+    /// allocation tables will not be updated in the file.
+    pub fn begin_transaction(&mut self) -> io::Result<()> {
+        self.write_dirty_dir_sectors()?;
+        self.allocator.begin_transaction()?;
+        self.transaction =
+            Some((self.dir_entries.clone(), self.dir_start_sector));
+        Ok(())
+    }
+}
+
 //===========================================================================//
 
 #[cfg(test)]
 mod tests {
     use super::Directory;
+    use crate::internal::path::compare_names;
     use crate::internal::{
         consts, Allocator, Color, DirEntry, ObjType, Sectors, Timestamp,
-        Validation, Version,
+        ValidationPolicy, Version,
     };
+    use std::cmp::Ordering;
     use std::io::Cursor;
 
     fn make_directory(
         entries: Vec<DirEntry>,
-        validation: Validation,
+        policy: ValidationPolicy,
     ) -> Directory<Cursor<Vec<u8>>> {
         let version = Version::V3;
         let num_sectors = 3;
@@ -490,14 +1403,15 @@ mod tests {
         let mut fat = vec![consts::END_OF_CHAIN; num_sectors];
         fat[0] = consts::FAT_SECTOR;
         let allocator =
-            Allocator::new(sectors, vec![], vec![0], fat, validation).unwrap();
-        Directory::new(allocator, entries, 1, validation).unwrap()
+            Allocator::new(sectors, vec![], vec![0], fat, &mut policy.into())
+                .unwrap();
+        Directory::new(allocator, entries, 1, &mut policy.into()).unwrap()
     }
 
     #[test]
     #[should_panic(expected = "Malformed directory (root entry is missing)")]
     fn no_root_entry() {
-        make_directory(vec![], Validation::Permissive);
+        make_directory(vec![], ValidationPolicy::permissive());
     }
 
     #[test]
@@ -509,7 +1423,7 @@ mod tests {
         let mut root_entry = DirEntry::empty_root_entry();
         root_entry.start_sector = 2;
         root_entry.stream_len = 147;
-        make_directory(vec![root_entry], Validation::Permissive);
+        make_directory(vec![root_entry], ValidationPolicy::permissive());
     }
 
     #[test]
@@ -520,7 +1434,10 @@ mod tests {
         let mut storage =
             DirEntry::new("foo", ObjType::Storage, Timestamp::zero());
         storage.child = 1;
-        make_directory(vec![root_entry, storage], Validation::Permissive);
+        make_directory(
+            vec![root_entry, storage],
+            ValidationPolicy::permissive(),
+        );
     }
 
     #[test]
@@ -530,7 +1447,7 @@ mod tests {
     fn root_has_wrong_type() {
         let mut root_entry = DirEntry::empty_root_entry();
         root_entry.obj_type = ObjType::Storage;
-        make_directory(vec![root_entry], Validation::Permissive);
+        make_directory(vec![root_entry], ValidationPolicy::permissive());
     }
 
     #[test]
@@ -541,7 +1458,10 @@ mod tests {
         let mut root_entry = DirEntry::empty_root_entry();
         root_entry.child = 1;
         let storage = DirEntry::new("foo", ObjType::Root, Timestamp::zero());
-        make_directory(vec![root_entry, storage], Validation::Permissive);
+        make_directory(
+            vec![root_entry, storage],
+            ValidationPolicy::permissive(),
+        );
     }
 
     #[test]
@@ -553,7 +1473,7 @@ mod tests {
         // we shouldn't complain if the root is red.
         let mut root_entry = DirEntry::empty_root_entry();
         root_entry.color = Color::Red;
-        make_directory(vec![root_entry], Validation::Permissive);
+        make_directory(vec![root_entry], ValidationPolicy::permissive());
     }
 
     fn make_entries_with_adjacent_red_nodes() -> Vec<DirEntry> {
@@ -576,7 +1496,7 @@ mod tests {
     fn adjacent_red_nodes_strict() {
         make_directory(
             make_entries_with_adjacent_red_nodes(),
-            Validation::Strict,
+            ValidationPolicy::strict(),
         );
     }
 
@@ -584,8 +1504,165 @@ mod tests {
     fn adjacent_red_nodes_permissive() {
         make_directory(
             make_entries_with_adjacent_red_nodes(),
-            Validation::Permissive,
+            ValidationPolicy::permissive(),
+        );
+    }
+
+    /// Checks that the sibling tree under the given parent is a valid
+    /// red-black tree, and returns its black height.
+    fn check_rb_tree<F>(directory: &Directory<F>, parent_id: u32) -> usize {
+        fn check<F>(directory: &Directory<F>, stream_id: u32) -> usize {
+            if stream_id == consts::NO_STREAM {
+                return 1;
+            }
+            let entry = directory.dir_entry(stream_id);
+            for &sibling_id in &[entry.left_sibling, entry.right_sibling] {
+                if entry.color == Color::Red && directory.is_red(sibling_id) {
+                    panic!("adjacent red nodes at {:?}", entry.name);
+                }
+            }
+            if entry.left_sibling != consts::NO_STREAM {
+                let left = directory.dir_entry(entry.left_sibling);
+                assert_eq!(
+                    compare_names(&left.name, &entry.name),
+                    Ordering::Less
+                );
+            }
+            if entry.right_sibling != consts::NO_STREAM {
+                let right = directory.dir_entry(entry.right_sibling);
+                assert_eq!(
+                    compare_names(&entry.name, &right.name),
+                    Ordering::Less
+                );
+            }
+            let left_height = check(directory, entry.left_sibling);
+            let right_height = check(directory, entry.right_sibling);
+            assert_eq!(left_height, right_height, "at {:?}", entry.name);
+            left_height + if entry.color == Color::Black { 1 } else { 0 }
+        }
+        let top_id = directory.dir_entry(parent_id).child;
+        assert!(!directory.is_red(top_id));
+        check(directory, top_id)
+    }
+
+    fn tree_names<F>(directory: &Directory<F>, parent_id: u32) -> Vec<String> {
+        directory
+            .sibling_tree_in_order(parent_id)
+            .into_iter()
+            .map(|id| directory.dir_entry(id).name.clone())
+            .collect()
+    }
+
+    #[test]
+    fn insert_keeps_tree_balanced() {
+        let mut directory = make_directory(
+            vec![DirEntry::empty_root_entry()],
+            ValidationPolicy::strict(),
         );
+        let mut names = Vec::new();
+        for index in 0..200 {
+            let name = format!("{:03}", index);
+            directory
+                .insert_dir_entry(
+                    consts::ROOT_STREAM_ID,
+                    &name,
+                    ObjType::Stream,
+                )
+                .unwrap();
+            names.push(name);
+            let black_height =
+                check_rb_tree(&directory, consts::ROOT_STREAM_ID);
+            // A red-black tree with n nodes has black height at most
+            // log2(n + 1) + 1 (counting the NIL leaves).
+            assert!((1 << (black_height - 1)) <= names.len() + 1);
+        }
+        assert_eq!(tree_names(&directory, consts::ROOT_STREAM_ID), names);
+    }
+
+    #[test]
+    fn remove_keeps_tree_balanced() {
+        let mut directory = make_directory(
+            vec![DirEntry::empty_root_entry()],
+            ValidationPolicy::strict(),
+        );
+        let mut names: Vec<String> =
+            (0..100).map(|index| format!("{:03}", index)).collect();
+        for name in names.iter() {
+            directory
+                .insert_dir_entry(
+                    consts::ROOT_STREAM_ID,
+                    name,
+                    ObjType::Stream,
+                )
+                .unwrap();
+        }
+        // Remove entries in a scrambled order, checking the tree each time.
+        let mut index = 0;
+        while !names.is_empty() {
+            index = (index + 37) % names.len();
+            let name = names.remove(index);
+            directory.remove_dir_entry(consts::ROOT_STREAM_ID, &name).unwrap();
+            check_rb_tree(&directory, consts::ROOT_STREAM_ID);
+            assert_eq!(tree_names(&directory, consts::ROOT_STREAM_ID), names);
+        }
+        assert_eq!(directory.root_dir_entry().child, consts::NO_STREAM);
+    }
+
+    #[test]
+    fn free_list_tracks_directory() {
+        let mut directory = make_directory(
+            vec![DirEntry::empty_root_entry()],
+            ValidationPolicy::strict(),
+        );
+        let mut stream_ids = Vec::new();
+        for index in 0..10 {
+            let name = format!("{}", index);
+            stream_ids.push(
+                directory
+                    .insert_dir_entry(
+                        consts::ROOT_STREAM_ID,
+                        &name,
+                        ObjType::Stream,
+                    )
+                    .unwrap(),
+            );
+            directory.verify_free_dir_entries().unwrap();
+        }
+        for index in [1, 2, 5].iter() {
+            let name = format!("{}", index);
+            directory.remove_dir_entry(consts::ROOT_STREAM_ID, &name).unwrap();
+            directory.verify_free_dir_entries().unwrap();
+        }
+        // Freed entries are reused, lowest first.
+        let stream_id = directory
+            .insert_dir_entry(consts::ROOT_STREAM_ID, "new", ObjType::Storage)
+            .unwrap();
+        assert_eq!(stream_id, stream_ids[1]);
+        directory.verify_free_dir_entries().unwrap();
+    }
+
+    #[test]
+    fn rebalance_degenerate_tree() {
+        // Build a tree where every entry is black and has only a right
+        // sibling, as older versions of this crate would write.
+        let mut root_entry = DirEntry::empty_root_entry();
+        root_entry.child = 1;
+        let mut entries = vec![root_entry];
+        for index in 0..20 {
+            let name = format!("{:02}", index);
+            let mut entry =
+                DirEntry::new(&name, ObjType::Stream, Timestamp::zero());
+            if index < 19 {
+                entry.right_sibling = index + 2;
+            }
+            entries.push(entry);
+        }
+        let mut directory =
+            make_directory(entries, ValidationPolicy::permissive());
+        let names = tree_names(&directory, consts::ROOT_STREAM_ID);
+        directory.rebalance_all().unwrap();
+        assert_eq!(check_rb_tree(&directory, consts::ROOT_STREAM_ID), 5);
+        assert_eq!(tree_names(&directory, consts::ROOT_STREAM_ID), names);
     }
 }
 
@@ -1,16 +1,28 @@
 use crate::internal::consts::{self, MAX_REGULAR_STREAM_ID, NO_STREAM};
-use crate::internal::{self, Color, ObjType, Timestamp, Validation, Version};
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use std::io::{self, Read, Write};
+use crate::internal::{self, Color, ObjType, Timestamp, Validator, Version};
+use crate::io::{self, Read, ReadBytesExt, Write, WriteBytesExt};
+use alloc::format;
+use alloc::string::String;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use byteorder::LittleEndian;
 use uuid::Uuid;
 
 //===========================================================================//
 
+macro_rules! malformation {
+    ($($arg:tt)+) => {
+        corruption!(
+            Directory { stream_id: None, path: None },
+            "Malformed directory entry ({})",
+            format!($($arg)+)
+        )
+    };
+}
+
 macro_rules! malformed {
-    ($e:expr) => { invalid_data!("Malformed directory entry ({})", $e) };
-    ($fmt:expr, $($arg:tt)+) => {
-        invalid_data!("Malformed directory entry ({})",
-                      format!($fmt, $($arg)+))
+    ($($arg:tt)+) => {
+        return Err(malformation!($($arg)+).into())
     };
 }
 
@@ -85,7 +97,7 @@ impl DirEntry {
         DirEntry::new(consts::ROOT_DIR_NAME, ObjType::Root, Timestamp::zero())
     }
 
-    fn read_clsid<R: Read>(reader: &mut R) -> io::Result<Uuid> {
+    pub fn read_clsid<R: Read>(reader: &mut R) -> io::Result<Uuid> {
         let d1 = reader.read_u32::<LittleEndian>()?;
         let d2 = reader.read_u16::<LittleEndian>()?;
         let d3 = reader.read_u16::<LittleEndian>()?;
@@ -106,18 +118,22 @@ impl DirEntry {
     pub fn read_from<R: Read>(
         reader: &mut R,
         version: Version,
-        validation: Validation,
+        validator: &mut Validator,
     ) -> io::Result<DirEntry> {
         let mut name: String = {
             let mut name_chars: Vec<u16> = Vec::with_capacity(32);
             for _ in 0..32 {
                 name_chars.push(reader.read_u16::<LittleEndian>()?);
             }
-            let name_len_bytes = reader.read_u16::<LittleEndian>()?;
+            let mut name_len_bytes = reader.read_u16::<LittleEndian>()?;
             if name_len_bytes > 64 {
                 malformed!("name length too large: {}", name_len_bytes);
             } else if name_len_bytes % 2 != 0 {
-                malformed!("odd name length: {}", name_len_bytes);
+                validator.tolerate(
+                    validator.policy().allow_odd_name_lengths,
+                    malformation!("odd name length: {}", name_len_bytes),
+                )?;
+                name_len_bytes += 1;
             }
             let name_len_chars = if name_len_bytes > 0 {
                 (name_len_bytes / 2 - 1) as usize
@@ -132,8 +148,11 @@ impl DirEntry {
             // Look, CFB is a weird format.)  Anyway, some CFB files in the
             // wild don't do this, so under Permissive validation we don't
             // enforce it.
-            if validation.is_strict() && name_chars[name_len_chars] != 0 {
-                malformed!("name not null-terminated");
+            if name_chars[name_len_chars] != 0 {
+                validator.tolerate(
+                    validator.policy().allow_unterminated_names,
+                    malformation!("name not null-terminated"),
+                )?;
             }
             match String::from_utf16(&name_chars[0..name_len_chars]) {
                 Ok(name) => name,
@@ -157,13 +176,14 @@ impl DirEntry {
         // file and treat it as though it were what it's supposed to be.
         if obj_type == ObjType::Root {
             if name != consts::ROOT_DIR_NAME {
-                if validation.is_strict() {
-                    malformed!(
+                validator.tolerate(
+                    validator.policy().allow_wrong_root_name,
+                    malformation!(
                         "root entry name is {:?}, but should be {:?}",
                         name,
                         consts::ROOT_DIR_NAME
-                    );
-                }
+                    ),
+                )?;
                 name = consts::ROOT_DIR_NAME.to_string();
             }
         } else {
@@ -202,9 +222,10 @@ impl DirEntry {
         // the CLSID data entirely and treat it as though it were nil.
         let mut clsid = DirEntry::read_clsid(reader)?;
         if obj_type == ObjType::Stream && !clsid.is_nil() {
-            if validation.is_strict() {
-                malformed!("non-null stream CLSID: {:?}", clsid);
-            }
+            validator.tolerate(
+                validator.policy().allow_stream_clsids,
+                malformation!("non-null stream CLSID: {:?}", clsid),
+            )?;
             clsid = Uuid::nil();
         }
 
@@ -216,22 +237,24 @@ impl DirEntry {
         // treat these fields as though they were zero.
         let mut creation_time = Timestamp::read_from(reader)?;
         if obj_type == ObjType::Stream && creation_time != Timestamp::zero() {
-            if validation.is_strict() {
-                malformed!(
+            validator.tolerate(
+                validator.policy().allow_stream_timestamps,
+                malformation!(
                     "non-zero stream creation time: {}",
                     creation_time.value()
-                );
-            }
+                ),
+            )?;
             creation_time = Timestamp::zero();
         }
         let mut modified_time = Timestamp::read_from(reader)?;
         if obj_type == ObjType::Stream && modified_time != Timestamp::zero() {
-            if validation.is_strict() {
-                malformed!(
+            validator.tolerate(
+                validator.policy().allow_stream_timestamps,
+                malformation!(
                     "non-zero stream modified time: {}",
                     modified_time.value()
-                );
-            }
+                ),
+            )?;
             modified_time = Timestamp::zero();
         }
 
@@ -246,14 +269,26 @@ impl DirEntry {
         let mut stream_len =
             reader.read_u64::<LittleEndian>()? & version.stream_len_mask();
         if obj_type == ObjType::Storage {
-            if validation.is_strict() && start_sector != 0 {
-                malformed!("non-zero storage start sector: {}", start_sector);
+            if start_sector != 0 {
+                validator.tolerate(
+                    validator.policy().allow_storage_stream_fields,
+                    malformation!(
+                        "non-zero storage start sector: {}",
+                        start_sector
+                    ),
+                )?;
+                start_sector = 0;
             }
-            start_sector = 0;
-            if validation.is_strict() && stream_len != 0 {
-                malformed!("non-zero storage stream length: {}", stream_len);
+            if stream_len != 0 {
+                validator.tolerate(
+                    validator.policy().allow_storage_stream_fields,
+                    malformation!(
+                        "non-zero storage stream length: {}",
+                        stream_len
+                    ),
+                )?;
+                stream_len = 0;
             }
-            stream_len = 0;
         }
 
         Ok(DirEntry {
@@ -304,7 +339,7 @@ impl DirEntry {
 mod tests {
     use super::DirEntry;
     use crate::internal::{
-        consts, Color, ObjType, Timestamp, Validation, Version,
+        consts, Color, ObjType, Timestamp, ValidationPolicy, Version,
     };
     use std::time::UNIX_EPOCH;
     use uuid::Uuid;
@@ -333,7 +368,7 @@ mod tests {
         let dir_entry = DirEntry::read_from(
             &mut (&input as &[u8]),
             Version::V4,
-            Validation::Permissive,
+            &mut ValidationPolicy::permissive().into(),
         )
         .unwrap();
         assert_eq!(&dir_entry.name, "Foobar");
@@ -377,7 +412,7 @@ mod tests {
         let dir_entry = DirEntry::read_from(
             &mut (&input as &[u8]),
             Version::V4,
-            Validation::Strict,
+            &mut ValidationPolicy::strict().into(),
         )
         .unwrap();
         assert_eq!(&dir_entry.name, "Foobar");
@@ -425,7 +460,7 @@ mod tests {
         DirEntry::read_from(
             &mut (&input as &[u8]),
             Version::V4,
-            Validation::Permissive,
+            &mut ValidationPolicy::permissive().into(),
         )
         .unwrap();
     }
@@ -454,7 +489,7 @@ mod tests {
         DirEntry::read_from(
             &mut (&input as &[u8]),
             Version::V4,
-            Validation::Permissive,
+            &mut ValidationPolicy::permissive().into(),
         )
         .unwrap();
     }
@@ -485,8 +520,12 @@ mod tests {
     )]
     fn non_zero_creation_time_on_stream_strict() {
         let mut input: &[u8] = &NON_ZERO_CREATION_TIME_ON_STREAM;
-        DirEntry::read_from(&mut input, Version::V4, Validation::Strict)
-            .unwrap();
+        DirEntry::read_from(
+            &mut input,
+            Version::V4,
+            &mut ValidationPolicy::strict().into(),
+        )
+        .unwrap();
     }
 
     #[test]
@@ -495,7 +534,7 @@ mod tests {
         let dir_entry = DirEntry::read_from(
             &mut input,
             Version::V4,
-            Validation::Permissive,
+            &mut ValidationPolicy::permissive().into(),
         )
         .unwrap();
         assert_eq!(dir_entry.obj_type, ObjType::Stream);
@@ -528,8 +567,12 @@ mod tests {
     )]
     fn non_zero_modified_time_on_stream_strict() {
         let mut input: &[u8] = &NON_ZERO_MODIFIED_TIME_ON_STREAM;
-        DirEntry::read_from(&mut input, Version::V4, Validation::Strict)
-            .unwrap();
+        DirEntry::read_from(
+            &mut input,
+            Version::V4,
+            &mut ValidationPolicy::strict().into(),
+        )
+        .unwrap();
     }
 
     #[test]
@@ -538,7 +581,7 @@ mod tests {
         let dir_entry = DirEntry::read_from(
             &mut input,
             Version::V4,
-            Validation::Permissive,
+            &mut ValidationPolicy::permissive().into(),
         )
         .unwrap();
         assert_eq!(dir_entry.obj_type, ObjType::Stream);
@@ -571,8 +614,12 @@ mod tests {
     )]
     fn non_null_clsid_on_stream_strict() {
         let mut input: &[u8] = &NON_NULL_CLSID_ON_STREAM;
-        DirEntry::read_from(&mut input, Version::V4, Validation::Strict)
-            .unwrap();
+        DirEntry::read_from(
+            &mut input,
+            Version::V4,
+            &mut ValidationPolicy::strict().into(),
+        )
+        .unwrap();
     }
 
     // Regression test for https://github.com/mdsteele/rust-cfb/issues/26
@@ -587,7 +634,7 @@ mod tests {
         let dir_entry = DirEntry::read_from(
             &mut input,
             Version::V4,
-            Validation::Permissive,
+            &mut ValidationPolicy::permissive().into(),
         )
         .unwrap();
         assert_eq!(dir_entry.obj_type, ObjType::Stream);
@@ -619,8 +666,12 @@ mod tests {
     )]
     fn non_null_terminated_name_strict() {
         let mut input: &[u8] = &NON_NULL_TERMINATED_NAME;
-        DirEntry::read_from(&mut input, Version::V4, Validation::Strict)
-            .unwrap();
+        DirEntry::read_from(
+            &mut input,
+            Version::V4,
+            &mut ValidationPolicy::strict().into(),
+        )
+        .unwrap();
     }
 
     // Regression test for https://github.com/mdsteele/rust-cfb/issues/26
@@ -634,7 +685,44 @@ mod tests {
         let dir_entry = DirEntry::read_from(
             &mut input,
             Version::V4,
-            Validation::Permissive,
+            &mut ValidationPolicy::permissive().into(),
+        )
+        .unwrap();
+        assert_eq!(dir_entry.name, "Foobar");
+    }
+
+    fn make_entry_with_odd_name_length() -> Vec<u8> {
+        let dir_entry =
+            DirEntry::new("Foobar", ObjType::Stream, Timestamp::zero());
+        let mut input = Vec::<u8>::new();
+        dir_entry.write_to(&mut input).unwrap();
+        input[64] = 13; // name length
+        input
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Malformed directory entry (odd name length: 13)"
+    )]
+    fn odd_name_length_permissive() {
+        let input = make_entry_with_odd_name_length();
+        DirEntry::read_from(
+            &mut input.as_slice(),
+            Version::V4,
+            &mut ValidationPolicy::permissive().into(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn odd_name_length_allowed_by_policy() {
+        let input = make_entry_with_odd_name_length();
+        let mut policy = ValidationPolicy::strict();
+        policy.allow_odd_name_lengths = true;
+        let dir_entry = DirEntry::read_from(
+            &mut input.as_slice(),
+            Version::V4,
+            &mut policy.into(),
         )
         .unwrap();
         assert_eq!(dir_entry.name, "Foobar");
@@ -650,7 +738,7 @@ mod tests {
         let result = DirEntry::read_from(
             &mut input.as_slice(),
             Version::V4,
-            Validation::Strict,
+            &mut ValidationPolicy::strict().into(),
         );
         assert_eq!(
             result.err().unwrap().to_string(),
@@ -668,7 +756,7 @@ mod tests {
         let result = DirEntry::read_from(
             &mut input.as_slice(),
             Version::V4,
-            Validation::Strict,
+            &mut ValidationPolicy::strict().into(),
         );
         assert_eq!(
             result.err().unwrap().to_string(),
@@ -693,7 +781,7 @@ mod tests {
         let dir_entry = DirEntry::read_from(
             &mut (&input as &[u8]),
             Version::V4,
-            Validation::Permissive,
+            &mut ValidationPolicy::permissive().into(),
         )
         .unwrap();
         assert_eq!(dir_entry.obj_type, ObjType::Storage);
@@ -727,8 +815,12 @@ mod tests {
     )]
     fn root_entry_with_incorrect_name_strict() {
         let mut input: &[u8] = &ROOT_ENTRY_WITH_INCORRECT_NAME;
-        DirEntry::read_from(&mut input, Version::V4, Validation::Strict)
-            .unwrap();
+        DirEntry::read_from(
+            &mut input,
+            Version::V4,
+            &mut ValidationPolicy::strict().into(),
+        )
+        .unwrap();
     }
 
     // Regression test for https://github.com/mdsteele/rust-cfb/issues/29
@@ -743,7 +835,7 @@ mod tests {
         let dir_entry = DirEntry::read_from(
             &mut input,
             Version::V4,
-            Validation::Permissive,
+            &mut ValidationPolicy::permissive().into(),
         )
         .unwrap();
         assert_eq!(dir_entry.obj_type, ObjType::Root);
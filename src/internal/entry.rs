@@ -1,7 +1,11 @@
-use crate::internal::{consts, DirEntry, MiniAllocator, ObjType, Timestamp};
-use std::cell::RefCell;
-use std::path::{Path, PathBuf};
-use std::rc::Rc;
+use crate::internal::path::{self, Path, PathBuf};
+use crate::internal::{
+    consts, DirEntry, MiniAllocator, ObjType, Shared, Timestamp,
+};
+use alloc::borrow::ToOwned;
+use alloc::string::String;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
 use std::time::SystemTime;
 use uuid::Uuid;
 
@@ -85,15 +89,30 @@ impl Entry {
 
     /// Returns the time when the object that this entry represents was
     /// created.
+    #[cfg(feature = "std")]
     pub fn created(&self) -> SystemTime {
         self.creation_time.to_system_time()
     }
 
     /// Returns the time when the object that this entry represents was last
     /// modified.
+    #[cfg(feature = "std")]
     pub fn modified(&self) -> SystemTime {
         self.modified_time.to_system_time()
     }
+
+    /// Returns the raw creation timestamp of the object that this entry
+    /// represents, as a Windows FILETIME value (the number of 100-nanosecond
+    /// intervals since January 1, 1601 UTC).
+    pub fn created_filetime(&self) -> u64 {
+        self.creation_time.value()
+    }
+
+    /// Returns the raw last-modified timestamp of the object that this entry
+    /// represents, as a Windows FILETIME value.
+    pub fn modified_filetime(&self) -> u64 {
+        self.modified_time.value()
+    }
 }
 
 //===========================================================================//
@@ -109,25 +128,26 @@ pub enum EntriesOrder {
 /// An iterator over the entries in a storage object.
 pub struct Entries<'a, F: 'a> {
     order: EntriesOrder,
-    // TODO: Consider storing a Weak<RefCell<MiniAllocator<F>>> here instead of
-    // a reference to the Rc.  That would allow e.g. opening streams during
+    // TODO: Consider storing a WeakShared<MiniAllocator<F>> here instead of
+    // a reference to the Shared.  That would allow e.g. opening streams during
     // iteration.  But we'd need to think about how the iterator should behave
     // if the CFB tree structure is modified during iteration.
-    minialloc: &'a Rc<RefCell<MiniAllocator<F>>>,
+    minialloc: &'a Shared<MiniAllocator<F>>,
     stack: Vec<(PathBuf, u32, bool)>,
 }
 
 impl<'a, F> Entries<'a, F> {
     pub(crate) fn new(
         order: EntriesOrder,
-        minialloc: &'a Rc<RefCell<MiniAllocator<F>>>,
+        minialloc: &'a Shared<MiniAllocator<F>>,
         parent_path: PathBuf,
         start: u32,
     ) -> Entries<'a, F> {
         let mut entries = Entries { order, minialloc, stack: Vec::new() };
         match order {
             EntriesOrder::Nonrecursive => {
-                entries.stack_left_spine(&parent_path, start);
+                let minialloc = minialloc.borrow();
+                entries.stack_left_spine(&minialloc, &parent_path, start);
             }
             EntriesOrder::Preorder => {
                 entries.stack.push((parent_path, start, false));
@@ -136,10 +156,14 @@ impl<'a, F> Entries<'a, F> {
         entries
     }
 
-    fn stack_left_spine(&mut self, parent_path: &Path, mut current_id: u32) {
-        let minialloc = self.minialloc.borrow();
+    fn stack_left_spine(
+        &mut self,
+        minialloc: &MiniAllocator<F>,
+        parent_path: &Path,
+        mut current_id: u32,
+    ) {
         while current_id != consts::NO_STREAM {
-            self.stack.push((parent_path.to_path_buf(), current_id, true));
+            self.stack.push((parent_path.to_owned(), current_id, true));
             current_id = minialloc.dir_entry(current_id).left_sibling;
         }
     }
@@ -154,13 +178,17 @@ impl<'a, F> Iterator for Entries<'a, F> {
             let dir_entry = minialloc.dir_entry(stream_id);
             let path = join_path(&parent, dir_entry);
             if visit_siblings {
-                self.stack_left_spine(&parent, dir_entry.right_sibling);
+                self.stack_left_spine(
+                    &minialloc,
+                    &parent,
+                    dir_entry.right_sibling,
+                );
             }
             if self.order == EntriesOrder::Preorder
                 && dir_entry.obj_type != ObjType::Stream
                 && dir_entry.child != consts::NO_STREAM
             {
-                self.stack_left_spine(&path, dir_entry.child);
+                self.stack_left_spine(&minialloc, &path, dir_entry.child);
             }
             Some(Entry::new(dir_entry, path))
         } else {
@@ -173,9 +201,9 @@ impl<'a, F> Iterator for Entries<'a, F> {
 
 fn join_path(parent_path: &Path, dir_entry: &DirEntry) -> PathBuf {
     if dir_entry.obj_type == ObjType::Root {
-        parent_path.to_path_buf()
+        parent_path.to_owned()
     } else {
-        parent_path.join(&dir_entry.name)
+        path::join_path(parent_path, &dir_entry.name)
     }
 }
 
@@ -187,11 +215,9 @@ mod tests {
     use crate::internal::consts::{self, NO_STREAM, ROOT_DIR_NAME};
     use crate::internal::{
         Allocator, DirEntry, Directory, MiniAllocator, ObjType, Sectors,
-        Timestamp, Validation, Version,
+        Shared, Timestamp, ValidationPolicy, Version,
     };
-    use std::cell::RefCell;
     use std::path::{Path, PathBuf};
-    use std::rc::Rc;
 
     fn make_entry(
         name: &str,
@@ -207,7 +233,7 @@ mod tests {
         dir_entry
     }
 
-    fn make_minialloc() -> Rc<RefCell<MiniAllocator<()>>> {
+    fn make_minialloc() -> Shared<MiniAllocator<()>> {
         // Root contains:      3 contains:
         //      5                  8
         //     / \                / \
@@ -236,20 +262,24 @@ mod tests {
             vec![],
             vec![0],
             vec![consts::FAT_SECTOR, consts::END_OF_CHAIN],
-            Validation::Strict,
+            &mut ValidationPolicy::strict().into(),
+        )
+        .unwrap();
+        let directory = Directory::new(
+            allocator,
+            dir_entries,
+            1,
+            &mut ValidationPolicy::strict().into(),
         )
         .unwrap();
-        let directory =
-            Directory::new(allocator, dir_entries, 1, Validation::Strict)
-                .unwrap();
         let minialloc = MiniAllocator::new(
             directory,
             vec![],
             consts::END_OF_CHAIN,
-            Validation::Strict,
+            &mut ValidationPolicy::strict().into(),
         )
         .unwrap();
-        Rc::new(RefCell::new(minialloc))
+        Shared::new(minialloc)
     }
 
     fn paths_for_entries(entries: &[Entry]) -> Vec<&Path> {
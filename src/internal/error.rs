@@ -0,0 +1,195 @@
+use crate::internal::path::{Path, PathBuf};
+use crate::io;
+use alloc::string::String;
+use core::fmt;
+
+//===========================================================================//
+
+/// A problem with the structure of a compound file, or with the name of an
+/// object within one.
+///
+/// Functions in this crate return `io::Error` values for compatibility with
+/// the standard I/O traits; when the failure is one of these problems, the
+/// `io::Error` carries an `Error` that can be retrieved with
+/// [`Error::from_io`](#method.from_io) and matched on, rather than by parsing
+/// the error message.  Each variant includes a human-readable `message`, which
+/// is also what the error displays as.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Error {
+    /// The file header is invalid, or the file is the wrong size to be a
+    /// compound file.
+    Header {
+        /// A description of the problem.
+        message: String,
+    },
+    /// The DIFAT (the list of the sectors that hold the FAT) is malformed.
+    Difat {
+        /// The sector at which the problem was found, if any.
+        sector_id: Option<u32>,
+        /// A description of the problem.
+        message: String,
+    },
+    /// The FAT (the table of sector chains) is malformed.
+    Fat {
+        /// The sector at which the problem was found, if any.
+        sector_id: Option<u32>,
+        /// A description of the problem.
+        message: String,
+    },
+    /// The MiniFAT (the table of mini sector chains) is malformed.
+    MiniFat {
+        /// The mini sector at which the problem was found, if any.
+        sector_id: Option<u32>,
+        /// A description of the problem.
+        message: String,
+    },
+    /// The directory, or the red-black tree of entries within it, is
+    /// malformed.
+    Directory {
+        /// The directory entry at which the problem was found, if any.
+        stream_id: Option<u32>,
+        /// The path of that directory entry, if it is known (for example,
+        /// when the problem was found while reading the stream at a given
+        /// path, rather than while parsing the directory itself).
+        path: Option<PathBuf>,
+        /// A description of the problem.
+        message: String,
+    },
+    /// An object name is too long or contains a forbidden character.
+    Name {
+        /// The invalid name.
+        name: String,
+        /// A description of the problem.
+        message: String,
+    },
+    /// A chain of sectors (or mini sectors) visits the same sector more than
+    /// once, and so would never end.
+    ChainLoop {
+        /// The sector that was visited twice.
+        sector_id: u32,
+        /// A description of the problem.
+        message: String,
+    },
+    /// A sector ID refers past the end of the file.
+    SectorOutOfRange {
+        /// The sector ID that is out of range.
+        sector_id: u32,
+        /// The number of sectors in the file.
+        num_sectors: u32,
+        /// A description of the problem.
+        message: String,
+    },
+    /// The file header is marked as being in journaling mode, so the file
+    /// may have been left partway through a commit, and must be opened with
+    /// [`CompoundFile::open_with_journal`](
+    /// struct.CompoundFile.html#method.open_with_journal) to complete or
+    /// undo that commit.
+    Journaled {
+        /// A description of the problem.
+        message: String,
+    },
+}
+
+impl Error {
+    /// Returns the `Error` carried by the given I/O error, if it has one.
+    pub fn from_io(error: &io::Error) -> Option<&Error> {
+        error.get_ref().and_then(|inner| inner.downcast_ref::<Error>())
+    }
+
+    /// Returns the kind of I/O error that this converts into:
+    /// `InvalidInput` for a `Name` error, and `InvalidData` otherwise.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Error::Name { .. } => io::ErrorKind::InvalidInput,
+            _ => io::ErrorKind::InvalidData,
+        }
+    }
+
+    /// Attributes a `Directory` error that doesn't already say which path it
+    /// is about to the given path; other errors are returned unchanged.
+    pub(crate) fn at_path(self, path: &Path) -> Error {
+        match self {
+            Error::Directory { stream_id, path: None, message } => {
+                Error::Directory {
+                    stream_id,
+                    path: Some(PathBuf::from(path)),
+                    message,
+                }
+            }
+            error => error,
+        }
+    }
+
+    /// Like `at_path`, but for an `Error` carried by an I/O error.
+    pub(crate) fn io_at_path(error: io::Error, path: &Path) -> io::Error {
+        match Error::from_io(&error) {
+            Some(inner @ Error::Directory { path: None, .. }) => {
+                inner.clone().at_path(path).into()
+            }
+            _ => error,
+        }
+    }
+
+    /// Returns the human-readable description of the problem.
+    pub fn message(&self) -> &str {
+        match self {
+            Error::Header { message }
+            | Error::Difat { message, .. }
+            | Error::Fat { message, .. }
+            | Error::MiniFat { message, .. }
+            | Error::Directory { message, .. }
+            | Error::Name { message, .. }
+            | Error::ChainLoop { message, .. }
+            | Error::SectorOutOfRange { message, .. }
+            | Error::Journaled { message } => message,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str(self.message())
+    }
+}
+
+impl core::error::Error for Error {}
+
+impl From<Error> for io::Error {
+    fn from(error: Error) -> io::Error {
+        io::Error::new(error.kind(), error)
+    }
+}
+
+//===========================================================================//
+
+#[cfg(test)]
+mod tests {
+    use super::Error;
+    use std::io;
+
+    #[test]
+    fn round_trip_through_io_error() {
+        let error = Error::ChainLoop {
+            sector_id: 7,
+            message: "Chain contained duplicate sector id 7".to_string(),
+        };
+        let io_error = io::Error::from(error.clone());
+        assert_eq!(io_error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(io_error.to_string(), error.to_string());
+        assert_eq!(Error::from_io(&io_error), Some(&error));
+        let other = io::Error::new(io::ErrorKind::InvalidData, "oops");
+        assert_eq!(Error::from_io(&other), None);
+    }
+
+    #[test]
+    fn name_errors_are_invalid_input() {
+        let error = Error::Name {
+            name: "a/b".to_string(),
+            message: "Object name cannot contain / character".to_string(),
+        };
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+}
+
+//===========================================================================//
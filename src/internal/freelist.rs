@@ -0,0 +1,144 @@
+use crate::internal::consts;
+#[cfg(test)]
+use crate::io;
+#[cfg(test)]
+use crate::Error;
+use alloc::collections::BTreeSet;
+#[cfg(test)]
+use alloc::string::String;
+
+//===========================================================================//
+
+/// Returns, for each entry of a FAT or MiniFAT, whether that entry is free.
+pub fn is_free(table: &[u32]) -> impl Iterator<Item = bool> + '_ {
+    table.iter().map(|&entry| entry == consts::FREE_SECTOR)
+}
+
+//===========================================================================//
+
+/// An index of the free slots in an allocation table (the FAT, the MiniFAT, or
+/// the directory), so that the lowest free slot can be found in logarithmic
+/// time rather than by scanning the whole table.
+#[derive(Clone, Debug, Default)]
+pub struct FreeList {
+    free: BTreeSet<u32>,
+}
+
+impl FreeList {
+    /// Builds an index from a sequence that says, for each slot in the table,
+    /// whether that slot is free.
+    pub fn new<I: IntoIterator<Item = bool>>(is_free: I) -> FreeList {
+        let free = is_free
+            .into_iter()
+            .enumerate()
+            .filter(|&(_, is_free)| is_free)
+            .map(|(index, _)| index as u32)
+            .collect();
+        FreeList { free }
+    }
+
+    /// Returns the lowest free slot, if any.
+    pub fn first(&self) -> Option<u32> {
+        self.free.iter().next().copied()
+    }
+
+    /// Records whether the given slot is free.
+    pub fn set_free(&mut self, index: u32, is_free: bool) {
+        if is_free {
+            self.free.insert(index);
+        } else {
+            self.free.remove(&index);
+        }
+    }
+
+    /// Forgets about any slots at or beyond `len`, for when the table is
+    /// truncated.
+    pub fn truncate(&mut self, len: usize) {
+        if len <= u32::MAX as usize {
+            self.free.split_off(&(len as u32));
+        }
+    }
+
+    /// Checks that this index matches the given table, returning an error
+    /// naming the first slot where they disagree.  The error is built by
+    /// `corruption`, from the slot's index and a description of the problem,
+    /// so that it can name the right table.
+    #[cfg(test)]
+    pub fn verify<I, C>(&self, is_free: I, corruption: C) -> io::Result<()>
+    where
+        I: IntoIterator<Item = bool>,
+        C: Fn(u32, String) -> Error,
+    {
+        let mut len = 0;
+        for (index, is_free) in is_free.into_iter().enumerate() {
+            if self.free.contains(&(index as u32)) != is_free {
+                let message = alloc::format!(
+                    "Free list is out of sync at index {}",
+                    index
+                );
+                return Err(corruption(index as u32, message).into());
+            }
+            len += 1;
+        }
+        if let Some(&index) = self.free.range(len..).next() {
+            let message = alloc::format!(
+                "Free list lists index {}, but table has only {} entries",
+                index,
+                len
+            );
+            return Err(corruption(index, message).into());
+        }
+        Ok(())
+    }
+}
+
+//===========================================================================//
+
+#[cfg(test)]
+mod tests {
+    use super::FreeList;
+    use crate::Error;
+
+    #[test]
+    fn lowest_free_slot() {
+        let mut list = FreeList::new(vec![false, true, false, true]);
+        assert_eq!(list.first(), Some(1));
+        list.set_free(1, false);
+        assert_eq!(list.first(), Some(3));
+        list.set_free(0, true);
+        assert_eq!(list.first(), Some(0));
+        list.truncate(0);
+        assert_eq!(list.first(), None);
+    }
+
+    fn fat_corruption(sector_id: u32, message: String) -> Error {
+        Error::Fat { sector_id: Some(sector_id), message }
+    }
+
+    #[test]
+    fn verify_against_table() {
+        let table = [false, true, false, true];
+        let mut list = FreeList::new(table.iter().copied());
+        list.verify(table.iter().copied(), fat_corruption).unwrap();
+        list.set_free(2, true);
+        let error =
+            list.verify(table.iter().copied(), fat_corruption).unwrap_err();
+        assert_eq!(
+            Error::from_io(&error),
+            Some(&fat_corruption(
+                2,
+                "Free list is out of sync at index 2".to_string()
+            ))
+        );
+        list.set_free(2, false);
+        list.set_free(7, true);
+        let error =
+            list.verify(table.iter().copied(), fat_corruption).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Free list lists index 7, but table has only 4 entries"
+        );
+    }
+}
+
+//===========================================================================//
@@ -1,8 +1,8 @@
-use std::io::{self, Read, Write};
+use crate::io::{self, Read, ReadBytesExt, Write, WriteBytesExt};
 
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::LittleEndian;
 
-use crate::internal::{consts, Validation, Version};
+use crate::internal::{consts, Validator, Version};
 
 //===========================================================================//
 
@@ -11,6 +11,7 @@ pub struct Header {
     pub num_dir_sectors: u32,
     pub num_fat_sectors: u32,
     pub first_dir_sector: u32,
+    pub transaction_signature: u32,
     pub first_minifat_sector: u32,
     pub num_minifat_sectors: u32,
     pub first_difat_sector: u32,
@@ -21,14 +22,15 @@ pub struct Header {
 impl Header {
     pub fn read_from<R: Read>(
         reader: &mut R,
-        validation: Validation,
+        validator: &mut Validator,
     ) -> io::Result<Header> {
         let mut magic = [0u8; 8];
         reader.read_exact(&mut magic)?;
         if magic != consts::MAGIC_NUMBER {
-            invalid_data!("Invalid CFB file (wrong magic number)");
+            corrupted!(Header {}, "Invalid CFB file (wrong magic number)");
         }
-        reader.read_exact(&mut [0u8; 16])?; // reserved field
+        let mut header_clsid = [0u8; 16];
+        reader.read_exact(&mut header_clsid)?;
 
         // Read the version number, but don't try to interpret it until after
         // we've checked the byte order mark.
@@ -37,7 +39,8 @@ impl Header {
 
         let byte_order_mark = reader.read_u16::<LittleEndian>()?;
         if byte_order_mark != consts::BYTE_ORDER_MARK {
-            invalid_data!(
+            corrupted!(
+                Header {},
                 "Invalid CFB byte order mark (expected 0x{:04X}, found \
                  0x{:04X})",
                 consts::BYTE_ORDER_MARK,
@@ -48,7 +51,8 @@ impl Header {
         let version = match Version::from_number(version_number) {
             Some(version) => version,
             None => {
-                invalid_data!(
+                corrupted!(
+                    Header {},
                     "CFB version {} is not supported",
                     version_number
                 );
@@ -57,7 +61,8 @@ impl Header {
 
         let sector_shift = reader.read_u16::<LittleEndian>()?;
         if sector_shift != version.sector_shift() {
-            invalid_data!(
+            corrupted!(
+                Header {},
                 "Incorrect sector shift for CFB version {} (expected {}, \
                  found {})",
                 version.number(),
@@ -68,39 +73,56 @@ impl Header {
 
         let mini_sector_shift = reader.read_u16::<LittleEndian>()?;
         if mini_sector_shift != consts::MINI_SECTOR_SHIFT {
-            invalid_data!(
+            corrupted!(
+                Header {},
                 "Incorrect mini sector shift (expected {}, found {})",
                 consts::MINI_SECTOR_SHIFT,
                 mini_sector_shift
             );
         }
 
-        // TODO: require reserved field to be all zeros under strict validation
-        reader.read_exact(&mut [0u8; 6])?; // reserved field
+        // Section 2.2 of the MS-CFB spec says that the header CLSID and the
+        // reserved field here MUST both be all zeros, but since nothing uses
+        // either of them, we can just ignore them if the validation policy
+        // allows it.
+        let mut reserved = [0u8; 6];
+        reader.read_exact(&mut reserved)?;
+        if header_clsid.iter().chain(reserved.iter()).any(|&byte| byte != 0) {
+            validator.tolerate(
+                validator.policy().allow_nonzero_reserved_fields,
+                corruption!(
+                    Header {},
+                    "Invalid reserved field in header (must be all zeros)"
+                ),
+            )?;
+        }
 
         // According to section 2.2 of the MS-CFB spec, "If Major Version is 3,
-        // the Number of Directory Sectors MUST be zero."  However, under
-        // Permissive validation, we don't enforce this, but instead just treat
-        // the field as though it were zero for V3 files.
+        // the Number of Directory Sectors MUST be zero."  However, if the
+        // validation policy allows it, we don't enforce this, but instead just
+        // treat the field as though it were zero for V3 files.
         let mut num_dir_sectors = reader.read_u32::<LittleEndian>()?;
         if version == Version::V3 && num_dir_sectors != 0 {
-            if validation.is_strict() {
-                invalid_data!(
+            validator.tolerate(
+                validator.policy().allow_v3_num_dir_sectors,
+                corruption!(
+                    Header {},
                     "Invalid number of directory sectors field (must be zero \
                      for CFB version 3, found {})",
                     num_dir_sectors
-                );
-            }
+                ),
+            )?;
             num_dir_sectors = 0;
         }
 
         let num_fat_sectors = reader.read_u32::<LittleEndian>()?;
         let first_dir_sector = reader.read_u32::<LittleEndian>()?;
-        let _transaction_signature = reader.read_u32::<LittleEndian>()?;
+        let transaction_signature = reader.read_u32::<LittleEndian>()?;
 
         let mini_stream_cutoff = reader.read_u32::<LittleEndian>()?;
         if mini_stream_cutoff != consts::MINI_STREAM_CUTOFF {
-            invalid_data!(
+            corrupted!(
+                Header {},
                 "Incorrect mini stream cutoff (expected {}, found {})",
                 consts::MINI_STREAM_CUTOFF,
                 mini_stream_cutoff
@@ -114,6 +136,14 @@ impl Header {
 
         // Some CFB implementations use FREE_SECTOR to indicate END_OF_CHAIN.
         if first_difat_sector == consts::FREE_SECTOR {
+            validator.tolerate(
+                validator.policy().allow_free_sector_as_end_of_chain,
+                corruption!(
+                    Difat { sector_id: None },
+                    "DIFAT chain in header ends with FREE_SECTOR instead of \
+                     END_OF_CHAIN"
+                ),
+            )?;
             first_difat_sector = consts::END_OF_CHAIN;
         }
 
@@ -124,7 +154,8 @@ impl Header {
             if next == consts::FREE_SECTOR {
                 break;
             } else if next > consts::MAX_REGULAR_SECTOR {
-                invalid_data!(
+                corrupted!(
+                    Difat { sector_id: None },
                     "Initial DIFAT array refers to invalid sector index \
                      0x{:08X}",
                     next
@@ -138,6 +169,7 @@ impl Header {
             num_dir_sectors,
             num_fat_sectors,
             first_dir_sector,
+            transaction_signature,
             first_minifat_sector,
             num_minifat_sectors,
             first_difat_sector,
@@ -158,7 +190,7 @@ impl Header {
         writer.write_u32::<LittleEndian>(self.num_dir_sectors)?;
         writer.write_u32::<LittleEndian>(self.num_fat_sectors)?;
         writer.write_u32::<LittleEndian>(self.first_dir_sector)?;
-        writer.write_u32::<LittleEndian>(0)?; // transaction signature (unused)
+        writer.write_u32::<LittleEndian>(self.transaction_signature)?;
         writer.write_u32::<LittleEndian>(consts::MINI_STREAM_CUTOFF)?;
         writer.write_u32::<LittleEndian>(self.first_minifat_sector)?;
         writer.write_u32::<LittleEndian>(self.num_minifat_sectors)?;
@@ -175,7 +207,7 @@ impl Header {
 
 #[cfg(test)]
 mod tests {
-    use crate::internal::{consts, Validation, Version};
+    use crate::internal::{consts, ValidationPolicy, Version};
 
     use super::Header;
 
@@ -185,6 +217,7 @@ mod tests {
             num_dir_sectors: 0,
             num_fat_sectors: 1,
             first_dir_sector: 1,
+            transaction_signature: 0,
             first_minifat_sector: 2,
             num_minifat_sectors: 3,
             first_difat_sector: consts::END_OF_CHAIN,
@@ -208,9 +241,11 @@ mod tests {
         let header1 = make_valid_header();
         let mut data = Vec::<u8>::new();
         header1.write_to(&mut data).unwrap();
-        let header2 =
-            Header::read_from(&mut data.as_slice(), Validation::Strict)
-                .unwrap();
+        let header2 = Header::read_from(
+            &mut data.as_slice(),
+            &mut ValidationPolicy::strict().into(),
+        )
+        .unwrap();
         assert_eq!(header1.version, header2.version);
         assert_eq!(header1.num_dir_sectors, header2.num_dir_sectors);
         assert_eq!(header1.num_fat_sectors, header2.num_fat_sectors);
@@ -230,7 +265,11 @@ mod tests {
     fn invalid_magic_number() {
         let mut data = make_valid_header_data();
         data[2] = 255;
-        Header::read_from(&mut data.as_slice(), Validation::Strict).unwrap();
+        Header::read_from(
+            &mut data.as_slice(),
+            &mut ValidationPolicy::strict().into(),
+        )
+        .unwrap();
     }
 
     #[test]
@@ -238,7 +277,11 @@ mod tests {
     fn invalid_version() {
         let mut data = make_valid_header_data();
         data[26] = 42;
-        Header::read_from(&mut data.as_slice(), Validation::Strict).unwrap();
+        Header::read_from(
+            &mut data.as_slice(),
+            &mut ValidationPolicy::strict().into(),
+        )
+        .unwrap();
     }
 
     #[test]
@@ -249,7 +292,11 @@ mod tests {
     fn invalid_byte_order_mark() {
         let mut data = make_valid_header_data();
         data[29] = 7;
-        Header::read_from(&mut data.as_slice(), Validation::Strict).unwrap();
+        Header::read_from(
+            &mut data.as_slice(),
+            &mut ValidationPolicy::strict().into(),
+        )
+        .unwrap();
     }
 
     #[test]
@@ -260,7 +307,11 @@ mod tests {
     fn invalid_sector_shift() {
         let mut data = make_valid_header_data();
         data[30] = 12;
-        Header::read_from(&mut data.as_slice(), Validation::Strict).unwrap();
+        Header::read_from(
+            &mut data.as_slice(),
+            &mut ValidationPolicy::strict().into(),
+        )
+        .unwrap();
     }
 
     #[test]
@@ -270,7 +321,11 @@ mod tests {
     fn invalid_mini_sector_shift() {
         let mut data = make_valid_header_data();
         data[32] = 7;
-        Header::read_from(&mut data.as_slice(), Validation::Strict).unwrap();
+        Header::read_from(
+            &mut data.as_slice(),
+            &mut ValidationPolicy::strict().into(),
+        )
+        .unwrap();
     }
 
     #[test]
@@ -281,19 +336,72 @@ mod tests {
     fn v3_non_zero_dir_sectors_strict() {
         let mut data = make_valid_header_data();
         data[40] = 37;
-        Header::read_from(&mut data.as_slice(), Validation::Strict).unwrap();
+        Header::read_from(
+            &mut data.as_slice(),
+            &mut ValidationPolicy::strict().into(),
+        )
+        .unwrap();
     }
 
     #[test]
     fn v3_non_zero_dir_sectors_permissive() {
         let mut data = make_valid_header_data();
         data[40] = 37;
-        let header =
-            Header::read_from(&mut data.as_slice(), Validation::Permissive)
-                .unwrap();
+        let header = Header::read_from(
+            &mut data.as_slice(),
+            &mut ValidationPolicy::permissive().into(),
+        )
+        .unwrap();
         assert_eq!(header.num_dir_sectors, 0);
     }
 
+    #[test]
+    #[should_panic(
+        expected = "Invalid reserved field in header (must be all zeros)"
+    )]
+    fn non_zero_reserved_field_strict() {
+        let mut data = make_valid_header_data();
+        data[36] = 1;
+        Header::read_from(
+            &mut data.as_slice(),
+            &mut ValidationPolicy::strict().into(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn non_zero_reserved_field_permissive() {
+        let mut data = make_valid_header_data();
+        data[8] = 1;
+        data[36] = 1;
+        Header::read_from(
+            &mut data.as_slice(),
+            &mut ValidationPolicy::permissive().into(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn free_sector_as_end_of_difat_chain() {
+        let mut data = make_valid_header_data();
+        data[68..72].copy_from_slice(&consts::FREE_SECTOR.to_le_bytes());
+        let header = Header::read_from(
+            &mut data.as_slice(),
+            &mut ValidationPolicy::permissive().into(),
+        )
+        .unwrap();
+        assert_eq!(header.first_difat_sector, consts::END_OF_CHAIN);
+        let mut policy = ValidationPolicy::permissive();
+        policy.allow_free_sector_as_end_of_chain = false;
+        let result =
+            Header::read_from(&mut data.as_slice(), &mut policy.into());
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "DIFAT chain in header ends with FREE_SECTOR instead of \
+             END_OF_CHAIN"
+        );
+    }
+
     #[test]
     #[should_panic(
         expected = "Incorrect mini stream cutoff (expected 4096, found 2048)"
@@ -301,7 +409,11 @@ mod tests {
     fn invalid_mini_stream_cutoff() {
         let mut data = make_valid_header_data();
         data[57] = 8;
-        Header::read_from(&mut data.as_slice(), Validation::Strict).unwrap();
+        Header::read_from(
+            &mut data.as_slice(),
+            &mut ValidationPolicy::strict().into(),
+        )
+        .unwrap();
     }
 
     #[test]
@@ -312,7 +424,11 @@ mod tests {
     fn invalid_difat_array() {
         let mut data = make_valid_header_data();
         data[80] = 0xFB;
-        Header::read_from(&mut data.as_slice(), Validation::Strict).unwrap();
+        Header::read_from(
+            &mut data.as_slice(),
+            &mut ValidationPolicy::strict().into(),
+        )
+        .unwrap();
     }
 }
 
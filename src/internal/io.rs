@@ -0,0 +1,585 @@
+//! The I/O traits and types used by this crate.
+//!
+//! With the `std` feature (enabled by default), these are simply re-exports
+//! from `std::io`.  Without it, this module provides minimal versions of
+//! them, with the same names and signatures, so that compound files can be
+//! read and written in `no_std` environments (with `alloc`).
+
+#[cfg(feature = "std")]
+pub use std::io::{
+    copy, BufRead, Cursor, Error, ErrorKind, Read, Result, Seek, SeekFrom,
+    Take, Write,
+};
+
+#[cfg(feature = "std")]
+pub(crate) use byteorder::{ReadBytesExt, WriteBytesExt};
+
+#[cfg(not(feature = "std"))]
+pub use self::nostd::{
+    copy, BufRead, Cursor, Error, ErrorKind, Read, Result, Seek, SeekFrom,
+    Take, Write,
+};
+
+#[cfg(not(feature = "std"))]
+pub(crate) use self::nostd::{ReadBytesExt, WriteBytesExt};
+
+//===========================================================================//
+
+#[cfg(not(feature = "std"))]
+mod nostd {
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+    use byteorder::ByteOrder;
+    use core::fmt;
+
+    /// A specialized `Result` type for I/O operations.
+    pub type Result<T> = core::result::Result<T, Error>;
+
+    /// A list specifying general categories of I/O error.
+    #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+    #[non_exhaustive]
+    pub enum ErrorKind {
+        /// An entity was not found.
+        NotFound,
+        /// An entity already exists.
+        AlreadyExists,
+        /// A parameter was incorrect.
+        InvalidInput,
+        /// Data not valid for the operation were encountered.
+        InvalidData,
+        /// An operation could not be completed, because it failed to
+        /// allocate enough memory.
+        OutOfMemory,
+        /// An operation could not be completed because an "end of file" was
+        /// reached prematurely.
+        UnexpectedEof,
+        /// An error returned when an operation could not be completed
+        /// because a call to `write` returned `Ok(0)`.
+        WriteZero,
+        /// This operation was interrupted, and can typically be retried.
+        Interrupted,
+        /// This operation is unsupported.
+        Unsupported,
+        /// Any I/O error not covered by the other kinds.
+        Other,
+    }
+
+    impl ErrorKind {
+        fn as_str(self) -> &'static str {
+            match self {
+                ErrorKind::NotFound => "entity not found",
+                ErrorKind::AlreadyExists => "entity already exists",
+                ErrorKind::InvalidInput => "invalid input parameter",
+                ErrorKind::InvalidData => "invalid data",
+                ErrorKind::OutOfMemory => "out of memory",
+                ErrorKind::UnexpectedEof => "unexpected end of file",
+                ErrorKind::WriteZero => "write zero",
+                ErrorKind::Interrupted => "operation interrupted",
+                ErrorKind::Unsupported => "unsupported",
+                ErrorKind::Other => "other error",
+            }
+        }
+    }
+
+    impl fmt::Display for ErrorKind {
+        fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str(self.as_str())
+        }
+    }
+
+    /// The error type for I/O operations.
+    pub struct Error {
+        kind: ErrorKind,
+        error: Option<Box<dyn core::error::Error + Send + Sync>>,
+    }
+
+    impl Error {
+        /// Creates a new I/O error from a known kind of error and an
+        /// arbitrary error payload (such as a message).
+        pub fn new<E>(kind: ErrorKind, error: E) -> Error
+        where
+            E: Into<Box<dyn core::error::Error + Send + Sync>>,
+        {
+            Error { kind, error: Some(error.into()) }
+        }
+
+        /// Creates a new I/O error of kind `ErrorKind::Other` from an
+        /// arbitrary error payload.
+        pub fn other<E>(error: E) -> Error
+        where
+            E: Into<Box<dyn core::error::Error + Send + Sync>>,
+        {
+            Error::new(ErrorKind::Other, error)
+        }
+
+        /// Returns the corresponding `ErrorKind` for this error.
+        pub fn kind(&self) -> ErrorKind {
+            self.kind
+        }
+
+        /// Returns a reference to the payload wrapped by this error, if any.
+        pub fn get_ref(
+            &self,
+        ) -> Option<&(dyn core::error::Error + Send + Sync + 'static)>
+        {
+            self.error.as_deref()
+        }
+
+        /// Consumes this error, returning its payload, if any.
+        pub fn into_inner(
+            self,
+        ) -> Option<Box<dyn core::error::Error + Send + Sync>> {
+            self.error
+        }
+    }
+
+    impl From<ErrorKind> for Error {
+        fn from(kind: ErrorKind) -> Error {
+            Error { kind, error: None }
+        }
+    }
+
+    impl fmt::Debug for Error {
+        fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            match self.error {
+                Some(ref error) => formatter
+                    .debug_struct("Custom")
+                    .field("kind", &self.kind)
+                    .field("error", error)
+                    .finish(),
+                None => {
+                    formatter.debug_tuple("Kind").field(&self.kind).finish()
+                }
+            }
+        }
+    }
+
+    impl fmt::Display for Error {
+        fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            match self.error {
+                Some(ref error) => fmt::Display::fmt(error, formatter),
+                None => formatter.write_str(self.kind.as_str()),
+            }
+        }
+    }
+
+    impl core::error::Error for Error {}
+
+    //=======================================================================//
+
+    /// Enumeration of possible methods to seek within an I/O object.
+    #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+    pub enum SeekFrom {
+        /// Sets the offset to the provided number of bytes.
+        Start(u64),
+        /// Sets the offset to the size of this object plus the specified
+        /// number of bytes.
+        End(i64),
+        /// Sets the offset to the current position plus the specified number
+        /// of bytes.
+        Current(i64),
+    }
+
+    /// The `Read` trait allows for reading bytes from a source.
+    pub trait Read {
+        /// Pulls some bytes from this source into the specified buffer,
+        /// returning how many bytes were read.
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize>;
+
+        /// Reads the exact number of bytes required to fill `buf`.
+        fn read_exact(&mut self, mut buf: &mut [u8]) -> Result<()> {
+            while !buf.is_empty() {
+                match self.read(buf) {
+                    Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                    Ok(num_bytes) => buf = &mut buf[num_bytes..],
+                    Err(error) if error.kind() == ErrorKind::Interrupted => {}
+                    Err(error) => return Err(error),
+                }
+            }
+            Ok(())
+        }
+
+        /// Reads all bytes until EOF in this source, appending them to
+        /// `buf`.
+        fn read_to_end(&mut self, buf: &mut Vec<u8>) -> Result<usize> {
+            let start_len = buf.len();
+            let mut chunk = [0u8; 4096];
+            loop {
+                match self.read(&mut chunk) {
+                    Ok(0) => return Ok(buf.len() - start_len),
+                    Ok(num_bytes) => {
+                        buf.extend_from_slice(&chunk[..num_bytes])
+                    }
+                    Err(error) if error.kind() == ErrorKind::Interrupted => {}
+                    Err(error) => return Err(error),
+                }
+            }
+        }
+
+        /// Creates an adapter which will read at most `limit` bytes from
+        /// this reader.
+        fn take(self, limit: u64) -> Take<Self>
+        where
+            Self: Sized,
+        {
+            Take { inner: self, limit }
+        }
+    }
+
+    /// A reader adapter which limits the bytes read from an underlying
+    /// reader; see `Read::take`.
+    #[derive(Debug)]
+    pub struct Take<T> {
+        inner: T,
+        limit: u64,
+    }
+
+    impl<T> Take<T> {
+        /// Returns the number of bytes that can be read before this adapter
+        /// will return EOF.
+        pub fn limit(&self) -> u64 {
+            self.limit
+        }
+
+        /// Consumes this adapter, returning the underlying reader.
+        pub fn into_inner(self) -> T {
+            self.inner
+        }
+    }
+
+    impl<T: Read> Read for Take<T> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let max = (buf.len() as u64).min(self.limit) as usize;
+            let num_bytes = self.inner.read(&mut buf[..max])?;
+            self.limit -= num_bytes as u64;
+            Ok(num_bytes)
+        }
+    }
+
+    /// A trait for objects which are byte-oriented sinks.
+    pub trait Write {
+        /// Writes a buffer into this writer, returning how many bytes were
+        /// written.
+        fn write(&mut self, buf: &[u8]) -> Result<usize>;
+
+        /// Flushes this output stream, ensuring that all intermediately
+        /// buffered contents reach their destination.
+        fn flush(&mut self) -> Result<()>;
+
+        /// Attempts to write an entire buffer into this writer.
+        fn write_all(&mut self, mut buf: &[u8]) -> Result<()> {
+            while !buf.is_empty() {
+                match self.write(buf) {
+                    Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                    Ok(num_bytes) => buf = &buf[num_bytes..],
+                    Err(error) if error.kind() == ErrorKind::Interrupted => {}
+                    Err(error) => return Err(error),
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// The `Seek` trait provides a cursor which can be moved within a
+    /// stream of bytes.
+    pub trait Seek {
+        /// Seeks to an offset, in bytes, in a stream, returning the new
+        /// position from the start of the stream.
+        fn seek(&mut self, pos: SeekFrom) -> Result<u64>;
+
+        /// Returns the current seek position from the start of the stream.
+        fn stream_position(&mut self) -> Result<u64> {
+            self.seek(SeekFrom::Current(0))
+        }
+    }
+
+    /// A `BufRead` is a type of `Read`er which has an internal buffer.
+    pub trait BufRead: Read {
+        /// Returns the contents of the internal buffer, filling it with more
+        /// data from the inner reader if it is empty.
+        fn fill_buf(&mut self) -> Result<&[u8]>;
+
+        /// Tells this buffer that `amt` bytes have been consumed from the
+        /// buffer, so they should no longer be returned in calls to `read`.
+        fn consume(&mut self, amt: usize);
+    }
+
+    /// Copies the entire contents of a reader into a writer, returning the
+    /// number of bytes copied.
+    pub fn copy<R, W>(reader: &mut R, writer: &mut W) -> Result<u64>
+    where
+        R: Read + ?Sized,
+        W: Write + ?Sized,
+    {
+        let mut buffer = [0u8; 4096];
+        let mut total = 0;
+        loop {
+            let num_bytes = match reader.read(&mut buffer) {
+                Ok(0) => return Ok(total),
+                Ok(num_bytes) => num_bytes,
+                Err(error) if error.kind() == ErrorKind::Interrupted => {
+                    continue
+                }
+                Err(error) => return Err(error),
+            };
+            writer.write_all(&buffer[..num_bytes])?;
+            total += num_bytes as u64;
+        }
+    }
+
+    //=======================================================================//
+
+    impl<R: Read + ?Sized> Read for &mut R {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            (**self).read(buf)
+        }
+    }
+
+    impl<R: Read + ?Sized> Read for Box<R> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            (**self).read(buf)
+        }
+    }
+
+    impl Read for &[u8] {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let num_bytes = buf.len().min(self.len());
+            let (head, tail) = self.split_at(num_bytes);
+            buf[..num_bytes].copy_from_slice(head);
+            *self = tail;
+            Ok(num_bytes)
+        }
+    }
+
+    impl<W: Write + ?Sized> Write for &mut W {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            (**self).write(buf)
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            (**self).flush()
+        }
+    }
+
+    impl<W: Write + ?Sized> Write for Box<W> {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            (**self).write(buf)
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            (**self).flush()
+        }
+    }
+
+    impl Write for &mut [u8] {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            let num_bytes = buf.len().min(self.len());
+            let (head, tail) = core::mem::take(self).split_at_mut(num_bytes);
+            head.copy_from_slice(&buf[..num_bytes]);
+            *self = tail;
+            Ok(num_bytes)
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Write for Vec<u8> {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<S: Seek + ?Sized> Seek for &mut S {
+        fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+            (**self).seek(pos)
+        }
+    }
+
+    impl<S: Seek + ?Sized> Seek for Box<S> {
+        fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+            (**self).seek(pos)
+        }
+    }
+
+    //=======================================================================//
+
+    /// Wraps an in-memory buffer and provides it with a `Seek`
+    /// implementation.
+    #[derive(Clone, Debug, Default, Eq, PartialEq)]
+    pub struct Cursor<T> {
+        inner: T,
+        position: u64,
+    }
+
+    impl<T> Cursor<T> {
+        /// Creates a new cursor wrapping the provided underlying buffer,
+        /// with an initial position of zero.
+        pub fn new(inner: T) -> Cursor<T> {
+            Cursor { inner, position: 0 }
+        }
+
+        /// Consumes this cursor, returning the underlying value.
+        pub fn into_inner(self) -> T {
+            self.inner
+        }
+
+        /// Gets a reference to the underlying value in this cursor.
+        pub fn get_ref(&self) -> &T {
+            &self.inner
+        }
+
+        /// Gets a mutable reference to the underlying value in this cursor.
+        pub fn get_mut(&mut self) -> &mut T {
+            &mut self.inner
+        }
+
+        /// Returns the current position of this cursor.
+        pub fn position(&self) -> u64 {
+            self.position
+        }
+
+        /// Sets the position of this cursor.
+        pub fn set_position(&mut self, position: u64) {
+            self.position = position;
+        }
+    }
+
+    impl<T: AsRef<[u8]>> Read for Cursor<T> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let data = self.inner.as_ref();
+            let start = (self.position as usize).min(data.len());
+            let num_bytes = (&data[start..]).read(buf)?;
+            self.position += num_bytes as u64;
+            Ok(num_bytes)
+        }
+    }
+
+    impl<T: AsRef<[u8]>> Seek for Cursor<T> {
+        fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+            let (base, offset) = match pos {
+                SeekFrom::Start(offset) => {
+                    self.position = offset;
+                    return Ok(offset);
+                }
+                SeekFrom::End(offset) => {
+                    (self.inner.as_ref().len() as u64, offset)
+                }
+                SeekFrom::Current(offset) => (self.position, offset),
+            };
+            match base.checked_add_signed(offset) {
+                Some(position) => {
+                    self.position = position;
+                    Ok(position)
+                }
+                None => Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "invalid seek to a negative or overflowing position",
+                )),
+            }
+        }
+    }
+
+    /// Writes `buf` into `vec` at the given position, zero-filling any gap
+    /// past the end of the vector.
+    fn write_to_vec(vec: &mut Vec<u8>, position: &mut u64, buf: &[u8]) {
+        let start = *position as usize;
+        if vec.len() < start {
+            vec.resize(start, 0);
+        }
+        let overlap = (vec.len() - start).min(buf.len());
+        vec[start..start + overlap].copy_from_slice(&buf[..overlap]);
+        vec.extend_from_slice(&buf[overlap..]);
+        *position += buf.len() as u64;
+    }
+
+    impl Write for Cursor<Vec<u8>> {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            write_to_vec(&mut self.inner, &mut self.position, buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Write for Cursor<&mut Vec<u8>> {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            write_to_vec(self.inner, &mut self.position, buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    //=======================================================================//
+
+    /// Extends `Read` with methods for reading integers (a `no_std`
+    /// counterpart to `byteorder::ReadBytesExt`).
+    pub trait ReadBytesExt: Read {
+        fn read_u8(&mut self) -> Result<u8> {
+            let mut buf = [0u8; 1];
+            self.read_exact(&mut buf)?;
+            Ok(buf[0])
+        }
+
+        fn read_u16<T: ByteOrder>(&mut self) -> Result<u16> {
+            let mut buf = [0u8; 2];
+            self.read_exact(&mut buf)?;
+            Ok(T::read_u16(&buf))
+        }
+
+        fn read_u32<T: ByteOrder>(&mut self) -> Result<u32> {
+            let mut buf = [0u8; 4];
+            self.read_exact(&mut buf)?;
+            Ok(T::read_u32(&buf))
+        }
+
+        fn read_u64<T: ByteOrder>(&mut self) -> Result<u64> {
+            let mut buf = [0u8; 8];
+            self.read_exact(&mut buf)?;
+            Ok(T::read_u64(&buf))
+        }
+    }
+
+    impl<R: Read + ?Sized> ReadBytesExt for R {}
+
+    /// Extends `Write` with methods for writing integers (a `no_std`
+    /// counterpart to `byteorder::WriteBytesExt`).
+    pub trait WriteBytesExt: Write {
+        fn write_u8(&mut self, value: u8) -> Result<()> {
+            self.write_all(&[value])
+        }
+
+        fn write_u16<T: ByteOrder>(&mut self, value: u16) -> Result<()> {
+            let mut buf = [0u8; 2];
+            T::write_u16(&mut buf, value);
+            self.write_all(&buf)
+        }
+
+        fn write_u32<T: ByteOrder>(&mut self, value: u32) -> Result<()> {
+            let mut buf = [0u8; 4];
+            T::write_u32(&mut buf, value);
+            self.write_all(&buf)
+        }
+
+        fn write_u64<T: ByteOrder>(&mut self, value: u64) -> Result<()> {
+            let mut buf = [0u8; 8];
+            T::write_u64(&mut buf, value);
+            self.write_all(&buf)
+        }
+    }
+
+    impl<W: Write + ?Sized> WriteBytesExt for W {}
+}
+
+//===========================================================================//
@@ -0,0 +1,205 @@
+use crate::io::{self, Read, Seek, SeekFrom, Write};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::hash::Hasher;
+
+use fnv::FnvHasher;
+
+use crate::internal::consts;
+
+//===========================================================================//
+
+const JOURNAL_MAGIC: [u8; 8] = *b"CFBJRNL1";
+const CHECKSUM_LEN: usize = 8;
+const RECORD_LEN: usize =
+    JOURNAL_MAGIC.len() + consts::HEADER_LEN + CHECKSUM_LEN;
+/// The offset within the header of the transaction signature field.
+const TRANSACTION_SIGNATURE_OFFSET: usize = 52;
+
+//===========================================================================//
+
+/// A reader/writer that can hold a compound file's journal (see
+/// [`CompoundFile::open_with_journal`](
+/// struct.CompoundFile.html#method.open_with_journal)).  This is
+/// implemented for every `Read + Write + Seek` type that owns its data; with
+/// the `sync` feature enabled, the type must also be `Send` and `Sync`.
+#[cfg(not(feature = "sync"))]
+pub trait JournalFile: Read + Write + Seek + 'static {}
+
+#[cfg(not(feature = "sync"))]
+impl<J: Read + Write + Seek + 'static> JournalFile for J {}
+
+/// A reader/writer that can hold a compound file's journal (see
+/// [`CompoundFile::open_with_journal`](
+/// struct.CompoundFile.html#method.open_with_journal)).  This is
+/// implemented for every `Read + Write + Seek` type that owns its data; with
+/// the `sync` feature enabled, the type must also be `Send` and `Sync`.
+#[cfg(feature = "sync")]
+pub trait JournalFile: Read + Write + Seek + Send + Sync + 'static {}
+
+#[cfg(feature = "sync")]
+impl<J: Read + Write + Seek + Send + Sync + 'static> JournalFile for J {}
+
+//===========================================================================//
+
+/// A sidecar file that records the header a commit is about to write to a
+/// compound file, so that a commit interrupted partway through writing the
+/// header can be completed the next time the compound file is opened.
+///
+/// A record consists of a magic number, the new header, and a checksum of
+/// both.  A record that is missing, cleared, or only partly written means
+/// that the compound file's own header is intact.
+///
+/// The journal's reader/writer is boxed, so that it needn't be the same type
+/// as the compound file's.
+pub struct Journal {
+    inner: Box<dyn JournalFile>,
+}
+
+impl Journal {
+    pub fn new<J: JournalFile>(inner: J) -> Journal {
+        Journal { inner: Box::new(inner) }
+    }
+
+    /// Records that the compound file's header is about to be replaced with
+    /// `header`, and flushes the record to the underlying file.
+    pub fn record(&mut self, header: &[u8]) -> io::Result<()> {
+        let record = encode_record(header);
+        self.inner.seek(SeekFrom::Start(0))?;
+        self.inner.write_all(&record)?;
+        self.inner.flush()
+    }
+
+    /// Invalidates the current record, if any, once the header it holds has
+    /// been written to the compound file.
+    pub fn clear(&mut self) -> io::Result<()> {
+        self.inner.seek(SeekFrom::Start(0))?;
+        self.inner.write_all(&[0; JOURNAL_MAGIC.len()])?;
+        self.inner.flush()
+    }
+
+    /// Returns the header held by the current record, if there is a complete
+    /// and valid one.
+    fn read_record(&mut self) -> io::Result<Option<Vec<u8>>> {
+        self.inner.seek(SeekFrom::Start(0))?;
+        let mut record = Vec::with_capacity(RECORD_LEN);
+        (&mut self.inner).take(RECORD_LEN as u64).read_to_end(&mut record)?;
+        if record.len() < RECORD_LEN || record[..8] != JOURNAL_MAGIC {
+            return Ok(None);
+        }
+        let (contents, checksum_bytes) =
+            record.split_at(RECORD_LEN - CHECKSUM_LEN);
+        let mut expected = [0; CHECKSUM_LEN];
+        expected.copy_from_slice(checksum_bytes);
+        if checksum(contents) != u64::from_le_bytes(expected) {
+            return Ok(None);
+        }
+        Ok(Some(contents[JOURNAL_MAGIC.len()..].to_vec()))
+    }
+
+    /// Completes any commit to the given compound file that was interrupted
+    /// while writing its header.  If the journal holds a valid record whose
+    /// header differs from the file's, that header is written to the file;
+    /// otherwise the file is left as it is (any sectors that the unfinished
+    /// commit wrote were unused by the file's current state).  Either way,
+    /// the header's journaling signature is then cleared, so that the file
+    /// can be opened normally.
+    pub fn recover<G: Read + Write + Seek>(
+        &mut self,
+        file: &mut G,
+    ) -> io::Result<()> {
+        file.seek(SeekFrom::Start(0))?;
+        let mut current = Vec::with_capacity(consts::HEADER_LEN);
+        file.take(consts::HEADER_LEN as u64).read_to_end(&mut current)?;
+        if let Some(header) = self.read_record()? {
+            if current != header {
+                file.seek(SeekFrom::Start(0))?;
+                file.write_all(&header)?;
+                current = header;
+            }
+        }
+        let signature = consts::JOURNALING_SIGNATURE.to_le_bytes();
+        let range =
+            TRANSACTION_SIGNATURE_OFFSET..TRANSACTION_SIGNATURE_OFFSET + 4;
+        if current.get(range) == Some(&signature[..]) {
+            file.seek(SeekFrom::Start(TRANSACTION_SIGNATURE_OFFSET as u64))?;
+            file.write_all(&[0; 4])?;
+        }
+        file.flush()
+    }
+}
+
+/// Returns the journal record for the given header.
+fn encode_record(header: &[u8]) -> Vec<u8> {
+    debug_assert_eq!(header.len(), consts::HEADER_LEN);
+    let mut record = Vec::with_capacity(RECORD_LEN);
+    record.extend_from_slice(&JOURNAL_MAGIC);
+    record.extend_from_slice(header);
+    let checksum = checksum(&record);
+    record.extend_from_slice(&checksum.to_le_bytes());
+    record
+}
+
+fn checksum(bytes: &[u8]) -> u64 {
+    let mut hasher = FnvHasher::default();
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+//===========================================================================//
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::internal::consts;
+
+    use super::{encode_record, Journal, RECORD_LEN};
+
+    fn make_header(fill: u8) -> Vec<u8> {
+        vec![fill; consts::HEADER_LEN]
+    }
+
+    #[test]
+    fn recover_from_valid_record() {
+        let mut journal = Journal::new(Cursor::new(Vec::new()));
+        journal.record(&make_header(1)).unwrap();
+        let mut file = Cursor::new(vec![2; 600]);
+        journal.recover(&mut file).unwrap();
+        let file = file.into_inner();
+        assert_eq!(&file[..consts::HEADER_LEN], &make_header(1)[..]);
+        assert_eq!(&file[consts::HEADER_LEN..], &[2; 88][..]);
+    }
+
+    #[test]
+    fn ignore_cleared_record() {
+        let mut journal = Journal::new(Cursor::new(Vec::new()));
+        journal.record(&make_header(1)).unwrap();
+        journal.clear().unwrap();
+        let mut file = Cursor::new(vec![2; 600]);
+        journal.recover(&mut file).unwrap();
+        assert_eq!(file.into_inner(), vec![2; 600]);
+    }
+
+    #[test]
+    fn ignore_incomplete_record() {
+        for len in [0, 8, RECORD_LEN - 1] {
+            let mut data = encode_record(&make_header(1));
+            data.truncate(len);
+            let mut file = Cursor::new(vec![2; 600]);
+            Journal::new(Cursor::new(data)).recover(&mut file).unwrap();
+            assert_eq!(file.into_inner(), vec![2; 600]);
+        }
+    }
+
+    #[test]
+    fn ignore_corrupted_record() {
+        let mut data = encode_record(&make_header(1));
+        data[100] = 0;
+        let mut file = Cursor::new(vec![2; 600]);
+        Journal::new(Cursor::new(data)).recover(&mut file).unwrap();
+        assert_eq!(file.into_inner(), vec![2; 600]);
+    }
+}
+
+//===========================================================================//
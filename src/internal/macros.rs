@@ -2,44 +2,53 @@
 
 macro_rules! already_exists {
     ($e:expr) => {
-        return Err(::std::io::Error::new(::std::io::ErrorKind::AlreadyExists,
+        return Err($crate::io::Error::new($crate::io::ErrorKind::AlreadyExists,
                                          $e))
     };
     ($fmt:expr, $($arg:tt)+) => {
-        return Err(::std::io::Error::new(::std::io::ErrorKind::AlreadyExists,
-                                         format!($fmt, $($arg)+)))
+        return Err($crate::io::Error::new($crate::io::ErrorKind::AlreadyExists,
+                                         ::alloc::format!($fmt, $($arg)+)))
     };
 }
 
-macro_rules! invalid_data {
-    ($e:expr) => {
-        return Err(::std::io::Error::new(::std::io::ErrorKind::InvalidData,
-                                         $e))
+/// Returns a `crate::Error` of the given variant, converted into an
+/// `io::Error`, with a `message` built from the remaining format arguments.
+macro_rules! corrupted {
+    ($($arg:tt)+) => {
+        return Err(corruption!($($arg)+).into())
     };
-    ($fmt:expr, $($arg:tt)+) => {
-        return Err(::std::io::Error::new(::std::io::ErrorKind::InvalidData,
-                                         format!($fmt, $($arg)+)))
+}
+
+/// Builds a `crate::Error` of the given variant, with a `message` built from
+/// the remaining format arguments.
+macro_rules! corruption {
+    ($variant:ident { $($field:ident $(: $value:expr)?),* $(,)? },
+     $($arg:tt)+) => {
+        $crate::Error::$variant {
+            $($field $(: $value)?,)*
+            message: ::alloc::format!($($arg)+),
+        }
     };
 }
 
 macro_rules! invalid_input {
     ($e:expr) => {
-        return Err(::std::io::Error::new(::std::io::ErrorKind::InvalidInput,
+        return Err($crate::io::Error::new($crate::io::ErrorKind::InvalidInput,
                                          $e))
     };
     ($fmt:expr, $($arg:tt)+) => {
-        return Err(::std::io::Error::new(::std::io::ErrorKind::InvalidInput,
-                                         format!($fmt, $($arg)+)))
+        return Err($crate::io::Error::new($crate::io::ErrorKind::InvalidInput,
+                                         ::alloc::format!($fmt, $($arg)+)))
     };
 }
 
 macro_rules! not_found {
     ($e:expr) => {
-        return Err(::std::io::Error::new(::std::io::ErrorKind::NotFound, $e))
+        return Err($crate::io::Error::new($crate::io::ErrorKind::NotFound, $e))
     };
     ($fmt:expr, $($arg:tt)+) => {
-        return Err(::std::io::Error::new(::std::io::ErrorKind::NotFound,
-                                         format!($fmt, $($arg)+)))
+        return Err($crate::io::Error::new($crate::io::ErrorKind::NotFound,
+                                         ::alloc::format!($fmt, $($arg)+)))
     };
 }
 
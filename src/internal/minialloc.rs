@@ -1,31 +1,72 @@
-use std::io::{self, Seek, SeekFrom, Write};
-use std::mem::size_of;
+use crate::io::{self, Read, Seek, SeekFrom, Write, WriteBytesExt};
+use alloc::collections::BTreeSet;
+use alloc::format;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem::{self, size_of};
 
-use byteorder::{LittleEndian, WriteBytesExt};
-use fnv::FnvHashSet;
+use crate::internal::IdSet;
+use byteorder::LittleEndian;
 
+use crate::internal::compact::Layout;
 use crate::internal::{
-    consts, Chain, DirEntry, Directory, MiniChain, ObjType, Sector,
-    SectorInit, Validation, Version,
+    consts, is_free, Chain, ChainCache, DirEntry, Directory, FreeList,
+    Journal, MiniChain, ObjType, ReadAt, Sector, SectorInit, SetLen,
+    Validator, Version, WriteMode,
 };
 
 //===========================================================================//
 
-macro_rules! malformed {
-    ($e:expr) => { invalid_data!("Malformed MiniFAT ({})", $e) };
-    ($fmt:expr, $($arg:tt)+) => {
-        invalid_data!("Malformed MiniFAT ({})", format!($fmt, $($arg)+))
+macro_rules! malformation {
+    ($sector_id:expr; $($arg:tt)+) => {
+        corruption!(
+            MiniFat { sector_id: Some($sector_id) },
+            "Malformed MiniFAT ({})",
+            format!($($arg)+)
+        )
+    };
+    ($($arg:tt)+) => {
+        corruption!(
+            MiniFat { sector_id: None },
+            "Malformed MiniFAT ({})",
+            format!($($arg)+)
+        )
     };
 }
 
 //===========================================================================//
 
+type FlushFn<F> = fn(&mut MiniAllocator<F>) -> io::Result<()>;
+
 /// A wrapper around the directory manager that additionally provides
 /// mini-sector allocation via the MiniFAT.
 pub struct MiniAllocator<F> {
     directory: Directory<F>,
     minifat: Vec<u32>,
     minifat_start_sector: u32,
+    free_mini_sectors: FreeList,
+    /// The mini sector IDs of recently used mini chains.
+    mini_chains: ChainCache,
+    /// In write-back mode, the indices (within the MiniFAT chain) of MiniFAT
+    /// sectors that have been changed in memory but not yet written to the
+    /// file.
+    dirty_minifat_sectors: BTreeSet<usize>,
+    /// In write-back or journaling mode, a function that flushes all pending
+    /// changes (and in journaling mode, finishes journaling) when the
+    /// compound file is dropped.  This is stored here because `Drop` can't
+    /// require `F: Read + Write + Seek`.
+    write_back_flush: Option<FlushFn<F>>,
+    /// While a transaction is in progress, the MiniFAT and the MiniFAT's
+    /// start sector as of its start.
+    transaction: Option<(Vec<u32>, u32)>,
+    /// In journaling mode, true if the transaction in progress was begun
+    /// automatically (and so is committed whenever the compound file is
+    /// flushed) rather than by `begin_transaction`.
+    implicit_transaction: bool,
+    /// Incremented each time a transaction begun by `begin_transaction` is
+    /// committed or reverted, so that streams that were open at the time can
+    /// tell that their buffered state is stale.
+    generation: u64,
 }
 
 impl<F> MiniAllocator<F> {
@@ -33,11 +74,22 @@ impl<F> MiniAllocator<F> {
         directory: Directory<F>,
         minifat: Vec<u32>,
         minifat_start_sector: u32,
-        validation: Validation,
+        validator: &mut Validator,
     ) -> io::Result<MiniAllocator<F>> {
-        let mut minialloc =
-            MiniAllocator { directory, minifat, minifat_start_sector };
-        minialloc.validate(validation)?;
+        let free_mini_sectors = FreeList::new(is_free(&minifat));
+        let mut minialloc = MiniAllocator {
+            directory,
+            minifat,
+            minifat_start_sector,
+            free_mini_sectors,
+            mini_chains: ChainCache::default(),
+            dirty_minifat_sectors: BTreeSet::new(),
+            write_back_flush: None,
+            transaction: None,
+            implicit_transaction: false,
+            generation: 0,
+        };
+        minialloc.validate(validator)?;
         Ok(minialloc)
     }
 
@@ -45,10 +97,40 @@ impl<F> MiniAllocator<F> {
         self.directory.version()
     }
 
+    pub fn write_mode(&self) -> WriteMode {
+        self.directory.write_mode()
+    }
+
+    /// Returns the number of transactions begun by `begin_transaction` that
+    /// have since been committed or reverted.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.directory.in_transaction()
+    }
+
+    pub fn is_journaling(&self) -> bool {
+        self.directory.is_journaling()
+    }
+
+    /// In write-back or journaling mode, writes all pending changes to the
+    /// underlying file (and in journaling mode, finishes journaling);
+    /// otherwise, does nothing.  This is called when the compound file is
+    /// dropped.
+    pub fn flush_write_back(&mut self) -> io::Result<()> {
+        match self.write_back_flush {
+            Some(flush) => flush(self),
+            None => Ok(()),
+        }
+    }
+
     pub fn next_mini_sector(&self, sector_id: u32) -> io::Result<u32> {
         let index = sector_id as usize;
         if index >= self.minifat.len() {
-            invalid_data!(
+            corrupted!(
+                MiniFat { sector_id: Some(sector_id) },
                 "Found reference to mini sector {}, but MiniFAT has only {} \
                  entries",
                 index,
@@ -60,11 +142,153 @@ impl<F> MiniAllocator<F> {
             && (next_id > consts::MAX_REGULAR_SECTOR
                 || next_id as usize >= self.minifat.len())
         {
-            invalid_data!("next_id ({}) is invalid", next_id);
+            corrupted!(
+                MiniFat { sector_id: Some(sector_id) },
+                "next_id ({}) is invalid",
+                next_id
+            );
         }
         Ok(next_id)
     }
 
+    /// Walks the MiniFAT to find the mini sector IDs of the mini chain
+    /// starting at the given mini sector, unless they are already cached.
+    pub fn load_mini_chain(&mut self, start_sector_id: u32) -> io::Result<()> {
+        if start_sector_id == consts::END_OF_CHAIN {
+            return Ok(());
+        }
+        if self.mini_chains.contains(start_sector_id) {
+            self.mini_chains.touch(start_sector_id);
+            return Ok(());
+        }
+        let mut sector_ids = Vec::<u32>::new();
+        let mut current_sector_id = start_sector_id;
+        while current_sector_id != consts::END_OF_CHAIN {
+            sector_ids.push(current_sector_id);
+            current_sector_id = self.next_mini_sector(current_sector_id)?;
+            if current_sector_id == start_sector_id {
+                corrupted!(
+                    ChainLoop { sector_id: current_sector_id },
+                    "Minichain contained duplicate sector id {}",
+                    current_sector_id
+                );
+            }
+        }
+        self.mini_chains.insert(start_sector_id, sector_ids);
+        Ok(())
+    }
+
+    /// Returns the mini sector IDs of the mini chain starting at the given
+    /// mini sector, which must already have been loaded with
+    /// `load_mini_chain`.
+    pub fn cached_mini_chain(&self, start_sector_id: u32) -> &[u32] {
+        match self.mini_chains.get(start_sector_id) {
+            Some(sector_ids) => sector_ids,
+            None => {
+                debug_assert_eq!(start_sector_id, consts::END_OF_CHAIN);
+                &[]
+            }
+        }
+    }
+
+    /// Returns the mini sector IDs of the mini chain starting at the given
+    /// mini sector, if they are cached.  Unlike `load_mini_chain`, this needs
+    /// only a shared reference.
+    pub fn try_cached_mini_chain(
+        &self,
+        start_sector_id: u32,
+    ) -> Option<&[u32]> {
+        if start_sector_id == consts::END_OF_CHAIN {
+            return Some(&[]);
+        }
+        self.mini_chains.get(start_sector_id)
+    }
+
+    pub fn try_cached_chain(&self, start_sector_id: u32) -> Option<&[u32]> {
+        self.directory.try_cached_chain(start_sector_id)
+    }
+
+    /// Returns the sector IDs of the mini stream's own chain, if they are
+    /// cached.
+    pub fn try_cached_mini_stream(&self) -> Option<&[u32]> {
+        let mini_stream_start_sector =
+            self.directory.root_dir_entry().start_sector;
+        self.directory.try_cached_chain(mini_stream_start_sector)
+    }
+
+    /// Returns the sector of the mini stream that holds the given mini
+    /// sector, and the offset of the mini sector within that sector.  The
+    /// mini stream's chain must already be cached (see `load_mini_stream`).
+    pub fn locate_mini_sector(
+        &self,
+        mini_sector: u32,
+    ) -> io::Result<(u32, u64)> {
+        let mini_sectors_per_sector =
+            self.sector_len() / consts::MINI_SECTOR_LEN;
+        let sector_index_within_chain =
+            mini_sector as usize / mini_sectors_per_sector;
+        let sector_id = match self
+            .try_cached_mini_stream()
+            .and_then(|mini_stream| mini_stream.get(sector_index_within_chain))
+        {
+            Some(&sector_id) => sector_id,
+            None => corrupted!(
+                MiniFat { sector_id: Some(mini_sector) },
+                "invalid sector id"
+            ),
+        };
+        let mini_sector_start = (mini_sector as usize
+            % mini_sectors_per_sector)
+            * consts::MINI_SECTOR_LEN;
+        Ok((sector_id, mini_sector_start as u64))
+    }
+
+    /// Loads the chain of the mini stream itself into the cache.
+    pub fn load_mini_stream(&mut self) -> io::Result<()> {
+        let mini_stream_start_sector =
+            self.directory.root_dir_entry().start_sector;
+        self.directory.load_chain(mini_stream_start_sector)
+    }
+
+    /// Loads the chain holding the given stream's data into the cache (along
+    /// with the mini stream's chain, for a stream in the mini stream), so
+    /// that the stream can then be read with only a shared reference.
+    pub fn load_stream_chain(&mut self, stream_id: u32) -> io::Result<()> {
+        let dir_entry = self.dir_entry(stream_id);
+        let start_sector = dir_entry.start_sector;
+        if dir_entry.stream_len < consts::MINI_STREAM_CUTOFF as u64 {
+            self.load_mini_chain(start_sector)?;
+            self.load_mini_stream()
+        } else {
+            self.directory.load_chain(start_sector)
+        }
+    }
+
+    pub fn inner(&self) -> &F {
+        self.directory.inner()
+    }
+
+    #[cfg(feature = "async")]
+    pub fn inner_mut(&mut self) -> &mut F {
+        self.directory.inner_mut()
+    }
+
+    /// Returns the IDs of the sectors that hold the DIFAT, the FAT, the
+    /// directory, the MiniFAT, and the mini stream; that is, every allocated
+    /// sector other than those of regular (non-mini) streams.
+    pub fn metadata_sector_ids(&mut self) -> io::Result<Vec<u32>> {
+        let mut sector_ids = self.directory.table_sector_ids()?;
+        let mini_stream_start_sector = self.root_dir_entry().start_sector;
+        for start_sector_id in
+            [self.minifat_start_sector, mini_stream_start_sector]
+        {
+            let chain =
+                self.directory.open_chain(start_sector_id, SectorInit::Fat)?;
+            sector_ids.extend_from_slice(chain.sector_ids());
+        }
+        Ok(sector_ids)
+    }
+
     pub fn into_inner(self) -> F {
         self.directory.into_inner()
     }
@@ -73,6 +297,18 @@ impl<F> MiniAllocator<F> {
         self.directory.stream_id_for_name_chain(names)
     }
 
+    pub fn num_sectors(&self) -> u32 {
+        self.directory.num_sectors()
+    }
+
+    pub fn sector_offset(
+        &self,
+        sector_id: u32,
+        offset_within_sector: u64,
+    ) -> io::Result<u64> {
+        self.directory.sector_offset(sector_id, offset_within_sector)
+    }
+
     pub fn open_chain(
         &mut self,
         start_sector_id: u32,
@@ -88,58 +324,177 @@ impl<F> MiniAllocator<F> {
         MiniChain::new(self, start_sector_id)
     }
 
+    pub fn fat(&self) -> &[u32] {
+        self.directory.fat()
+    }
+
+    pub fn difat(&self) -> &[u32] {
+        self.directory.difat()
+    }
+
+    pub fn difat_sector_ids(&self) -> &[u32] {
+        self.directory.difat_sector_ids()
+    }
+
+    pub fn minifat(&self) -> &[u32] {
+        &self.minifat
+    }
+
+    /// Checks the index of free mini sectors against the MiniFAT.
+    #[cfg(test)]
+    pub fn verify_free_mini_sectors(&self) -> io::Result<()> {
+        self.free_mini_sectors.verify(
+            is_free(&self.minifat),
+            |sector_id, message| crate::Error::MiniFat {
+                sector_id: Some(sector_id),
+                message,
+            },
+        )
+    }
+
+    pub fn dir_start_sector(&self) -> u32 {
+        self.directory.dir_start_sector()
+    }
+
+    pub fn num_dir_entries(&self) -> u32 {
+        self.directory.num_dir_entries()
+    }
+
     pub fn root_dir_entry(&self) -> &DirEntry {
         self.directory.root_dir_entry()
     }
 
+    pub fn sector_len(&self) -> usize {
+        self.directory.sector_len()
+    }
+
     pub fn dir_entry(&self, stream_id: u32) -> &DirEntry {
         self.directory.dir_entry(stream_id)
     }
 
-    fn validate(&mut self, validation: Validation) -> io::Result<()> {
+    /// Replaces all in-memory allocation tables and directory entries with
+    /// those of the given layout, which must already have been written to the
+    /// underlying file.
+    pub fn replace_layout(&mut self, layout: Layout) {
+        self.directory.replace_tables(
+            layout.dir_entries,
+            layout.dir_start_sector,
+            layout.difat_sector_ids,
+            layout.difat,
+            layout.fat,
+        );
+        self.free_mini_sectors = FreeList::new(is_free(&layout.minifat));
+        self.mini_chains.clear();
+        self.dirty_minifat_sectors.clear();
+        self.minifat = layout.minifat;
+        self.minifat_start_sector = layout.minifat_start_sector;
+    }
+
+    /// Discards all changes made since the current transaction began,
+    /// restoring all allocation tables and directory entries from that time.
+    fn revert(&mut self) {
+        if let Some((minifat, minifat_start_sector)) = self.transaction.take()
+        {
+            self.free_mini_sectors = FreeList::new(is_free(&minifat));
+            self.mini_chains.clear();
+            self.dirty_minifat_sectors.clear();
+            self.minifat = minifat;
+            self.minifat_start_sector = minifat_start_sector;
+        }
+        self.directory.revert_transaction();
+    }
+
+    fn validate(&mut self, validator: &mut Validator) -> io::Result<()> {
         let root_entry = self.directory.root_dir_entry();
         let root_stream_mini_sectors =
             root_entry.stream_len / (consts::MINI_SECTOR_LEN as u64);
         if root_stream_mini_sectors < (self.minifat.len() as u64) {
-            if validation.is_strict() {
-                malformed!(
-                "MiniFAT has {} entries, but root stream has only {} mini \
-                 sectors",
-                self.minifat.len(),
-                root_stream_mini_sectors
-            );
-            } else {
-                self.minifat.truncate(root_stream_mini_sectors as usize);
-            }
+            validator.tolerate(
+                validator.policy().allow_long_minifat,
+                malformation!(
+                    "MiniFAT has {} entries, but root stream has only {} mini \
+                     sectors",
+                    self.minifat.len(),
+                    root_stream_mini_sectors
+                ),
+            )?;
+            self.minifat.truncate(root_stream_mini_sectors as usize);
+            self.free_mini_sectors.truncate(self.minifat.len());
         }
-        let mut pointees = FnvHashSet::default();
+        let mut pointees = IdSet::default();
         for (from_mini_sector, &to_mini_sector) in
             self.minifat.iter().enumerate()
         {
             if to_mini_sector <= consts::MAX_REGULAR_SECTOR {
                 if to_mini_sector as usize >= self.minifat.len() {
-                    malformed!(
-                        "MiniFAT has {} entries, but mini sector {} points to \
-                         {}",
+                    validator.reject(malformation!(
+                        from_mini_sector as u32;
+                        "MiniFAT has {} entries, but mini sector {} points \
+                         to {}",
                         self.minifat.len(),
                         from_mini_sector,
                         to_mini_sector
-                    );
+                    ))?;
                 }
                 if pointees.contains(&to_mini_sector) {
-                    malformed!(
+                    validator.reject(malformation!(
+                        to_mini_sector;
                         "mini sector {} pointed to twice",
                         to_mini_sector
-                    );
+                    ))?;
                 }
                 pointees.insert(to_mini_sector);
             }
         }
+        validator.finish_stage()?;
         Ok(())
     }
 }
 
+impl<F: SetLen> MiniAllocator<F> {
+    pub fn set_num_sectors(&mut self, num_sectors: u32) -> io::Result<()> {
+        self.directory.set_num_sectors(num_sectors)
+    }
+}
+
+impl<F: Write + Seek + SetLen> MiniAllocator<F> {
+    /// Truncates the underlying file to remove any free sectors at the end.
+    pub fn shrink_to_fit(&mut self) -> io::Result<()> {
+        self.directory.shrink_to_fit()
+    }
+}
+
+impl<F: ReadAt> MiniAllocator<F> {
+    pub fn read_at_sector(
+        &self,
+        sector_id: u32,
+        offset_within_sector: u64,
+        buf: &mut [u8],
+    ) -> io::Result<usize> {
+        self.directory.read_at_sector(sector_id, offset_within_sector, buf)
+    }
+}
+
 impl<F: Seek> MiniAllocator<F> {
+    pub fn seek_within_header(
+        &mut self,
+        offset_within_header: u64,
+    ) -> io::Result<Sector<F>> {
+        self.directory.seek_within_header(offset_within_header)
+    }
+
+    pub fn seek_to_sector(&mut self, sector_id: u32) -> io::Result<Sector<F>> {
+        self.directory.seek_to_sector(sector_id)
+    }
+
+    pub fn seek_within_sector(
+        &mut self,
+        sector_id: u32,
+        offset_within_sector: u64,
+    ) -> io::Result<Sector<F>> {
+        self.directory.seek_within_sector(sector_id, offset_within_sector)
+    }
+
     pub fn seek_within_mini_sector(
         &mut self,
         mini_sector: u32,
@@ -187,6 +542,29 @@ impl<F: Write + Seek> MiniAllocator<F> {
         self.directory.remove_dir_entry(parent_id, name)
     }
 
+    /// Moves a directory entry from one parent to another (or to the same
+    /// parent), giving it a new name.
+    pub fn move_dir_entry(
+        &mut self,
+        old_parent_id: u32,
+        old_name: &str,
+        new_parent_id: u32,
+        new_name: &str,
+    ) -> io::Result<u32> {
+        self.directory.move_dir_entry(
+            old_parent_id,
+            old_name,
+            new_parent_id,
+            new_name,
+        )
+    }
+
+    /// Rebuilds every sibling tree in the directory as a balanced red-black
+    /// tree.
+    pub fn rebalance_all(&mut self) -> io::Result<()> {
+        self.directory.rebalance_all()
+    }
+
     /// Calls the given function with a mutable reference to the specified
     /// directory entry, then writes the updated directory entry to the
     /// underlying file once the function returns.
@@ -204,7 +582,9 @@ impl<F: Write + Seek> MiniAllocator<F> {
     /// Allocates a new mini chain with one sector, and returns the starting
     /// sector number.
     pub fn begin_mini_chain(&mut self) -> io::Result<u32> {
-        self.allocate_mini_sector(consts::END_OF_CHAIN)
+        let mini_sector = self.allocate_mini_sector(consts::END_OF_CHAIN)?;
+        self.mini_chains.insert(mini_sector, vec![mini_sector]);
+        Ok(mini_sector)
     }
 
     /// Given the starting mini sector (or any internal mini sector) of a mini
@@ -215,17 +595,31 @@ impl<F: Write + Seek> MiniAllocator<F> {
         start_mini_sector: u32,
     ) -> io::Result<u32> {
         debug_assert_ne!(start_mini_sector, consts::END_OF_CHAIN);
-        let mut last_mini_sector = start_mini_sector;
-        loop {
-            let next = self.minifat[last_mini_sector as usize];
-            if next == consts::END_OF_CHAIN {
-                break;
+        let last_mini_sector = match self.mini_chains.get(start_mini_sector) {
+            Some(sector_ids) => *sector_ids.last().unwrap(),
+            None => {
+                let mut last_mini_sector = start_mini_sector;
+                loop {
+                    let next = self.minifat[last_mini_sector as usize];
+                    if next == consts::END_OF_CHAIN {
+                        break;
+                    }
+                    last_mini_sector = next;
+                }
+                last_mini_sector
             }
-            last_mini_sector = next;
-        }
+        };
         let new_mini_sector =
             self.allocate_mini_sector(consts::END_OF_CHAIN)?;
         self.set_minifat(last_mini_sector, new_mini_sector)?;
+        if !self.mini_chains.push(start_mini_sector, new_mini_sector) {
+            // We were given an internal mini sector of the chain (or the
+            // chain was evicted from the cache), so drop whichever cached
+            // chain (if any) we just extended.
+            self.mini_chains.retain(|sector_ids| {
+                sector_ids.last() != Some(&last_mini_sector)
+            });
+        }
         Ok(new_mini_sector)
     }
 
@@ -233,12 +627,9 @@ impl<F: Write + Seek> MiniAllocator<F> {
     /// returns the new mini sector number.
     fn allocate_mini_sector(&mut self, value: u32) -> io::Result<u32> {
         // If there's an existing free mini sector, use that.
-        for mini_sector in 0..self.minifat.len() {
-            if self.minifat[mini_sector] == consts::FREE_SECTOR {
-                let mini_sector = mini_sector as u32;
-                self.set_minifat(mini_sector, value)?;
-                return Ok(mini_sector);
-            }
+        if let Some(mini_sector) = self.free_mini_sectors.first() {
+            self.set_minifat(mini_sector, value)?;
+            return Ok(mini_sector);
         }
         // Otherwise, we need a new mini sector; if there's not room in the
         // MiniFAT to add it, then first we need to allocate a new MiniFAT
@@ -248,9 +639,8 @@ impl<F: Write + Seek> MiniAllocator<F> {
             debug_assert!(self.minifat.is_empty());
             self.minifat_start_sector =
                 self.directory.begin_chain(SectorInit::Fat)?;
-            let mut header = self.directory.seek_within_header(60)?;
-            header.write_u32::<LittleEndian>(self.minifat_start_sector)?;
-            header.write_u32::<LittleEndian>(1)?;
+            let minifat_fields = [self.minifat_start_sector, 1];
+            self.directory.write_header_fields(60, &minifat_fields)?;
         } else if self.minifat.len() % minifat_entries_per_sector == 0 {
             let start = self.minifat_start_sector;
             self.directory.extend_chain(start, SectorInit::Fat)?;
@@ -258,8 +648,7 @@ impl<F: Write + Seek> MiniAllocator<F> {
                 .directory
                 .open_chain(start, SectorInit::Fat)?
                 .num_sectors() as u32;
-            let mut header = self.directory.seek_within_header(64)?;
-            header.write_u32::<LittleEndian>(num_minifat_sectors)?;
+            self.directory.write_header_fields(64, &[num_minifat_sectors])?;
         }
         // Add a new mini sector to the end of the mini stream and return it.
         let new_mini_sector = self.minifat.len() as u32;
@@ -283,7 +672,7 @@ impl<F: Write + Seek> MiniAllocator<F> {
                 debug_assert_eq!(mini_stream_len, 0);
                 self.directory.begin_chain(SectorInit::Zero)?
             } else {
-                if mini_stream_len % sector_len as u64 == 0 {
+                if mini_stream_len % (sector_len as u64) == 0 {
                     self.directory.extend_chain(
                         mini_stream_start_sector,
                         SectorInit::Zero,
@@ -307,13 +696,60 @@ impl<F: Write + Seek> MiniAllocator<F> {
         while self.minifat.last() == Some(&consts::FREE_SECTOR) {
             mini_stream_len -= consts::MINI_SECTOR_LEN as u64;
             self.minifat.pop();
-            // TODO: Truncate MiniFAT if last MiniFAT sector is now all free.
         }
+        self.free_mini_sectors.truncate(self.minifat.len());
 
         if mini_stream_len != self.directory.root_dir_entry().stream_len {
             self.directory.with_root_dir_entry_mut(|dir_entry| {
                 dir_entry.stream_len = mini_stream_len;
             })?;
+            self.truncate_minifat()?;
+        }
+        Ok(())
+    }
+
+    /// Releases any sectors at the end of the MiniFAT chain and of the mini
+    /// stream that are no longer needed, updating the header and root
+    /// directory entry accordingly.
+    fn truncate_minifat(&mut self) -> io::Result<()> {
+        let sector_len = self.directory.sector_len();
+        let minifat_entries_per_sector = sector_len / size_of::<u32>();
+        let start = self.minifat_start_sector;
+        if start != consts::END_OF_CHAIN {
+            let num_minifat_sectors =
+                (self.minifat.len() + minifat_entries_per_sector - 1)
+                    / minifat_entries_per_sector;
+            let old_num_minifat_sectors = self
+                .directory
+                .open_chain(start, SectorInit::Fat)?
+                .num_sectors();
+            if num_minifat_sectors == 0 {
+                self.directory.free_chain(start)?;
+                self.minifat_start_sector = consts::END_OF_CHAIN;
+                let minifat_fields = [consts::END_OF_CHAIN, 0];
+                self.directory.write_header_fields(60, &minifat_fields)?;
+            } else if num_minifat_sectors < old_num_minifat_sectors {
+                self.directory.truncate_chain(start, num_minifat_sectors)?;
+                self.directory
+                    .write_header_fields(64, &[num_minifat_sectors as u32])?;
+            }
+        }
+
+        let mini_stream_start_sector =
+            self.directory.root_dir_entry().start_sector;
+        if mini_stream_start_sector != consts::END_OF_CHAIN {
+            let mini_stream_len = self.directory.root_dir_entry().stream_len;
+            let num_sectors = ((mini_stream_len + (sector_len as u64) - 1)
+                / (sector_len as u64)) as usize;
+            if num_sectors == 0 {
+                self.directory.free_chain(mini_stream_start_sector)?;
+                self.directory.with_root_dir_entry_mut(|dir_entry| {
+                    dir_entry.start_sector = consts::END_OF_CHAIN;
+                })?;
+            } else {
+                self.directory
+                    .truncate_chain(mini_stream_start_sector, num_sectors)?;
+            }
         }
         Ok(())
     }
@@ -323,6 +759,7 @@ impl<F: Write + Seek> MiniAllocator<F> {
         &mut self,
         start_mini_sector: u32,
     ) -> io::Result<()> {
+        self.mini_chains.remove(start_mini_sector);
         let mut mini_sector = start_mini_sector;
         while mini_sector != consts::END_OF_CHAIN {
             let next = self.minifat[mini_sector as usize];
@@ -332,15 +769,29 @@ impl<F: Write + Seek> MiniAllocator<F> {
         Ok(())
     }
 
-    /// Sets the given mini sector to point to `END_OF_CHAIN`, and deallocates
-    /// all subsequent mini sectors in the chain.
-    pub fn free_mini_chain_after(
+    /// Truncates the mini chain starting at the given mini sector so that it
+    /// has only `num_sectors` mini sectors (which must be nonzero), and
+    /// deallocates all subsequent mini sectors in the chain.
+    pub fn truncate_mini_chain(
         &mut self,
-        mini_sector: u32,
+        start_mini_sector: u32,
+        num_sectors: usize,
     ) -> io::Result<()> {
-        let next = self.minifat[mini_sector as usize];
-        self.set_minifat(mini_sector, consts::END_OF_CHAIN)?;
-        self.free_mini_chain(next)?;
+        debug_assert!(num_sectors > 0);
+        self.load_mini_chain(start_mini_sector)?;
+        let freed_mini_sectors = self
+            .mini_chains
+            .split_off(start_mini_sector, num_sectors)
+            .unwrap();
+        if freed_mini_sectors.is_empty() {
+            return Ok(());
+        }
+        let last_mini_sector =
+            self.cached_mini_chain(start_mini_sector)[num_sectors - 1];
+        self.set_minifat(last_mini_sector, consts::END_OF_CHAIN)?;
+        for mini_sector in freed_mini_sectors {
+            self.free_mini_sector(mini_sector)?;
+        }
         Ok(())
     }
 
@@ -348,23 +799,222 @@ impl<F: Write + Seek> MiniAllocator<F> {
     /// underlying file.  The `index` must be <= `self.minifat.len()`.
     fn set_minifat(&mut self, index: u32, value: u32) -> io::Result<()> {
         debug_assert!(index as usize <= self.minifat.len());
-        let mut chain = self
-            .directory
-            .open_chain(self.minifat_start_sector, SectorInit::Fat)?;
-        let offset = (index as u64) * size_of::<u32>() as u64;
-        debug_assert!(chain.len() >= offset + size_of::<u32>() as u64);
-        chain.seek(SeekFrom::Start(offset))?;
-        chain.write_u32::<LittleEndian>(value)?;
+        if self.directory.defers_writes() {
+            let minifat_entries_per_sector =
+                self.directory.sector_len() / size_of::<u32>();
+            self.dirty_minifat_sectors
+                .insert(index as usize / minifat_entries_per_sector);
+        } else {
+            let mut chain = self
+                .directory
+                .open_chain(self.minifat_start_sector, SectorInit::Fat)?;
+            let offset = (index as u64) * size_of::<u32>() as u64;
+            debug_assert!(chain.len() >= offset + size_of::<u32>() as u64);
+            chain.seek(SeekFrom::Start(offset))?;
+            chain.write_u32::<LittleEndian>(value)?;
+        }
         if (index as usize) == self.minifat.len() {
             self.minifat.push(value);
         } else {
             self.minifat[index as usize] = value;
         }
+        self.free_mini_sectors.set_free(index, value == consts::FREE_SECTOR);
+        Ok(())
+    }
+
+    /// Writes each dirty MiniFAT sector to the underlying file.
+    fn write_dirty_minifat_sectors(&mut self) -> io::Result<()> {
+        let sector_len = self.directory.sector_len();
+        let minifat_entries_per_sector = sector_len / size_of::<u32>();
+        let mut buffer = Vec::with_capacity(sector_len);
+        for index in mem::take(&mut self.dirty_minifat_sectors) {
+            // MiniFAT sectors that have since been removed are skipped.
+            let start = index * minifat_entries_per_sector;
+            if start >= self.minifat.len() {
+                continue;
+            }
+            buffer.clear();
+            for entry_index in start..(start + minifat_entries_per_sector) {
+                let entry = self
+                    .minifat
+                    .get(entry_index)
+                    .copied()
+                    .unwrap_or(consts::FREE_SECTOR);
+                buffer.write_u32::<LittleEndian>(entry)?;
+            }
+            let mut chain = self
+                .directory
+                .open_chain(self.minifat_start_sector, SectorInit::Fat)?;
+            chain.seek(SeekFrom::Start((index * sector_len) as u64))?;
+            chain.write_all(&buffer)?;
+            // During a transaction, writing may have moved the first sector.
+            self.minifat_start_sector = chain.start_sector_id();
+        }
         Ok(())
     }
 
-    /// Flushes all changes to the underlying file.
+    /// Prepares to write to the given mini sector: during a transaction, if
+    /// the regular sector holding it is still part of the last committed
+    /// state, that sector is first replaced in the mini stream with a copy.
+    pub fn copy_mini_sector_on_write(
+        &mut self,
+        mini_sector: u32,
+    ) -> io::Result<()> {
+        let mini_sectors_per_sector =
+            self.directory.sector_len() / consts::MINI_SECTOR_LEN;
+        let index = mini_sector as usize / mini_sectors_per_sector;
+        let start_sector = self.directory.root_dir_entry().start_sector;
+        let sector_id = self.directory.copy_on_write(start_sector, index)?;
+        if index == 0 && sector_id != start_sector {
+            self.directory.with_root_dir_entry_mut(|dir_entry| {
+                dir_entry.start_sector = sector_id;
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Commits the current transaction, writing all pending changes to the
+    /// allocation tables and directory (without overwriting any sector that
+    /// the last committed state uses), and then switching the file over to
+    /// the new state by rewriting its header.
+    fn commit(&mut self) -> io::Result<()> {
+        debug_assert!(self.in_transaction());
+        self.write_dirty_minifat_sectors()?;
+        let num_minifat_sectors = self
+            .directory
+            .open_chain(self.minifat_start_sector, SectorInit::Fat)?
+            .num_sectors() as u32;
+        self.directory.commit_transaction(
+            self.minifat_start_sector,
+            num_minifat_sectors,
+        )?;
+        self.transaction = None;
+        Ok(())
+    }
+}
+
+impl<F: Read + Write + Seek> MiniAllocator<F> {
+    /// Begins a transaction, taking a snapshot of all allocation tables and
+    /// directory entries.  Until it is committed or reverted, no sector that
+    /// is currently allocated will be written to, and the header, allocation
+    /// tables, and directory will not be updated in the file.
+    fn begin(&mut self) -> io::Result<()> {
+        self.write_dirty_minifat_sectors()?;
+        self.directory.begin_transaction()?;
+        self.transaction =
+            Some((self.minifat.clone(), self.minifat_start_sector));
+        Ok(())
+    }
+
+    /// In journaling mode, begins a new implicit transaction; otherwise,
+    /// does nothing.
+    fn resume_journaling(&mut self) -> io::Result<()> {
+        if self.is_journaling() {
+            self.begin()?;
+            self.implicit_transaction = true;
+        }
+        Ok(())
+    }
+
+    /// Begins a transaction that lasts until it is explicitly committed or
+    /// reverted.  In journaling mode, the changes made since the last flush
+    /// are committed first.
+    pub fn begin_transaction(&mut self) -> io::Result<()> {
+        if self.in_transaction() {
+            if !self.implicit_transaction {
+                invalid_input!("A transaction is already in progress");
+            }
+            self.commit()?;
+        }
+        self.begin()?;
+        self.implicit_transaction = false;
+        Ok(())
+    }
+
+    /// Commits the transaction begun by `begin_transaction`.
+    pub fn commit_transaction(&mut self) -> io::Result<()> {
+        debug_assert!(!self.implicit_transaction);
+        self.generation += 1;
+        self.commit()?;
+        self.resume_journaling()
+    }
+
+    /// Discards all changes made since `begin_transaction` was called.
+    pub fn revert_transaction(&mut self) -> io::Result<()> {
+        self.generation += 1;
+        self.revert();
+        self.resume_journaling()
+    }
+
+    /// Switches to journaling mode, in which the compound file is always
+    /// modified within a transaction, and that transaction is committed (and
+    /// a new one begun) each time the compound file is flushed.  Any pending
+    /// changes are written out first.
+    ///
+    /// Until journaling is finished, the file's header carries a transaction
+    /// signature saying so, so that opening the file without its journal
+    /// (which couldn't complete an interrupted commit) fails instead.
+    pub fn enable_journal(&mut self, mut journal: Journal) -> io::Result<()> {
+        if self.in_transaction() {
+            invalid_input!(
+                "Cannot enable journaling while a transaction is in progress"
+            );
+        }
+        self.flush()?;
+        journal.clear()?;
+        self.directory.set_journal(journal);
+        self.directory
+            .write_header_fields(52, &[consts::JOURNALING_SIGNATURE])?;
+        self.write_back_flush = Some(MiniAllocator::finish_journaling);
+        self.resume_journaling()
+    }
+
+    /// In journaling mode, commits all changes made since the last flush,
+    /// and then clears the header's journaling signature, since the file no
+    /// longer depends on its journal.  This is called when the compound file
+    /// is dropped.
+    fn finish_journaling(&mut self) -> io::Result<()> {
+        if self.in_transaction() {
+            if !self.implicit_transaction {
+                invalid_input!("A transaction is still in progress");
+            }
+            self.commit()?;
+            self.implicit_transaction = false;
+        }
+        self.directory.write_header_fields(52, &[0])?;
+        self.directory.flush()
+    }
+
+    /// Changes when FAT, MiniFAT, and directory updates are written to the
+    /// underlying file.  Any pending changes are written out when switching
+    /// to write-through mode (unless a transaction is in progress).
+    pub fn set_write_mode(&mut self, write_mode: WriteMode) -> io::Result<()> {
+        if !write_mode.is_write_back() && !self.in_transaction() {
+            self.write_dirty_minifat_sectors()?;
+        }
+        if !self.is_journaling() {
+            self.write_back_flush = if write_mode.is_write_back() {
+                Some(MiniAllocator::flush)
+            } else {
+                None
+            };
+        }
+        self.directory.set_write_mode(write_mode)
+    }
+
+    /// Flushes all changes to the underlying file.  In journaling mode
+    /// (outside of a transaction begun by `begin_transaction`), this commits
+    /// all changes made since the last flush.  Otherwise, during a
+    /// transaction, changes to the allocation tables and directory wait until
+    /// the transaction is committed.
     pub fn flush(&mut self) -> io::Result<()> {
+        if self.in_transaction() && self.implicit_transaction {
+            self.commit()?;
+            return self.resume_journaling();
+        }
+        if !self.in_transaction() {
+            self.write_dirty_minifat_sectors()?;
+        }
         self.directory.flush()
     }
 }
@@ -377,7 +1027,7 @@ mod tests {
 
     use crate::internal::{
         consts, Allocator, DirEntry, Directory, ObjType, Sectors, Timestamp,
-        Validation, Version,
+        ValidationPolicy, Version,
     };
 
     use super::MiniAllocator;
@@ -391,7 +1041,7 @@ mod tests {
         minifat: Vec<u32>,
         root_stream_len: u64,
     ) -> MiniAllocator<Cursor<Vec<u8>>> {
-        let validation = Validation::Strict;
+        let policy = ValidationPolicy::strict();
         let version = Version::V3;
         let num_sectors = 4; // FAT, Directory, MiniFAT, and mini chain
         let data_len = (1 + num_sectors) * version.sector_len();
@@ -400,7 +1050,8 @@ mod tests {
         let mut fat = vec![consts::END_OF_CHAIN; num_sectors];
         fat[0] = consts::FAT_SECTOR;
         let allocator =
-            Allocator::new(sectors, vec![], vec![0], fat, validation).unwrap();
+            Allocator::new(sectors, vec![], vec![0], fat, &mut policy.into())
+                .unwrap();
         let mut root_entry = DirEntry::empty_root_entry();
         root_entry.child = 1;
         root_entry.start_sector = 3;
@@ -411,8 +1062,8 @@ mod tests {
         stream_entry.stream_len = root_entry.stream_len;
         let entries = vec![root_entry, stream_entry];
         let directory =
-            Directory::new(allocator, entries, 1, validation).unwrap();
-        MiniAllocator::new(directory, minifat, 2, validation).unwrap()
+            Directory::new(allocator, entries, 1, &mut policy.into()).unwrap();
+        MiniAllocator::new(directory, minifat, 2, &mut policy.into()).unwrap()
     }
 
     #[test]
@@ -444,6 +1095,73 @@ mod tests {
         let minifat = vec![1, 2, 1];
         make_minialloc(minifat);
     }
+
+    #[test]
+    fn free_list_tracks_minifat() {
+        let mut minialloc = make_minialloc(vec![consts::END_OF_CHAIN]);
+        let mut chain = vec![0];
+        for _ in 0..4 {
+            chain.push(minialloc.extend_mini_chain(0).unwrap());
+            minialloc.verify_free_mini_sectors().unwrap();
+        }
+        minialloc.truncate_mini_chain(0, 2).unwrap();
+        minialloc.verify_free_mini_sectors().unwrap();
+        // Freed mini sectors are reused, lowest first.
+        assert_eq!(minialloc.extend_mini_chain(0).unwrap(), chain[2]);
+        minialloc.verify_free_mini_sectors().unwrap();
+        minialloc.free_mini_chain(0).unwrap();
+        minialloc.verify_free_mini_sectors().unwrap();
+    }
+
+    /// Walks the MiniFAT directly (bypassing the mini chain cache) to list
+    /// the mini sectors in a mini chain.
+    fn walk_minifat(
+        minialloc: &MiniAllocator<Cursor<Vec<u8>>>,
+        start: u32,
+    ) -> Vec<u32> {
+        let mut sector_ids = Vec::new();
+        let mut sector_id = start;
+        while sector_id != consts::END_OF_CHAIN {
+            sector_ids.push(sector_id);
+            sector_id = minialloc.minifat[sector_id as usize];
+        }
+        sector_ids
+    }
+
+    #[test]
+    fn mini_chain_cache_tracks_minifat() {
+        let mut minialloc = make_minialloc(vec![consts::END_OF_CHAIN]);
+        let start1 = 0;
+        let start2 = minialloc.begin_mini_chain().unwrap();
+        for _ in 0..3 {
+            minialloc.extend_mini_chain(start1).unwrap();
+            minialloc.extend_mini_chain(start2).unwrap();
+        }
+        for &start in &[start1, start2] {
+            let chain = minialloc.open_mini_chain(start).unwrap();
+            assert_eq!(chain.num_sectors(), 4);
+            let sector_ids = chain.sector_ids().to_vec();
+            assert_eq!(sector_ids, walk_minifat(&minialloc, start));
+        }
+
+        // Truncating or freeing a mini chain should update the cache.
+        minialloc.truncate_mini_chain(start1, 2).unwrap();
+        minialloc.free_mini_chain(start2).unwrap();
+        assert_eq!(
+            minialloc.cached_mini_chain(start1),
+            &walk_minifat(&minialloc, start1)[..]
+        );
+        assert_eq!(minialloc.cached_mini_chain(start1).len(), 2);
+
+        // A new mini chain that reuses the freed chain's start sector should
+        // not see stale cached data.
+        let start3 = minialloc.begin_mini_chain().unwrap();
+        minialloc.extend_mini_chain(start3).unwrap();
+        let chain = minialloc.open_mini_chain(start3).unwrap();
+        let sector_ids = chain.sector_ids().to_vec();
+        assert_eq!(sector_ids, walk_minifat(&minialloc, start3));
+        assert_eq!(sector_ids.len(), 2);
+    }
 }
 
 //===========================================================================//
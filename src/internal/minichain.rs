@@ -1,11 +1,12 @@
-use crate::internal::{consts, MiniAllocator};
-use std::io::{self, Read, Seek, SeekFrom, Write};
+use crate::internal::{consts, MiniAllocator, SectorInit};
+use crate::io::{self, Read, Seek, SeekFrom, Write};
+use alloc::vec::Vec;
 
 //===========================================================================//
 
 pub struct MiniChain<'a, F: 'a> {
     minialloc: &'a mut MiniAllocator<F>,
-    sector_ids: Vec<u32>,
+    start_sector_id: u32,
     offset_from_start: u64,
 }
 
@@ -14,29 +15,36 @@ impl<'a, F> MiniChain<'a, F> {
         minialloc: &'a mut MiniAllocator<F>,
         start_sector_id: u32,
     ) -> io::Result<MiniChain<'a, F>> {
-        let mut sector_ids = Vec::<u32>::new();
-        let mut current_sector_id = start_sector_id;
-        let first_sector_id = start_sector_id;
-        while current_sector_id != consts::END_OF_CHAIN {
-            sector_ids.push(current_sector_id);
-            current_sector_id =
-                minialloc.next_mini_sector(current_sector_id)?;
-            if current_sector_id == first_sector_id {
-                invalid_data!(
-                    "Minichain contained duplicate sector id {}",
-                    current_sector_id
-                );
-            }
-        }
-        Ok(MiniChain { minialloc, sector_ids, offset_from_start: 0 })
+        minialloc.load_mini_chain(start_sector_id)?;
+        Ok(MiniChain { minialloc, start_sector_id, offset_from_start: 0 })
     }
 
     pub fn start_sector_id(&self) -> u32 {
-        self.sector_ids.first().copied().unwrap_or(consts::END_OF_CHAIN)
+        self.start_sector_id
+    }
+
+    pub fn sector_ids(&self) -> &[u32] {
+        self.minialloc.cached_mini_chain(self.start_sector_id)
+    }
+
+    pub fn num_sectors(&self) -> usize {
+        self.sector_ids().len()
     }
 
     pub fn len(&self) -> u64 {
-        (consts::MINI_SECTOR_LEN as u64) * (self.sector_ids.len() as u64)
+        (consts::MINI_SECTOR_LEN as u64) * (self.num_sectors() as u64)
+    }
+
+    /// Returns the offset within the underlying file of the start of each
+    /// mini sector in the chain.
+    pub fn sector_offsets(&mut self) -> io::Result<Vec<u64>> {
+        let sector_ids = self.sector_ids().to_vec();
+        let mini_stream_start_sector =
+            self.minialloc.root_dir_entry().start_sector;
+        let mini_stream = self
+            .minialloc
+            .open_chain(mini_stream_start_sector, SectorInit::Fat)?;
+        mini_stream.subsector_offsets(&sector_ids, consts::MINI_SECTOR_LEN)
     }
 }
 
@@ -48,31 +56,39 @@ impl<'a, F: Read + Write + Seek> MiniChain<'a, F> {
         let sector_len = consts::MINI_SECTOR_LEN as u64;
         let new_num_sectors =
             ((sector_len + new_len - 1) / sector_len) as usize;
+        let old_num_sectors = self.num_sectors();
         if new_num_sectors == 0 {
-            if let Some(&start_sector) = self.sector_ids.first() {
-                self.minialloc.free_mini_chain(start_sector)?;
+            if self.start_sector_id != consts::END_OF_CHAIN {
+                self.minialloc.free_mini_chain(self.start_sector_id)?;
+                self.start_sector_id = consts::END_OF_CHAIN;
             }
-        } else if new_num_sectors <= self.sector_ids.len() {
-            if new_num_sectors < self.sector_ids.len() {
-                self.minialloc.free_mini_chain_after(
-                    self.sector_ids[new_num_sectors - 1],
+        } else if new_num_sectors <= old_num_sectors {
+            if new_num_sectors < old_num_sectors {
+                self.minialloc.truncate_mini_chain(
+                    self.start_sector_id,
+                    new_num_sectors,
                 )?;
             }
             // TODO: zero remainder of final sector
         } else {
-            for _ in self.sector_ids.len()..new_num_sectors {
-                let new_sector_id =
-                    if let Some(&last_sector_id) = self.sector_ids.last() {
-                        self.minialloc.extend_mini_chain(last_sector_id)?
-                    } else {
-                        self.minialloc.begin_mini_chain()?
-                    };
-                self.sector_ids.push(new_sector_id);
+            for _ in old_num_sectors..new_num_sectors {
+                self.push_sector()?;
             }
         }
         Ok(())
     }
 
+    /// Adds a new mini sector to the end of the chain (starting the chain if
+    /// it was empty).
+    fn push_sector(&mut self) -> io::Result<u32> {
+        if self.start_sector_id == consts::END_OF_CHAIN {
+            self.start_sector_id = self.minialloc.begin_mini_chain()?;
+            Ok(self.start_sector_id)
+        } else {
+            self.minialloc.extend_mini_chain(self.start_sector_id)
+        }
+    }
+
     pub fn free(self) -> io::Result<()> {
         self.minialloc.free_mini_chain(self.start_sector_id())
     }
@@ -110,8 +126,7 @@ impl<'a, F: Read + Seek> Read for MiniChain<'a, F> {
         let sector_len = consts::MINI_SECTOR_LEN as u64;
         let current_sector_index =
             (self.offset_from_start / sector_len) as usize;
-        debug_assert!(current_sector_index < self.sector_ids.len());
-        let current_sector_id = self.sector_ids[current_sector_index];
+        let current_sector_id = self.sector_ids()[current_sector_index];
         let offset_within_sector = self.offset_from_start % sector_len;
         let mut sector = self.minialloc.seek_within_mini_sector(
             current_sector_id,
@@ -132,21 +147,15 @@ impl<'a, F: Read + Write + Seek> Write for MiniChain<'a, F> {
         let mut total_len = self.len();
         let sector_len = consts::MINI_SECTOR_LEN as u64;
         if self.offset_from_start == total_len {
-            let new_sector_id =
-                if let Some(&last_sector_id) = self.sector_ids.last() {
-                    self.minialloc.extend_mini_chain(last_sector_id)?
-                } else {
-                    self.minialloc.begin_mini_chain()?
-                };
-            self.sector_ids.push(new_sector_id);
+            self.push_sector()?;
             total_len += sector_len;
             debug_assert_eq!(total_len, self.len());
         }
         let current_sector_index =
             (self.offset_from_start / sector_len) as usize;
-        debug_assert!(current_sector_index < self.sector_ids.len());
-        let current_sector_id = self.sector_ids[current_sector_index];
+        let current_sector_id = self.sector_ids()[current_sector_index];
         let offset_within_sector = self.offset_from_start % sector_len;
+        self.minialloc.copy_mini_sector_on_write(current_sector_id)?;
         let mut sector = self.minialloc.seek_within_mini_sector(
             current_sector_id,
             offset_within_sector,
@@ -0,0 +1,227 @@
+use crate::internal::{seek_position, ReadAt, SetLen};
+use memmap2::{Mmap, MmapMut};
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+//===========================================================================//
+
+/// A read-only file accessed through a memory map, for use as the underlying
+/// file of a `CompoundFile` (see [`open_mmap`](fn.open_mmap.html)).  Reads
+/// copy directly out of the map, without a system call per read.
+///
+/// `MmapFile` implements [`ReadAt`](trait.ReadAt.html), so a compound file
+/// opened with `open_mmap` can be read through
+/// [`StreamReader`](struct.StreamReader.html)s.  Cloning an `MmapFile` is
+/// cheap: the clone shares the same map, but has its own cursor.
+///
+/// The file must not be modified (e.g. by another process) while it is
+/// mapped; the contents of the map are undefined if it is.
+#[derive(Clone)]
+pub struct MmapFile {
+    map: Arc<Mmap>,
+    position: u64,
+}
+
+impl MmapFile {
+    /// Maps the existing file at the given path for reading.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<MmapFile> {
+        MmapFile::from_file(&fs::File::open(path)?)
+    }
+
+    /// Maps the given file, which must be open for reading.  The file handle
+    /// may be closed afterwards without affecting the map.
+    pub fn from_file(file: &fs::File) -> io::Result<MmapFile> {
+        // Safety: see the struct documentation above.
+        let map = unsafe { Mmap::map(file)? };
+        Ok(MmapFile { map: Arc::new(map), position: 0 })
+    }
+}
+
+impl Read for MmapFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let num_bytes = self.map[..].read_at(buf, self.position)?;
+        self.position += num_bytes as u64;
+        Ok(num_bytes)
+    }
+}
+
+impl Seek for MmapFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position =
+            seek_position(pos, self.position, self.map.len() as u64)?;
+        Ok(self.position)
+    }
+}
+
+impl ReadAt for MmapFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.map[..].read_at(buf, offset)
+    }
+}
+
+//===========================================================================//
+
+/// A readable and writable file accessed through a memory map, for use as the
+/// underlying file of a `CompoundFile` (see
+/// [`open_rw_mmap`](fn.open_rw_mmap.html)).  Reads and writes copy directly
+/// to and from the map.
+///
+/// When a write extends past the end of the file (as happens whenever the
+/// compound file allocates a new sector), the file and the map are grown
+/// together.  To avoid remapping the file for every new sector, they are
+/// grown by doubling, and the file is truncated back to its real length only
+/// by [`sync_all`](#method.sync_all) or when it is dropped (so that flushing
+/// the compound file, which happens often, never remaps it).
+///
+/// The file must not be modified (e.g. by another process) while it is
+/// mapped; the contents of the map are undefined if it is.
+pub struct MmapFileMut {
+    file: fs::File,
+    /// The map of the whole file, or `None` while the file is being resized
+    /// (some platforms don't allow resizing a mapped file).
+    map: Option<MmapMut>,
+    len: u64,
+    position: u64,
+}
+
+impl MmapFileMut {
+    /// Maps the existing file at the given path for reading and writing.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<MmapFileMut> {
+        let file = fs::OpenOptions::new().read(true).write(true).open(path)?;
+        MmapFileMut::from_file(file)
+    }
+
+    /// Maps the given file, which must be open for reading and writing.
+    pub fn from_file(file: fs::File) -> io::Result<MmapFileMut> {
+        let len = file.metadata()?.len();
+        let mut mmap_file = MmapFileMut { file, map: None, len, position: 0 };
+        mmap_file.remap()?;
+        Ok(mmap_file)
+    }
+
+    /// Truncates the file to its real length, then writes all changes to
+    /// disk and waits for them to reach it.
+    pub fn sync_all(&mut self) -> io::Result<()> {
+        self.trim()?;
+        self.flush()?;
+        self.file.sync_all()
+    }
+
+    /// Shrinks the file (and the map) back to the file's real length, if it
+    /// was grown past it.
+    fn trim(&mut self) -> io::Result<()> {
+        if self.capacity() > self.len {
+            self.set_capacity(self.len)?;
+        }
+        Ok(())
+    }
+
+    fn remap(&mut self) -> io::Result<()> {
+        // Safety: see the struct documentation above.
+        self.map = Some(unsafe { MmapMut::map_mut(&self.file)? });
+        Ok(())
+    }
+
+    fn map(&self) -> &[u8] {
+        match self.map {
+            Some(ref map) => &map[..self.len as usize],
+            None => &[],
+        }
+    }
+
+    fn capacity(&self) -> u64 {
+        self.map.as_ref().map_or(0, |map| map.len() as u64)
+    }
+
+    /// Resizes the file (and the map) to exactly `capacity` bytes.
+    fn set_capacity(&mut self, capacity: u64) -> io::Result<()> {
+        if let Some(map) = self.map.take() {
+            map.flush()?;
+        }
+        self.file.set_len(capacity)?;
+        self.remap()
+    }
+
+    /// Grows the file (and the map), if necessary, so that it can hold at
+    /// least `min_capacity` bytes.
+    fn reserve(&mut self, min_capacity: u64) -> io::Result<()> {
+        let capacity = self.capacity();
+        if min_capacity > capacity {
+            self.set_capacity(min_capacity.max(2 * capacity))?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for MmapFileMut {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let num_bytes = self.map().read_at(buf, self.position)?;
+        self.position += num_bytes as u64;
+        Ok(num_bytes)
+    }
+}
+
+impl Write for MmapFileMut {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let end = self.position + buf.len() as u64;
+        self.reserve(end)?;
+        if let Some(ref mut map) = self.map {
+            map[self.position as usize..end as usize].copy_from_slice(buf);
+        }
+        self.len = self.len.max(end);
+        self.position = end;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.map {
+            Some(ref map) => map.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Seek for MmapFileMut {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = seek_position(pos, self.position, self.len)?;
+        Ok(self.position)
+    }
+}
+
+impl SetLen for MmapFileMut {
+    fn set_len(&mut self, size: u64) -> io::Result<()> {
+        if size < self.len {
+            // Zero the truncated bytes, in case the file is extended again
+            // before the map is shrunk.
+            if let Some(ref mut map) = self.map {
+                map[size as usize..self.len as usize].fill(0);
+            }
+        } else {
+            self.reserve(size)?;
+        }
+        self.len = size;
+        Ok(())
+    }
+}
+
+impl ReadAt for MmapFileMut {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.map().read_at(buf, offset)
+    }
+}
+
+impl Drop for MmapFileMut {
+    fn drop(&mut self) {
+        // Errors can't be reported from here; callers who care should call
+        // `sync_all` before dropping the file.
+        let _ = self.trim();
+        let _ = self.flush();
+    }
+}
+
+//===========================================================================//
@@ -2,35 +2,99 @@
 mod macros;
 
 mod alloc;
+#[cfg(feature = "async")]
+mod asyncfile;
 mod chain;
+mod chaincache;
+mod check;
 mod color;
+mod compact;
 pub mod consts;
 mod directory;
 mod direntry;
 mod entry;
+mod error;
+mod freelist;
 mod header;
+pub mod io;
+mod journal;
 mod minialloc;
 mod minichain;
+#[cfg(feature = "mmap")]
+mod mmap;
 mod objtype;
+mod orphan;
 pub mod path;
+pub mod raw;
+mod readat;
+mod repair;
+mod report;
 mod sector;
+#[cfg(feature = "async")]
+mod sectorcache;
+mod seek;
+mod setlen;
+mod shared;
+mod slack;
 mod stream;
+mod streamreader;
+mod streamslices;
 mod timestamp;
 mod validate;
 mod version;
+mod writemode;
 
 pub use self::alloc::Allocator;
+#[cfg(feature = "async")]
+pub use self::asyncfile::{AsyncCompoundFile, AsyncStream};
 pub use self::chain::Chain;
+pub use self::chaincache::ChainCache;
+pub use self::check::check;
 pub use self::color::Color;
+pub use self::compact::{compact_in_place, compact_into};
 pub use self::directory::Directory;
 pub use self::direntry::DirEntry;
 pub use self::entry::{Entries, EntriesOrder, Entry};
+pub use self::error::Error;
+pub use self::freelist::{is_free, FreeList};
 pub use self::header::Header;
+pub use self::journal::{Journal, JournalFile};
 pub use self::minialloc::MiniAllocator;
 pub use self::minichain::MiniChain;
+#[cfg(feature = "mmap")]
+pub use self::mmap::{MmapFile, MmapFileMut};
 pub use self::objtype::ObjType;
+pub use self::orphan::{
+    orphaned_chains, orphaned_entries, reachable_stream_ids, OrphanedChain,
+    OrphanedEntry,
+};
+pub use self::readat::ReadAt;
+pub use self::repair::repair;
+pub use self::report::{ImportReport, Loss, RepairReport, Report};
 pub use self::sector::{Sector, SectorInit, Sectors};
+#[cfg(feature = "async")]
+pub use self::sectorcache::{NotCached, SectorCache};
+#[cfg(any(feature = "async", feature = "mmap"))]
+pub use self::seek::seek_position;
+pub use self::seek::seek_within;
+pub use self::setlen::SetLen;
+pub use self::shared::{Shared, SharedRef, SharedRefMut, WeakShared};
+pub use self::slack::{slack_regions, SlackKind, SlackReader, SlackRegion};
 pub use self::stream::Stream;
+pub use self::streamreader::StreamReader;
+pub use self::streamslices::StreamSlices;
 pub use self::timestamp::Timestamp;
-pub use self::validate::Validation;
+pub use self::validate::{Severity, ValidationPolicy, Validator, Violation};
 pub use self::version::Version;
+pub use self::writemode::WriteMode;
+
+/// Maps and sets keyed by sector or stream IDs.  These are FNV hash maps when
+/// the standard library is available, and B-trees otherwise.
+#[cfg(feature = "std")]
+pub type IdMap<K, V> = fnv::FnvHashMap<K, V>;
+#[cfg(feature = "std")]
+pub type IdSet<T> = fnv::FnvHashSet<T>;
+#[cfg(not(feature = "std"))]
+pub type IdMap<K, V> = ::alloc::collections::BTreeMap<K, V>;
+#[cfg(not(feature = "std"))]
+pub type IdSet<T> = ::alloc::collections::BTreeSet<T>;
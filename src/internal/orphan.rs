@@ -0,0 +1,235 @@
+use crate::internal::{
+    consts, DirEntry, IdSet, MiniAllocator, ObjType, Timestamp,
+};
+use crate::io;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use uuid::Uuid;
+
+//===========================================================================//
+
+/// A directory entry that is allocated, but isn't in the tree of any storage
+/// reachable from the root, such as one left behind by another CFB
+/// implementation when it removed the object.  See
+/// `CompoundFile::orphaned_entries`.
+#[derive(Clone, Debug)]
+pub struct OrphanedEntry {
+    stream_id: u32,
+    name: String,
+    obj_type: ObjType,
+    clsid: Uuid,
+    state_bits: u32,
+    creation_time: Timestamp,
+    modified_time: Timestamp,
+    start_sector: u32,
+    stream_len: u64,
+}
+
+impl OrphanedEntry {
+    fn new(stream_id: u32, dir_entry: &DirEntry) -> OrphanedEntry {
+        OrphanedEntry {
+            stream_id,
+            name: dir_entry.name.clone(),
+            obj_type: dir_entry.obj_type,
+            clsid: dir_entry.clsid,
+            state_bits: dir_entry.state_bits,
+            creation_time: dir_entry.creation_time,
+            modified_time: dir_entry.modified_time,
+            start_sector: dir_entry.start_sector,
+            stream_len: dir_entry.stream_len,
+        }
+    }
+
+    /// Returns the index of this entry within the directory.
+    pub fn stream_id(&self) -> u32 {
+        self.stream_id
+    }
+
+    /// Returns the name stored in this entry.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns whether this entry is for a stream object.
+    pub fn is_stream(&self) -> bool {
+        self.obj_type == ObjType::Stream
+    }
+
+    /// Returns whether this entry is for a storage object.
+    pub fn is_storage(&self) -> bool {
+        self.obj_type == ObjType::Storage || self.obj_type == ObjType::Root
+    }
+
+    /// Returns the length, in bytes, recorded for the stream.
+    pub fn len(&self) -> u64 {
+        self.stream_len
+    }
+
+    /// Returns true if the recorded stream length is zero.
+    pub fn is_empty(&self) -> bool {
+        self.stream_len == 0
+    }
+
+    /// Returns the first sector (or, for a stream shorter than the mini
+    /// stream cutoff, the first mini sector) of the stream's chain.
+    pub fn start_sector(&self) -> u32 {
+        self.start_sector
+    }
+
+    /// Returns the CLSID stored in this entry.
+    pub fn clsid(&self) -> &Uuid {
+        &self.clsid
+    }
+
+    /// Returns the user-defined bitflags stored in this entry.
+    pub fn state_bits(&self) -> u32 {
+        self.state_bits
+    }
+
+    /// Returns the raw creation timestamp stored in this entry, as a Windows
+    /// FILETIME value.
+    pub fn created_filetime(&self) -> u64 {
+        self.creation_time.value()
+    }
+
+    /// Returns the raw last-modified timestamp stored in this entry, as a
+    /// Windows FILETIME value.
+    pub fn modified_filetime(&self) -> u64 {
+        self.modified_time.value()
+    }
+}
+
+//===========================================================================//
+
+/// A chain of allocated sectors in the FAT that doesn't belong to the DIFAT,
+/// the FAT, the directory, the MiniFAT, the mini stream, or any stream
+/// recorded in the directory (whether reachable or orphaned), such as the
+/// data of a stream whose directory entry was removed without freeing its
+/// chain.  See `CompoundFile::orphaned_chains`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OrphanedChain {
+    sector_ids: Vec<u32>,
+    sector_len: usize,
+}
+
+impl OrphanedChain {
+    /// Returns the first sector of the chain.
+    pub fn start_sector(&self) -> u32 {
+        self.sector_ids[0]
+    }
+
+    /// Returns the IDs of the sectors in the chain, in order.
+    pub fn sector_ids(&self) -> &[u32] {
+        &self.sector_ids
+    }
+
+    /// Returns the total length of the chain's sectors, in bytes.  (The
+    /// length of the stream that the chain once held isn't recorded
+    /// anywhere, so the last sector may end with unused bytes.)
+    pub fn len(&self) -> u64 {
+        self.sector_ids.len() as u64 * self.sector_len as u64
+    }
+
+    /// Always returns false, since every chain has at least one sector.
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+}
+
+//===========================================================================//
+
+/// Returns every allocated directory entry that isn't reachable from the
+/// root, in stream ID order.
+pub fn orphaned_entries<F>(
+    minialloc: &MiniAllocator<F>,
+) -> Vec<OrphanedEntry> {
+    let reachable = reachable_stream_ids(minialloc);
+    (0..minialloc.num_dir_entries())
+        .filter(|stream_id| !reachable.contains(stream_id))
+        .filter_map(|stream_id| {
+            let dir_entry = minialloc.dir_entry(stream_id);
+            if dir_entry.obj_type == ObjType::Unallocated {
+                None
+            } else {
+                Some(OrphanedEntry::new(stream_id, dir_entry))
+            }
+        })
+        .collect()
+}
+
+/// Returns every chain of allocated sectors that nothing refers to, in order
+/// of starting sector.  Chains are followed only while they stay within such
+/// sectors, so a chain that runs into a sector that belongs to something else
+/// (or to another orphaned chain) is cut short there.
+pub fn orphaned_chains<F>(
+    minialloc: &mut MiniAllocator<F>,
+) -> io::Result<Vec<OrphanedChain>> {
+    let mut claimed: IdSet<u32> =
+        minialloc.metadata_sector_ids()?.into_iter().collect();
+    let fat = minialloc.fat();
+    for stream_id in 1..minialloc.num_dir_entries() {
+        let dir_entry = minialloc.dir_entry(stream_id);
+        if dir_entry.obj_type == ObjType::Stream
+            && dir_entry.stream_len >= consts::MINI_STREAM_CUTOFF as u64
+        {
+            let mut sector_id = dir_entry.start_sector;
+            while (sector_id as usize) < fat.len() && claimed.insert(sector_id)
+            {
+                sector_id = fat[sector_id as usize];
+            }
+        }
+    }
+
+    let num_sectors = minialloc.num_sectors() as usize;
+    let is_orphaned = |sector_id: u32| {
+        (sector_id as usize) < num_sectors.min(fat.len())
+            && !claimed.contains(&sector_id)
+            && (fat[sector_id as usize] <= consts::MAX_REGULAR_SECTOR
+                || fat[sector_id as usize] == consts::END_OF_CHAIN)
+    };
+    let orphaned: Vec<u32> =
+        (0..fat.len() as u32).filter(|&id| is_orphaned(id)).collect();
+    // Start with the sectors that no other orphaned sector points to, and
+    // then pick up any loops that remain.
+    let pointed_to: IdSet<u32> =
+        orphaned.iter().map(|&sector_id| fat[sector_id as usize]).collect();
+    let (heads, rest): (Vec<u32>, Vec<u32>) = orphaned
+        .into_iter()
+        .partition(|sector_id| !pointed_to.contains(sector_id));
+    let sector_len = minialloc.sector_len();
+    let mut visited = IdSet::default();
+    let mut chains = Vec::new();
+    for start_sector in heads.into_iter().chain(rest) {
+        let mut sector_ids = vec![];
+        let mut sector_id = start_sector;
+        while is_orphaned(sector_id) && visited.insert(sector_id) {
+            sector_ids.push(sector_id);
+            sector_id = fat[sector_id as usize];
+        }
+        if !sector_ids.is_empty() {
+            chains.push(OrphanedChain { sector_ids, sector_len });
+        }
+    }
+    chains.sort_by_key(OrphanedChain::start_sector);
+    Ok(chains)
+}
+
+/// Returns the IDs of every directory entry in the tree of a storage that is
+/// reachable from the root, including the root itself.
+pub fn reachable_stream_ids<F>(minialloc: &MiniAllocator<F>) -> IdSet<u32> {
+    let mut reachable = IdSet::default();
+    let mut stack = vec![consts::ROOT_STREAM_ID];
+    while let Some(stream_id) = stack.pop() {
+        if stream_id == consts::NO_STREAM || !reachable.insert(stream_id) {
+            continue;
+        }
+        let dir_entry = minialloc.dir_entry(stream_id);
+        stack.push(dir_entry.left_sibling);
+        stack.push(dir_entry.right_sibling);
+        stack.push(dir_entry.child);
+    }
+    reachable
+}
+
+//===========================================================================//
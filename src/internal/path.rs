@@ -1,6 +1,20 @@
-use std::cmp::Ordering;
-use std::io;
-use std::path::{Component, Path, PathBuf};
+use crate::io;
+#[cfg(not(feature = "std"))]
+use alloc::string::String;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+#[cfg(feature = "std")]
+use std::path::Component;
+#[cfg(feature = "std")]
+pub use std::path::{Path, PathBuf};
+
+/// The path of an object within a compound file, as stored in an `Entry`.
+/// Without the `std` feature, this is a string of `/`-separated names.
+#[cfg(not(feature = "std"))]
+pub type Path = str;
+#[cfg(not(feature = "std"))]
+pub type PathBuf = String;
 
 // ========================================================================= //
 
@@ -30,7 +44,8 @@ pub fn validate_name(name: &str) -> io::Result<Vec<u16>> {
     let name_utf16: Vec<u16> =
         name.encode_utf16().take(MAX_NAME_LEN + 1).collect();
     if name_utf16.len() > MAX_NAME_LEN {
-        invalid_input!(
+        corrupted!(
+            Name { name: name.to_string() },
             "Object name cannot be more than {} UTF-16 code units (was {})",
             MAX_NAME_LEN,
             name.encode_utf16().count()
@@ -38,7 +53,11 @@ pub fn validate_name(name: &str) -> io::Result<Vec<u16>> {
     }
     for &chr in &['/', '\\', ':', '!'] {
         if name.contains(chr) {
-            invalid_input!("Object name cannot contain {} character", chr);
+            corrupted!(
+                Name { name: name.to_string() },
+                "Object name cannot contain {} character",
+                chr
+            );
         }
     }
     Ok(name_utf16)
@@ -46,8 +65,73 @@ pub fn validate_name(name: &str) -> io::Result<Vec<u16>> {
 
 // ========================================================================= //
 
+/// A path to a storage or stream object within a compound file.
+///
+/// With the `std` feature (enabled by default), this is implemented for
+/// anything that can be referenced as a `std::path::Path`, such as
+/// `"/foo/bar"`.  Without it, a path is given as a *name chain*: the names of
+/// the storages leading from the root storage down to the object, followed
+/// by the name of the object itself, such as `["foo", "bar"]`.  An empty name
+/// chain refers to the root storage.  Paths returned by `Entry::path` (which
+/// are strings of `/`-separated names in that mode) are accepted as well.
+pub trait ObjectPath {
+    /// Returns the chain of names leading from the root storage to the
+    /// object, or an error if the path is invalid.
+    fn name_chain(&self) -> io::Result<Vec<&str>>;
+}
+
+#[cfg(feature = "std")]
+impl<P: AsRef<Path> + ?Sized> ObjectPath for P {
+    fn name_chain(&self) -> io::Result<Vec<&str>> {
+        name_chain_from_path(self.as_ref())
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl ObjectPath for str {
+    fn name_chain(&self) -> io::Result<Vec<&str>> {
+        Ok(self.split('/').filter(|name| !name.is_empty()).collect())
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl ObjectPath for String {
+    fn name_chain(&self) -> io::Result<Vec<&str>> {
+        self.as_str().name_chain()
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<S: AsRef<str>> ObjectPath for [S] {
+    fn name_chain(&self) -> io::Result<Vec<&str>> {
+        Ok(self.iter().map(AsRef::as_ref).collect())
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<S: AsRef<str>, const N: usize> ObjectPath for [S; N] {
+    fn name_chain(&self) -> io::Result<Vec<&str>> {
+        self[..].name_chain()
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<S: AsRef<str>> ObjectPath for Vec<S> {
+    fn name_chain(&self) -> io::Result<Vec<&str>> {
+        self[..].name_chain()
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<T: ObjectPath + ?Sized> ObjectPath for &T {
+    fn name_chain(&self) -> io::Result<Vec<&str>> {
+        (**self).name_chain()
+    }
+}
+
 /// Given a path within a compound file, turns it into a list of child names
 /// descending from the root.  Returns an error if the name is invalid.
+#[cfg(feature = "std")]
 pub fn name_chain_from_path(path: &Path) -> io::Result<Vec<&str>> {
     let mut names: Vec<&str> = Vec::new();
     for component in path.components() {
@@ -74,8 +158,27 @@ pub fn name_chain_from_path(path: &Path) -> io::Result<Vec<&str>> {
 pub fn path_from_name_chain(names: &[&str]) -> PathBuf {
     let mut path = PathBuf::from("/");
     for name in names {
-        path.push(name);
+        path = join_path(&path, name);
+    }
+    path
+}
+
+/// Returns the path of the object with the given name within the storage at
+/// `parent_path`.
+#[cfg(feature = "std")]
+pub fn join_path(parent_path: &Path, name: &str) -> PathBuf {
+    parent_path.join(name)
+}
+
+/// Returns the path of the object with the given name within the storage at
+/// `parent_path`.
+#[cfg(not(feature = "std"))]
+pub fn join_path(parent_path: &Path, name: &str) -> PathBuf {
+    let mut path = PathBuf::from(parent_path);
+    if !path.ends_with('/') {
+        path.push('/');
     }
+    path.push_str(name);
     path
 }
 
@@ -0,0 +1,316 @@
+//! Read-only access to the low-level structures of a compound file: the
+//! header, the DIFAT, the FAT and MiniFAT, and the directory entries.
+//!
+//! These are intended for tools that analyze the layout of compound files,
+//! and are obtained from an open `CompoundFile` via its `raw_*` methods.
+//! Sector IDs and stream IDs are reported as they are stored, so the
+//! constants in this module are needed to interpret them.
+
+use crate::internal::{consts, DirEntry as InternalDirEntry};
+use crate::io::{self, Read, ReadBytesExt};
+use alloc::string::String;
+use byteorder::LittleEndian;
+use uuid::Uuid;
+
+//===========================================================================//
+
+/// The largest sector ID that refers to an actual sector.
+pub const MAX_REGULAR_SECTOR: u32 = consts::MAX_REGULAR_SECTOR;
+/// The FAT entry for a sector that holds part of the DIFAT.
+pub const DIFAT_SECTOR: u32 = consts::DIFAT_SECTOR;
+/// The FAT entry for a sector that holds part of the FAT.
+pub const FAT_SECTOR: u32 = consts::FAT_SECTOR;
+/// The FAT (or MiniFAT) entry for the last sector of a chain.
+pub const END_OF_CHAIN: u32 = consts::END_OF_CHAIN;
+/// The FAT (or MiniFAT) entry for an unallocated sector.
+pub const FREE_SECTOR: u32 = consts::FREE_SECTOR;
+/// The stream ID of the root storage's directory entry.
+pub const ROOT_STREAM_ID: u32 = consts::ROOT_STREAM_ID;
+/// The sibling or child ID that means there is no such directory entry.
+pub const NO_STREAM: u32 = consts::NO_STREAM;
+/// The object type of an unused directory entry.
+pub const OBJ_TYPE_UNALLOCATED: u8 = consts::OBJ_TYPE_UNALLOCATED;
+/// The object type of a storage's directory entry.
+pub const OBJ_TYPE_STORAGE: u8 = consts::OBJ_TYPE_STORAGE;
+/// The object type of a stream's directory entry.
+pub const OBJ_TYPE_STREAM: u8 = consts::OBJ_TYPE_STREAM;
+/// The object type of the root storage's directory entry.
+pub const OBJ_TYPE_ROOT: u8 = consts::OBJ_TYPE_ROOT;
+/// The color of a red node in a directory entry red-black tree.
+pub const COLOR_RED: u8 = consts::COLOR_RED;
+/// The color of a black node in a directory entry red-black tree.
+pub const COLOR_BLACK: u8 = consts::COLOR_BLACK;
+
+//===========================================================================//
+
+/// The fields of a compound file's header, exactly as stored in the file.
+/// See `CompoundFile::raw_header`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Header {
+    clsid: Uuid,
+    minor_version: u16,
+    major_version: u16,
+    byte_order: u16,
+    sector_shift: u16,
+    mini_sector_shift: u16,
+    num_dir_sectors: u32,
+    num_fat_sectors: u32,
+    first_dir_sector: u32,
+    transaction_signature: u32,
+    mini_stream_cutoff: u32,
+    first_minifat_sector: u32,
+    num_minifat_sectors: u32,
+    first_difat_sector: u32,
+    num_difat_sectors: u32,
+    initial_difat_entries: [u32; consts::NUM_DIFAT_ENTRIES_IN_HEADER],
+}
+
+impl Header {
+    /// Reads the header fields that follow the magic number, without
+    /// validating any of them.
+    pub(crate) fn read_from<R: Read>(reader: &mut R) -> io::Result<Header> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        let clsid = InternalDirEntry::read_clsid(reader)?;
+        let minor_version = reader.read_u16::<LittleEndian>()?;
+        let major_version = reader.read_u16::<LittleEndian>()?;
+        let byte_order = reader.read_u16::<LittleEndian>()?;
+        let sector_shift = reader.read_u16::<LittleEndian>()?;
+        let mini_sector_shift = reader.read_u16::<LittleEndian>()?;
+        let mut reserved = [0u8; 6];
+        reader.read_exact(&mut reserved)?;
+        let num_dir_sectors = reader.read_u32::<LittleEndian>()?;
+        let num_fat_sectors = reader.read_u32::<LittleEndian>()?;
+        let first_dir_sector = reader.read_u32::<LittleEndian>()?;
+        let transaction_signature = reader.read_u32::<LittleEndian>()?;
+        let mini_stream_cutoff = reader.read_u32::<LittleEndian>()?;
+        let first_minifat_sector = reader.read_u32::<LittleEndian>()?;
+        let num_minifat_sectors = reader.read_u32::<LittleEndian>()?;
+        let first_difat_sector = reader.read_u32::<LittleEndian>()?;
+        let num_difat_sectors = reader.read_u32::<LittleEndian>()?;
+        let mut initial_difat_entries =
+            [0u32; consts::NUM_DIFAT_ENTRIES_IN_HEADER];
+        for entry in initial_difat_entries.iter_mut() {
+            *entry = reader.read_u32::<LittleEndian>()?;
+        }
+        Ok(Header {
+            clsid,
+            minor_version,
+            major_version,
+            byte_order,
+            sector_shift,
+            mini_sector_shift,
+            num_dir_sectors,
+            num_fat_sectors,
+            first_dir_sector,
+            transaction_signature,
+            mini_stream_cutoff,
+            first_minifat_sector,
+            num_minifat_sectors,
+            first_difat_sector,
+            num_difat_sectors,
+            initial_difat_entries,
+        })
+    }
+
+    /// Returns the header CLSID, which the spec requires to be all zeros.
+    pub fn clsid(&self) -> &Uuid {
+        &self.clsid
+    }
+
+    /// Returns the minor version number (normally 0x3E).
+    pub fn minor_version(&self) -> u16 {
+        self.minor_version
+    }
+
+    /// Returns the major version number (3 or 4).
+    pub fn major_version(&self) -> u16 {
+        self.major_version
+    }
+
+    /// Returns the byte order mark (always 0xFFFE).
+    pub fn byte_order(&self) -> u16 {
+        self.byte_order
+    }
+
+    /// Returns the base-2 logarithm of the sector length (9 for version 3,
+    /// 12 for version 4).
+    pub fn sector_shift(&self) -> u16 {
+        self.sector_shift
+    }
+
+    /// Returns the base-2 logarithm of the mini sector length (always 6).
+    pub fn mini_sector_shift(&self) -> u16 {
+        self.mini_sector_shift
+    }
+
+    /// Returns the number of directory sectors (always zero for version 3).
+    pub fn num_dir_sectors(&self) -> u32 {
+        self.num_dir_sectors
+    }
+
+    /// Returns the number of FAT sectors.
+    pub fn num_fat_sectors(&self) -> u32 {
+        self.num_fat_sectors
+    }
+
+    /// Returns the first sector of the directory chain.
+    pub fn first_dir_sector(&self) -> u32 {
+        self.first_dir_sector
+    }
+
+    /// Returns the transaction signature number.  This crate writes it as
+    /// zero, except that it marks a file open in journaling mode.
+    pub fn transaction_signature(&self) -> u32 {
+        self.transaction_signature
+    }
+
+    /// Returns the maximum length of a stream stored in the mini stream
+    /// (always 4096).
+    pub fn mini_stream_cutoff(&self) -> u32 {
+        self.mini_stream_cutoff
+    }
+
+    /// Returns the first sector of the MiniFAT chain.
+    pub fn first_minifat_sector(&self) -> u32 {
+        self.first_minifat_sector
+    }
+
+    /// Returns the number of MiniFAT sectors.
+    pub fn num_minifat_sectors(&self) -> u32 {
+        self.num_minifat_sectors
+    }
+
+    /// Returns the first sector of the DIFAT chain.
+    pub fn first_difat_sector(&self) -> u32 {
+        self.first_difat_sector
+    }
+
+    /// Returns the number of DIFAT sectors.
+    pub fn num_difat_sectors(&self) -> u32 {
+        self.num_difat_sectors
+    }
+
+    /// Returns the 109 DIFAT entries stored in the header itself, including
+    /// any unused (`FREE_SECTOR`) ones.
+    pub fn initial_difat_entries(&self) -> &[u32] {
+        &self.initial_difat_entries
+    }
+}
+
+//===========================================================================//
+
+/// A directory entry, with its object type, color, and sibling and child IDs
+/// as numbers rather than as a tree.  See `CompoundFile::raw_dir_entries`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DirEntry {
+    stream_id: u32,
+    name: String,
+    obj_type: u8,
+    color: u8,
+    left_sibling: u32,
+    right_sibling: u32,
+    child: u32,
+    clsid: Uuid,
+    state_bits: u32,
+    created_filetime: u64,
+    modified_filetime: u64,
+    start_sector: u32,
+    stream_len: u64,
+}
+
+impl DirEntry {
+    pub(crate) fn new(
+        stream_id: u32,
+        dir_entry: &InternalDirEntry,
+    ) -> DirEntry {
+        DirEntry {
+            stream_id,
+            name: dir_entry.name.clone(),
+            obj_type: dir_entry.obj_type.as_byte(),
+            color: dir_entry.color.as_byte(),
+            left_sibling: dir_entry.left_sibling,
+            right_sibling: dir_entry.right_sibling,
+            child: dir_entry.child,
+            clsid: dir_entry.clsid,
+            state_bits: dir_entry.state_bits,
+            created_filetime: dir_entry.creation_time.value(),
+            modified_filetime: dir_entry.modified_time.value(),
+            start_sector: dir_entry.start_sector,
+            stream_len: dir_entry.stream_len,
+        }
+    }
+
+    /// Returns the index of this entry within the directory.
+    pub fn stream_id(&self) -> u32 {
+        self.stream_id
+    }
+
+    /// Returns the name stored in this entry.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the object type byte (one of the `OBJ_TYPE_*` constants).
+    pub fn obj_type(&self) -> u8 {
+        self.obj_type
+    }
+
+    /// Returns the color byte (`COLOR_RED` or `COLOR_BLACK`).
+    pub fn color(&self) -> u8 {
+        self.color
+    }
+
+    /// Returns the stream ID of the left sibling, or `NO_STREAM`.
+    pub fn left_sibling(&self) -> u32 {
+        self.left_sibling
+    }
+
+    /// Returns the stream ID of the right sibling, or `NO_STREAM`.
+    pub fn right_sibling(&self) -> u32 {
+        self.right_sibling
+    }
+
+    /// Returns the stream ID of the root of this storage's tree of children,
+    /// or `NO_STREAM`.
+    pub fn child(&self) -> u32 {
+        self.child
+    }
+
+    /// Returns the CLSID stored in this entry.
+    pub fn clsid(&self) -> &Uuid {
+        &self.clsid
+    }
+
+    /// Returns the user-defined bitflags stored in this entry.
+    pub fn state_bits(&self) -> u32 {
+        self.state_bits
+    }
+
+    /// Returns the raw creation timestamp stored in this entry, as a Windows
+    /// FILETIME value.
+    pub fn created_filetime(&self) -> u64 {
+        self.created_filetime
+    }
+
+    /// Returns the raw last-modified timestamp stored in this entry, as a
+    /// Windows FILETIME value.
+    pub fn modified_filetime(&self) -> u64 {
+        self.modified_filetime
+    }
+
+    /// Returns the first sector of the entry's chain.  For the root entry,
+    /// this is the start of the mini stream; for a stream shorter than the
+    /// mini stream cutoff, it's a mini sector ID.
+    pub fn start_sector(&self) -> u32 {
+        self.start_sector
+    }
+
+    /// Returns the stream length stored in this entry.  For the root entry,
+    /// this is the length of the mini stream.
+    pub fn stream_len(&self) -> u64 {
+        self.stream_len
+    }
+}
+
+//===========================================================================//
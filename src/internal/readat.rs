@@ -0,0 +1,154 @@
+use crate::io::{self, Cursor};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::fs;
+
+//===========================================================================//
+
+/// A reader that can read from any offset without a shared cursor, like
+/// POSIX `pread`, such as a
+/// [`File`](https://doc.rust-lang.org/std/fs/struct.File.html) or an
+/// in-memory byte slice.  Because reads only need `&self`, many threads can
+/// read from the same underlying storage at once.  This is needed for
+/// [`StreamReader`](struct.StreamReader.html).
+pub trait ReadAt {
+    /// Reads some bytes starting at `offset` into `buf`, returning the number
+    /// of bytes read (which is zero at or past the end of the storage).  This
+    /// does not change any cursor position of the underlying storage.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+
+    /// Reads exactly enough bytes starting at `offset` to fill `buf`,
+    /// returning an `UnexpectedEof` error if the storage ends first.
+    fn read_exact_at(
+        &self,
+        mut buf: &mut [u8],
+        mut offset: u64,
+    ) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(buf, offset) {
+                Ok(0) => break,
+                Ok(num_bytes) => {
+                    buf = &mut buf[num_bytes..];
+                    offset += num_bytes as u64;
+                }
+                Err(ref error)
+                    if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) => return Err(error),
+            }
+        }
+        if !buf.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "failed to fill whole buffer",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl ReadAt for [u8] {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        if offset >= self.len() as u64 {
+            return Ok(0);
+        }
+        let data = &self[offset as usize..];
+        let num_bytes = data.len().min(buf.len());
+        buf[..num_bytes].copy_from_slice(&data[..num_bytes]);
+        Ok(num_bytes)
+    }
+}
+
+impl ReadAt for Vec<u8> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.as_slice().read_at(buf, offset)
+    }
+}
+
+/// Reads from the wrapped bytes, ignoring the cursor's position.
+impl<T: AsRef<[u8]>> ReadAt for Cursor<T> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.get_ref().as_ref().read_at(buf, offset)
+    }
+}
+
+#[cfg(all(feature = "std", unix))]
+impl ReadAt for fs::File {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::read_at(self, buf, offset)
+    }
+}
+
+// On Windows, this moves the file's cursor, but a read at an explicit offset
+// never depends on it.
+#[cfg(all(feature = "std", windows))]
+impl ReadAt for fs::File {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        std::os::windows::fs::FileExt::seek_read(self, buf, offset)
+    }
+}
+
+impl<T: ReadAt + ?Sized> ReadAt for &T {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        (**self).read_at(buf, offset)
+    }
+}
+
+impl<T: ReadAt + ?Sized> ReadAt for &mut T {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        (**self).read_at(buf, offset)
+    }
+}
+
+impl<T: ReadAt + ?Sized> ReadAt for Box<T> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        (**self).read_at(buf, offset)
+    }
+}
+
+impl<T: ReadAt + ?Sized> ReadAt for Arc<T> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        (**self).read_at(buf, offset)
+    }
+}
+
+//===========================================================================//
+
+#[cfg(test)]
+mod tests {
+    use super::ReadAt;
+    use std::io::{Cursor, Seek, SeekFrom};
+
+    #[test]
+    fn read_from_slice() {
+        let data: &[u8] = &[1, 2, 3, 4, 5];
+        let mut buf = [0u8; 3];
+        assert_eq!(data.read_at(&mut buf, 1).unwrap(), 3);
+        assert_eq!(buf, [2, 3, 4]);
+        assert_eq!(data.read_at(&mut buf, 3).unwrap(), 2);
+        assert_eq!(buf, [4, 5, 4]);
+        assert_eq!(data.read_at(&mut buf, 5).unwrap(), 0);
+        assert_eq!(data.read_at(&mut buf, 100).unwrap(), 0);
+    }
+
+    #[test]
+    fn read_from_cursor_ignores_position() {
+        let mut cursor = Cursor::new(vec![1, 2, 3, 4, 5]);
+        cursor.seek(SeekFrom::Start(4)).unwrap();
+        let mut buf = [0u8; 2];
+        cursor.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, [1, 2]);
+        assert_eq!(cursor.position(), 4);
+    }
+
+    #[test]
+    fn read_exact_past_end() {
+        let data = vec![1, 2, 3];
+        let mut buf = [0u8; 2];
+        let error = data.read_exact_at(&mut buf, 2).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+}
+
+//===========================================================================//
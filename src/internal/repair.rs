@@ -0,0 +1,566 @@
+use crate::internal::path::{path_from_name_chain, PathBuf};
+use crate::internal::{
+    consts, DirEntry, Error, Header, IdSet, ObjType, RepairReport, Sectors,
+    Stream, Validator, Version,
+};
+use crate::io::{self, Read, Seek, SeekFrom, Write};
+use crate::CompoundFile;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, LittleEndian};
+use core::cmp;
+
+//===========================================================================//
+
+/// Salvages everything readable from a damaged compound file, and writes it
+/// to `output` as a new compound file that `CompoundFile::open_strict` will
+/// accept.
+///
+/// Rather than relying on the file's own bookkeeping, this follows each
+/// chain of sectors only as far as it remains intact, and recreates every
+/// reachable storage and stream in a fresh file.  As a result, the output has
+/// a correctly ordered and colored directory tree, accurate header counts,
+/// and no dangling chains or leaked sectors.  A stream whose chain is cut
+/// short keeps the data before the break.  The returned report lists every
+/// object (or part of a stream) that couldn't be salvaged, and why.
+///
+/// An error is returned only if reading from `input` or writing to `output`
+/// fails, or if `input` doesn't have a usable CFB header.
+pub fn repair<F, W>(input: F, output: W) -> io::Result<RepairReport>
+where
+    F: Read + Seek,
+    W: Read + Write + Seek,
+{
+    let mut salvager = Salvager::new(input)?;
+    let mut comp =
+        CompoundFile::create_with_version(salvager.version, output)?;
+    let mut report = RepairReport::default();
+    salvager.copy_tree(&mut comp, &mut report)?;
+    comp.flush()?;
+    Ok(report)
+}
+
+//===========================================================================//
+
+/// A lenient reader for a damaged compound file, which reads whatever it
+/// can and records the problems it finds instead of giving up at the first
+/// one.
+struct Salvager<F> {
+    version: Version,
+    sectors: Sectors<F>,
+    fat: Vec<u32>,
+    dir_entries: Vec<Result<DirEntry, Error>>,
+    minifat: Vec<u32>,
+    mini_stream: Vec<u8>,
+}
+
+impl<F: Read + Seek> Salvager<F> {
+    fn new(mut inner: F) -> io::Result<Salvager<F>> {
+        let inner_len = inner.seek(SeekFrom::End(0))?;
+        if inner_len < consts::HEADER_LEN as u64 {
+            corrupted!(
+                Header {},
+                "Invalid CFB file ({} bytes is too small)",
+                inner_len
+            );
+        }
+        inner.seek(SeekFrom::Start(0))?;
+        let mut validator = Validator::exhaustive();
+        let header = Header::read_from(&mut inner, &mut validator)?;
+        let sector_len = header.version.sector_len() as u64;
+        // Anything past the last possible sector can't be part of the file,
+        // so just ignore it.
+        let inner_len = cmp::min(
+            inner_len,
+            (consts::MAX_REGULAR_SECTOR as u64 + 1) * sector_len,
+        );
+        if inner_len < sector_len {
+            corrupted!(
+                Header {},
+                "Invalid CFB file (length of {} < sector length of {})",
+                inner_len,
+                sector_len
+            );
+        }
+        let mut salvager = Salvager {
+            version: header.version,
+            sectors: Sectors::new(header.version, inner_len, inner),
+            fat: Vec::new(),
+            dir_entries: Vec::new(),
+            minifat: Vec::new(),
+            mini_stream: Vec::new(),
+        };
+        salvager.read_fat(&header)?;
+        salvager.read_directory(&header, &mut validator)?;
+        salvager.read_mini_stream(&header)?;
+        Ok(salvager)
+    }
+
+    /// Reads as much of the FAT as the DIFAT leads to.  FAT sectors that
+    /// can't be found are treated as though all their entries were free, so
+    /// that the rest of the FAT stays at the correct offsets.
+    fn read_fat(&mut self, header: &Header) -> io::Result<()> {
+        let num_sectors = self.sectors.num_sectors();
+        let entries_per_sector = self.sectors.sector_len() / 4;
+        let mut difat = header.initial_difat_entries.to_vec();
+        let mut seen_sector_ids = IdSet::default();
+        let mut current_difat_sector = header.first_difat_sector;
+        while current_difat_sector < num_sectors
+            && seen_sector_ids.insert(current_difat_sector)
+        {
+            let entries = self.read_entries(current_difat_sector)?;
+            let (&next, entries) = entries.split_last().unwrap();
+            difat.extend_from_slice(entries);
+            current_difat_sector = next;
+        }
+        while difat.last() == Some(&consts::FREE_SECTOR) {
+            difat.pop();
+        }
+        for sector_id in difat {
+            if sector_id < num_sectors {
+                let entries = self.read_entries(sector_id)?;
+                self.fat.extend_from_slice(&entries);
+            } else {
+                let len = self.fat.len() + entries_per_sector;
+                self.fat.resize(len, consts::FREE_SECTOR);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads as much of the directory as its chain leads to.  Entries that
+    /// can't be parsed are kept as errors, to be reported if anything refers
+    /// to them.
+    fn read_directory(
+        &mut self,
+        header: &Header,
+        validator: &mut Validator,
+    ) -> io::Result<()> {
+        let mut data = Vec::<u8>::new();
+        self.read_chain(header.first_dir_sector, u64::MAX, &mut |chunk| {
+            data.extend_from_slice(chunk);
+            Ok(())
+        })?;
+        for chunk in data.chunks_exact(consts::DIR_ENTRY_LEN) {
+            let mut buffer = [0u8; consts::DIR_ENTRY_LEN];
+            buffer.copy_from_slice(chunk);
+            // The tree is rebuilt from scratch, so an entry's color doesn't
+            // matter, and an invalid one shouldn't cost us the entry.
+            buffer[67] = consts::COLOR_BLACK;
+            let stream_id = self.dir_entries.len() as u32;
+            validator.set_stream_id(Some(stream_id));
+            let result = DirEntry::read_from(
+                &mut buffer.as_slice(),
+                self.version,
+                validator,
+            );
+            validator.set_stream_id(None);
+            self.dir_entries.push(match result {
+                Ok(dir_entry) => Ok(dir_entry),
+                Err(error) => match Error::from_io(&error) {
+                    Some(error) => Err(error.clone()),
+                    None => return Err(error),
+                },
+            });
+        }
+        Ok(())
+    }
+
+    /// Reads as much of the MiniFAT and of the mini stream as their chains
+    /// lead to.
+    fn read_mini_stream(&mut self, header: &Header) -> io::Result<()> {
+        let mut data = Vec::<u8>::new();
+        self.read_chain(
+            header.first_minifat_sector,
+            u64::MAX,
+            &mut |chunk| {
+                data.extend_from_slice(chunk);
+                Ok(())
+            },
+        )?;
+        self.minifat =
+            data.chunks_exact(4).map(LittleEndian::read_u32).collect();
+        let (start_sector, stream_len) = match self.dir_entries.first() {
+            Some(Ok(root)) if root.obj_type == ObjType::Root => {
+                (root.start_sector, root.stream_len)
+            }
+            _ => return Ok(()),
+        };
+        let mut mini_stream = Vec::<u8>::new();
+        self.read_chain(start_sector, stream_len, &mut |chunk| {
+            mini_stream.extend_from_slice(chunk);
+            Ok(())
+        })?;
+        self.mini_stream = mini_stream;
+        Ok(())
+    }
+
+    /// Reads every 32-bit entry of the given sector.
+    fn read_entries(&mut self, sector_id: u32) -> io::Result<Vec<u32>> {
+        let mut buffer = vec![0u8; self.sectors.sector_len()];
+        self.read_sector(sector_id, &mut buffer)?;
+        Ok(buffer.chunks_exact(4).map(LittleEndian::read_u32).collect())
+    }
+
+    /// Reads the given sector into `buffer`.  If the file ends partway
+    /// through the sector, the rest of the buffer is filled with zeros.
+    fn read_sector(
+        &mut self,
+        sector_id: u32,
+        buffer: &mut [u8],
+    ) -> io::Result<()> {
+        buffer.fill(0);
+        let mut sector = self.sectors.seek_to_sector(sector_id)?;
+        let mut filled = 0;
+        while filled < buffer.len() {
+            match sector.read(&mut buffer[filled..])? {
+                0 => break,
+                num_bytes => filled += num_bytes,
+            }
+        }
+        Ok(())
+    }
+
+    /// Follows the chain of regular sectors starting at `start_sector`,
+    /// passing the data of each to `sink`, until `len` bytes have been read
+    /// or the chain ends.  Returns the number of bytes read, along with the
+    /// problem that broke the chain, if it broke before then.
+    fn read_chain(
+        &mut self,
+        start_sector: u32,
+        len: u64,
+        sink: &mut dyn FnMut(&[u8]) -> io::Result<()>,
+    ) -> io::Result<(u64, Option<Error>)> {
+        let num_sectors = self.sectors.num_sectors();
+        let mut buffer = vec![0u8; self.sectors.sector_len()];
+        let mut seen_sector_ids = IdSet::default();
+        let mut num_read = 0;
+        let mut sector_id = start_sector;
+        while num_read < len && sector_id != consts::END_OF_CHAIN {
+            let error = if sector_id > consts::MAX_REGULAR_SECTOR {
+                corruption!(
+                    Fat { sector_id: Some(sector_id) },
+                    "Chain includes invalid sector index {}",
+                    sector_id
+                )
+            } else if sector_id >= num_sectors {
+                corruption!(
+                    SectorOutOfRange { sector_id, num_sectors },
+                    "Chain includes sector index {}, but sector count is \
+                     only {}",
+                    sector_id,
+                    num_sectors
+                )
+            } else if !seen_sector_ids.insert(sector_id) {
+                corruption!(
+                    ChainLoop { sector_id },
+                    "Chain contained duplicate sector id {}",
+                    sector_id
+                )
+            } else {
+                self.read_sector(sector_id, &mut buffer)?;
+                let chunk_len = cmp::min(buffer.len() as u64, len - num_read);
+                sink(&buffer[..chunk_len as usize])?;
+                num_read += chunk_len;
+                match self.fat.get(sector_id as usize) {
+                    Some(&next_id) => {
+                        sector_id = next_id;
+                        continue;
+                    }
+                    None if num_read == len => break,
+                    None => corruption!(
+                        Fat { sector_id: Some(sector_id) },
+                        "Found reference to sector {}, but FAT has only {} \
+                         entries",
+                        sector_id,
+                        self.fat.len()
+                    ),
+                }
+            };
+            return Ok((num_read, Some(error)));
+        }
+        Ok((num_read, None))
+    }
+
+    /// Like `read_chain`, but follows a chain of mini sectors within the mini
+    /// stream.
+    fn read_mini_chain(
+        &self,
+        start_sector: u32,
+        len: u64,
+        sink: &mut dyn FnMut(&[u8]) -> io::Result<()>,
+    ) -> io::Result<(u64, Option<Error>)> {
+        let mini_sector_len = consts::MINI_SECTOR_LEN;
+        let num_mini_sectors = ((self.mini_stream.len() + mini_sector_len - 1)
+            / mini_sector_len) as u32;
+        let mut seen_sector_ids = IdSet::default();
+        let mut num_read = 0;
+        let mut sector_id = start_sector;
+        while num_read < len && sector_id != consts::END_OF_CHAIN {
+            let error = if sector_id > consts::MAX_REGULAR_SECTOR {
+                corruption!(
+                    MiniFat { sector_id: Some(sector_id) },
+                    "Minichain includes invalid sector index {}",
+                    sector_id
+                )
+            } else if sector_id >= num_mini_sectors {
+                corruption!(
+                    MiniFat { sector_id: Some(sector_id) },
+                    "Mini sector {} is past the end of the mini stream",
+                    sector_id
+                )
+            } else if !seen_sector_ids.insert(sector_id) {
+                corruption!(
+                    ChainLoop { sector_id },
+                    "Minichain contained duplicate sector id {}",
+                    sector_id
+                )
+            } else {
+                let start = sector_id as usize * mini_sector_len;
+                let end =
+                    cmp::min(start + mini_sector_len, self.mini_stream.len());
+                let chunk_len = cmp::min((end - start) as u64, len - num_read);
+                sink(&self.mini_stream[start..][..chunk_len as usize])?;
+                num_read += chunk_len;
+                match self.minifat.get(sector_id as usize) {
+                    Some(&next_id) => {
+                        sector_id = next_id;
+                        continue;
+                    }
+                    None if num_read == len => break,
+                    None => corruption!(
+                        MiniFat { sector_id: Some(sector_id) },
+                        "Found reference to mini sector {}, but MiniFAT has \
+                         only {} entries",
+                        sector_id,
+                        self.minifat.len()
+                    ),
+                }
+            };
+            return Ok((num_read, Some(error)));
+        }
+        Ok((num_read, None))
+    }
+
+    /// Recreates the root storage, and every storage and stream that can be
+    /// reached from it, within `comp`.  Directory entries that can't be
+    /// reached are reported as lost.
+    fn copy_tree<W: Read + Write + Seek>(
+        &mut self,
+        comp: &mut CompoundFile<W>,
+        report: &mut RepairReport,
+    ) -> io::Result<()> {
+        let root_entry = match self.dir_entries.first() {
+            Some(Ok(dir_entry)) if dir_entry.obj_type == ObjType::Root => {
+                dir_entry.clone()
+            }
+            Some(Err(error)) => {
+                report.lose(None, 0, error.clone());
+                return Ok(());
+            }
+            _ => {
+                report.lose(
+                    None,
+                    0,
+                    corruption!(
+                        Directory {
+                            stream_id: Some(consts::ROOT_STREAM_ID),
+                            path: Some(PathBuf::from("/")),
+                        },
+                        "root entry is missing"
+                    ),
+                );
+                return Ok(());
+            }
+        };
+        copy_metadata(comp, consts::ROOT_STREAM_ID, &root_entry)?;
+
+        // Each storage still to be copied, as the stream ID of the root of
+        // its tree of children, the stream ID of its copy, and its path.
+        let mut storages: Vec<(u32, u32, Vec<String>)> =
+            vec![(root_entry.child, consts::ROOT_STREAM_ID, Vec::new())];
+        let mut visited = IdSet::default();
+        visited.insert(consts::ROOT_STREAM_ID);
+        while let Some((child, dst_parent_id, names)) = storages.pop() {
+            // The siblings may not be in order (or even form a tree), so
+            // visit every entry that can be reached from the first one,
+            // rather than searching by name.
+            let mut stack = vec![child];
+            while let Some(stream_id) = stack.pop() {
+                if stream_id == consts::NO_STREAM || !visited.insert(stream_id)
+                {
+                    continue;
+                }
+                let dir_entry = match self.dir_entries.get(stream_id as usize)
+                {
+                    Some(Ok(dir_entry))
+                        if dir_entry.obj_type == ObjType::Storage
+                            || dir_entry.obj_type == ObjType::Stream =>
+                    {
+                        dir_entry.clone()
+                    }
+                    Some(Ok(dir_entry)) => {
+                        report.lose(
+                            None,
+                            0,
+                            corruption!(
+                                Directory {
+                                    stream_id: Some(stream_id),
+                                    path: None
+                                },
+                                "Directory entry {} is referenced from the \
+                                 tree, but has type {:?}",
+                                stream_id,
+                                dir_entry.obj_type
+                            ),
+                        );
+                        continue;
+                    }
+                    Some(Err(error)) => {
+                        report.lose(None, 0, error.clone());
+                        continue;
+                    }
+                    None => {
+                        report.lose(
+                            None,
+                            0,
+                            corruption!(
+                                Directory {
+                                    stream_id: Some(stream_id),
+                                    path: None
+                                },
+                                "Directory entry {} is past the end of the \
+                                 directory",
+                                stream_id
+                            ),
+                        );
+                        continue;
+                    }
+                };
+                stack.push(dir_entry.left_sibling);
+                stack.push(dir_entry.right_sibling);
+
+                let mut child_names = names.clone();
+                child_names.push(dir_entry.name.clone());
+                let name_chain: Vec<&str> =
+                    child_names.iter().map(String::as_str).collect();
+                let path = path_from_name_chain(&name_chain);
+                if comp.stream_id_for_name_chain(&name_chain).is_some() {
+                    report.lose(
+                        Some(path.clone()),
+                        dir_entry.stream_len,
+                        corruption!(
+                            Directory {
+                                stream_id: Some(stream_id),
+                                path: Some(path),
+                            },
+                            "Another object in the same storage is also \
+                             named {:?}",
+                            dir_entry.name
+                        ),
+                    );
+                    continue;
+                }
+                let dst_id = comp.minialloc_mut().insert_dir_entry(
+                    dst_parent_id,
+                    &dir_entry.name,
+                    dir_entry.obj_type,
+                )?;
+                if dir_entry.obj_type == ObjType::Stream {
+                    let (num_read, error) =
+                        self.copy_stream(comp, dst_id, &dir_entry)?;
+                    report.num_bytes += num_read;
+                    if num_read < dir_entry.stream_len {
+                        let error = error
+                            .unwrap_or_else(|| {
+                                corruption!(
+                                    Directory {
+                                        stream_id: Some(stream_id),
+                                        path: None,
+                                    },
+                                    "Stream is {} bytes long, but its chain \
+                                     ends after {} bytes",
+                                    dir_entry.stream_len,
+                                    num_read
+                                )
+                            })
+                            .at_path(&path);
+                        report.lose(
+                            Some(path),
+                            dir_entry.stream_len - num_read,
+                            error,
+                        );
+                    }
+                } else {
+                    storages.push((dir_entry.child, dst_id, child_names));
+                }
+                copy_metadata(comp, dst_id, &dir_entry)?;
+                report.num_entries += 1;
+            }
+        }
+
+        for (stream_id, dir_entry) in self.dir_entries.iter().enumerate() {
+            let stream_id = stream_id as u32;
+            if let Ok(dir_entry) = dir_entry {
+                if dir_entry.obj_type != ObjType::Unallocated
+                    && !visited.contains(&stream_id)
+                {
+                    report.lose(
+                        None,
+                        dir_entry.stream_len,
+                        corruption!(
+                            Directory {
+                                stream_id: Some(stream_id),
+                                path: None
+                            },
+                            "Directory entry {} ({:?}) is not reachable from \
+                             the root",
+                            stream_id,
+                            dir_entry.name
+                        ),
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies as much of the given stream's data as can be read into the
+    /// stream with ID `dst_id` in `comp`.
+    fn copy_stream<W: Read + Write + Seek>(
+        &mut self,
+        comp: &CompoundFile<W>,
+        dst_id: u32,
+        src_entry: &DirEntry,
+    ) -> io::Result<(u64, Option<Error>)> {
+        let mut dst_stream = Stream::new(&comp.minialloc, dst_id);
+        let mut sink = |chunk: &[u8]| dst_stream.write_all(chunk);
+        let (start_sector, len) =
+            (src_entry.start_sector, src_entry.stream_len);
+        let result = if len < consts::MINI_STREAM_CUTOFF as u64 {
+            self.read_mini_chain(start_sector, len, &mut sink)?
+        } else {
+            self.read_chain(start_sector, len, &mut sink)?
+        };
+        dst_stream.flush()?;
+        Ok(result)
+    }
+}
+
+/// Copies the CLSID, state bits, and timestamps of `src_entry` to the entry
+/// with ID `dst_id` in `comp`.
+fn copy_metadata<W: Read + Write + Seek>(
+    comp: &mut CompoundFile<W>,
+    dst_id: u32,
+    src_entry: &DirEntry,
+) -> io::Result<()> {
+    comp.minialloc_mut().with_dir_entry_mut(dst_id, |dir_entry| {
+        dir_entry.clsid = src_entry.clsid;
+        dir_entry.state_bits = src_entry.state_bits;
+        dir_entry.creation_time = src_entry.creation_time;
+        dir_entry.modified_time = src_entry.modified_time;
+    })
+}
+
+//===========================================================================//
@@ -0,0 +1,156 @@
+use crate::internal::path::{Path, PathBuf};
+use crate::internal::{Error, Severity, Violation};
+use alloc::vec::Vec;
+
+/// A summary of the objects transferred by `CompoundFile::import_from`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ImportReport {
+    pub(crate) num_entries: u64,
+    pub(crate) num_bytes: u64,
+}
+
+impl ImportReport {
+    /// Returns the number of stream and storage objects that were created in
+    /// the destination compound file.
+    pub fn num_entries(&self) -> u64 {
+        self.num_entries
+    }
+
+    /// Returns the total number of bytes of stream data that were copied.
+    pub fn num_bytes(&self) -> u64 {
+        self.num_bytes
+    }
+}
+
+//===========================================================================//
+
+/// The result of checking a compound file against the CFB spec with
+/// [`cfb::check`](fn.check.html).
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Report {
+    pub(crate) violations: Vec<Violation>,
+    pub(crate) can_open_permissive: bool,
+}
+
+impl Report {
+    /// Returns every spec violation that was found, in the order in which
+    /// they were found.
+    pub fn violations(&self) -> &[Violation] {
+        &self.violations
+    }
+
+    /// Returns an iterator over the violations that make the file (or some
+    /// part of it) unreadable.
+    pub fn errors(&self) -> impl Iterator<Item = &Violation> {
+        self.with_severity(Severity::Error)
+    }
+
+    /// Returns an iterator over the violations that `CompoundFile::open`
+    /// tolerates, but that `CompoundFile::open_strict` rejects.
+    pub fn warnings(&self) -> impl Iterator<Item = &Violation> {
+        self.with_severity(Severity::Warning)
+    }
+
+    fn with_severity(
+        &self,
+        severity: Severity,
+    ) -> impl Iterator<Item = &Violation> {
+        self.violations
+            .iter()
+            .filter(move |violation| violation.severity() == severity)
+    }
+
+    /// Returns true if no violations were found at all.
+    pub fn is_valid(&self) -> bool {
+        self.violations.is_empty()
+    }
+
+    /// Returns true if `CompoundFile::open` can open the file.  Even so, some
+    /// of its streams may be unreadable if the report includes any errors.
+    pub fn can_open_permissive(&self) -> bool {
+        self.can_open_permissive
+    }
+
+    /// Returns true if `CompoundFile::open_strict` can open the file; that
+    /// is, if `CompoundFile::open` can open it and there are no warnings.
+    pub fn can_open_strict(&self) -> bool {
+        self.can_open_permissive && self.warnings().next().is_none()
+    }
+}
+
+//===========================================================================//
+
+/// The result of salvaging a damaged compound file with
+/// [`cfb::repair`](fn.repair.html).
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RepairReport {
+    pub(crate) num_entries: u64,
+    pub(crate) num_bytes: u64,
+    pub(crate) losses: Vec<Loss>,
+}
+
+impl RepairReport {
+    pub(crate) fn lose(
+        &mut self,
+        path: Option<PathBuf>,
+        bytes_lost: u64,
+        error: Error,
+    ) {
+        self.losses.push(Loss { path, bytes_lost, error });
+    }
+
+    /// Returns the number of stream and storage objects (not counting the
+    /// root storage) that were salvaged into the repaired file.
+    pub fn num_entries(&self) -> u64 {
+        self.num_entries
+    }
+
+    /// Returns the total number of bytes of stream data that were salvaged.
+    pub fn num_bytes(&self) -> u64 {
+        self.num_bytes
+    }
+
+    /// Returns everything that couldn't be salvaged, in the order in which
+    /// it was found.
+    pub fn losses(&self) -> &[Loss] {
+        &self.losses
+    }
+
+    /// Returns true if everything in the original file was salvaged.
+    pub fn is_lossless(&self) -> bool {
+        self.losses.is_empty()
+    }
+}
+
+/// An object, or part of a stream, that [`cfb::repair`](fn.repair.html)
+/// couldn't salvage.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Loss {
+    pub(crate) path: Option<PathBuf>,
+    pub(crate) bytes_lost: u64,
+    pub(crate) error: Error,
+}
+
+impl Loss {
+    /// Returns the path of the object that was lost (or that lost some of
+    /// its data), or `None` if the object isn't reachable from the root, or
+    /// its directory entry is too damaged to tell what it was.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Returns the number of bytes of stream data that were lost.  For a
+    /// stream whose chain was cut short, this is the part of the stream past
+    /// the point where its chain broke; the rest of the stream is still
+    /// salvaged.
+    pub fn bytes_lost(&self) -> u64 {
+        self.bytes_lost
+    }
+
+    /// Returns the problem that caused the loss.
+    pub fn error(&self) -> &Error {
+        &self.error
+    }
+}
+
+//===========================================================================//
@@ -1,7 +1,9 @@
-use crate::internal::{consts, DirEntry, Version};
-use byteorder::{LittleEndian, WriteBytesExt};
-use std::cmp;
-use std::io::{self, Read, Seek, SeekFrom, Write};
+use crate::internal::{consts, DirEntry, ReadAt, SetLen, Version};
+use crate::io::{self, Read, Seek, SeekFrom, Write, WriteBytesExt};
+use alloc::vec;
+use alloc::vec::Vec;
+use byteorder::LittleEndian;
+use core::cmp;
 
 // ========================================================================= //
 
@@ -34,9 +36,49 @@ impl<F> Sectors<F> {
         self.num_sectors
     }
 
+    pub fn inner(&self) -> &F {
+        &self.inner
+    }
+
+    #[cfg(feature = "async")]
+    pub fn inner_mut(&mut self) -> &mut F {
+        &mut self.inner
+    }
+
     pub fn into_inner(self) -> F {
         self.inner
     }
+
+    /// Returns the offset within the underlying file of the given byte within
+    /// the specified sector.
+    pub fn sector_offset(
+        &self,
+        sector_id: u32,
+        offset_within_sector: u64,
+    ) -> io::Result<u64> {
+        debug_assert!(offset_within_sector <= self.sector_len() as u64);
+        if sector_id >= self.num_sectors {
+            corrupted!(
+                SectorOutOfRange { sector_id, num_sectors: self.num_sectors },
+                "Tried to seek to sector {}, but sector count is only {}",
+                sector_id,
+                self.num_sectors
+            );
+        }
+        let sector_len = self.sector_len() as u64;
+        Ok((sector_id + 1) as u64 * sector_len + offset_within_sector)
+    }
+}
+
+impl<F: SetLen> Sectors<F> {
+    /// Truncates or extends the underlying file so that it holds exactly
+    /// `num_sectors` sectors after the header.
+    pub fn set_num_sectors(&mut self, num_sectors: u32) -> io::Result<()> {
+        let sector_len = self.sector_len() as u64;
+        self.inner.set_len((num_sectors as u64 + 1) * sector_len)?;
+        self.num_sectors = num_sectors;
+        Ok(())
+    }
 }
 
 impl<F: Seek> Sectors<F> {
@@ -62,18 +104,9 @@ impl<F: Seek> Sectors<F> {
         sector_id: u32,
         offset_within_sector: u64,
     ) -> io::Result<Sector<F>> {
-        debug_assert!(offset_within_sector <= self.sector_len() as u64);
-        if sector_id >= self.num_sectors {
-            invalid_data!(
-                "Tried to seek to sector {}, but sector count is only {}",
-                sector_id,
-                self.num_sectors
-            );
-        }
+        let offset = self.sector_offset(sector_id, offset_within_sector)?;
         let sector_len = self.sector_len();
-        self.inner.seek(SeekFrom::Start(
-            (sector_id + 1) as u64 * sector_len as u64 + offset_within_sector,
-        ))?;
+        self.inner.seek(SeekFrom::Start(offset))?;
         Ok(Sector {
             inner: &mut self.inner,
             sector_len,
@@ -82,6 +115,24 @@ impl<F: Seek> Sectors<F> {
     }
 }
 
+impl<F: ReadAt> Sectors<F> {
+    /// Reads from the given offset within the specified sector, stopping at
+    /// the end of that sector.  Since this uses a positional read, it needs
+    /// only a shared reference, and so can be done by several readers at
+    /// once.
+    pub fn read_at_sector(
+        &self,
+        sector_id: u32,
+        offset_within_sector: u64,
+        buf: &mut [u8],
+    ) -> io::Result<usize> {
+        let offset = self.sector_offset(sector_id, offset_within_sector)?;
+        let remaining = self.sector_len() - offset_within_sector as usize;
+        let max_len = cmp::min(buf.len(), remaining);
+        self.inner.read_at(&mut buf[..max_len], offset)
+    }
+}
+
 impl<F: Write + Seek> Sectors<F> {
     /// Creates or resets the specified sector using the given initializer.
     pub fn init_sector(
@@ -90,7 +141,8 @@ impl<F: Write + Seek> Sectors<F> {
         init: SectorInit,
     ) -> io::Result<()> {
         match sector_id.cmp(&self.num_sectors) {
-            cmp::Ordering::Greater => invalid_data!(
+            cmp::Ordering::Greater => corrupted!(
+                SectorOutOfRange { sector_id, num_sectors: self.num_sectors },
                 "Tried to initialize sector {}, but sector count is only {}",
                 sector_id,
                 self.num_sectors
@@ -109,6 +161,15 @@ impl<F: Write + Seek> Sectors<F> {
     }
 }
 
+impl<F: Read + Write + Seek> Sectors<F> {
+    /// Overwrites the contents of one sector with those of another.
+    pub fn copy_sector(&mut self, from_id: u32, to_id: u32) -> io::Result<()> {
+        let mut buffer = vec![0u8; self.sector_len()];
+        self.seek_to_sector(from_id)?.read_exact(&mut buffer)?;
+        self.seek_to_sector(to_id)?.write_all(&buffer)
+    }
+}
+
 // ========================================================================= //
 
 /// A wrapper around a single sector or mini sector within a CFB file, allowing
@@ -205,36 +266,36 @@ pub enum SectorInit {
 impl SectorInit {
     fn initialize<F: Write>(self, sector: &mut Sector<F>) -> io::Result<()> {
         debug_assert_eq!(sector.offset_within_sector, 0);
+        // Build the sector's contents in memory first, so that the sector is
+        // written with a single write.
+        let mut buffer = Vec::with_capacity(sector.len());
         match self {
             SectorInit::Zero => {
-                io::copy(
-                    &mut io::repeat(0).take(sector.len() as u64),
-                    sector,
-                )?;
+                buffer.resize(sector.len(), 0);
             }
             SectorInit::Fat => {
                 debug_assert_eq!(sector.len() % 4, 0);
                 for _ in 0..(sector.len() / 4) {
-                    sector.write_u32::<LittleEndian>(consts::FREE_SECTOR)?;
+                    buffer.write_u32::<LittleEndian>(consts::FREE_SECTOR)?;
                 }
             }
             SectorInit::Difat => {
                 debug_assert_eq!(sector.len() % 4, 0);
                 debug_assert!(sector.len() >= 4);
                 for _ in 0..((sector.len() - 4) / 4) {
-                    sector.write_u32::<LittleEndian>(consts::FREE_SECTOR)?;
+                    buffer.write_u32::<LittleEndian>(consts::FREE_SECTOR)?;
                 }
-                sector.write_u32::<LittleEndian>(consts::END_OF_CHAIN)?;
+                buffer.write_u32::<LittleEndian>(consts::END_OF_CHAIN)?;
             }
             SectorInit::Dir => {
                 debug_assert_eq!(sector.len() % consts::DIR_ENTRY_LEN, 0);
                 let dir_entry = DirEntry::unallocated();
                 for _ in 0..(sector.len() / consts::DIR_ENTRY_LEN) {
-                    dir_entry.write_to(sector)?;
+                    dir_entry.write_to(&mut buffer)?;
                 }
             }
         }
-        Ok(())
+        sector.write_all(&buffer)
     }
 }
 
@@ -243,7 +304,9 @@ impl SectorInit {
 #[cfg(test)]
 mod tests {
     use super::{SectorInit, Sectors};
-    use crate::internal::{consts, DirEntry, ObjType, Validation, Version};
+    use crate::internal::{
+        consts, DirEntry, ObjType, ValidationPolicy, Version,
+    };
     use byteorder::{LittleEndian, ReadBytesExt};
     use std::io::{Cursor, Read, Seek, SeekFrom, Write};
 
@@ -277,6 +340,24 @@ mod tests {
         }
     }
 
+    #[test]
+    fn sector_read_at() {
+        let mut data = vec![1u8; 512];
+        data.append(&mut vec![2; 512]);
+        data.append(&mut vec![3; 512]);
+        let sectors = Sectors::new(Version::V3, 1536, Cursor::new(data));
+        let mut buffer = vec![0; 400];
+        assert_eq!(sectors.read_at_sector(0, 0, &mut buffer).unwrap(), 400);
+        assert_eq!(buffer, vec![2; 400]);
+        // Reads stop at the end of the sector.
+        let mut buffer = vec![0; 400];
+        assert_eq!(sectors.read_at_sector(0, 312, &mut buffer).unwrap(), 200);
+        assert_eq!(&buffer[..200], &[2; 200][..]);
+        assert_eq!(sectors.read_at_sector(1, 0, &mut buffer).unwrap(), 400);
+        assert_eq!(buffer, vec![3; 400]);
+        assert!(sectors.read_at_sector(2, 0, &mut buffer).is_err());
+    }
+
     #[test]
     fn sector_write() {
         let cursor = Cursor::new(vec![0u8; 2048]);
@@ -365,7 +446,7 @@ mod tests {
                 let dir_entry = DirEntry::read_from(
                     &mut sector,
                     Version::V3,
-                    Validation::Strict,
+                    &mut ValidationPolicy::strict().into(),
                 )
                 .unwrap();
                 assert_eq!(dir_entry.obj_type, ObjType::Unallocated);
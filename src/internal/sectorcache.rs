@@ -0,0 +1,393 @@
+use crate::internal::{seek_position, ReadAt};
+use fnv::FnvHashMap;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+//===========================================================================//
+
+/// The number of bytes in each chunk of the cache.  This is the length of a
+/// mini sector, so that every sector and mini sector is made of whole chunks.
+const CHUNK_LEN: u64 = 64;
+
+//===========================================================================//
+
+/// An in-memory cache of parts of a compound file's underlying file, which
+/// stands in for that file in an
+/// [`AsyncCompoundFile`](struct.AsyncCompoundFile.html).
+///
+/// The cache holds the parts of the file that the compound file reads
+/// synchronously (its header, allocation tables, directory, and mini
+/// stream), along with any changes that have not yet been written back to
+/// the underlying file.  Reading a part of the file that isn't in the cache
+/// fails, rather than blocking.
+pub struct SectorCache {
+    chunks: FnvHashMap<u64, Chunk>,
+    len: u64,
+    position: u64,
+}
+
+/// Part of the cached file, along with which of its bytes are known (i.e.
+/// have been loaded from the underlying file or written), and which of those
+/// have been written since they were last written back.
+struct Chunk {
+    data: [u8; CHUNK_LEN as usize],
+    valid: u64,
+    dirty: u64,
+}
+
+impl SectorCache {
+    pub(crate) fn new(len: u64) -> SectorCache {
+        SectorCache { chunks: FnvHashMap::default(), len, position: 0 }
+    }
+
+    /// Returns the current length of the cached file, including any changes
+    /// that haven't been written back.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns true if the cached file is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the chunk-aligned ranges, as `(offset, len)` pairs, within the
+    /// given range of the file (clamped to the end of the file) that aren't
+    /// entirely in the cache.
+    pub(crate) fn missing_ranges(
+        &self,
+        offset: u64,
+        len: u64,
+    ) -> Vec<(u64, u64)> {
+        let end = (offset + len).min(self.len);
+        let mut ranges: Vec<(u64, u64)> = Vec::new();
+        let mut chunk_index = offset / CHUNK_LEN;
+        while chunk_index * CHUNK_LEN < end {
+            let chunk_start = chunk_index * CHUNK_LEN;
+            let first = offset.max(chunk_start) - chunk_start;
+            let last = end.min(chunk_start + CHUNK_LEN) - chunk_start;
+            let mask = byte_mask(first, last);
+            let is_cached = match self.chunks.get(&chunk_index) {
+                Some(chunk) => chunk.valid & mask == mask,
+                None => false,
+            };
+            if !is_cached {
+                let chunk_len = CHUNK_LEN.min(self.len - chunk_start);
+                match ranges.last_mut() {
+                    Some((start, len)) if *start + *len == chunk_start => {
+                        *len += chunk_len;
+                    }
+                    _ => ranges.push((chunk_start, chunk_len)),
+                }
+            }
+            chunk_index += 1;
+        }
+        ranges
+    }
+
+    /// Stores data loaded from the underlying file at the given offset.  Any
+    /// bytes that are already in the cache are left as they are, since they
+    /// may have been changed since the underlying file was last written.
+    pub(crate) fn fill(&mut self, offset: u64, data: &[u8]) {
+        for_each_chunk(offset, data.len(), |index, range, data_range| {
+            let chunk = self.chunks.entry(index).or_insert_with(Chunk::new);
+            let new = byte_mask(range.0, range.1) & !chunk.valid;
+            for (byte, &value) in (range.0..range.1).zip(&data[data_range]) {
+                if new & (1 << byte) != 0 {
+                    chunk.data[byte as usize] = value;
+                }
+            }
+            chunk.valid |= new;
+        });
+    }
+
+    /// Copies any cached bytes that haven't been written back yet over the
+    /// given buffer, which holds data read from the underlying file at the
+    /// given offset.
+    pub(crate) fn overlay_dirty(&self, offset: u64, buf: &mut [u8]) {
+        for_each_chunk(offset, buf.len(), |index, range, buf_range| {
+            if let Some(chunk) = self.chunks.get(&index) {
+                let dirty = chunk.dirty & byte_mask(range.0, range.1);
+                if dirty != 0 {
+                    for (byte, value) in
+                        (range.0..range.1).zip(&mut buf[buf_range])
+                    {
+                        if dirty & (1 << byte) != 0 {
+                            *value = chunk.data[byte as usize];
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    /// Returns true if every byte in the given range of the file is in the
+    /// cache.
+    pub(crate) fn contains(&self, offset: u64, len: u64) -> bool {
+        offset + len <= self.len && self.missing_ranges(offset, len).is_empty()
+    }
+
+    /// Removes and returns the runs of bytes that have been written but not
+    /// yet written back to the underlying file, as `(offset, data)` pairs in
+    /// order of offset.  The bytes stay in the cache, but are no longer
+    /// considered dirty.
+    pub(crate) fn take_dirty_runs(&mut self) -> Vec<(u64, Vec<u8>)> {
+        let mut indices: Vec<u64> = self
+            .chunks
+            .iter()
+            .filter(|(_, chunk)| chunk.dirty != 0)
+            .map(|(&index, _)| index)
+            .collect();
+        indices.sort_unstable();
+        let mut runs: Vec<(u64, Vec<u8>)> = Vec::new();
+        for index in indices {
+            let chunk = self.chunks.get_mut(&index).unwrap();
+            for byte in 0..CHUNK_LEN {
+                if chunk.dirty & (1 << byte) == 0 {
+                    continue;
+                }
+                let offset = index * CHUNK_LEN + byte;
+                let value = chunk.data[byte as usize];
+                match runs.last_mut() {
+                    Some((start, data))
+                        if *start + data.len() as u64 == offset =>
+                    {
+                        data.push(value);
+                    }
+                    _ => runs.push((offset, vec![value])),
+                }
+            }
+            chunk.dirty = 0;
+        }
+        runs
+    }
+
+    /// Marks the given range of the file as not yet written back again, e.g.
+    /// after an attempt to write it back failed.
+    pub(crate) fn mark_dirty(&mut self, offset: u64, len: u64) {
+        for_each_chunk(offset, len as usize, |index, range, _| {
+            if let Some(chunk) = self.chunks.get_mut(&index) {
+                chunk.dirty |= byte_mask(range.0, range.1) & chunk.valid;
+            }
+        });
+    }
+
+    /// Drops cached data that has been written back, unless `keep` returns
+    /// true for its offset.
+    pub(crate) fn evict<K: Fn(u64) -> bool>(&mut self, keep: K) {
+        self.chunks.retain(|&index, chunk| {
+            chunk.dirty != 0 || keep(index * CHUNK_LEN)
+        });
+    }
+}
+
+impl Chunk {
+    fn new() -> Chunk {
+        Chunk { data: [0; CHUNK_LEN as usize], valid: 0, dirty: 0 }
+    }
+}
+
+impl ReadAt for SectorCache {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let max_len = self.len.saturating_sub(offset).min(buf.len() as u64);
+        let mut num_bytes = 0;
+        while num_bytes < max_len {
+            let position = offset + num_bytes;
+            let byte = position % CHUNK_LEN;
+            let run_len = match self.chunks.get(&(position / CHUNK_LEN)) {
+                Some(chunk) => (chunk.valid >> byte).trailing_ones() as u64,
+                None => 0,
+            }
+            .min(max_len - num_bytes);
+            if run_len == 0 {
+                break;
+            }
+            let chunk = &self.chunks[&(position / CHUNK_LEN)];
+            buf[num_bytes as usize..(num_bytes + run_len) as usize]
+                .copy_from_slice(
+                    &chunk.data[byte as usize..(byte + run_len) as usize],
+                );
+            num_bytes += run_len;
+        }
+        if num_bytes == 0 && max_len > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                NotCached { offset },
+            ));
+        }
+        Ok(num_bytes as usize)
+    }
+}
+
+impl Read for SectorCache {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let num_bytes = self.read_at(buf, self.position)?;
+        self.position += num_bytes as u64;
+        Ok(num_bytes)
+    }
+}
+
+impl Write for SectorCache {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.position > self.len {
+            // The gap between the old end of the file and the write is filled
+            // with zeroes.
+            let gap = vec![0u8; (self.position - self.len) as usize];
+            let old_len = self.len;
+            self.len = self.position;
+            self.fill(old_len, &gap);
+            self.mark_dirty(old_len, gap.len() as u64);
+        }
+        let position = self.position;
+        for_each_chunk(position, buf.len(), |index, range, buf_range| {
+            let chunk = self.chunks.entry(index).or_insert_with(Chunk::new);
+            chunk.data[range.0 as usize..range.1 as usize]
+                .copy_from_slice(&buf[buf_range]);
+            let mask = byte_mask(range.0, range.1);
+            chunk.valid |= mask;
+            chunk.dirty |= mask;
+        });
+        self.position += buf.len() as u64;
+        self.len = self.len.max(self.position);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for SectorCache {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = seek_position(pos, self.position, self.len)?;
+        Ok(self.position)
+    }
+}
+
+//===========================================================================//
+
+/// The error payload for a read from a part of a `SectorCache` that isn't in
+/// the cache.
+#[derive(Debug)]
+pub struct NotCached {
+    pub offset: u64,
+}
+
+impl NotCached {
+    /// Returns the offset of the missing data, if the given error is from
+    /// reading data that isn't in the cache.
+    pub fn offset_of(error: &io::Error) -> Option<u64> {
+        error
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<NotCached>())
+            .map(|not_cached| not_cached.offset)
+    }
+}
+
+impl fmt::Display for NotCached {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "Data at offset {} is not cached", self.offset)
+    }
+}
+
+impl Error for NotCached {}
+
+//===========================================================================//
+
+/// Returns a mask with one bit set for each of the bytes `first..last` of a
+/// chunk.
+fn byte_mask(first: u64, last: u64) -> u64 {
+    debug_assert!(first <= last && last <= CHUNK_LEN);
+    if last - first == CHUNK_LEN {
+        !0
+    } else {
+        ((1 << (last - first)) - 1) << first
+    }
+}
+
+/// Calls `func` for each chunk overlapping the `len` bytes at `offset`, with
+/// the chunk's index, the range of bytes within the chunk, and the
+/// corresponding range within the `len` bytes.
+fn for_each_chunk<C>(offset: u64, len: usize, mut func: C)
+where
+    C: FnMut(u64, (u64, u64), std::ops::Range<usize>),
+{
+    let end = offset + len as u64;
+    let mut position = offset;
+    while position < end {
+        let index = position / CHUNK_LEN;
+        let chunk_start = index * CHUNK_LEN;
+        let first = position - chunk_start;
+        let last = (end - chunk_start).min(CHUNK_LEN);
+        let start = (position - offset) as usize;
+        func(index, (first, last), start..start + (last - first) as usize);
+        position = chunk_start + last;
+    }
+}
+
+//===========================================================================//
+
+#[cfg(test)]
+mod tests {
+    use super::{NotCached, SectorCache};
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    #[test]
+    fn read_missing_data() {
+        let mut cache = SectorCache::new(1000);
+        cache.fill(64, &[7; 128]);
+        cache.fill(300, &[7; 10]);
+        // Chunks that are only partly cached count as missing.
+        assert_eq!(cache.missing_ranges(0, 1000), vec![(0, 64), (192, 808)]);
+        assert_eq!(cache.missing_ranges(100, 50), vec![]);
+        let mut buf = [0u8; 100];
+        cache.seek(SeekFrom::Start(120)).unwrap();
+        assert_eq!(cache.read(&mut buf).unwrap(), 72);
+        let error = cache.read(&mut buf).unwrap_err();
+        assert_eq!(NotCached::offset_of(&error), Some(192));
+    }
+
+    #[test]
+    fn fill_keeps_written_data() {
+        let mut cache = SectorCache::new(200);
+        cache.seek(SeekFrom::Start(10)).unwrap();
+        cache.write_all(&[1; 20]).unwrap();
+        cache.fill(0, &[2; 200]);
+        let mut buf = [0u8; 40];
+        cache.seek(SeekFrom::Start(0)).unwrap();
+        cache.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..10], &[2; 10]);
+        assert_eq!(&buf[10..30], &[1; 20]);
+        assert_eq!(&buf[30..], &[2; 10]);
+        let mut buf = [0u8; 40];
+        cache.overlay_dirty(5, &mut buf);
+        assert_eq!(&buf[..5], &[0; 5]);
+        assert_eq!(&buf[5..25], &[1; 20]);
+        assert_eq!(&buf[25..], &[0; 15]);
+    }
+
+    #[test]
+    fn take_dirty_runs() {
+        let mut cache = SectorCache::new(100);
+        cache.seek(SeekFrom::Start(60)).unwrap();
+        cache.write_all(&[1; 10]).unwrap();
+        cache.seek(SeekFrom::Start(120)).unwrap();
+        cache.write_all(&[2; 5]).unwrap();
+        assert_eq!(cache.len(), 125);
+        let runs = cache.take_dirty_runs();
+        // The gap before the second write is zero-filled, and adjacent runs
+        // are merged.
+        let mut expected = vec![0; 20];
+        expected.extend_from_slice(&[2; 5]);
+        assert_eq!(runs, vec![(60, vec![1; 10]), (100, expected)]);
+        assert!(cache.take_dirty_runs().is_empty());
+        cache.mark_dirty(65, 10);
+        assert_eq!(cache.take_dirty_runs(), vec![(65, vec![1; 5])]);
+        cache.evict(|offset| offset < 64);
+        assert!(cache.contains(60, 4));
+        assert!(!cache.contains(64, 1));
+    }
+}
+
+//===========================================================================//
@@ -0,0 +1,75 @@
+use crate::io::{self, SeekFrom};
+
+//===========================================================================//
+
+/// Returns the position that seeking to `pos` moves to, given the current
+/// position and the length of the data being sought within.  Returns an
+/// error if that position would be before the start of the data (or past
+/// the largest possible offset), but not if it's past the end of the data.
+pub fn seek_position(
+    pos: SeekFrom,
+    current: u64,
+    len: u64,
+) -> io::Result<u64> {
+    let (base, delta) = match pos {
+        SeekFrom::Start(offset) => return Ok(offset),
+        SeekFrom::End(delta) => (len, delta),
+        SeekFrom::Current(delta) => (current, delta),
+    };
+    match base.checked_add_signed(delta) {
+        Some(position) => Ok(position),
+        None if delta < 0 => invalid_input!(
+            "Cannot seek to {} bytes before offset {}, which is before the \
+             start",
+            delta.unsigned_abs(),
+            base
+        ),
+        None => invalid_input!(
+            "Cannot seek to {} bytes after offset {}, which is too far",
+            delta,
+            base
+        ),
+    }
+}
+
+/// Like `seek_position`, but also returns an error if the new position
+/// would be past the end of the data.
+pub fn seek_within(pos: SeekFrom, current: u64, len: u64) -> io::Result<u64> {
+    let position = seek_position(pos, current, len)?;
+    if position > len {
+        invalid_input!(
+            "Cannot seek to {}, because the length is only {} bytes",
+            position,
+            len
+        );
+    }
+    Ok(position)
+}
+
+//===========================================================================//
+
+#[cfg(test)]
+mod tests {
+    use super::{seek_position, seek_within};
+    use crate::io::SeekFrom;
+
+    #[test]
+    fn seek_relative_to_start_end_and_current() {
+        assert_eq!(seek_position(SeekFrom::Start(7), 3, 5).unwrap(), 7);
+        assert_eq!(seek_position(SeekFrom::End(-2), 3, 5).unwrap(), 3);
+        assert_eq!(seek_position(SeekFrom::End(4), 3, 5).unwrap(), 9);
+        assert_eq!(seek_position(SeekFrom::Current(-3), 3, 5).unwrap(), 0);
+        assert_eq!(seek_within(SeekFrom::Current(2), 3, 5).unwrap(), 5);
+    }
+
+    #[test]
+    fn seek_out_of_bounds() {
+        assert!(seek_position(SeekFrom::Current(-4), 3, 5).is_err());
+        assert!(seek_position(SeekFrom::End(i64::MIN), 3, 5).is_err());
+        assert!(seek_position(SeekFrom::Current(1), u64::MAX, 5).is_err());
+        assert!(seek_within(SeekFrom::Start(6), 3, 5).is_err());
+        assert!(seek_within(SeekFrom::End(1), 3, 5).is_err());
+    }
+}
+
+//===========================================================================//
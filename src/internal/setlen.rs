@@ -0,0 +1,44 @@
+use crate::io::{self, Cursor};
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::fs;
+
+//===========================================================================//
+
+/// A reader/writer whose total length can be changed, such as a
+/// [`File`](https://doc.rust-lang.org/std/fs/struct.File.html).  This is
+/// needed for operations that shrink a compound file in place.
+pub trait SetLen {
+    /// Truncates or extends the underlying storage to `size` bytes.  If it is
+    /// extended, the new space should be filled with zeroes.
+    fn set_len(&mut self, size: u64) -> io::Result<()>;
+}
+
+#[cfg(feature = "std")]
+impl SetLen for fs::File {
+    fn set_len(&mut self, size: u64) -> io::Result<()> {
+        fs::File::set_len(self, size)
+    }
+}
+
+impl SetLen for Cursor<Vec<u8>> {
+    fn set_len(&mut self, size: u64) -> io::Result<()> {
+        self.get_mut().resize(size as usize, 0);
+        Ok(())
+    }
+}
+
+impl SetLen for Cursor<&mut Vec<u8>> {
+    fn set_len(&mut self, size: u64) -> io::Result<()> {
+        self.get_mut().resize(size as usize, 0);
+        Ok(())
+    }
+}
+
+impl<T: SetLen + ?Sized> SetLen for &mut T {
+    fn set_len(&mut self, size: u64) -> io::Result<()> {
+        (**self).set_len(size)
+    }
+}
+
+//===========================================================================//
@@ -0,0 +1,130 @@
+#[cfg(not(feature = "sync"))]
+use alloc::rc::{Rc, Weak};
+#[cfg(not(feature = "sync"))]
+use core::cell::{Ref, RefCell, RefMut};
+#[cfg(feature = "sync")]
+use std::sync::{
+    Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak,
+};
+
+//===========================================================================//
+
+/// Shared ownership of a compound file's state, by the `CompoundFile` itself
+/// and (through `WeakShared`) by each of its open streams.
+///
+/// By default this is an `Rc<RefCell<T>>`.  With the `sync` feature enabled,
+/// it is an `Arc<RwLock<T>>` instead, so that a compound file and its streams
+/// can be sent to and shared between threads: any number of threads can
+/// borrow the compound file's state at once (e.g. to read streams), while
+/// mutable borrows are exclusive.  Either way, a value must not be borrowed
+/// mutably while it is already borrowed, nor borrowed again while it is
+/// already borrowed mutably.
+#[cfg(not(feature = "sync"))]
+pub struct Shared<T>(Rc<RefCell<T>>);
+
+#[cfg(feature = "sync")]
+pub struct Shared<T>(Arc<RwLock<T>>);
+
+/// A weak reference to a `Shared` value, which doesn't keep it alive.
+#[cfg(not(feature = "sync"))]
+pub struct WeakShared<T>(Weak<RefCell<T>>);
+
+#[cfg(feature = "sync")]
+pub struct WeakShared<T>(Weak<RwLock<T>>);
+
+#[cfg(not(feature = "sync"))]
+pub type SharedRef<'a, T> = Ref<'a, T>;
+
+#[cfg(feature = "sync")]
+pub type SharedRef<'a, T> = RwLockReadGuard<'a, T>;
+
+#[cfg(not(feature = "sync"))]
+pub type SharedRefMut<'a, T> = RefMut<'a, T>;
+
+#[cfg(feature = "sync")]
+pub type SharedRefMut<'a, T> = RwLockWriteGuard<'a, T>;
+
+//===========================================================================//
+
+#[cfg(not(feature = "sync"))]
+impl<T> Shared<T> {
+    pub fn new(value: T) -> Shared<T> {
+        Shared(Rc::new(RefCell::new(value)))
+    }
+
+    pub fn borrow(&self) -> SharedRef<T> {
+        self.0.borrow()
+    }
+
+    pub fn borrow_mut(&self) -> SharedRefMut<T> {
+        self.0.borrow_mut()
+    }
+
+    /// Borrows the value mutably for use in a destructor, which mustn't
+    /// panic: returns `None` if the value is already borrowed.
+    pub fn borrow_mut_for_drop(&self) -> Option<SharedRefMut<T>> {
+        self.0.try_borrow_mut().ok()
+    }
+
+    pub fn downgrade(&self) -> WeakShared<T> {
+        WeakShared(Rc::downgrade(&self.0))
+    }
+
+    /// Returns the value, if this is the only strong reference to it.
+    pub fn try_unwrap(self) -> Result<T, Shared<T>> {
+        Rc::try_unwrap(self.0).map(RefCell::into_inner).map_err(Shared)
+    }
+}
+
+#[cfg(feature = "sync")]
+impl<T> Shared<T> {
+    pub fn new(value: T) -> Shared<T> {
+        Shared(Arc::new(RwLock::new(value)))
+    }
+
+    // A thread that panics while holding the lock may leave the value
+    // partway through an update, just as a panic while it is borrowed from a
+    // `RefCell` would, so poisoning is ignored here for consistency.
+    pub fn borrow(&self) -> SharedRef<T> {
+        self.0.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn borrow_mut(&self) -> SharedRefMut<T> {
+        self.0.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Borrows the value mutably for use in a destructor.  Unlike a
+    /// `RefCell`, the lock can only be held by other threads, so this always
+    /// waits for them to finish with it (rather than skipping whatever the
+    /// destructor needed to do), and never returns `None`.
+    pub fn borrow_mut_for_drop(&self) -> Option<SharedRefMut<T>> {
+        Some(self.borrow_mut())
+    }
+
+    pub fn downgrade(&self) -> WeakShared<T> {
+        WeakShared(Arc::downgrade(&self.0))
+    }
+
+    /// Returns the value, if this is the only strong reference to it.
+    pub fn try_unwrap(self) -> Result<T, Shared<T>> {
+        Arc::try_unwrap(self.0)
+            .map(|lock| {
+                lock.into_inner().unwrap_or_else(PoisonError::into_inner)
+            })
+            .map_err(Shared)
+    }
+}
+
+impl<T> Clone for Shared<T> {
+    fn clone(&self) -> Shared<T> {
+        Shared(self.0.clone())
+    }
+}
+
+impl<T> WeakShared<T> {
+    pub fn upgrade(&self) -> Option<Shared<T>> {
+        self.0.upgrade().map(Shared)
+    }
+}
+
+//===========================================================================//
@@ -0,0 +1,307 @@
+use crate::internal::{
+    consts, reachable_stream_ids, seek_within, MiniAllocator, ObjType,
+    SectorInit, Shared, WeakShared,
+};
+use crate::io::{self, Read, Seek, SeekFrom};
+use alloc::vec::Vec;
+use core::cmp;
+
+//===========================================================================//
+
+/// The kind of unused space that a [`SlackRegion`](struct.SlackRegion.html)
+/// is.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum SlackKind {
+    /// A sector that the FAT marks as free.
+    FreeSector,
+    /// A mini sector, within the mini stream, that the MiniFAT marks as free.
+    FreeMiniSector,
+    /// The part of a stream's chain (of sectors or mini sectors) past the end
+    /// of the stream.  This includes the end of the mini stream itself.
+    StreamTail,
+    /// A directory entry slot that isn't in use.
+    UnallocatedDirEntry,
+}
+
+/// A contiguous range of bytes in a compound file that isn't part of any
+/// live stream or of the file's metadata.  Each region lies within a single
+/// sector.  See `CompoundFile::slack_regions`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SlackRegion {
+    kind: SlackKind,
+    sector_id: u32,
+    offset_within_sector: u64,
+    offset: u64,
+    len: u64,
+}
+
+impl SlackRegion {
+    /// Returns what kind of unused space this region is.
+    pub fn kind(&self) -> SlackKind {
+        self.kind
+    }
+
+    /// Returns the offset of the start of this region within the underlying
+    /// file.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Returns the length of this region, in bytes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Always returns false, since empty regions are never reported.
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+}
+
+//===========================================================================//
+
+/// Returns every slack region in the file, in order of offset.  Streams whose
+/// chains are broken are skipped, rather than causing an error.
+pub fn slack_regions<F>(
+    minialloc: &mut MiniAllocator<F>,
+) -> io::Result<Vec<SlackRegion>> {
+    let sector_len = minialloc.sector_len() as u64;
+    let mini_sector_len = consts::MINI_SECTOR_LEN as u64;
+    // The regions' file offsets are filled in once all the chains they lie
+    // in have been walked (which needs `minialloc` to be borrowed mutably).
+    let mut regions = Vec::<SlackRegion>::new();
+    let mut add = |kind, sector_id: u32, offset_within_sector: u64, len| {
+        if len > 0 {
+            regions.push(SlackRegion {
+                kind,
+                sector_id,
+                offset_within_sector,
+                offset: 0,
+                len,
+            });
+        }
+    };
+
+    let fat = minialloc.fat();
+    for sector_id in 0..minialloc.num_sectors() {
+        let entry = fat.get(sector_id as usize).copied();
+        if entry.unwrap_or(consts::FREE_SECTOR) == consts::FREE_SECTOR {
+            add(SlackKind::FreeSector, sector_id, 0, sector_len);
+        }
+    }
+
+    let dir_start_sector = minialloc.dir_start_sector();
+    let dir_sector_ids = minialloc
+        .open_chain(dir_start_sector, SectorInit::Dir)?
+        .sector_ids()
+        .to_vec();
+    let dir_entries_per_sector = minialloc.version().dir_entries_per_sector();
+    for stream_id in 0..minialloc.num_dir_entries() {
+        if minialloc.dir_entry(stream_id).obj_type == ObjType::Unallocated {
+            let index = stream_id as usize;
+            add(
+                SlackKind::UnallocatedDirEntry,
+                dir_sector_ids[index / dir_entries_per_sector],
+                (index % dir_entries_per_sector * consts::DIR_ENTRY_LEN)
+                    as u64,
+                consts::DIR_ENTRY_LEN as u64,
+            );
+        }
+    }
+
+    // Mini sectors are located by their offset within the mini stream, whose
+    // sectors are each a whole number of mini sectors long.
+    let mini_stream_start_sector = minialloc.root_dir_entry().start_sector;
+    let mini_stream_len = minialloc.root_dir_entry().stream_len;
+    let mini_stream_sector_ids = minialloc
+        .open_chain(mini_stream_start_sector, SectorInit::Fat)?
+        .sector_ids()
+        .to_vec();
+    let locate_mini_sector = |mini_sector: u32| {
+        let offset = mini_sector as u64 * mini_sector_len;
+        let index = (offset / sector_len) as usize;
+        mini_stream_sector_ids
+            .get(index)
+            .map(|&sector_id| (sector_id, offset % sector_len))
+    };
+    let minifat = minialloc.minifat();
+    let num_mini_sectors =
+        (mini_stream_len + mini_sector_len - 1) / mini_sector_len;
+    for mini_sector in 0..num_mini_sectors as u32 {
+        let entry = minifat.get(mini_sector as usize).copied();
+        if entry.unwrap_or(consts::FREE_SECTOR) == consts::FREE_SECTOR {
+            if let Some((sector_id, offset)) = locate_mini_sector(mini_sector)
+            {
+                let start = mini_sector as u64 * mini_sector_len;
+                let len = cmp::min(mini_sector_len, mini_stream_len - start);
+                add(SlackKind::FreeMiniSector, sector_id, offset, len);
+            }
+        }
+    }
+
+    let mut stream_ids: Vec<u32> =
+        reachable_stream_ids(minialloc).into_iter().collect();
+    stream_ids.sort_unstable();
+    for stream_id in stream_ids {
+        let dir_entry = minialloc.dir_entry(stream_id);
+        let (obj_type, start_sector, stream_len) =
+            (dir_entry.obj_type, dir_entry.start_sector, dir_entry.stream_len);
+        if obj_type == ObjType::Root {
+            for (index, offset, len) in chain_tail(
+                &mini_stream_sector_ids,
+                mini_stream_len,
+                sector_len,
+            ) {
+                let sector_id = mini_stream_sector_ids[index];
+                add(SlackKind::StreamTail, sector_id, offset, len);
+            }
+        } else if obj_type != ObjType::Stream {
+            continue;
+        } else if stream_len < consts::MINI_STREAM_CUTOFF as u64 {
+            let mini_sector_ids = match minialloc.open_mini_chain(start_sector)
+            {
+                Ok(chain) => chain.sector_ids().to_vec(),
+                Err(_) => continue,
+            };
+            for (index, offset, len) in
+                chain_tail(&mini_sector_ids, stream_len, mini_sector_len)
+            {
+                let mini_sector = mini_sector_ids[index];
+                if let Some((sector_id, mini_sector_offset)) =
+                    locate_mini_sector(mini_sector)
+                {
+                    let offset = mini_sector_offset + offset;
+                    add(SlackKind::StreamTail, sector_id, offset, len);
+                }
+            }
+        } else {
+            let sector_ids =
+                match minialloc.open_chain(start_sector, SectorInit::Zero) {
+                    Ok(chain) => chain.sector_ids().to_vec(),
+                    Err(_) => continue,
+                };
+            for (index, offset, len) in
+                chain_tail(&sector_ids, stream_len, sector_len)
+            {
+                add(SlackKind::StreamTail, sector_ids[index], offset, len);
+            }
+        }
+    }
+
+    for region in regions.iter_mut() {
+        region.offset = minialloc
+            .sector_offset(region.sector_id, region.offset_within_sector)?;
+    }
+    regions.sort_by_key(SlackRegion::offset);
+    Ok(regions)
+}
+
+/// Returns the parts of a chain of the given sectors that lie past the end of
+/// a stream of length `stream_len`, as (index of the sector within the
+/// chain, offset within the sector, length) triples.  Returns nothing if the
+/// chain is too short for the stream.
+fn chain_tail(
+    sector_ids: &[u32],
+    stream_len: u64,
+    sector_len: u64,
+) -> Vec<(usize, u64, u64)> {
+    let chain_len = sector_ids.len() as u64 * sector_len;
+    let mut tail = Vec::new();
+    let mut offset = stream_len;
+    while offset < chain_len {
+        let offset_within_sector = offset % sector_len;
+        let len = sector_len - offset_within_sector;
+        tail.push(((offset / sector_len) as usize, offset_within_sector, len));
+        offset += len;
+    }
+    tail
+}
+
+//===========================================================================//
+
+/// A read-only view of a sequence of slack regions, as though they were
+/// concatenated into a single stream.  See `CompoundFile::open_slack`.
+///
+/// Like a `Stream`, a `SlackReader` doesn't borrow the `CompoundFile`, but
+/// it reads the regions as they were when it was opened; if the compound file
+/// is modified afterwards, they may no longer be slack.
+pub struct SlackReader<F> {
+    minialloc: WeakShared<MiniAllocator<F>>,
+    regions: Vec<SlackRegion>,
+    /// The offset within the reader of the start of each region.
+    region_starts: Vec<u64>,
+    total_len: u64,
+    position: u64,
+}
+
+impl<F> SlackReader<F> {
+    pub(crate) fn new(
+        minialloc: &Shared<MiniAllocator<F>>,
+        regions: Vec<SlackRegion>,
+    ) -> SlackReader<F> {
+        let mut region_starts = Vec::with_capacity(regions.len());
+        let mut total_len = 0;
+        for region in regions.iter() {
+            region_starts.push(total_len);
+            total_len += region.len;
+        }
+        SlackReader {
+            minialloc: minialloc.downgrade(),
+            regions,
+            region_starts,
+            total_len,
+            position: 0,
+        }
+    }
+
+    /// Returns the regions that this reader reads, in order.
+    pub fn regions(&self) -> &[SlackRegion] {
+        &self.regions
+    }
+
+    /// Returns the total length of the regions, in bytes.
+    pub fn len(&self) -> u64 {
+        self.total_len
+    }
+
+    /// Returns true if there are no regions to read.
+    pub fn is_empty(&self) -> bool {
+        self.total_len == 0
+    }
+}
+
+impl<F: Read + Seek> Read for SlackReader<F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.position >= self.total_len {
+            return Ok(0);
+        }
+        let index = self
+            .region_starts
+            .partition_point(|&start| start <= self.position)
+            - 1;
+        let region = &self.regions[index];
+        let offset_within_region = self.position - self.region_starts[index];
+        let max_len =
+            cmp::min(buf.len() as u64, region.len - offset_within_region);
+        let minialloc = self.minialloc.upgrade().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::Other, "CompoundFile was dropped")
+        })?;
+        let mut minialloc = minialloc.borrow_mut();
+        let mut sector = minialloc.seek_within_sector(
+            region.sector_id,
+            region.offset_within_sector + offset_within_region,
+        )?;
+        let num_bytes = sector.read(&mut buf[..max_len as usize])?;
+        self.position += num_bytes as u64;
+        Ok(num_bytes)
+    }
+}
+
+impl<F> Seek for SlackReader<F> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = seek_within(pos, self.position, self.total_len)?;
+        Ok(self.position)
+    }
+}
+
+//===========================================================================//
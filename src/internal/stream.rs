@@ -1,7 +1,9 @@
-use crate::internal::{consts, MiniAllocator, ObjType, SectorInit};
-use std::cell::RefCell;
-use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
-use std::rc::{Rc, Weak};
+use crate::internal::{
+    consts, MiniAllocator, ObjType, ReadAt, SectorInit, Shared, WeakShared,
+};
+use crate::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+use alloc::boxed::Box;
+use alloc::vec;
 
 //===========================================================================//
 
@@ -11,8 +13,10 @@ const BUFFER_SIZE: usize = 8192;
 
 /// A stream entry in a compound file, much like a filesystem file.
 pub struct Stream<F> {
-    minialloc: Weak<RefCell<MiniAllocator<F>>>,
+    minialloc: WeakShared<MiniAllocator<F>>,
     stream_id: u32,
+    /// The `MiniAllocator::generation` when this stream was opened.
+    generation: u64,
     total_len: u64,
     buffer: Box<[u8; BUFFER_SIZE]>,
     buf_pos: usize,
@@ -23,13 +27,17 @@ pub struct Stream<F> {
 
 impl<F> Stream<F> {
     pub(crate) fn new(
-        minialloc: &Rc<RefCell<MiniAllocator<F>>>,
+        minialloc: &Shared<MiniAllocator<F>>,
         stream_id: u32,
     ) -> Stream<F> {
-        let total_len = minialloc.borrow().dir_entry(stream_id).stream_len;
+        let (generation, total_len) = {
+            let minialloc = minialloc.borrow();
+            (minialloc.generation(), minialloc.dir_entry(stream_id).stream_len)
+        };
         Stream {
-            minialloc: Rc::downgrade(minialloc),
+            minialloc: minialloc.downgrade(),
             stream_id,
+            generation,
             total_len,
             buffer: Box::new([0; BUFFER_SIZE]),
             buf_pos: 0,
@@ -39,10 +47,17 @@ impl<F> Stream<F> {
         }
     }
 
-    fn minialloc(&self) -> io::Result<Rc<RefCell<MiniAllocator<F>>>> {
-        self.minialloc.upgrade().ok_or_else(|| {
+    fn minialloc(&self) -> io::Result<Shared<MiniAllocator<F>>> {
+        let minialloc = self.minialloc.upgrade().ok_or_else(|| {
             io::Error::new(io::ErrorKind::Other, "CompoundFile was dropped")
-        })
+        })?;
+        if minialloc.borrow().generation() != self.generation {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Stream was open when a transaction was committed or reverted",
+            ));
+        }
+        Ok(minialloc)
     }
 
     /// Returns the current length of the stream, in bytes.
@@ -109,12 +124,19 @@ impl<F: Read + Seek> BufRead for Stream<F> {
             self.buf_offset_from_start += self.buf_pos as u64;
             self.buf_pos = 0;
             let minialloc = self.minialloc()?;
-            self.buf_cap = read_data_from_stream(
-                &mut minialloc.borrow_mut(),
-                self.stream_id,
-                self.buf_offset_from_start,
-                &mut self.buffer[..],
-            )?;
+            let mut minialloc = minialloc.borrow_mut();
+            self.buf_cap = loop {
+                let num_bytes = read_data_from_stream(
+                    &mut *minialloc,
+                    self.stream_id,
+                    self.buf_offset_from_start,
+                    &mut self.buffer[..],
+                )?;
+                match num_bytes {
+                    Some(num_bytes) => break num_bytes,
+                    None => minialloc.load_stream_chain(self.stream_id)?,
+                }
+            };
         }
         Ok(&self.buffer[self.buf_pos..self.buf_cap])
     }
@@ -247,7 +269,10 @@ impl<F> Drop for Stream<F> {
 
 //===========================================================================//
 
-trait Flusher<F> {
+// Flushers are stateless, so requiring `Send + Sync` keeps `Stream`
+// thread-safe whenever the underlying file is (as is needed for the `sync`
+// feature).
+trait Flusher<F>: Send + Sync {
     fn flush_changes(&self, stream: &mut Stream<F>) -> io::Result<()>;
 }
 
@@ -272,14 +297,108 @@ impl<F: Read + Write + Seek> Flusher<F> for FlushBuffer {
 
 //===========================================================================//
 
-fn read_data_from_stream<F: Read + Seek>(
-    minialloc: &mut MiniAllocator<F>,
+/// Access to a compound file's sectors for reading stream data.  Shared
+/// access (`&MiniAllocator`) reads with positional reads, and so needs the
+/// underlying file to implement `ReadAt`; exclusive access (`&mut
+/// MiniAllocator`) seeks within the underlying file, and so works with any
+/// `Read + Seek` file.  Either way, the same code finds the sectors to read.
+pub(crate) trait SectorAccess {
+    type File;
+
+    fn minialloc(&self) -> &MiniAllocator<Self::File>;
+
+    /// Reads from the given offset within a sector, stopping at the end of
+    /// that sector.
+    fn read_at_sector(
+        &mut self,
+        sector_id: u32,
+        offset_within_sector: u64,
+        buf: &mut [u8],
+    ) -> io::Result<usize>;
+}
+
+impl<F: ReadAt> SectorAccess for &MiniAllocator<F> {
+    type File = F;
+
+    fn minialloc(&self) -> &MiniAllocator<F> {
+        self
+    }
+
+    fn read_at_sector(
+        &mut self,
+        sector_id: u32,
+        offset_within_sector: u64,
+        buf: &mut [u8],
+    ) -> io::Result<usize> {
+        MiniAllocator::read_at_sector(
+            self,
+            sector_id,
+            offset_within_sector,
+            buf,
+        )
+    }
+}
+
+impl<F: Read + Seek> SectorAccess for &mut MiniAllocator<F> {
+    type File = F;
+
+    fn minialloc(&self) -> &MiniAllocator<F> {
+        self
+    }
+
+    fn read_at_sector(
+        &mut self,
+        sector_id: u32,
+        offset_within_sector: u64,
+        buf: &mut [u8],
+    ) -> io::Result<usize> {
+        self.seek_within_sector(sector_id, offset_within_sector)?.read(buf)
+    }
+}
+
+/// The sectors (or mini sectors) of a chain to read data from.
+#[derive(Clone, Copy)]
+pub(crate) enum ChainSectors<'a> {
+    /// The cached chain starting at the given sector.
+    Cached(u32),
+    /// The given sectors, which needn't be recorded in the FAT at all.
+    Listed(&'a [u32]),
+}
+
+impl<'a> ChainSectors<'a> {
+    /// Returns the chain's sector IDs, or `None` if they aren't cached.
+    fn sector_ids<'b, F>(
+        self,
+        minialloc: &'b MiniAllocator<F>,
+        in_mini_stream: bool,
+    ) -> Option<&'b [u32]>
+    where
+        'a: 'b,
+    {
+        match self {
+            ChainSectors::Cached(start_sector_id) if in_mini_stream => {
+                minialloc.try_cached_mini_chain(start_sector_id)
+            }
+            ChainSectors::Cached(start_sector_id) => {
+                minialloc.try_cached_chain(start_sector_id)
+            }
+            ChainSectors::Listed(sector_ids) => Some(sector_ids),
+        }
+    }
+}
+
+/// Reads stream data at the given offset into `buf`.  Returns `None`
+/// (without reading anything) if the stream's chain (or, for a stream in the
+/// mini stream, the mini stream's chain) isn't cached;
+/// `MiniAllocator::load_stream_chain` loads them.
+pub(crate) fn read_data_from_stream<A: SectorAccess>(
+    access: A,
     stream_id: u32,
     buf_offset_from_start: u64,
     buf: &mut [u8],
-) -> io::Result<usize> {
+) -> io::Result<Option<usize>> {
     let (start_sector, stream_len) = {
-        let dir_entry = minialloc.dir_entry(stream_id);
+        let dir_entry = access.minialloc().dir_entry(stream_id);
         debug_assert_eq!(dir_entry.obj_type, ObjType::Stream);
         (dir_entry.start_sector, dir_entry.stream_len)
     };
@@ -293,22 +412,81 @@ fn read_data_from_stream<F: Read + Seek>(
             buf.len()
         }
     };
-    if num_bytes > 0 {
-        if stream_len < consts::MINI_STREAM_CUTOFF as u64 {
-            let mut chain = minialloc.open_mini_chain(start_sector)?;
-            chain.seek(SeekFrom::Start(buf_offset_from_start))?;
-            chain.read_exact(&mut buf[..num_bytes])?;
+    let in_mini_stream = stream_len < consts::MINI_STREAM_CUTOFF as u64;
+    read_data_from_chain(
+        access,
+        ChainSectors::Cached(start_sector),
+        in_mini_stream,
+        buf_offset_from_start,
+        &mut buf[..num_bytes],
+    )
+}
+
+/// Fills `buf` with the data at the given offset within the given chain of
+/// sectors (or mini sectors, if `in_mini_stream` is true).  Returns `None`
+/// (without reading anything) if a chain that is needed isn't cached.
+pub(crate) fn read_data_from_chain<A: SectorAccess>(
+    mut access: A,
+    chain: ChainSectors<'_>,
+    in_mini_stream: bool,
+    offset_from_start: u64,
+    buf: &mut [u8],
+) -> io::Result<Option<usize>> {
+    let sector_len = if in_mini_stream {
+        consts::MINI_SECTOR_LEN as u64
+    } else {
+        access.minialloc().sector_len() as u64
+    };
+    {
+        let minialloc = access.minialloc();
+        if chain.sector_ids(minialloc, in_mini_stream).is_none()
+            || (in_mini_stream && minialloc.try_cached_mini_stream().is_none())
+        {
+            return Ok(None);
+        }
+    }
+    let mut num_bytes_read = 0;
+    while num_bytes_read < buf.len() {
+        let offset = offset_from_start + num_bytes_read as u64;
+        let index = (offset / sector_len) as usize;
+        let minialloc = access.minialloc();
+        let sector_id = match chain
+            .sector_ids(minialloc, in_mini_stream)
+            .and_then(|sector_ids| sector_ids.get(index))
+        {
+            Some(&sector_id) => sector_id,
+            None => break,
+        };
+        let offset_within_sector = offset % sector_len;
+        let remaining = (sector_len - offset_within_sector) as usize;
+        let max_len = (buf.len() - num_bytes_read).min(remaining);
+        let (sector_id, offset_within_sector) = if in_mini_stream {
+            let (sector_id, mini_sector_start) =
+                minialloc.locate_mini_sector(sector_id)?;
+            (sector_id, mini_sector_start + offset_within_sector)
         } else {
-            let mut chain =
-                minialloc.open_chain(start_sector, SectorInit::Zero)?;
-            chain.seek(SeekFrom::Start(buf_offset_from_start))?;
-            chain.read_exact(&mut buf[..num_bytes])?;
+            (sector_id, offset_within_sector)
+        };
+        let bytes_read = access.read_at_sector(
+            sector_id,
+            offset_within_sector,
+            &mut buf[num_bytes_read..num_bytes_read + max_len],
+        )?;
+        if bytes_read == 0 {
+            break;
         }
+        num_bytes_read += bytes_read;
+    }
+    if num_bytes_read < buf.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "failed to fill whole buffer",
+        ));
     }
-    Ok(num_bytes)
+    Ok(Some(num_bytes_read))
 }
 
-fn write_data_to_stream<F: Read + Write + Seek>(
+pub(crate) fn write_data_to_stream<F: Read + Write + Seek>(
     minialloc: &mut MiniAllocator<F>,
     stream_id: u32,
     buf_offset_from_start: u64,
@@ -373,13 +551,13 @@ fn write_data_to_stream<F: Read + Write + Seek>(
         // Case 3: The stream currently exists in a regular chain.  After the
         // write, it will of course still be too big to be in the mini stream.
         // Therefore, we should write into this stream's existing chain.
+        // (During a transaction, the write may move the chain's first sector.)
         debug_assert!(new_stream_len >= consts::MINI_STREAM_CUTOFF as u64);
         let mut chain =
             minialloc.open_chain(old_start_sector, SectorInit::Zero)?;
         chain.seek(SeekFrom::Start(buf_offset_from_start))?;
         chain.write_all(buf)?;
-        debug_assert_eq!(chain.start_sector_id(), old_start_sector);
-        old_start_sector
+        chain.start_sector_id()
     };
     // Update the directory entry for this stream.
     minialloc.with_dir_entry_mut(stream_id, |dir_entry| {
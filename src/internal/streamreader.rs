@@ -0,0 +1,184 @@
+use crate::internal::stream::{
+    read_data_from_chain, read_data_from_stream, ChainSectors, SectorAccess,
+};
+use crate::internal::{
+    seek_within, MiniAllocator, ReadAt, Shared, WeakShared,
+};
+use crate::io::{self, Read, Seek, SeekFrom};
+use alloc::vec::Vec;
+
+//===========================================================================//
+
+/// A read-only handle to a stream in a compound file, which has no buffer,
+/// and which can read from any offset without moving its own position.
+///
+/// Like a `Stream`, a `StreamReader` doesn't borrow its `CompoundFile`.  When
+/// the underlying file implements [`ReadAt`](trait.ReadAt.html), so does the
+/// reader, and its positional reads need only shared access to the compound
+/// file, so any number of readers can read at once; with the `sync` feature
+/// enabled, a single reader can also be shared between threads, each reading
+/// from it at its own offsets.  Reading through `Read` instead works with any
+/// `Read + Seek` file, but like reading a `Stream`, takes exclusive access to
+/// the compound file for each read.
+///
+/// The reader sees data written through a `Stream` once that stream has been
+/// flushed, but its length is fixed when it is opened.  Like a `Stream`, it
+/// returns an error if the compound file is dropped, or if a transaction is
+/// committed or reverted while it is open.
+pub struct StreamReader<F> {
+    minialloc: WeakShared<MiniAllocator<F>>,
+    /// The `MiniAllocator::generation` when this reader was opened.
+    generation: u64,
+    source: Source,
+    total_len: u64,
+    position: u64,
+}
+
+/// Where a `StreamReader` reads its data from.
+enum Source {
+    /// The stream with the given stream ID.
+    Stream(u32),
+    /// A chain of regular sectors that isn't recorded in the directory.
+    Sectors(Vec<u32>),
+}
+
+impl<F> StreamReader<F> {
+    pub(crate) fn new(
+        minialloc: &Shared<MiniAllocator<F>>,
+        stream_id: u32,
+    ) -> StreamReader<F> {
+        let (generation, total_len) = {
+            let minialloc = minialloc.borrow();
+            (minialloc.generation(), minialloc.dir_entry(stream_id).stream_len)
+        };
+        StreamReader {
+            minialloc: minialloc.downgrade(),
+            generation,
+            source: Source::Stream(stream_id),
+            total_len,
+            position: 0,
+        }
+    }
+
+    pub(crate) fn from_sectors(
+        minialloc: &Shared<MiniAllocator<F>>,
+        sector_ids: Vec<u32>,
+    ) -> StreamReader<F> {
+        let (generation, sector_len) = {
+            let minialloc = minialloc.borrow();
+            (minialloc.generation(), minialloc.sector_len())
+        };
+        let total_len = sector_ids.len() as u64 * sector_len as u64;
+        StreamReader {
+            minialloc: minialloc.downgrade(),
+            generation,
+            source: Source::Sectors(sector_ids),
+            total_len,
+            position: 0,
+        }
+    }
+
+    fn minialloc(&self) -> io::Result<Shared<MiniAllocator<F>>> {
+        let minialloc = self.minialloc.upgrade().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::Other, "CompoundFile was dropped")
+        })?;
+        if minialloc.borrow().generation() != self.generation {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Stream was open when a transaction was committed or reverted",
+            ));
+        }
+        Ok(minialloc)
+    }
+
+    /// Returns the length of the stream, in bytes.
+    pub fn len(&self) -> u64 {
+        self.total_len
+    }
+
+    /// Returns true if the stream is empty.
+    pub fn is_empty(&self) -> bool {
+        self.total_len == 0
+    }
+}
+
+impl<F> StreamReader<F> {
+    /// Reads from the given offset within the stream.  Returns `None` if a
+    /// chain that is needed isn't cached; `load_chain` loads it.
+    fn read_with<A: SectorAccess<File = F>>(
+        &self,
+        access: A,
+        buf: &mut [u8],
+        offset: u64,
+    ) -> io::Result<Option<usize>> {
+        if offset >= self.total_len {
+            return Ok(Some(0));
+        }
+        let max_len = (self.total_len - offset).min(buf.len() as u64);
+        let buf = &mut buf[..max_len as usize];
+        match self.source {
+            Source::Stream(stream_id) => {
+                read_data_from_stream(access, stream_id, offset, buf)
+            }
+            Source::Sectors(ref sector_ids) => read_data_from_chain(
+                access,
+                ChainSectors::Listed(sector_ids),
+                false,
+                offset,
+                buf,
+            ),
+        }
+    }
+
+    fn load_chain(&self, minialloc: &mut MiniAllocator<F>) -> io::Result<()> {
+        match self.source {
+            Source::Stream(stream_id) => {
+                minialloc.load_stream_chain(stream_id)
+            }
+            Source::Sectors(_) => minialloc.load_mini_stream(),
+        }
+    }
+}
+
+impl<F: ReadAt> ReadAt for StreamReader<F> {
+    /// Reads from the given offset within the stream, regardless of the
+    /// reader's current position.  This needs only shared access to the
+    /// compound file, except to load a chain that isn't cached.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let minialloc = self.minialloc()?;
+        loop {
+            if let Some(num_bytes) =
+                self.read_with(&*minialloc.borrow(), buf, offset)?
+            {
+                return Ok(num_bytes);
+            }
+            self.load_chain(&mut minialloc.borrow_mut())?;
+        }
+    }
+}
+
+impl<F: Read + Seek> Read for StreamReader<F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let minialloc = self.minialloc()?;
+        let mut minialloc = minialloc.borrow_mut();
+        let num_bytes = loop {
+            if let Some(num_bytes) =
+                self.read_with(&mut *minialloc, buf, self.position)?
+            {
+                break num_bytes;
+            }
+            self.load_chain(&mut minialloc)?;
+        };
+        self.position += num_bytes as u64;
+        Ok(num_bytes)
+    }
+}
+
+impl<F> Seek for StreamReader<F> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = seek_within(pos, self.position, self.total_len)?;
+        Ok(self.position)
+    }
+}
+
+//===========================================================================//
@@ -0,0 +1,150 @@
+use crate::io;
+use alloc::borrow::Cow;
+use alloc::vec::Vec;
+
+//===========================================================================//
+
+/// An iterator over the sectors of a stream in an in-memory compound file,
+/// as slices borrowed from the compound file's data.  Concatenating the
+/// slices gives the contents of the stream.
+#[derive(Clone)]
+pub struct StreamSlices<'a> {
+    data: &'a [u8],
+    sector_offsets: Vec<u64>,
+    sector_len: usize,
+    next_index: usize,
+    remaining: u64,
+}
+
+impl<'a> StreamSlices<'a> {
+    pub(crate) fn new(
+        data: &'a [u8],
+        sector_offsets: Vec<u64>,
+        sector_len: usize,
+        stream_len: u64,
+    ) -> io::Result<StreamSlices<'a>> {
+        debug_assert!(
+            stream_len <= sector_offsets.len() as u64 * sector_len as u64
+        );
+        let mut remaining = stream_len;
+        for &offset in sector_offsets.iter() {
+            if remaining == 0 {
+                break;
+            }
+            let len = remaining.min(sector_len as u64);
+            if offset + len > data.len() as u64 {
+                corrupted!(
+                    Header {},
+                    "Stream data at offset {} extends past end of file \
+                     ({} bytes)",
+                    offset,
+                    data.len()
+                );
+            }
+            remaining -= len;
+        }
+        Ok(StreamSlices {
+            data,
+            sector_offsets,
+            sector_len,
+            next_index: 0,
+            remaining: stream_len,
+        })
+    }
+
+    /// Returns the remaining contents of the stream, borrowed directly from
+    /// the compound file's data if the remaining sectors are contiguous, or
+    /// copied into a new buffer otherwise.
+    pub(crate) fn into_cow(self) -> Cow<'a, [u8]> {
+        if self.remaining == 0 {
+            return Cow::Borrowed(&[]);
+        }
+        let offsets = &self.sector_offsets[self.next_index..];
+        let num_sectors = ((self.remaining as usize) + self.sector_len - 1)
+            / self.sector_len;
+        let is_contiguous = offsets[..num_sectors]
+            .windows(2)
+            .all(|pair| pair[1] == pair[0] + self.sector_len as u64);
+        if is_contiguous {
+            let start = offsets[0] as usize;
+            Cow::Borrowed(&self.data[start..start + self.remaining as usize])
+        } else {
+            let mut buffer = Vec::with_capacity(self.remaining as usize);
+            for slice in self {
+                buffer.extend_from_slice(slice);
+            }
+            Cow::Owned(buffer)
+        }
+    }
+}
+
+impl<'a> Iterator for StreamSlices<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let start = self.sector_offsets[self.next_index] as usize;
+        let len = self.remaining.min(self.sector_len as u64) as usize;
+        self.next_index += 1;
+        self.remaining -= len as u64;
+        Some(&self.data[start..start + len])
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = ((self.remaining as usize) + self.sector_len - 1)
+            / self.sector_len;
+        (len, Some(len))
+    }
+}
+
+impl<'a> ExactSizeIterator for StreamSlices<'a> {}
+
+//===========================================================================//
+
+#[cfg(test)]
+mod tests {
+    use super::StreamSlices;
+    use std::borrow::Cow;
+
+    fn make_data() -> Vec<u8> {
+        (0..16).collect()
+    }
+
+    #[test]
+    fn contiguous_sectors_are_borrowed() {
+        let data = make_data();
+        let slices = StreamSlices::new(&data, vec![4, 8, 12], 4, 10).unwrap();
+        assert_eq!(slices.len(), 3);
+        let collected: Vec<&[u8]> = slices.clone().collect();
+        assert_eq!(collected, vec![&data[4..8], &data[8..12], &data[12..14]]);
+        match slices.into_cow() {
+            Cow::Borrowed(bytes) => assert_eq!(bytes, &data[4..14]),
+            Cow::Owned(_) => panic!("contiguous stream should be borrowed"),
+        }
+    }
+
+    #[test]
+    fn fragmented_sectors_are_copied() {
+        let data = make_data();
+        let slices = StreamSlices::new(&data, vec![8, 0, 12], 4, 10).unwrap();
+        let collected: Vec<&[u8]> = slices.clone().collect();
+        assert_eq!(collected, vec![&data[8..12], &data[0..4], &data[12..14]]);
+        match slices.into_cow() {
+            Cow::Borrowed(_) => panic!("fragmented stream should be copied"),
+            Cow::Owned(bytes) => {
+                assert_eq!(bytes, vec![8, 9, 10, 11, 0, 1, 2, 3, 12, 13])
+            }
+        }
+    }
+
+    #[test]
+    fn data_past_end_of_file() {
+        let data = make_data();
+        assert!(StreamSlices::new(&data, vec![8, 14], 4, 6).is_ok());
+        assert!(StreamSlices::new(&data, vec![8, 14], 4, 7).is_err());
+    }
+}
+
+//===========================================================================//
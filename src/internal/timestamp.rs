@@ -1,5 +1,6 @@
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use std::io::{self, Read, Write};
+use crate::io::{self, Read, ReadBytesExt, Write, WriteBytesExt};
+use byteorder::LittleEndian;
+#[cfg(feature = "std")]
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 //===========================================================================//
@@ -22,16 +23,26 @@ impl Timestamp {
     }
 
     /// Returns a timestamp representing the current system time.
+    #[cfg(feature = "std")]
     pub fn now() -> Timestamp {
         Timestamp::from_system_time(SystemTime::now())
     }
 
+    /// Without the standard library there is no system clock to read, so new
+    /// objects get a zero timestamp.
+    #[cfg(not(feature = "std"))]
+    pub fn now() -> Timestamp {
+        Timestamp::zero()
+    }
+
     /// Returns a timestamp representing the given system time.
+    #[cfg(feature = "std")]
     pub fn from_system_time(system_time: SystemTime) -> Timestamp {
         Timestamp(timestamp_from_system_time(system_time))
     }
 
     /// Returns the local system time that this timestamp represents.
+    #[cfg(feature = "std")]
     pub fn to_system_time(self) -> SystemTime {
         system_time_from_timestamp(self.0)
     }
@@ -48,9 +59,11 @@ impl Timestamp {
 //===========================================================================//
 
 /// The CFB timestamp value for the Unix epoch (Jan 1, 1970 UTC).
+#[cfg(feature = "std")]
 const UNIX_EPOCH_TIMESTAMP: u64 = 116444736000000000;
 
 /// Converts a local `SystemTime` to a CFB file timestamp value.
+#[cfg(feature = "std")]
 fn timestamp_from_system_time(system_time: SystemTime) -> u64 {
     match system_time.duration_since(UNIX_EPOCH) {
         Ok(duration) => {
@@ -65,6 +78,7 @@ fn timestamp_from_system_time(system_time: SystemTime) -> u64 {
 }
 
 /// Converts a CFB file timestamp value to a local `SystemTime`.
+#[cfg(feature = "std")]
 fn system_time_from_timestamp(timestamp: u64) -> SystemTime {
     // The maximum range of SystemTime varies by system, and some systems
     // (e.g. 32-bit Linux) can't represent, say, a zero CFB timestamp.  So we
@@ -90,6 +104,7 @@ fn system_time_from_timestamp(timestamp: u64) -> SystemTime {
     system_time.unwrap_or(UNIX_EPOCH)
 }
 
+#[cfg(feature = "std")]
 fn duration_to_timestamp_delta(duration: Duration) -> u64 {
     duration
         .as_secs()
@@ -97,6 +112,7 @@ fn duration_to_timestamp_delta(duration: Duration) -> u64 {
         .saturating_add((duration.subsec_nanos() / 100) as u64)
 }
 
+#[cfg(feature = "std")]
 fn timestamp_delta_to_duration(delta: u64) -> Duration {
     Duration::new(delta / 10_000_000, (delta % 10_000_000) as u32 * 100)
 }
@@ -1,22 +1,310 @@
+use crate::internal::Error;
+use crate::io;
+use alloc::vec::Vec;
+use core::fmt;
+
 //===========================================================================//
 
-/// A parsing validation strategy.
+/// Which deviations from the CFB spec to tolerate when parsing a compound
+/// file.
+///
+/// Each field allows one kind of deviation that turns up in CFB files in the
+/// wild; when a field is false, that deviation is an error.  Deviations that
+/// are allowed are ignored, or repaired in memory, as each field describes.
+/// The [`permissive`](#method.permissive) and [`strict`](#method.strict)
+/// presets (which are what `CompoundFile::open` and
+/// `CompoundFile::open_strict` use) are the starting point for a custom
+/// policy:
+///
+/// ```
+/// let mut policy = cfb::ValidationPolicy::strict();
+/// policy.allow_zero_padded_fat = true;
+/// policy.allow_adjacent_red_nodes = true;
+/// ```
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub struct ValidationPolicy {
+    /// Allow non-zero bytes in the header CLSID and the other reserved
+    /// fields of the header, which MS-CFB section 2.2 says must be zero.
+    pub allow_nonzero_reserved_fields: bool,
+    /// Allow a non-zero number of directory sectors in the header of a
+    /// version 3 file (MS-CFB section 2.2); the field is treated as zero.
+    pub allow_v3_num_dir_sectors: bool,
+    /// Allow the header to use FREE_SECTOR, rather than END_OF_CHAIN, to
+    /// indicate that there are no DIFAT sectors.
+    pub allow_free_sector_as_end_of_chain: bool,
+    /// Allow the header's counts of DIFAT, FAT, and MiniFAT sectors to
+    /// disagree with the actual chains; the chains are used as they are.
+    pub allow_wrong_sector_counts: bool,
+    /// Allow DIFAT and FAT sectors that aren't marked as such in the FAT; the
+    /// FAT entries are repaired.
+    pub allow_unmarked_fat_sectors: bool,
+    /// Allow the last FAT sector to be padded with zeros, rather than with
+    /// FREE_SECTOR, past the end of the file (see
+    /// <https://github.com/mdsteele/rust-cfb/issues/8>); the padding is
+    /// dropped.
+    pub allow_zero_padded_fat: bool,
+    /// Allow a MiniFAT with more entries than the mini stream has mini
+    /// sectors; the extra entries are dropped.
+    pub allow_long_minifat: bool,
+    /// Allow directory entry names that aren't null-terminated.
+    pub allow_unterminated_names: bool,
+    /// Allow directory entry name lengths that are an odd number of bytes;
+    /// the length is rounded up to a whole number of UTF-16 characters.
+    pub allow_odd_name_lengths: bool,
+    /// Allow a root entry with a name other than "Root Entry"; the name is
+    /// replaced.
+    pub allow_wrong_root_name: bool,
+    /// Allow stream entries with a non-nil CLSID; it is treated as nil.
+    pub allow_stream_clsids: bool,
+    /// Allow stream entries with non-zero creation or modified times; they
+    /// are treated as zero.
+    pub allow_stream_timestamps: bool,
+    /// Allow storage entries with a non-zero starting sector or stream
+    /// length; they are treated as zero.
+    pub allow_storage_stream_fields: bool,
+    /// Allow two adjacent red nodes in the red-black tree of a storage's
+    /// children (see <https://github.com/mdsteele/rust-cfb/issues/10>).
+    pub allow_adjacent_red_nodes: bool,
+}
+
+impl ValidationPolicy {
+    /// Returns the policy used by `CompoundFile::open`, which allows every
+    /// deviation except for odd name lengths.
+    pub const fn permissive() -> ValidationPolicy {
+        ValidationPolicy {
+            allow_nonzero_reserved_fields: true,
+            allow_v3_num_dir_sectors: true,
+            allow_free_sector_as_end_of_chain: true,
+            allow_wrong_sector_counts: true,
+            allow_unmarked_fat_sectors: true,
+            allow_zero_padded_fat: true,
+            allow_long_minifat: true,
+            allow_unterminated_names: true,
+            allow_odd_name_lengths: false,
+            allow_wrong_root_name: true,
+            allow_stream_clsids: true,
+            allow_stream_timestamps: true,
+            allow_storage_stream_fields: true,
+            allow_adjacent_red_nodes: true,
+        }
+    }
+
+    /// Returns the policy used by `CompoundFile::open_strict`, which allows
+    /// no deviations at all.
+    pub const fn strict() -> ValidationPolicy {
+        ValidationPolicy {
+            allow_nonzero_reserved_fields: false,
+            allow_v3_num_dir_sectors: false,
+            allow_free_sector_as_end_of_chain: false,
+            allow_wrong_sector_counts: false,
+            allow_unmarked_fat_sectors: false,
+            allow_zero_padded_fat: false,
+            allow_long_minifat: false,
+            allow_unterminated_names: false,
+            allow_odd_name_lengths: false,
+            allow_wrong_root_name: false,
+            allow_stream_clsids: false,
+            allow_stream_timestamps: false,
+            allow_storage_stream_fields: false,
+            allow_adjacent_red_nodes: false,
+        }
+    }
+}
+
+impl Default for ValidationPolicy {
+    fn default() -> ValidationPolicy {
+        ValidationPolicy::permissive()
+    }
+}
+
+//===========================================================================//
+
+/// How serious a spec violation is.
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
-pub enum Validation {
-    /// As much as possible, spec violations will be ignored when parsing.
-    Permissive,
-    /// Any violation of the CFB spec will be treated as an error when parsing.
-    Strict,
+pub enum Severity {
+    /// A deviation that the permissive validation policy allows (by ignoring
+    /// it, or by repairing it in memory), but that the strict one rejects.
+    Warning,
+    /// A violation that makes the file, or some part of it, unreadable under
+    /// the permissive validation policy.
+    Error,
 }
 
-impl Validation {
-    /// Returns true for `Strict` validation, false otherwise.
-    pub fn is_strict(self) -> bool {
+impl fmt::Display for Severity {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Validation::Permissive => false,
-            Validation::Strict => true,
+            Severity::Warning => formatter.write_str("warning"),
+            Severity::Error => formatter.write_str("error"),
+        }
+    }
+}
+
+/// A single violation of the CFB spec found within a compound file.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Violation {
+    severity: Severity,
+    error: Error,
+}
+
+impl Violation {
+    pub(crate) fn new(severity: Severity, error: Error) -> Violation {
+        Violation { severity, error }
+    }
+
+    /// Returns how serious this violation is.
+    pub fn severity(&self) -> Severity {
+        self.severity
+    }
+
+    /// Returns a description of the violation, including where in the file
+    /// it was found.
+    pub fn error(&self) -> &Error {
+        &self.error
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "{}: {}", self.severity, self.error)
+    }
+}
+
+//===========================================================================//
+
+/// Applies a validation policy while parsing a compound file, and keeps track
+/// of the spec violations found along the way.
+pub struct Validator {
+    policy: ValidationPolicy,
+    exhaustive: bool,
+    stream_id: Option<u32>,
+    violations: Vec<Violation>,
+}
+
+impl Validator {
+    pub fn new(policy: ValidationPolicy) -> Validator {
+        Validator {
+            policy,
+            exhaustive: false,
+            stream_id: None,
+            violations: Vec::new(),
+        }
+    }
+
+    /// Returns a validator that applies the permissive policy, but that
+    /// records errors rather than stopping at the first one, wherever parsing
+    /// can carry on past them (see `finish_stage`).
+    pub fn exhaustive() -> Validator {
+        Validator {
+            policy: ValidationPolicy::permissive(),
+            exhaustive: true,
+            stream_id: None,
+            violations: Vec::new(),
+        }
+    }
+
+    pub fn policy(&self) -> &ValidationPolicy {
+        &self.policy
+    }
+
+    /// Sets the directory entry that is currently being parsed, if any.
+    /// Directory errors reported while this is set, which don't otherwise say
+    /// which entry they are about, are attributed to this one.
+    pub fn set_stream_id(&mut self, stream_id: Option<u32>) {
+        self.stream_id = stream_id;
+    }
+
+    fn locate(&self, error: Error) -> Error {
+        match (error, self.stream_id) {
+            (
+                Error::Directory { stream_id: None, path, message },
+                Some(stream_id),
+            ) => {
+                Error::Directory { stream_id: Some(stream_id), path, message }
+            }
+            (error, _) => error,
+        }
+    }
+
+    /// Reports a deviation from the spec that the policy may allow, as given
+    /// by `allowed`.  If allowed, this records the deviation as a warning and
+    /// returns `Ok`, and the caller should go on to ignore or repair the
+    /// problem.  Otherwise, it is rejected (see `reject`), but the caller
+    /// should still repair the problem if `Ok` is returned.
+    pub fn tolerate(&mut self, allowed: bool, error: Error) -> io::Result<()> {
+        if !allowed {
+            return self.reject(error);
+        }
+        self.warn(error);
+        Ok(())
+    }
+
+    /// Records a deviation from the spec as a warning, for a caller that has
+    /// already checked that the policy allows it.
+    pub fn warn(&mut self, error: Error) {
+        let error = self.locate(error);
+        self.violations.push(Violation::new(Severity::Warning, error));
+    }
+
+    /// Reports a spec violation that can't be tolerated.  Normally, this
+    /// returns the violation as an error; an exhaustive validator instead
+    /// records it and returns `Ok`, in which case the caller should skip past
+    /// the problem and keep looking for others.
+    pub fn reject(&mut self, error: Error) -> io::Result<()> {
+        let error = self.locate(error);
+        if !self.exhaustive {
+            return Err(error.into());
+        }
+        self.violations.push(Violation::new(Severity::Error, error));
+        Ok(())
+    }
+
+    /// Like `reject`, but for an error that a parsing step has already
+    /// returned.  Errors other than spec violations (such as I/O failures)
+    /// are always passed back.
+    pub fn reject_io(&mut self, error: io::Error) -> io::Result<()> {
+        match Error::from_io(&error) {
+            Some(violation) => self.reject(violation.clone()),
+            None => Err(error),
         }
     }
+
+    /// Returns the first error recorded so far, if any.  This must be called
+    /// before any step of parsing that relies on the checks that `reject`
+    /// reported on.
+    pub fn finish_stage(&self) -> io::Result<()> {
+        match self.first_error() {
+            Some(error) => Err(error.clone().into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the first error recorded so far, if any.
+    pub fn first_error(&self) -> Option<&Error> {
+        self.violations
+            .iter()
+            .find(|violation| violation.severity == Severity::Error)
+            .map(Violation::error)
+    }
+
+    /// Returns the deviations that have been tolerated so far.
+    pub fn warnings(&self) -> Vec<Violation> {
+        self.violations
+            .iter()
+            .filter(|violation| violation.severity == Severity::Warning)
+            .cloned()
+            .collect()
+    }
+
+    pub fn into_violations(self) -> Vec<Violation> {
+        self.violations
+    }
+}
+
+impl From<ValidationPolicy> for Validator {
+    fn from(policy: ValidationPolicy) -> Validator {
+        Validator::new(policy)
+    }
 }
 
 //===========================================================================//
@@ -0,0 +1,31 @@
+//===========================================================================//
+
+/// A strategy for when changes to a compound file's allocation tables and
+/// directory are written to the underlying file.
+#[derive(
+    Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd,
+)]
+pub enum WriteMode {
+    /// Every change to a FAT, MiniFAT, or directory entry is written to the
+    /// underlying file immediately.  This is the default.
+    #[default]
+    WriteThrough,
+    /// Changes to the FAT, MiniFAT, and directory entries are kept in memory,
+    /// and each modified sector is written to the underlying file only once,
+    /// when the compound file is flushed or dropped.  This greatly reduces
+    /// the number of small writes made when building large files, but means
+    /// that the underlying file may be inconsistent until it is flushed.
+    WriteBack,
+}
+
+impl WriteMode {
+    /// Returns true for `WriteBack` mode, false otherwise.
+    pub fn is_write_back(self) -> bool {
+        match self {
+            WriteMode::WriteThrough => false,
+            WriteMode::WriteBack => true,
+        }
+    }
+}
+
+//===========================================================================//
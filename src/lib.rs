@@ -44,24 +44,54 @@
 //! ```
 
 #![warn(missing_docs)]
-
-use std::cell::{Ref, RefCell, RefMut};
+// Keep the pre-1.87 spellings (`x % n == 0`, `(x + n - 1) / n`, elided
+// lifetimes in return types) that newer toolchains lint against.
+#![allow(unknown_lints)]
+#![allow(mismatched_lifetime_syntaxes)]
+#![allow(clippy::io_other_error)]
+#![allow(clippy::manual_div_ceil)]
+#![allow(clippy::manual_is_multiple_of)]
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+use alloc::borrow::Cow;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ops::{Deref, DerefMut};
+#[cfg(feature = "std")]
 use std::fs;
-use std::io::{self, Read, Seek, SeekFrom, Write};
-use std::mem::size_of;
-use std::path::{Path, PathBuf};
-use std::rc::Rc;
 
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use fnv::FnvHashSet;
+use crate::io::{
+    Cursor, Read, ReadBytesExt, Seek, SeekFrom, Write, WriteBytesExt,
+};
+
+use byteorder::LittleEndian;
 use uuid::Uuid;
 
 use crate::internal::consts;
+pub use crate::internal::io;
+pub use crate::internal::path::ObjectPath;
+#[cfg(feature = "std")]
+use crate::internal::path::Path;
+use crate::internal::path::PathBuf;
+pub use crate::internal::raw;
+pub use crate::internal::{
+    check, repair, Entries, Entry, Error, ImportReport, JournalFile, Loss,
+    OrphanedChain, OrphanedEntry, ReadAt, RepairReport, Report, SetLen,
+    Severity, SlackKind, SlackReader, SlackRegion, Stream, StreamReader,
+    StreamSlices, ValidationPolicy, Version, Violation, WriteMode,
+};
 use crate::internal::{
-    Allocator, DirEntry, Directory, EntriesOrder, Header, MiniAllocator,
-    ObjType, SectorInit, Sectors, Timestamp, Validation,
+    Allocator, DirEntry, Directory, EntriesOrder, Header, IdSet, Journal,
+    MiniAllocator, ObjType, SectorInit, Sectors, Shared, SharedRef,
+    SharedRefMut, Timestamp, Validator,
 };
-pub use crate::internal::{Entries, Entry, Stream, Version};
+#[cfg(feature = "async")]
+pub use crate::internal::{AsyncCompoundFile, AsyncStream, SectorCache};
+#[cfg(feature = "mmap")]
+pub use crate::internal::{MmapFile, MmapFileMut};
 
 #[macro_use]
 mod internal;
@@ -69,28 +99,82 @@ mod internal;
 //===========================================================================//
 
 /// Opens an existing compound file at the given path in read-only mode.
+#[cfg(feature = "std")]
 pub fn open<P: AsRef<Path>>(path: P) -> io::Result<CompoundFile<fs::File>> {
     CompoundFile::open(fs::File::open(path)?)
 }
 
 /// Opens an existing compound file at the given path in read-write mode.
+#[cfg(feature = "std")]
 pub fn open_rw<P: AsRef<Path>>(path: P) -> io::Result<CompoundFile<fs::File>> {
     open_rw_with_path(path.as_ref())
 }
 
+#[cfg(feature = "std")]
 fn open_rw_with_path(path: &Path) -> io::Result<CompoundFile<fs::File>> {
     let file = fs::OpenOptions::new().read(true).write(true).open(path)?;
     CompoundFile::open(file)
 }
 
+/// Opens an existing compound file at the given path in read-write mode, with
+/// journaling enabled (see [`CompoundFile::open_with_journal`](
+/// struct.CompoundFile.html#method.open_with_journal)).  The journal is kept
+/// in a sidecar file whose path is that of the compound file with
+/// `-journal` appended, which is created if it doesn't already exist.
+#[cfg(feature = "std")]
+pub fn open_rw_with_journal<P: AsRef<Path>>(
+    path: P,
+) -> io::Result<CompoundFile<fs::File>> {
+    open_rw_with_journal_with_path(path.as_ref())
+}
+
+#[cfg(feature = "std")]
+fn open_rw_with_journal_with_path(
+    path: &Path,
+) -> io::Result<CompoundFile<fs::File>> {
+    let file = fs::OpenOptions::new().read(true).write(true).open(path)?;
+    let mut journal_path = path.as_os_str().to_owned();
+    journal_path.push("-journal");
+    let journal = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(journal_path)?;
+    CompoundFile::open_with_journal(file, journal)
+}
+
+/// Opens an existing compound file at the given path in read-only mode,
+/// accessing it through a memory map (see [`MmapFile`](struct.MmapFile.html))
+/// rather than with a system call for each read.
+#[cfg(feature = "mmap")]
+pub fn open_mmap<P: AsRef<Path>>(
+    path: P,
+) -> io::Result<CompoundFile<MmapFile>> {
+    CompoundFile::open(MmapFile::open(path)?)
+}
+
+/// Opens an existing compound file at the given path in read-write mode,
+/// accessing it through a memory map (see
+/// [`MmapFileMut`](struct.MmapFileMut.html)) rather than with a system call
+/// for each read or write.
+#[cfg(feature = "mmap")]
+pub fn open_rw_mmap<P: AsRef<Path>>(
+    path: P,
+) -> io::Result<CompoundFile<MmapFileMut>> {
+    CompoundFile::open(MmapFileMut::open(path)?)
+}
+
 /// Creates a new compound file with no contents at the given path.
 ///
 /// The returned `CompoundFile` object will be both readable and writable.  If
 /// a file already exists at the given path, this will overwrite it.
+#[cfg(feature = "std")]
 pub fn create<P: AsRef<Path>>(path: P) -> io::Result<CompoundFile<fs::File>> {
     create_with_path(path.as_ref())
 }
 
+#[cfg(feature = "std")]
 fn create_with_path(path: &Path) -> io::Result<CompoundFile<fs::File>> {
     let file = fs::OpenOptions::new()
         .read(true)
@@ -106,16 +190,37 @@ fn create_with_path(path: &Path) -> io::Result<CompoundFile<fs::File>> {
 /// A compound file, backed by an underlying reader/writer (such as a
 /// [`File`](https://doc.rust-lang.org/std/fs/struct.File.html) or
 /// [`Cursor`](https://doc.rust-lang.org/std/io/struct.Cursor.html)).
+///
+/// By default, a `CompoundFile` and its `Stream`s are confined to a single
+/// thread.  With the `sync` cargo feature enabled, they are `Send` and `Sync`
+/// whenever the underlying reader/writer is `Send` and `Sync`.  Streams are
+/// opened through a shared reference, and a `Stream` doesn't borrow its
+/// `CompoundFile`, so worker threads can share an `Arc<CompoundFile<F>>`,
+/// each opening and then reading or writing its own streams concurrently.
+/// Methods that change the directory (such as `create_stream`) still take
+/// `&mut self`, so threads that need those must share the `CompoundFile`
+/// behind a `Mutex` or `RwLock` instead.
+///
+/// Reading a `Stream` takes exclusive access to the underlying file (and to
+/// the allocation tables and directory) for the duration of each read, since
+/// it seeks within the file.  When the underlying file also implements
+/// [`ReadAt`](trait.ReadAt.html), streams can instead be opened with
+/// [`open_stream_reader`](#method.open_stream_reader), whose positional reads
+/// share access to the file, and with the `sync` feature can run in
+/// parallel.
 pub struct CompoundFile<F> {
-    minialloc: Rc<RefCell<MiniAllocator<F>>>,
+    minialloc: Shared<MiniAllocator<F>>,
+    /// The deviations from the CFB spec that were tolerated when opening the
+    /// file.
+    warnings: Vec<Violation>,
 }
 
 impl<F> CompoundFile<F> {
-    fn minialloc(&self) -> Ref<MiniAllocator<F>> {
+    fn minialloc(&self) -> SharedRef<MiniAllocator<F>> {
         self.minialloc.borrow()
     }
 
-    fn minialloc_mut(&mut self) -> RefMut<MiniAllocator<F>> {
+    fn minialloc_mut(&mut self) -> SharedRefMut<MiniAllocator<F>> {
         self.minialloc.borrow_mut()
     }
 
@@ -124,10 +229,184 @@ impl<F> CompoundFile<F> {
         self.minialloc().version()
     }
 
+    /// Returns every deviation from the CFB spec that the validation policy
+    /// tolerated when this compound file was opened (such as FAT entries that
+    /// were repaired, or header fields that were ignored), in the order in
+    /// which they were found.  This is empty for a file that was opened with
+    /// `open_strict`, or created rather than opened.
+    pub fn warnings(&self) -> &[Violation] {
+        &self.warnings
+    }
+
+    /// Returns every directory entry that is allocated but can't be reached
+    /// from the root storage, in stream ID order.  Such entries are invisible
+    /// to every other method, but other CFB implementations sometimes leave
+    /// them behind (with their data intact) when removing objects.  Orphaned
+    /// streams can be read with
+    /// [`open_orphaned_entry`](#method.open_orphaned_entry).
+    ///
+    /// Entries removed by this crate are zeroed, and so are never orphaned.
+    pub fn orphaned_entries(&self) -> Vec<OrphanedEntry> {
+        internal::orphaned_entries(&self.minialloc())
+    }
+
+    /// Returns every chain of allocated sectors that doesn't belong to the
+    /// file's own metadata or to any stream in the directory (including
+    /// orphaned ones), in order of starting sector.  Such chains typically
+    /// hold the data of streams whose directory entries were removed without
+    /// their chains being freed.  They can be read with
+    /// [`open_orphaned_chain`](#method.open_orphaned_chain).
+    ///
+    /// Only regular sectors are considered; the data of small streams, which
+    /// lives in the mini stream, isn't found this way.
+    pub fn orphaned_chains(&mut self) -> io::Result<Vec<OrphanedChain>> {
+        internal::orphaned_chains(&mut self.minialloc_mut())
+    }
+
+    /// Returns every region of the underlying file that holds neither stream
+    /// data nor metadata, in order of offset: free sectors, free mini
+    /// sectors, unused directory entry slots, and the parts of the last
+    /// sector (or mini sector) of each stream past the end of the stream.
+    /// These regions often still hold data from removed or truncated
+    /// streams, and can be read with [`open_slack`](#method.open_slack).
+    pub fn slack_regions(&mut self) -> io::Result<Vec<SlackRegion>> {
+        internal::slack_regions(&mut self.minialloc_mut())
+    }
+
+    /// Opens a reader over the given slack regions (see
+    /// [`slack_regions`](#method.slack_regions)), which reads them one after
+    /// another as though they were a single stream.
+    pub fn open_slack(&self, regions: Vec<SlackRegion>) -> SlackReader<F> {
+        SlackReader::new(&self.minialloc, regions)
+    }
+
+    /// Returns the DIFAT, i.e. the IDs of the sectors that hold the FAT, in
+    /// order.  The first 109 of these are stored in the header, and the rest
+    /// in the sectors returned by
+    /// [`raw_difat_sector_ids`](#method.raw_difat_sector_ids).
+    pub fn raw_difat(&self) -> Vec<u32> {
+        self.minialloc().difat().to_vec()
+    }
+
+    /// Returns the IDs of the sectors in the DIFAT chain, in order.  This is
+    /// empty unless the FAT has more than 109 sectors.
+    pub fn raw_difat_sector_ids(&self) -> Vec<u32> {
+        self.minialloc().difat_sector_ids().to_vec()
+    }
+
+    /// Returns the FAT, with one entry per sector: either the ID of the next
+    /// sector in the chain, or one of the special values in the
+    /// [`raw`](raw/index.html) module.  Free entries at the end of the last
+    /// FAT sector may be omitted.
+    pub fn raw_fat(&self) -> Vec<u32> {
+        self.minialloc().fat().to_vec()
+    }
+
+    /// Returns the MiniFAT, with one entry per mini sector of the mini
+    /// stream.  Free entries at the end of the last MiniFAT sector may be
+    /// omitted.
+    pub fn raw_minifat(&self) -> Vec<u32> {
+        self.minialloc().minifat().to_vec()
+    }
+
+    /// Returns every directory entry slot, including unallocated ones and
+    /// ones not reachable from the root, in stream ID order.  The entries
+    /// reflect any changes made since the file was opened, and any
+    /// deviations from the spec that were tolerated when opening it (see
+    /// [`warnings`](#method.warnings)) may have been corrected.
+    pub fn raw_dir_entries(&self) -> Vec<raw::DirEntry> {
+        let minialloc = self.minialloc();
+        (0..minialloc.num_dir_entries())
+            .map(|stream_id| {
+                raw::DirEntry::new(stream_id, minialloc.dir_entry(stream_id))
+            })
+            .collect()
+    }
+
     fn stream_id_for_name_chain(&self, names: &[&str]) -> Option<u32> {
         self.minialloc().stream_id_for_name_chain(names)
     }
 
+    /// Returns the offset within the underlying file of the start of each
+    /// sector (or mini sector) of the stream at the given path, along with the
+    /// length of those sectors and the length of the stream.
+    fn stream_sector_offsets(
+        &mut self,
+        path: &dyn ObjectPath,
+    ) -> io::Result<(Vec<u64>, usize, u64)> {
+        let names = path.name_chain()?;
+        let stream_id = self.stream_id_for_path(path)?;
+        self.stream_sector_offsets_for_id(stream_id).map_err(|error| {
+            let path = internal::path::path_from_name_chain(&names);
+            Error::io_at_path(error, &path)
+        })
+    }
+
+    /// Returns the file offsets of the (mini or regular) sectors holding the
+    /// given stream's data, along with the sector length and the stream
+    /// length.
+    fn stream_sector_offsets_for_id(
+        &mut self,
+        stream_id: u32,
+    ) -> io::Result<(Vec<u64>, usize, u64)> {
+        let mut minialloc = self.minialloc_mut();
+        let dir_entry = minialloc.dir_entry(stream_id);
+        let name = dir_entry.name.clone();
+        let start_sector = dir_entry.start_sector;
+        let stream_len = dir_entry.stream_len;
+        let (sector_len, sector_offsets) =
+            if stream_len < consts::MINI_STREAM_CUTOFF as u64 {
+                let mut chain = minialloc.open_mini_chain(start_sector)?;
+                (consts::MINI_SECTOR_LEN, chain.sector_offsets()?)
+            } else {
+                let sector_len = minialloc.sector_len();
+                let chain =
+                    minialloc.open_chain(start_sector, SectorInit::Zero)?;
+                (sector_len, chain.sector_offsets()?)
+            };
+        if (sector_offsets.len() as u64) * (sector_len as u64) < stream_len {
+            corrupted!(
+                Directory { stream_id: Some(stream_id), path: None },
+                "Stream {:?} is {} bytes long, but its chain has only {} \
+                 sectors",
+                name,
+                stream_len,
+                sector_offsets.len()
+            );
+        }
+        Ok((sector_offsets, sector_len, stream_len))
+    }
+
+    /// Returns the stream ID of the stream object at the given path.
+    fn stream_id_for_path(&self, path: &dyn ObjectPath) -> io::Result<u32> {
+        let names = path.name_chain()?;
+        let path = internal::path::path_from_name_chain(&names);
+        let stream_id = match self.stream_id_for_name_chain(&names) {
+            Some(stream_id) => stream_id,
+            None => not_found!("No such stream: {:?}", path),
+        };
+        if self.minialloc().dir_entry(stream_id).obj_type != ObjType::Stream {
+            invalid_input!("Not a stream: {:?}", path);
+        }
+        Ok(stream_id)
+    }
+
+    /// Returns the stream ID of the storage object that a new entry with the
+    /// given parent name chain should be created under.
+    fn parent_storage_id(&self, names: &[&str]) -> io::Result<u32> {
+        let parent_id = match self.stream_id_for_name_chain(names) {
+            Some(parent_id) => parent_id,
+            None => not_found!("Parent storage doesn't exist"),
+        };
+        if self.minialloc().dir_entry(parent_id).obj_type == ObjType::Stream {
+            invalid_input!(
+                "Not a storage: {:?}",
+                internal::path::path_from_name_chain(names)
+            );
+        }
+        Ok(parent_id)
+    }
+
     /// Returns information about the root storage object.  This is equivalent
     /// to `self.entry("/").unwrap()` (but always succeeds).
     pub fn root_entry(&self) -> Entry {
@@ -136,12 +415,12 @@ impl<F> CompoundFile<F> {
 
     /// Given a path within the compound file, get information about that
     /// stream or storage object.
-    pub fn entry<P: AsRef<Path>>(&self, path: P) -> io::Result<Entry> {
-        self.entry_with_path(path.as_ref())
+    pub fn entry<P: ObjectPath>(&self, path: P) -> io::Result<Entry> {
+        self.entry_with_path(&path)
     }
 
-    fn entry_with_path(&self, path: &Path) -> io::Result<Entry> {
-        let names = internal::path::name_chain_from_path(path)?;
+    fn entry_with_path(&self, path: &dyn ObjectPath) -> io::Result<Entry> {
+        let names = path.name_chain()?;
         let path = internal::path::path_from_name_chain(&names);
         let stream_id = match self.stream_id_for_name_chain(&names) {
             Some(stream_id) => stream_id,
@@ -164,15 +443,18 @@ impl<F> CompoundFile<F> {
     }
 
     /// Returns an iterator over the entries within a storage object.
-    pub fn read_storage<P: AsRef<Path>>(
+    pub fn read_storage<P: ObjectPath>(
         &self,
         path: P,
     ) -> io::Result<Entries<F>> {
-        self.read_storage_with_path(path.as_ref())
+        self.read_storage_with_path(&path)
     }
 
-    fn read_storage_with_path(&self, path: &Path) -> io::Result<Entries<F>> {
-        let names = internal::path::name_chain_from_path(path)?;
+    fn read_storage_with_path(
+        &self,
+        path: &dyn ObjectPath,
+    ) -> io::Result<Entries<F>> {
+        let names = path.name_chain()?;
         let path = internal::path::path_from_name_chain(&names);
         let stream_id = match self.stream_id_for_name_chain(&names) {
             Some(stream_id) => stream_id,
@@ -214,15 +496,18 @@ impl<F> CompoundFile<F> {
     /// Returns an iterator over all entries under a storage subtree, including
     /// the given path itself.  The iterator walks the storage tree in a
     /// preorder traversal.
-    pub fn walk_storage<P: AsRef<Path>>(
+    pub fn walk_storage<P: ObjectPath>(
         &self,
         path: P,
     ) -> io::Result<Entries<F>> {
-        self.walk_storage_with_path(path.as_ref())
+        self.walk_storage_with_path(&path)
     }
 
-    fn walk_storage_with_path(&self, path: &Path) -> io::Result<Entries<F>> {
-        let mut names = internal::path::name_chain_from_path(path)?;
+    fn walk_storage_with_path(
+        &self,
+        path: &dyn ObjectPath,
+    ) -> io::Result<Entries<F>> {
+        let mut names = path.name_chain()?;
         let stream_id = match self.stream_id_for_name_chain(&names) {
             Some(stream_id) => stream_id,
             None => {
@@ -244,8 +529,8 @@ impl<F> CompoundFile<F> {
 
     /// Returns true if there is an existing stream or storage at the given
     /// path, or false if there is nothing at that path.
-    pub fn exists<P: AsRef<Path>>(&self, path: P) -> bool {
-        match internal::path::name_chain_from_path(path.as_ref()) {
+    pub fn exists<P: ObjectPath>(&self, path: P) -> bool {
+        match path.name_chain() {
             Ok(names) => self.stream_id_for_name_chain(&names).is_some(),
             Err(_) => false,
         }
@@ -253,8 +538,8 @@ impl<F> CompoundFile<F> {
 
     /// Returns true if there is an existing stream at the given path, or false
     /// if there is a storage or nothing at that path.
-    pub fn is_stream<P: AsRef<Path>>(&self, path: P) -> bool {
-        match internal::path::name_chain_from_path(path.as_ref()) {
+    pub fn is_stream<P: ObjectPath>(&self, path: P) -> bool {
+        match path.name_chain() {
             Ok(names) => match self.stream_id_for_name_chain(&names) {
                 Some(stream_id) => {
                     self.minialloc().dir_entry(stream_id).obj_type
@@ -268,8 +553,8 @@ impl<F> CompoundFile<F> {
 
     /// Returns true if there is an existing storage at the given path, or
     /// false if there is a stream or nothing at that path.
-    pub fn is_storage<P: AsRef<Path>>(&self, path: P) -> bool {
-        match internal::path::name_chain_from_path(path.as_ref()) {
+    pub fn is_storage<P: ObjectPath>(&self, path: P) -> bool {
+        match path.name_chain() {
             Ok(names) => match self.stream_id_for_name_chain(&names) {
                 Some(stream_id) => {
                     self.minialloc().dir_entry(stream_id).obj_type
@@ -281,52 +566,196 @@ impl<F> CompoundFile<F> {
         }
     }
 
-    // TODO: pub fn copy_stream
+    /// Returns the current write mode for this compound file.
+    pub fn write_mode(&self) -> WriteMode {
+        self.minialloc().write_mode()
+    }
 
-    // TODO: pub fn rename
+    /// Returns true if this compound file is in journaling mode.
+    pub fn is_journaling(&self) -> bool {
+        self.minialloc().is_journaling()
+    }
 
     /// Consumes the `CompoundFile`, returning the underlying reader/writer.
+    ///
+    /// In write-back or journaling mode, any pending changes are written to
+    /// the underlying file first; call `flush` beforehand to detect any
+    /// errors in doing so.
     pub fn into_inner(self) -> F {
-        // We only ever retain Weak copies of the CompoundFile's minialloc Rc
-        // (e.g. in Stream structs), so the Rc::try_unwrap() should always
+        // Dropping the CompoundFile flushes any pending write-back changes, so
+        // keep another reference to the minialloc around to unwrap.
+        let minialloc = self.minialloc.clone();
+        drop(self);
+        // We only ever retain weak copies of the CompoundFile's minialloc
+        // (e.g. in Stream structs), so the try_unwrap() should always
         // succeed.
-        match Rc::try_unwrap(self.minialloc) {
-            Ok(ref_cell) => ref_cell.into_inner().into_inner(),
+        match minialloc.try_unwrap() {
+            Ok(minialloc) => minialloc.into_inner(),
             Err(_) => unreachable!(),
         }
     }
 }
 
+impl<F> Drop for CompoundFile<F> {
+    fn drop(&mut self) {
+        // Errors can't be reported from here; callers who care should call
+        // `flush` before dropping the CompoundFile.
+        if let Some(mut minialloc) = self.minialloc.borrow_mut_for_drop() {
+            let _ = minialloc.flush_write_back();
+        }
+    }
+}
+
 impl<F: Seek> CompoundFile<F> {
     /// Opens an existing stream in the compound file for reading and/or
     /// writing (depending on what the underlying file supports).
-    pub fn open_stream<P: AsRef<Path>>(
-        &mut self,
+    ///
+    /// The returned `Stream` doesn't borrow the `CompoundFile`, so several
+    /// streams may be open at once.  With the `sync` feature enabled, they
+    /// can also be sent to (and used from) other threads, and since this
+    /// only needs a shared reference, threads sharing an
+    /// `Arc<CompoundFile<F>>` can each open their own streams.
+    pub fn open_stream<P: ObjectPath>(
+        &self,
         path: P,
     ) -> io::Result<Stream<F>> {
-        self.open_stream_with_path(path.as_ref())
+        self.open_stream_with_path(&path)
     }
 
-    fn open_stream_with_path(&mut self, path: &Path) -> io::Result<Stream<F>> {
-        let names = internal::path::name_chain_from_path(path)?;
-        let path = internal::path::path_from_name_chain(&names);
-        let stream_id = match self.stream_id_for_name_chain(&names) {
-            Some(stream_id) => stream_id,
-            None => not_found!("No such stream: {:?}", path),
-        };
-        if self.minialloc().dir_entry(stream_id).obj_type != ObjType::Stream {
-            invalid_input!("Not a stream: {:?}", path);
-        }
+    fn open_stream_with_path(
+        &self,
+        path: &dyn ObjectPath,
+    ) -> io::Result<Stream<F>> {
+        let stream_id = self.stream_id_for_path(path)?;
         Ok(Stream::new(&self.minialloc, stream_id))
     }
+
+    /// Opens an orphaned stream entry (see
+    /// [`orphaned_entries`](#method.orphaned_entries)) for read-only access,
+    /// as a pseudo-stream with the length and chain recorded in the entry.
+    /// Returns an error if the entry isn't for a stream, or if its chain is
+    /// broken or too short.
+    pub fn open_orphaned_entry(
+        &mut self,
+        entry: &OrphanedEntry,
+    ) -> io::Result<StreamReader<F>> {
+        let stream_id = entry.stream_id();
+        if stream_id >= self.minialloc().num_dir_entries()
+            || self.minialloc().dir_entry(stream_id).obj_type
+                != ObjType::Stream
+        {
+            invalid_input!("Not a stream: directory entry {}", stream_id);
+        }
+        // Walk the entry's chain now, so that a broken one is reported here
+        // rather than by the first read.
+        self.stream_sector_offsets_for_id(stream_id)?;
+        Ok(StreamReader::new(&self.minialloc, stream_id))
+    }
+
+    /// Opens an orphaned chain (see
+    /// [`orphaned_chains`](#method.orphaned_chains)) for read-only access, as
+    /// a pseudo-stream made up of all of the chain's sectors.
+    pub fn open_orphaned_chain(
+        &self,
+        chain: &OrphanedChain,
+    ) -> io::Result<StreamReader<F>> {
+        let num_sectors = self.minialloc().num_sectors();
+        if let Some(&sector_id) = chain
+            .sector_ids()
+            .iter()
+            .find(|&&sector_id| sector_id >= num_sectors)
+        {
+            not_found!("Sector {} no longer exists", sector_id);
+        }
+        Ok(StreamReader::from_sectors(
+            &self.minialloc,
+            chain.sector_ids().to_vec(),
+        ))
+    }
+}
+
+impl<F: ReadAt> CompoundFile<F> {
+    /// Opens an existing stream in the compound file for read-only access
+    /// with positional reads (see
+    /// [`StreamReader`](struct.StreamReader.html)).  Unlike a `Stream`, the
+    /// returned reader has no buffer and no cursor of its own within the
+    /// underlying file, so it can read from any offset through a shared
+    /// reference, and with the `sync` feature enabled, one reader can be
+    /// shared between threads.
+    ///
+    /// The reader sees data written through a `Stream` once that stream has
+    /// been flushed.
+    pub fn open_stream_reader<P: ObjectPath>(
+        &self,
+        path: P,
+    ) -> io::Result<StreamReader<F>> {
+        self.open_stream_reader_with_path(&path)
+    }
+
+    fn open_stream_reader_with_path(
+        &self,
+        path: &dyn ObjectPath,
+    ) -> io::Result<StreamReader<F>> {
+        let stream_id = self.stream_id_for_path(path)?;
+        Ok(StreamReader::new(&self.minialloc, stream_id))
+    }
+}
+
+impl<'a> CompoundFile<Cursor<&'a [u8]>> {
+    /// Opens an existing compound file that is already entirely in memory,
+    /// in read-only mode.  Stream data can then be borrowed directly from
+    /// `data`, rather than copied out through a `Stream`, using
+    /// [`stream_bytes`](#method.stream_bytes) or
+    /// [`stream_slices`](#method.stream_slices).
+    pub fn from_slice(data: &'a [u8]) -> io::Result<Self> {
+        CompoundFile::open(Cursor::new(data))
+    }
+
+    /// Returns the contents of the stream at the given path.  If the stream's
+    /// sectors are contiguous within the compound file, this borrows them
+    /// without copying; otherwise, the sectors are copied into a new buffer.
+    pub fn stream_bytes<P: ObjectPath>(
+        &mut self,
+        path: P,
+    ) -> io::Result<Cow<'a, [u8]>> {
+        Ok(self.stream_slices_with_path(&path)?.into_cow())
+    }
+
+    /// Returns an iterator over slices of the compound file that, in order,
+    /// make up the contents of the stream at the given path: one slice per
+    /// sector of the stream, with the last slice truncated to the end of the
+    /// stream.
+    pub fn stream_slices<P: ObjectPath>(
+        &mut self,
+        path: P,
+    ) -> io::Result<StreamSlices<'a>> {
+        self.stream_slices_with_path(&path)
+    }
+
+    fn stream_slices_with_path(
+        &mut self,
+        path: &dyn ObjectPath,
+    ) -> io::Result<StreamSlices<'a>> {
+        let (sector_offsets, sector_len, stream_len) =
+            self.stream_sector_offsets(path)?;
+        let data: &'a [u8] = self.minialloc().inner().get_ref();
+        StreamSlices::new(data, sector_offsets, sector_len, stream_len)
+    }
 }
 
 impl<F: Read + Seek> CompoundFile<F> {
     /// Opens an existing compound file, using the underlying reader.  If the
     /// underlying reader also supports the `Write` trait, then the
     /// `CompoundFile` object will be writable as well.
+    ///
+    /// A compound file left in journaling mode (see
+    /// [`enable_journal`](#method.enable_journal)) may hold an interrupted
+    /// commit, which can only be completed or undone with its journal.
+    /// Opening such a file fails with an `InvalidData` error carrying
+    /// [`Error::Journaled`](enum.Error.html#variant.Journaled); reopen it
+    /// with [`open_with_journal`](#method.open_with_journal) instead.
     pub fn open(inner: F) -> io::Result<CompoundFile<F>> {
-        CompoundFile::open_internal(inner, Validation::Permissive)
+        CompoundFile::open_with_policy(inner, ValidationPolicy::permissive())
     }
 
     /// Like `open()`, but is stricter when parsing and will return an error if
@@ -335,35 +764,55 @@ impl<F: Read + Seek> CompoundFile<F> {
     /// implemention (such as this crate itself) to help ensure compatibility
     /// with other readers.
     pub fn open_strict(inner: F) -> io::Result<CompoundFile<F>> {
-        CompoundFile::open_internal(inner, Validation::Strict)
+        CompoundFile::open_with_policy(inner, ValidationPolicy::strict())
+    }
+
+    /// Like `open()`, but uses the given policy to decide which deviations
+    /// from the CFB spec to tolerate when parsing.  (`open()` and
+    /// `open_strict()` use the `permissive` and `strict` presets.)
+    pub fn open_with_policy(
+        inner: F,
+        policy: ValidationPolicy,
+    ) -> io::Result<CompoundFile<F>> {
+        CompoundFile::open_internal(inner, &mut Validator::new(policy))
     }
 
     fn open_internal(
         mut inner: F,
-        validation: Validation,
+        validator: &mut Validator,
     ) -> io::Result<CompoundFile<F>> {
         let inner_len = inner.seek(SeekFrom::End(0))?;
         if inner_len < consts::HEADER_LEN as u64 {
-            invalid_data!(
+            corrupted!(
+                Header {},
                 "Invalid CFB file ({} bytes is too small)",
                 inner_len
             );
         }
         inner.seek(SeekFrom::Start(0))?;
 
-        let header = Header::read_from(&mut inner, validation)?;
+        let header = Header::read_from(&mut inner, validator)?;
+        if header.transaction_signature == consts::JOURNALING_SIGNATURE {
+            corrupted!(
+                Journaled {},
+                "Compound file is in journaling mode, and may have an \
+                 interrupted commit; open it with open_with_journal"
+            );
+        }
         let sector_len = header.version.sector_len();
         if inner_len
             > ((consts::MAX_REGULAR_SECTOR + 1) as u64) * (sector_len as u64)
         {
-            invalid_data!(
+            corrupted!(
+                Header {},
                 "Invalid CFB file ({} bytes is too large)",
                 inner_len
             );
         }
 
         if inner_len < header.version.sector_len() as u64 {
-            invalid_data!(
+            corrupted!(
+                Header {},
                 "Invalid CFB file (length of {} < sector length of {})",
                 inner_len,
                 header.version.sector_len()
@@ -375,17 +824,22 @@ impl<F: Read + Seek> CompoundFile<F> {
         // Read in DIFAT.
         let mut difat = Vec::<u32>::new();
         difat.extend_from_slice(&header.initial_difat_entries);
-        let mut seen_sector_ids = FnvHashSet::default();
+        let mut seen_sector_ids = IdSet::default();
         let mut difat_sector_ids = Vec::new();
         let mut current_difat_sector = header.first_difat_sector;
         while current_difat_sector != consts::END_OF_CHAIN {
             if current_difat_sector > consts::MAX_REGULAR_SECTOR {
-                invalid_data!(
+                corrupted!(
+                    Difat { sector_id: Some(current_difat_sector) },
                     "DIFAT chain includes invalid sector index {}",
                     current_difat_sector
                 );
             } else if current_difat_sector >= num_sectors {
-                invalid_data!(
+                corrupted!(
+                    SectorOutOfRange {
+                        sector_id: current_difat_sector,
+                        num_sectors,
+                    },
                     "DIFAT chain includes sector index {}, but sector count \
                      is only {}",
                     current_difat_sector,
@@ -393,7 +847,8 @@ impl<F: Read + Seek> CompoundFile<F> {
                 );
             }
             if seen_sector_ids.contains(&current_difat_sector) {
-                invalid_data!(
+                corrupted!(
+                    ChainLoop { sector_id: current_difat_sector },
                     "DIFAT chain includes duplicate sector index {}",
                     current_difat_sector,
                 );
@@ -406,7 +861,8 @@ impl<F: Read + Seek> CompoundFile<F> {
                 if next != consts::FREE_SECTOR
                     && next > consts::MAX_REGULAR_SECTOR
                 {
-                    invalid_data!(
+                    corrupted!(
+                        Difat { sector_id: Some(current_difat_sector) },
                         "DIFAT refers to invalid sector index {}",
                         next
                     );
@@ -415,34 +871,40 @@ impl<F: Read + Seek> CompoundFile<F> {
             }
             current_difat_sector = sector.read_u32::<LittleEndian>()?;
         }
-        if validation.is_strict()
-            && header.num_difat_sectors as usize != difat_sector_ids.len()
-        {
-            invalid_data!(
-                "Incorrect DIFAT chain length (header says {}, actual is {})",
-                header.num_difat_sectors,
-                difat_sector_ids.len()
-            );
+        if header.num_difat_sectors as usize != difat_sector_ids.len() {
+            validator.tolerate(
+                validator.policy().allow_wrong_sector_counts,
+                corruption!(
+                    Difat { sector_id: None },
+                    "Incorrect DIFAT chain length (header says {}, actual is \
+                     {})",
+                    header.num_difat_sectors,
+                    difat_sector_ids.len()
+                ),
+            )?;
         }
         while difat.last() == Some(&consts::FREE_SECTOR) {
             difat.pop();
         }
-        if validation.is_strict()
-            && header.num_fat_sectors as usize != difat.len()
-        {
-            invalid_data!(
-                "Incorrect number of FAT sectors (header says {}, DIFAT says \
-                 {})",
-                header.num_fat_sectors,
-                difat.len()
-            );
+        if header.num_fat_sectors as usize != difat.len() {
+            validator.tolerate(
+                validator.policy().allow_wrong_sector_counts,
+                corruption!(
+                    Difat { sector_id: None },
+                    "Incorrect number of FAT sectors (header says {}, DIFAT \
+                     says {})",
+                    header.num_fat_sectors,
+                    difat.len()
+                ),
+            )?;
         }
 
         // Read in FAT.
         let mut fat = Vec::<u32>::new();
         for &sector_index in difat.iter() {
             if sector_index >= num_sectors {
-                invalid_data!(
+                corrupted!(
+                    SectorOutOfRange { sector_id: sector_index, num_sectors },
                     "DIFAT refers to sector {}, but sector count is only {}",
                     sector_index,
                     num_sectors
@@ -458,11 +920,19 @@ impl<F: Read + Seek> CompoundFile<F> {
         // with FREE_SECTOR entries (see MS-CFB section 2.3).  However, some
         // CFB implementations incorrectly pad the last FAT sector with zeros
         // (see https://github.com/mdsteele/rust-cfb/issues/8), so we allow
-        // this under Permissive validation.  Since zero is normally a
+        // this if the validation policy does.  Since zero is normally a
         // meaningful FAT entry (referring to sector 0), we only want to strip
         // zeros from the end of the FAT if they are beyond the number of
-        // sectors in the file.
-        if !validation.is_strict() {
+        // sectors in the file.  (If the policy doesn't allow this, the FAT is
+        // left as it is, and so will fail validation for being too long.)
+        if validator.policy().allow_zero_padded_fat
+            && fat.len() > num_sectors as usize
+            && fat.last() == Some(&0)
+        {
+            validator.warn(corruption!(
+                Fat { sector_id: None },
+                "FAT is padded with zeros past the last sector of the file"
+            ));
             while fat.len() > num_sectors as usize && fat.last() == Some(&0) {
                 fat.pop();
             }
@@ -476,20 +946,25 @@ impl<F: Read + Seek> CompoundFile<F> {
         }
 
         let mut allocator =
-            Allocator::new(sectors, difat_sector_ids, difat, fat, validation)?;
+            Allocator::new(sectors, difat_sector_ids, difat, fat, validator)?;
 
         // Read in directory.
         let mut dir_entries = Vec::<DirEntry>::new();
-        let mut seen_dir_sectors = FnvHashSet::default();
+        let mut seen_dir_sectors = IdSet::default();
         let mut current_dir_sector = header.first_dir_sector;
         while current_dir_sector != consts::END_OF_CHAIN {
             if current_dir_sector > consts::MAX_REGULAR_SECTOR {
-                invalid_data!(
+                corrupted!(
+                    Directory { stream_id: None, path: None },
                     "Directory chain includes invalid sector index {}",
                     current_dir_sector
                 );
             } else if current_dir_sector >= num_sectors {
-                invalid_data!(
+                corrupted!(
+                    SectorOutOfRange {
+                        sector_id: current_dir_sector,
+                        num_sectors,
+                    },
                     "Directory chain includes sector index {}, but sector \
                      count is only {}",
                     current_dir_sector,
@@ -497,7 +972,8 @@ impl<F: Read + Seek> CompoundFile<F> {
                 );
             }
             if seen_dir_sectors.contains(&current_dir_sector) {
-                invalid_data!(
+                corrupted!(
+                    ChainLoop { sector_id: current_dir_sector },
                     "Directory chain includes duplicate sector index {}",
                     current_dir_sector,
                 );
@@ -507,36 +983,51 @@ impl<F: Read + Seek> CompoundFile<F> {
                 let mut sector =
                     allocator.seek_to_sector(current_dir_sector)?;
                 for _ in 0..header.version.dir_entries_per_sector() {
-                    dir_entries.push(DirEntry::read_from(
-                        &mut sector,
+                    let mut buffer = [0u8; consts::DIR_ENTRY_LEN];
+                    sector.read_exact(&mut buffer)?;
+                    validator.set_stream_id(Some(dir_entries.len() as u32));
+                    let result = DirEntry::read_from(
+                        &mut buffer.as_slice(),
                         header.version,
-                        validation,
-                    )?);
+                        validator,
+                    );
+                    let dir_entry = match result {
+                        Ok(dir_entry) => dir_entry,
+                        Err(error) => {
+                            validator.reject_io(error)?;
+                            DirEntry::unallocated()
+                        }
+                    };
+                    validator.set_stream_id(None);
+                    dir_entries.push(dir_entry);
                 }
             }
             current_dir_sector = allocator.next(current_dir_sector)?;
         }
+        validator.finish_stage()?;
 
         let mut directory = Directory::new(
             allocator,
             dir_entries,
             header.first_dir_sector,
-            validation,
+            validator,
         )?;
 
         // Read in MiniFAT.
         let minifat = {
             let mut chain = directory
                 .open_chain(header.first_minifat_sector, SectorInit::Fat)?;
-            if validation.is_strict()
-                && header.num_minifat_sectors as usize != chain.num_sectors()
-            {
-                invalid_data!(
-                    "Incorrect MiniFAT chain length (header says {}, actual \
-                     is {})",
-                    header.num_minifat_sectors,
-                    chain.num_sectors()
-                );
+            if header.num_minifat_sectors as usize != chain.num_sectors() {
+                validator.tolerate(
+                    validator.policy().allow_wrong_sector_counts,
+                    corruption!(
+                        MiniFat { sector_id: None },
+                        "Incorrect MiniFAT chain length (header says {}, \
+                         actual is {})",
+                        header.num_minifat_sectors,
+                        chain.num_sectors()
+                    ),
+                )?;
             }
             let num_minifat_entries = (chain.len() / 4) as usize;
             let mut minifat = Vec::<u32>::with_capacity(num_minifat_entries);
@@ -553,10 +1044,38 @@ impl<F: Read + Seek> CompoundFile<F> {
             directory,
             minifat,
             header.first_minifat_sector,
-            validation,
+            validator,
         )?;
 
-        Ok(CompoundFile { minialloc: Rc::new(RefCell::new(minialloc)) })
+        Ok(CompoundFile {
+            minialloc: Shared::new(minialloc),
+            warnings: validator.warnings(),
+        })
+    }
+
+    /// Writes a compacted copy of this compound file to the beginning of
+    /// `writer`, leaving this compound file unchanged.  In the copy, every
+    /// chain is contiguous and there are no free sectors, so the copy is
+    /// usually considerably smaller than the original if many objects have
+    /// been removed or resized.
+    ///
+    /// Any data that has been written to an open `Stream` but not yet flushed
+    /// will not be included in the copy.
+    pub fn compact_into<W: Write + Seek>(
+        &mut self,
+        mut writer: W,
+    ) -> io::Result<()> {
+        writer.seek(SeekFrom::Start(0))?;
+        internal::compact_into(&mut self.minialloc_mut(), &mut writer)
+    }
+
+    /// Reads the header from the underlying file, without validating any of
+    /// its fields.  During a transaction, this is the header as of the last
+    /// commit.
+    pub fn raw_header(&mut self) -> io::Result<raw::Header> {
+        let mut data = [0u8; consts::HEADER_LEN];
+        self.minialloc_mut().seek_within_header(0)?.read_exact(&mut data)?;
+        raw::Header::read_from(&mut &data[..])
     }
 }
 
@@ -579,6 +1098,7 @@ impl<F: Read + Write + Seek> CompoundFile<F> {
             num_dir_sectors: if version == Version::V3 { 0 } else { 1 },
             num_fat_sectors: 1,
             first_dir_sector: 1,
+            transaction_signature: 0,
             first_minifat_sector: consts::END_OF_CHAIN,
             num_minifat_sectors: 0,
             first_difat_sector: consts::END_OF_CHAIN,
@@ -620,34 +1140,94 @@ impl<F: Read + Write + Seek> CompoundFile<F> {
             difat_sector_ids,
             difat,
             fat,
-            Validation::Strict,
+            &mut ValidationPolicy::strict().into(),
         )?;
         let directory = Directory::new(
             allocator,
             vec![root_dir_entry],
             1,
-            Validation::Strict,
+            &mut ValidationPolicy::strict().into(),
         )?;
         let minialloc = MiniAllocator::new(
             directory,
             vec![],
             consts::END_OF_CHAIN,
-            Validation::Strict,
+            &mut ValidationPolicy::strict().into(),
         )?;
-        Ok(CompoundFile { minialloc: Rc::new(RefCell::new(minialloc)) })
+        Ok(CompoundFile {
+            minialloc: Shared::new(minialloc),
+            warnings: vec![],
+        })
+    }
+
+    /// Opens an existing compound file in journaling mode, using `journal`
+    /// (which should be a separate reader/writer, initially empty) to make
+    /// updates crash-safe; see [`enable_journal`](#method.enable_journal).
+    ///
+    /// If the process previously died while committing changes to this
+    /// compound file, the journal records how far the commit got.  If the
+    /// new header was recorded in the journal, the commit is rolled forward
+    /// by writing that header to the file; otherwise, the file still holds
+    /// its state from before the commit, and the commit is rolled back by
+    /// simply ignoring the sectors it wrote.
+    ///
+    /// The journal needn't be the same type as the compound file's
+    /// reader/writer; for example, a compound file accessed through a
+    /// wrapper type can still keep its journal in a plain `File`.
+    pub fn open_with_journal<J: JournalFile>(
+        mut inner: F,
+        journal: J,
+    ) -> io::Result<CompoundFile<F>> {
+        let mut journal = Journal::new(journal);
+        journal.recover(&mut inner)?;
+        let mut comp = CompoundFile::open(inner)?;
+        comp.minialloc_mut().enable_journal(journal)?;
+        Ok(comp)
+    }
+
+    /// Switches this compound file into journaling mode, writing out any
+    /// pending changes first.  In journaling mode, the underlying file always
+    /// holds the compound file as it was when last flushed, even if the
+    /// process dies partway through a later change: all changes are made as
+    /// if within a [`Transaction`](struct.Transaction.html), which is
+    /// committed each time this compound file is flushed (or dropped), and
+    /// `journal` records each commit's new header before the header itself
+    /// is written.  Reopen the compound file with
+    /// [`open_with_journal`](#method.open_with_journal), passing the same
+    /// journal, to recover from an interrupted commit.
+    ///
+    /// Transactions begun with `begin_transaction` can still be used in
+    /// journaling mode, and are committed in the same crash-safe way.
+    /// Compacting or shrinking the compound file is not allowed in
+    /// journaling mode.  If a commit fails partway through (for example,
+    /// because of an I/O error), the compound file should be dropped and
+    /// reopened with `open_with_journal` before making any further changes.
+    ///
+    /// Until the compound file is dropped, its header is marked as being in
+    /// journaling mode, and opening it with anything but `open_with_journal`
+    /// fails.  That way, if the process dies, the file can't be opened
+    /// without the journal that may be needed to complete its last commit.
+    pub fn enable_journal<J: JournalFile>(
+        &mut self,
+        journal: J,
+    ) -> io::Result<()> {
+        self.minialloc_mut().enable_journal(Journal::new(journal))
     }
 
     /// Creates a new, empty storage object (i.e. "directory") at the provided
     /// path.  The parent storage object must already exist.
-    pub fn create_storage<P: AsRef<Path>>(
+    pub fn create_storage<P: ObjectPath>(
         &mut self,
         path: P,
     ) -> io::Result<()> {
-        self.create_storage_with_path(path.as_ref())
+        self.create_storage_with_path(&path)
     }
 
-    fn create_storage_with_path(&mut self, path: &Path) -> io::Result<()> {
-        let mut names = internal::path::name_chain_from_path(path)?;
+    fn create_storage_with_path(
+        &mut self,
+        path: &dyn ObjectPath,
+    ) -> io::Result<()> {
+        let mut names = path.name_chain()?;
         if let Some(stream_id) = self.stream_id_for_name_chain(&names) {
             let path = internal::path::path_from_name_chain(&names);
             if self.minialloc().dir_entry(stream_id).obj_type
@@ -670,6 +1250,7 @@ impl<F: Read + Write + Seek> CompoundFile<F> {
         // the root always already exists and will have been rejected above.
         debug_assert!(!names.is_empty());
         let name = names.pop().unwrap();
+        internal::path::validate_name(name)?;
         let parent_id = match self.stream_id_for_name_chain(&names) {
             Some(stream_id) => stream_id,
             None => {
@@ -686,15 +1267,18 @@ impl<F: Read + Write + Seek> CompoundFile<F> {
 
     /// Recursively creates a storage and all of its parent storages if they
     /// are missing.
-    pub fn create_storage_all<P: AsRef<Path>>(
+    pub fn create_storage_all<P: ObjectPath>(
         &mut self,
         path: P,
     ) -> io::Result<()> {
-        self.create_storage_all_with_path(path.as_ref())
+        self.create_storage_all_with_path(&path)
     }
 
-    fn create_storage_all_with_path(&mut self, path: &Path) -> io::Result<()> {
-        let names = internal::path::name_chain_from_path(path)?;
+    fn create_storage_all_with_path(
+        &mut self,
+        path: &dyn ObjectPath,
+    ) -> io::Result<()> {
+        let names = path.name_chain()?;
         for length in 1..(names.len() + 1) {
             let prefix_path =
                 internal::path::path_from_name_chain(&names[..length]);
@@ -708,15 +1292,19 @@ impl<F: Read + Write + Seek> CompoundFile<F> {
 
     /// Removes the storage object at the provided path.  The storage object
     /// must exist and have no children.
-    pub fn remove_storage<P: AsRef<Path>>(
+    pub fn remove_storage<P: ObjectPath>(
         &mut self,
         path: P,
     ) -> io::Result<()> {
-        self.remove_storage_with_path(path.as_ref())
+        self.remove_storage_with_path(&path)
     }
 
-    fn remove_storage_with_path(&mut self, path: &Path) -> io::Result<()> {
-        let mut names = internal::path::name_chain_from_path(path)?;
+    fn remove_storage_with_path(
+        &mut self,
+        path: &dyn ObjectPath,
+    ) -> io::Result<()> {
+        let mut names = path.name_chain()?;
+        let path = internal::path::path_from_name_chain(&names);
         let stream_id = match self.stream_id_for_name_chain(&names) {
             Some(parent_id) => parent_id,
             None => not_found!("No such storage: {:?}", path),
@@ -745,20 +1333,24 @@ impl<F: Read + Write + Seek> CompoundFile<F> {
     /// Recursively removes a storage and all of its children.  If called on
     /// the root storage, recursively removes all of its children but not the
     /// root storage itself (which cannot be removed).
-    pub fn remove_storage_all<P: AsRef<Path>>(
+    pub fn remove_storage_all<P: ObjectPath>(
         &mut self,
         path: P,
     ) -> io::Result<()> {
-        self.remove_storage_all_with_path(path.as_ref())
+        self.remove_storage_all_with_path(&path)
     }
 
-    fn remove_storage_all_with_path(&mut self, path: &Path) -> io::Result<()> {
-        let mut stack = self.walk_storage(path)?.collect::<Vec<Entry>>();
+    fn remove_storage_all_with_path(
+        &mut self,
+        path: &dyn ObjectPath,
+    ) -> io::Result<()> {
+        let mut stack =
+            self.walk_storage_with_path(path)?.collect::<Vec<Entry>>();
         while let Some(entry) = stack.pop() {
             if entry.is_stream() {
-                self.remove_stream_with_path(entry.path())?;
+                self.remove_stream_with_path(&entry.path())?;
             } else if !entry.is_root() {
-                self.remove_storage_with_path(entry.path())?;
+                self.remove_storage_with_path(&entry.path())?;
             }
         }
         Ok(())
@@ -767,20 +1359,20 @@ impl<F: Read + Write + Seek> CompoundFile<F> {
     /// Sets the CLSID for the storage object at the provided path.  (To get
     /// the current CLSID for a storage object, use
     /// `self.entry(path)?.clsid()`.)
-    pub fn set_storage_clsid<P: AsRef<Path>>(
+    pub fn set_storage_clsid<P: ObjectPath>(
         &mut self,
         path: P,
         clsid: Uuid,
     ) -> io::Result<()> {
-        self.set_storage_clsid_with_path(path.as_ref(), clsid)
+        self.set_storage_clsid_with_path(&path, clsid)
     }
 
     fn set_storage_clsid_with_path(
         &mut self,
-        path: &Path,
+        path: &dyn ObjectPath,
         clsid: Uuid,
     ) -> io::Result<()> {
-        let names = internal::path::name_chain_from_path(path)?;
+        let names = path.name_chain()?;
         let stream_id = match self.stream_id_for_name_chain(&names) {
             Some(stream_id) => stream_id,
             None => not_found!(
@@ -803,29 +1395,29 @@ impl<F: Read + Write + Seek> CompoundFile<F> {
     /// Creates and returns a new, empty stream object at the provided path.
     /// If a stream already exists at that path, it will be replaced by the new
     /// stream.  The parent storage object must already exist.
-    pub fn create_stream<P: AsRef<Path>>(
+    pub fn create_stream<P: ObjectPath>(
         &mut self,
         path: P,
     ) -> io::Result<Stream<F>> {
-        self.create_stream_with_path(path.as_ref(), true)
+        self.create_stream_with_path(&path, true)
     }
 
     /// Creates and returns a new, empty stream object at the provided path.
     /// Returns an error if a stream already exists at that path.  The parent
     /// storage object must already exist.
-    pub fn create_new_stream<P: AsRef<Path>>(
+    pub fn create_new_stream<P: ObjectPath>(
         &mut self,
         path: P,
     ) -> io::Result<Stream<F>> {
-        self.create_stream_with_path(path.as_ref(), false)
+        self.create_stream_with_path(&path, false)
     }
 
     fn create_stream_with_path(
         &mut self,
-        path: &Path,
+        path: &dyn ObjectPath,
         overwrite: bool,
     ) -> io::Result<Stream<F>> {
-        let mut names = internal::path::name_chain_from_path(path)?;
+        let mut names = path.name_chain()?;
         if let Some(stream_id) = self.stream_id_for_name_chain(&names) {
             if self.minialloc().dir_entry(stream_id).obj_type
                 != ObjType::Stream
@@ -851,6 +1443,7 @@ impl<F: Read + Write + Seek> CompoundFile<F> {
         // the root always already exists and will have been rejected above.
         debug_assert!(!names.is_empty());
         let name = names.pop().unwrap();
+        internal::path::validate_name(name)?;
         let parent_id = match self.stream_id_for_name_chain(&names) {
             Some(stream_id) => stream_id,
             None => {
@@ -866,15 +1459,16 @@ impl<F: Read + Write + Seek> CompoundFile<F> {
     }
 
     /// Removes the stream object at the provided path.
-    pub fn remove_stream<P: AsRef<Path>>(
-        &mut self,
-        path: P,
-    ) -> io::Result<()> {
-        self.remove_stream_with_path(path.as_ref())
+    pub fn remove_stream<P: ObjectPath>(&mut self, path: P) -> io::Result<()> {
+        self.remove_stream_with_path(&path)
     }
 
-    fn remove_stream_with_path(&mut self, path: &Path) -> io::Result<()> {
-        let mut names = internal::path::name_chain_from_path(path)?;
+    fn remove_stream_with_path(
+        &mut self,
+        path: &dyn ObjectPath,
+    ) -> io::Result<()> {
+        let mut names = path.name_chain()?;
+        let path = internal::path::path_from_name_chain(&names);
         let stream_id = match self.stream_id_for_name_chain(&names) {
             Some(parent_id) => parent_id,
             None => not_found!("No such stream: {:?}", path),
@@ -903,23 +1497,370 @@ impl<F: Read + Write + Seek> CompoundFile<F> {
         Ok(())
     }
 
+    /// Copies the stream object at the `src` path to the `dst` path, along
+    /// with its CLSID, state bits, and timestamps.  If a stream already
+    /// exists at `dst`, it will be replaced by the copy, but only once the
+    /// copy is complete; if copying fails, the existing stream is left as it
+    /// was.  The parent storage object of `dst` must already exist.
+    pub fn copy_stream<P: ObjectPath, Q: ObjectPath>(
+        &mut self,
+        src: P,
+        dst: Q,
+    ) -> io::Result<()> {
+        self.copy_stream_with_paths(&src, &dst)
+    }
+
+    fn copy_stream_with_paths(
+        &mut self,
+        src: &dyn ObjectPath,
+        dst: &dyn ObjectPath,
+    ) -> io::Result<()> {
+        let src_names = src.name_chain()?;
+        let src_path = internal::path::path_from_name_chain(&src_names);
+        let src_id = match self.stream_id_for_name_chain(&src_names) {
+            Some(stream_id) => stream_id,
+            None => not_found!("No such stream: {:?}", src_path),
+        };
+        if self.minialloc().dir_entry(src_id).obj_type != ObjType::Stream {
+            invalid_input!("Not a stream: {:?}", src_path);
+        }
+        let mut dst_names = dst.name_chain()?;
+        let dst_path = internal::path::path_from_name_chain(&dst_names);
+        let dst_exists = match self.stream_id_for_name_chain(&dst_names) {
+            Some(stream_id) => {
+                if stream_id == src_id {
+                    invalid_input!("Cannot copy {:?} onto itself", src_path);
+                }
+                if self.minialloc().dir_entry(stream_id).obj_type
+                    != ObjType::Stream
+                {
+                    already_exists!(
+                        "Cannot copy stream to {:?} because a storage \
+                         already exists there",
+                        dst_path
+                    );
+                }
+                true
+            }
+            None => false,
+        };
+        // If dst_names is empty, then the destination is the root, which
+        // always already exists and will have been rejected above.
+        debug_assert!(!dst_names.is_empty());
+        let dst_name = dst_names.pop().unwrap();
+        internal::path::validate_name(dst_name)?;
+        let dst_parent_id = self.parent_storage_id(&dst_names)?;
+        let src = self.minialloc.clone();
+        if !dst_exists {
+            self.copy_tree(&src, src_id, dst_parent_id, dst_name)?;
+            return Ok(());
+        }
+        // Copy to a temporary name next to the existing stream, and only
+        // replace that stream once the copy has succeeded, so that a failed
+        // copy leaves it intact.
+        let mut index = 0;
+        let temp_name = loop {
+            let name = alloc::format!("~copy{}", index);
+            let mut names = dst_names.clone();
+            names.push(&name);
+            if self.stream_id_for_name_chain(&names).is_none() {
+                break name;
+            }
+            index += 1;
+        };
+        let mut temp_names = dst_names.clone();
+        temp_names.push(&temp_name);
+        if let Err(error) =
+            self.copy_tree(&src, src_id, dst_parent_id, &temp_name)
+        {
+            if self.stream_id_for_name_chain(&temp_names).is_some() {
+                let temp_path =
+                    internal::path::path_from_name_chain(&temp_names);
+                let _ = self.remove_stream_with_path(&temp_path);
+            }
+            return Err(error);
+        }
+        self.remove_stream_with_path(&dst_path)?;
+        self.minialloc_mut().move_dir_entry(
+            dst_parent_id,
+            &temp_name,
+            dst_parent_id,
+            dst_name,
+        )?;
+        Ok(())
+    }
+
+    /// Recursively copies the storage object at the `src` path, and all of
+    /// its children, to the `dst` path.  CLSIDs, state bits, and timestamps
+    /// are copied along with stream data.  There must not already be an
+    /// object at `dst`, and the parent storage object of `dst` must already
+    /// exist (and must not be within `src`).
+    pub fn copy_storage<P: ObjectPath, Q: ObjectPath>(
+        &mut self,
+        src: P,
+        dst: Q,
+    ) -> io::Result<()> {
+        self.copy_storage_with_paths(&src, &dst)
+    }
+
+    fn copy_storage_with_paths(
+        &mut self,
+        src: &dyn ObjectPath,
+        dst: &dyn ObjectPath,
+    ) -> io::Result<()> {
+        let src_names = src.name_chain()?;
+        let src_path = internal::path::path_from_name_chain(&src_names);
+        let src_id = match self.stream_id_for_name_chain(&src_names) {
+            Some(stream_id) => stream_id,
+            None => not_found!("No such storage: {:?}", src_path),
+        };
+        if self.minialloc().dir_entry(src_id).obj_type == ObjType::Stream {
+            invalid_input!("Not a storage: {:?}", src_path);
+        }
+        let mut dst_names = dst.name_chain()?;
+        let dst_path = internal::path::path_from_name_chain(&dst_names);
+        if self.stream_id_for_name_chain(&dst_names).is_some() {
+            already_exists!(
+                "Cannot copy storage to {:?} because an object already \
+                 exists there",
+                dst_path
+            );
+        }
+        // If dst_names is empty, then the destination is the root, which
+        // always already exists and will have been rejected above.
+        debug_assert!(!dst_names.is_empty());
+        let dst_name = dst_names.pop().unwrap();
+        internal::path::validate_name(dst_name)?;
+        let dst_parent_id = self.parent_storage_id(&dst_names)?;
+        for length in 0..(dst_names.len() + 1) {
+            if self.stream_id_for_name_chain(&dst_names[..length])
+                == Some(src_id)
+            {
+                invalid_input!(
+                    "Cannot copy {:?} into itself (to {:?})",
+                    src_path,
+                    dst_path
+                );
+            }
+        }
+        let src = self.minialloc.clone();
+        self.copy_tree(&src, src_id, dst_parent_id, dst_name)?;
+        Ok(())
+    }
+
+    /// Copies the stream or storage object at `src_path` within another
+    /// compound file (which may have a different underlying reader type) to
+    /// `dst_path` within this compound file.  Storage objects are copied
+    /// recursively, and CLSIDs, state bits, and timestamps are copied along
+    /// with stream data.  There must not already be an object at `dst_path`,
+    /// and its parent storage object must already exist.  Returns a summary
+    /// of what was transferred.
+    pub fn import_from<G, P, Q>(
+        &mut self,
+        src: &CompoundFile<G>,
+        src_path: P,
+        dst_path: Q,
+    ) -> io::Result<ImportReport>
+    where
+        G: Read + Seek,
+        P: ObjectPath,
+        Q: ObjectPath,
+    {
+        self.import_from_with_paths(src, &src_path, &dst_path)
+    }
+
+    fn import_from_with_paths<G: Read + Seek>(
+        &mut self,
+        src: &CompoundFile<G>,
+        src_path: &dyn ObjectPath,
+        dst_path: &dyn ObjectPath,
+    ) -> io::Result<ImportReport> {
+        let src_names = src_path.name_chain()?;
+        let src_id = match src.stream_id_for_name_chain(&src_names) {
+            Some(stream_id) => stream_id,
+            None => not_found!(
+                "No such object: {:?}",
+                internal::path::path_from_name_chain(&src_names)
+            ),
+        };
+        let mut dst_names = dst_path.name_chain()?;
+        if self.stream_id_for_name_chain(&dst_names).is_some() {
+            already_exists!(
+                "Cannot import to {:?} because an object already exists \
+                 there",
+                internal::path::path_from_name_chain(&dst_names)
+            );
+        }
+        // If dst_names is empty, then the destination is the root, which
+        // always already exists and will have been rejected above.
+        debug_assert!(!dst_names.is_empty());
+        let dst_name = dst_names.pop().unwrap();
+        internal::path::validate_name(dst_name)?;
+        let dst_parent_id = self.parent_storage_id(&dst_names)?;
+        self.copy_tree(&src.minialloc, src_id, dst_parent_id, dst_name)
+    }
+
+    /// Copies the entry with the given stream ID in `src` (which may or may
+    /// not be this compound file's own allocator), along with all of its
+    /// descendants, into a new entry with the given name under the specified
+    /// parent storage.  The shape of the source tree is captured before
+    /// anything is created, so that the copy is unaffected by its own
+    /// insertions.
+    fn copy_tree<G: Read + Seek>(
+        &mut self,
+        src: &Shared<MiniAllocator<G>>,
+        src_id: u32,
+        dst_parent_id: u32,
+        dst_name: &str,
+    ) -> io::Result<ImportReport> {
+        // Snapshot the source tree as (source stream ID, index of parent
+        // within the snapshot) pairs, with each parent before its children.
+        let mut snapshot: Vec<(u32, usize)> = vec![(src_id, 0)];
+        {
+            let minialloc = src.borrow();
+            let mut index = 0;
+            while index < snapshot.len() {
+                let dir_entry = minialloc.dir_entry(snapshot[index].0);
+                if dir_entry.obj_type != ObjType::Stream {
+                    let mut stack = vec![dir_entry.child];
+                    while let Some(stream_id) = stack.pop() {
+                        if stream_id == consts::NO_STREAM {
+                            continue;
+                        }
+                        snapshot.push((stream_id, index));
+                        let sibling = minialloc.dir_entry(stream_id);
+                        stack.push(sibling.left_sibling);
+                        stack.push(sibling.right_sibling);
+                    }
+                }
+                index += 1;
+            }
+        }
+
+        let mut dst_ids = Vec::<u32>::with_capacity(snapshot.len());
+        let mut report = ImportReport::default();
+        for (index, &(src_id, parent_index)) in snapshot.iter().enumerate() {
+            let src_entry = src.borrow().dir_entry(src_id).clone();
+            let (parent_id, name) = if index == 0 {
+                (dst_parent_id, dst_name)
+            } else {
+                (dst_ids[parent_index], src_entry.name.as_str())
+            };
+            let obj_type = if src_entry.obj_type == ObjType::Stream {
+                ObjType::Stream
+            } else {
+                ObjType::Storage
+            };
+            let dst_id = self
+                .minialloc_mut()
+                .insert_dir_entry(parent_id, name, obj_type)?;
+            if obj_type == ObjType::Stream {
+                let mut src_stream = Stream::new(src, src_id);
+                let mut dst_stream = Stream::new(&self.minialloc, dst_id);
+                report.num_bytes +=
+                    io::copy(&mut src_stream, &mut dst_stream)?;
+                dst_stream.flush()?;
+            }
+            self.minialloc_mut().with_dir_entry_mut(dst_id, |dir_entry| {
+                dir_entry.clsid = src_entry.clsid;
+                dir_entry.state_bits = src_entry.state_bits;
+                dir_entry.creation_time = src_entry.creation_time;
+                dir_entry.modified_time = src_entry.modified_time;
+            })?;
+            dst_ids.push(dst_id);
+            report.num_entries += 1;
+        }
+        Ok(report)
+    }
+
+    /// Renames the stream or storage object at the `from` path to the `to`
+    /// path, moving it under a different parent storage if necessary.  A
+    /// storage object is moved along with all of its children.  No stream
+    /// data is copied, and the object keeps its CLSID, state bits, and
+    /// timestamps.  The new parent storage object must already exist, and
+    /// there must not already be an object at the `to` path (other than the
+    /// object being renamed, which allows changing the case of a name).
+    pub fn rename<P: ObjectPath, Q: ObjectPath>(
+        &mut self,
+        from: P,
+        to: Q,
+    ) -> io::Result<()> {
+        self.rename_with_paths(&from, &to)
+    }
+
+    fn rename_with_paths(
+        &mut self,
+        from: &dyn ObjectPath,
+        to: &dyn ObjectPath,
+    ) -> io::Result<()> {
+        let mut from_names = from.name_chain()?;
+        let from_path = internal::path::path_from_name_chain(&from_names);
+        let stream_id = match self.stream_id_for_name_chain(&from_names) {
+            Some(stream_id) => stream_id,
+            None => not_found!("No such object: {:?}", from_path),
+        };
+        if stream_id == consts::ROOT_STREAM_ID {
+            invalid_input!("Cannot rename the root storage object");
+        }
+        let mut to_names = to.name_chain()?;
+        let to_path = internal::path::path_from_name_chain(&to_names);
+        match self.stream_id_for_name_chain(&to_names) {
+            Some(id) if id == stream_id => {}
+            Some(_) => already_exists!(
+                "Cannot rename {:?} to {:?} because an object already exists \
+                 there",
+                from_path,
+                to_path
+            ),
+            None => {}
+        }
+        // If to_names is empty, then the destination is the root, which
+        // always already exists and will have been rejected above.
+        debug_assert!(!to_names.is_empty());
+        let new_name = to_names.pop().unwrap();
+        internal::path::validate_name(new_name)?;
+        let new_parent_id = self.parent_storage_id(&to_names)?;
+        for length in 1..(to_names.len() + 1) {
+            if self.stream_id_for_name_chain(&to_names[..length])
+                == Some(stream_id)
+            {
+                invalid_input!(
+                    "Cannot move {:?} into itself (to {:?})",
+                    from_path,
+                    to_path
+                );
+            }
+        }
+        debug_assert!(!from_names.is_empty());
+        let old_name = from_names.pop().unwrap();
+        let old_parent_id =
+            self.stream_id_for_name_chain(&from_names).unwrap();
+        self.minialloc_mut().move_dir_entry(
+            old_parent_id,
+            old_name,
+            new_parent_id,
+            new_name,
+        )?;
+        Ok(())
+    }
+
     /// Sets the user-defined bitflags for the object at the provided path.
     /// (To get the current state bits for an object, use
     /// `self.entry(path)?.state_bits()`.)
-    pub fn set_state_bits<P: AsRef<Path>>(
+    pub fn set_state_bits<P: ObjectPath>(
         &mut self,
         path: P,
         bits: u32,
     ) -> io::Result<()> {
-        self.set_state_bits_with_path(path.as_ref(), bits)
+        self.set_state_bits_with_path(&path, bits)
     }
 
     fn set_state_bits_with_path(
         &mut self,
-        path: &Path,
+        path: &dyn ObjectPath,
         bits: u32,
     ) -> io::Result<()> {
-        let names = internal::path::name_chain_from_path(path)?;
+        let names = path.name_chain()?;
         let stream_id = match self.stream_id_for_name_chain(&names) {
             Some(stream_id) => stream_id,
             None => not_found!(
@@ -934,12 +1875,12 @@ impl<F: Read + Write + Seek> CompoundFile<F> {
 
     /// Sets the modified time for the object at the given path to now.  Has no
     /// effect when called on the root storage.
-    pub fn touch<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
-        self.touch_with_path(path.as_ref())
+    pub fn touch<P: ObjectPath>(&mut self, path: P) -> io::Result<()> {
+        self.touch_with_path(&path)
     }
 
-    fn touch_with_path(&mut self, path: &Path) -> io::Result<()> {
-        let names = internal::path::name_chain_from_path(path)?;
+    fn touch_with_path(&mut self, path: &dyn ObjectPath) -> io::Result<()> {
+        let names = path.name_chain()?;
         let path = internal::path::path_from_name_chain(&names);
         let stream_id = match self.stream_id_for_name_chain(&names) {
             Some(stream_id) => stream_id,
@@ -958,12 +1899,162 @@ impl<F: Read + Write + Seek> CompoundFile<F> {
         Ok(())
     }
 
-    /// Flushes all changes to the underlying file.
+    /// Rebuilds the red-black tree of children within every storage object
+    /// so that it is balanced and validly colored, as required by the MS-CFB
+    /// spec.  Trees are always kept balanced as objects are added and
+    /// removed, but compound files written by other implementations (and
+    /// opened with `open` rather than `open_strict`) may contain unbalanced
+    /// trees, which are slow to search and may be rejected by other readers.
+    pub fn rebalance_directory(&mut self) -> io::Result<()> {
+        self.minialloc_mut().rebalance_all()
+    }
+
+    /// Sets when changes to the FAT, MiniFAT, and directory entries are
+    /// written to the underlying file.  In `WriteMode::WriteBack` mode, each
+    /// modified sector is written only once, when this compound file is
+    /// flushed or dropped; switching back to `WriteMode::WriteThrough` writes
+    /// out any pending changes immediately.
+    ///
+    /// Stream data is always written through to the underlying file as soon
+    /// as the stream's own buffer is flushed, regardless of this setting.
+    pub fn set_write_mode(&mut self, write_mode: WriteMode) -> io::Result<()> {
+        self.minialloc_mut().set_write_mode(write_mode)
+    }
+
+    /// Begins a transaction, returning a guard through which this compound
+    /// file can be modified as usual.  None of the changes made through the
+    /// guard are visible in the underlying file until the transaction is
+    /// committed; see [`Transaction`](struct.Transaction.html) for details.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::io::{Cursor, Write};
+    /// let mut comp = cfb::CompoundFile::create(Cursor::new(Vec::new()))?;
+    /// let mut transaction = comp.begin_transaction()?;
+    /// transaction.create_stream("/foo")?.write_all(b"Hello, world!")?;
+    /// transaction.commit()?;
+    /// assert!(comp.is_stream("/foo"));
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn begin_transaction(&mut self) -> io::Result<Transaction<F>> {
+        self.minialloc_mut().begin_transaction()?;
+        Ok(Transaction { comp: self, finished: false })
+    }
+
+    /// Flushes all changes to the underlying file.  In journaling mode (and
+    /// outside of any transaction begun with `begin_transaction`), this
+    /// commits all changes made since the last flush.
     pub fn flush(&mut self) -> io::Result<()> {
         self.minialloc_mut().flush()
     }
 }
 
+impl<F: Read + Write + Seek + SetLen> CompoundFile<F> {
+    /// Defragments this compound file in place, so that every chain is
+    /// contiguous and all free space is at the end of the file, and then
+    /// truncates the underlying file to remove that free space.  Directory
+    /// entries that are no longer reachable from the root are discarded.
+    ///
+    /// Existing `Stream` objects for this compound file remain valid.
+    /// However, if this returns an error partway through, the underlying file
+    /// may be left in an inconsistent state.
+    pub fn compact(&mut self) -> io::Result<()> {
+        internal::compact_in_place(&mut self.minialloc_mut())
+    }
+
+    /// Truncates the underlying file to remove any free sectors at the end of
+    /// it.  Unlike `compact`, this never moves any data, so free sectors that
+    /// are followed by allocated sectors will remain in the file.
+    pub fn shrink_to_fit(&mut self) -> io::Result<()> {
+        self.minialloc_mut().shrink_to_fit()?;
+        self.flush()
+    }
+}
+
+//===========================================================================//
+
+/// A set of changes to a compound file that take effect all at once, created
+/// by [`CompoundFile::begin_transaction`](
+/// struct.CompoundFile.html#method.begin_transaction).
+///
+/// A `Transaction` dereferences to its `CompoundFile`, so changes are made
+/// through it in the usual way.  While the transaction is in progress, new
+/// data is only ever written to sectors that the compound file isn't already
+/// using, and the file's header, allocation tables, and directory are left
+/// untouched; the underlying file therefore continues to hold the compound
+/// file as it was before the transaction began.  Calling `commit` switches
+/// the underlying file over to the new contents, while calling `revert` (or
+/// dropping the transaction without committing it) discards them.
+///
+/// Any `Stream` should be dropped (or flushed) before the transaction is
+/// committed or reverted.  A stream that is still open at that point can no
+/// longer be used: it returns an error as soon as it needs to access the
+/// compound file (for example, when it is flushed, or when reading past what
+/// it has buffered), and any changes still buffered in it are discarded.
+/// Compacting or shrinking the compound file is not allowed while a
+/// transaction is in progress.
+///
+/// In journaling mode, beginning a transaction first commits any changes
+/// made since the compound file was last flushed, and flushing the compound
+/// file during the transaction doesn't commit anything.
+pub struct Transaction<'a, F: Read + Write + Seek> {
+    comp: &'a mut CompoundFile<F>,
+    finished: bool,
+}
+
+impl<'a, F: Read + Write + Seek> Transaction<'a, F> {
+    /// Writes all changes made during this transaction to the underlying
+    /// file, and then switches the file over to them by rewriting its header.
+    /// If this returns an error, all changes made during the transaction are
+    /// discarded, and the underlying file will normally still hold the
+    /// compound file as it was before the transaction began.
+    pub fn commit(mut self) -> io::Result<()> {
+        self.finished = true;
+        let mut minialloc = self.comp.minialloc_mut();
+        let result = minialloc.commit_transaction();
+        if result.is_err() {
+            let _ = minialloc.revert_transaction();
+        }
+        result
+    }
+
+    /// Discards all changes made during this transaction.  Sectors that were
+    /// written during the transaction remain at the end of the underlying
+    /// file, if that is where they were added, until they are reused or the
+    /// file is shrunk.
+    ///
+    /// In journaling mode, reverting immediately begins a new implicit
+    /// transaction, which writes out any pending allocation table changes
+    /// first; that is the only way this can fail.
+    pub fn revert(mut self) -> io::Result<()> {
+        self.finished = true;
+        self.comp.minialloc_mut().revert_transaction()
+    }
+}
+
+impl<'a, F: Read + Write + Seek> Deref for Transaction<'a, F> {
+    type Target = CompoundFile<F>;
+
+    fn deref(&self) -> &CompoundFile<F> {
+        self.comp
+    }
+}
+
+impl<'a, F: Read + Write + Seek> DerefMut for Transaction<'a, F> {
+    fn deref_mut(&mut self) -> &mut CompoundFile<F> {
+        self.comp
+    }
+}
+
+impl<'a, F: Read + Write + Seek> Drop for Transaction<'a, F> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.comp.minialloc_mut().revert_transaction();
+        }
+    }
+}
+
 //===========================================================================//
 
 #[cfg(test)]
@@ -975,7 +2066,7 @@ mod tests {
 
     use crate::internal::{consts, DirEntry, Header, Version};
 
-    use super::CompoundFile;
+    use super::{CompoundFile, ValidationPolicy};
 
     fn make_cfb_file_with_zero_padded_fat() -> io::Result<Vec<u8>> {
         let version = Version::V3;
@@ -985,6 +2076,7 @@ mod tests {
             num_dir_sectors: 0,
             num_fat_sectors: 1,
             first_dir_sector: 1,
+            transaction_signature: 0,
             first_minifat_sector: consts::END_OF_CHAIN,
             num_minifat_sectors: 0,
             first_difat_sector: consts::END_OF_CHAIN,
@@ -1031,6 +2123,22 @@ mod tests {
         // under Permissive validation.
         CompoundFile::open(Cursor::new(data)).expect("open");
     }
+
+    #[test]
+    fn zero_padded_fat_custom_policy() {
+        let data = make_cfb_file_with_zero_padded_fat().unwrap();
+        let mut policy = ValidationPolicy::strict();
+        policy.allow_zero_padded_fat = true;
+        CompoundFile::open_with_policy(Cursor::new(data.clone()), policy)
+            .expect("open");
+        let mut policy = ValidationPolicy::permissive();
+        policy.allow_zero_padded_fat = false;
+        let result = CompoundFile::open_with_policy(Cursor::new(data), policy);
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "Malformed FAT (FAT has 128 entries, but file has only 2 sectors)"
+        );
+    }
 }
 
 //===========================================================================//
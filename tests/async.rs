@@ -0,0 +1,255 @@
+#![cfg(feature = "async")]
+
+use cfb::{AsyncCompoundFile, CompoundFile, Version};
+use futures::executor::block_on;
+use futures::io::{
+    AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite,
+    AsyncWriteExt, Cursor,
+};
+use std::io::{self, Read, SeekFrom};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+mod common;
+use common::{create_compound_file, make_data};
+
+//===========================================================================//
+
+/// An in-memory file that returns `Poll::Pending` from every other call, to
+/// check that callers resume operations correctly.
+struct Stuttering {
+    cursor: Cursor<Vec<u8>>,
+    ready: bool,
+}
+
+impl Stuttering {
+    fn new(data: Vec<u8>) -> Stuttering {
+        Stuttering { cursor: Cursor::new(data), ready: false }
+    }
+
+    fn into_inner(self) -> Vec<u8> {
+        self.cursor.into_inner()
+    }
+
+    fn stutter(&mut self, cx: &mut Context<'_>) -> bool {
+        self.ready = !self.ready;
+        if !self.ready {
+            cx.waker().wake_by_ref();
+        }
+        !self.ready
+    }
+}
+
+impl AsyncRead for Stuttering {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.stutter(cx) {
+            return Poll::Pending;
+        }
+        Pin::new(&mut self.cursor).poll_read(cx, buf)
+    }
+}
+
+impl AsyncSeek for Stuttering {
+    fn poll_seek(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        pos: SeekFrom,
+    ) -> Poll<io::Result<u64>> {
+        if self.stutter(cx) {
+            return Poll::Pending;
+        }
+        Pin::new(&mut self.cursor).poll_seek(cx, pos)
+    }
+}
+
+impl AsyncWrite for Stuttering {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.stutter(cx) {
+            return Poll::Pending;
+        }
+        Pin::new(&mut self.cursor).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.cursor).poll_flush(cx)
+    }
+
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.cursor).poll_close(cx)
+    }
+}
+
+fn make_compound_file(version: Version) -> Vec<u8> {
+    let mut objects = vec![
+        ("/storage/".to_string(), 0),
+        ("/storage/small".to_string(), 1000),
+        ("/large".to_string(), 100000),
+    ];
+    // Enough entries for the directory to span several sectors.
+    for index in 0..40 {
+        objects.push((format!("/storage/{}", index), 0));
+    }
+    create_compound_file(version, &objects).into_inner().into_inner()
+}
+
+fn read_sync_stream(data: Vec<u8>, path: &str) -> Vec<u8> {
+    let comp = CompoundFile::open_strict(io::Cursor::new(data)).unwrap();
+    let mut buffer = Vec::new();
+    comp.open_stream(path).unwrap().read_to_end(&mut buffer).unwrap();
+    buffer
+}
+
+//===========================================================================//
+// Tests for asynchronous compound files:
+
+fn open_and_read(version: Version) {
+    let data = make_compound_file(version);
+    block_on(async {
+        let mut comp = AsyncCompoundFile::open_strict(Stuttering::new(data))
+            .await
+            .unwrap();
+        assert_eq!(comp.version(), version);
+        let names: Vec<String> = comp
+            .read_root_storage()
+            .map(|entry| entry.name().to_string())
+            .collect();
+        assert_eq!(names, vec!["large", "storage"]);
+        assert_eq!(comp.read_storage("/storage").unwrap().count(), 41);
+        assert!(comp.is_stream("/storage/small"));
+
+        let mut buffer = Vec::new();
+        let mut stream = comp.open_stream("/storage/small").unwrap();
+        stream.read_to_end(&mut buffer).await.unwrap();
+        assert_eq!(buffer, make_data(1, 1000));
+
+        let mut stream = comp.open_stream("/large").unwrap();
+        assert_eq!(stream.len(), 100000);
+        stream.seek(SeekFrom::Start(30000)).await.unwrap();
+        let mut buffer = vec![0u8; 1000];
+        stream.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(buffer, &make_data(2, 100000)[30000..31000]);
+        stream.seek(SeekFrom::Start(0)).await.unwrap();
+        let mut buffer = Vec::new();
+        stream.read_to_end(&mut buffer).await.unwrap();
+        assert_eq!(buffer, make_data(2, 100000));
+    });
+}
+
+#[test]
+fn open_and_read_v3() {
+    open_and_read(Version::V3);
+}
+
+#[test]
+fn open_and_read_v4() {
+    open_and_read(Version::V4);
+}
+
+#[test]
+fn open_invalid_file() {
+    block_on(async {
+        let cursor = Cursor::new(vec![0u8; 1024]);
+        assert!(AsyncCompoundFile::open(cursor).await.is_err());
+        let mut data = make_compound_file(Version::V3);
+        data.truncate(100);
+        assert!(AsyncCompoundFile::open(Cursor::new(data)).await.is_err());
+    });
+}
+
+#[test]
+fn write_and_flush() {
+    let data = make_compound_file(Version::V3);
+    let data = block_on(async {
+        let mut comp =
+            AsyncCompoundFile::open(Stuttering::new(data)).await.unwrap();
+        let mut stream = comp.open_stream("/large").unwrap();
+        stream.seek(SeekFrom::Start(50000)).await.unwrap();
+        stream.write_all(&make_data(3, 1000)).await.unwrap();
+        // Unflushed writes are visible to reads.
+        stream.seek(SeekFrom::Start(49500)).await.unwrap();
+        let mut buffer = vec![0u8; 2000];
+        stream.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..500], &make_data(2, 100000)[49500..50000]);
+        assert_eq!(&buffer[500..1500], &make_data(3, 1000)[..]);
+        stream.flush().await.unwrap();
+
+        comp.create_storage("/new").unwrap();
+        let mut stream = comp.create_stream("/new/stream").unwrap();
+        stream.write_all(&make_data(4, 20000)).await.unwrap();
+        comp.remove_stream("/storage/0").unwrap();
+        comp.flush().await.unwrap();
+
+        // Reads of flushed data go back to the underlying file.
+        let mut stream = comp.open_stream("/new/stream").unwrap();
+        let mut buffer = Vec::new();
+        stream.read_to_end(&mut buffer).await.unwrap();
+        assert_eq!(buffer, make_data(4, 20000));
+        comp.into_inner().into_inner()
+    });
+    let mut expected = make_data(2, 100000);
+    expected[50000..51000].copy_from_slice(&make_data(3, 1000));
+    assert_eq!(read_sync_stream(data.clone(), "/large"), expected);
+    assert_eq!(
+        read_sync_stream(data.clone(), "/new/stream"),
+        make_data(4, 20000)
+    );
+    let comp = CompoundFile::open_strict(io::Cursor::new(data)).unwrap();
+    assert!(!comp.exists("/storage/0"));
+}
+
+#[test]
+fn create_new_file() {
+    let data = block_on(async {
+        let cursor = Stuttering::new(Vec::new());
+        let mut comp = AsyncCompoundFile::create(cursor).await.unwrap();
+        let mut stream = comp.create_stream("/small").unwrap();
+        stream.write_all(&make_data(5, 100)).await.unwrap();
+        let mut stream = comp.create_stream("/large").unwrap();
+        stream.write_all(&make_data(6, 10000)).await.unwrap();
+        stream.close().await.unwrap();
+        comp.into_inner().into_inner()
+    });
+    assert_eq!(read_sync_stream(data.clone(), "/small"), make_data(5, 100));
+    assert_eq!(read_sync_stream(data.clone(), "/large"), make_data(6, 10000));
+    // The file can be opened again asynchronously too.
+    block_on(async {
+        let mut comp =
+            AsyncCompoundFile::open(Cursor::new(data)).await.unwrap();
+        let mut buffer = Vec::new();
+        let mut stream = comp.open_stream("/large").unwrap();
+        stream.read_to_end(&mut buffer).await.unwrap();
+        assert_eq!(buffer, make_data(6, 10000));
+    });
+}
+
+#[test]
+fn unflushed_changes_are_discarded() {
+    let data = make_compound_file(Version::V4);
+    let data = block_on(async {
+        let mut comp =
+            AsyncCompoundFile::open(Cursor::new(data.clone())).await.unwrap();
+        let mut stream = comp.create_stream("/unflushed").unwrap();
+        stream.write_all(&make_data(7, 5000)).await.unwrap();
+        let data_after = comp.into_inner().into_inner();
+        assert_eq!(data_after, data);
+        data_after
+    });
+    let comp = CompoundFile::open(io::Cursor::new(data)).unwrap();
+    assert!(!comp.exists("/unflushed"));
+}
+
+//===========================================================================//
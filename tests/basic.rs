@@ -1,6 +1,9 @@
-use cfb::{CompoundFile, Entry, Version};
-use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use byteorder::{ByteOrder, LittleEndian};
+use cfb::{CompoundFile, Entry, Version, WriteMode};
+use std::cell::Cell;
+use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom, Write};
 use std::path::Path;
+use std::rc::Rc;
 use uuid::Uuid;
 
 //===========================================================================//
@@ -55,6 +58,7 @@ fn empty_compound_file_has_no_children() {
 }
 
 #[test]
+#[allow(clippy::needless_range_loop)]
 fn partial_final_sector() {
     // Create a CFB with 4096-byte sectors.
     let mut comp = CompoundFile::create_with_version(
@@ -86,7 +90,7 @@ fn partial_final_sector() {
     // partial final sectors, we should consider it valid and still be able
     // to read the stream.
     cfb_data.truncate(5 * 4096 + stream_data.len() % 4096);
-    let mut comp = CompoundFile::open(Cursor::new(cfb_data)).unwrap();
+    let comp = CompoundFile::open(Cursor::new(cfb_data)).unwrap();
     assert_eq!(comp.entry("s").unwrap().len(), stream_data.len() as u64);
     let mut actual_data = Vec::new();
     comp.open_stream("s").unwrap().read_to_end(&mut actual_data).unwrap();
@@ -179,6 +183,30 @@ fn open_stream_on_storage() {
     comp.open_stream("/foo").unwrap();
 }
 
+#[test]
+fn many_children_stay_ordered() {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create(cursor).expect("create");
+    let names: Vec<String> = (0..500).map(|i| format!("{:04}", i)).collect();
+    for name in names.iter() {
+        comp.create_stream(name).unwrap();
+    }
+    for name in names.iter().step_by(3) {
+        comp.remove_stream(name).unwrap();
+    }
+    comp.rebalance_directory().unwrap();
+
+    let cursor = comp.into_inner();
+    let comp = CompoundFile::open_strict(cursor).expect("open");
+    let expected: Vec<String> = names
+        .iter()
+        .enumerate()
+        .filter(|&(index, _)| index % 3 != 0)
+        .map(|(_, name)| name.clone())
+        .collect();
+    assert_eq!(read_root_storage_to_vec(&comp), expected);
+}
+
 //===========================================================================//
 // Tests for path methods:
 
@@ -468,7 +496,7 @@ fn create_streams() {
     comp.create_stream("/baz").unwrap().write_all(b"baz!").unwrap();
 
     let cursor = comp.into_inner();
-    let mut comp = CompoundFile::open_strict(cursor).expect("open");
+    let comp = CompoundFile::open_strict(cursor).expect("open");
     {
         let mut stream = comp.open_stream("/foo").unwrap();
         let mut data = String::new();
@@ -493,7 +521,7 @@ fn create_small_stream() {
     comp.create_stream("foobar").unwrap().write_all(&data).unwrap();
 
     let cursor = comp.into_inner();
-    let mut comp = CompoundFile::open_strict(cursor).expect("open");
+    let comp = CompoundFile::open_strict(cursor).expect("open");
     let mut stream = comp.open_stream("foobar").unwrap();
     let mut actual_data = Vec::new();
     stream.read_to_end(&mut actual_data).unwrap();
@@ -510,7 +538,7 @@ fn create_large_stream() {
     comp.create_stream("foobar").unwrap().write_all(&data).unwrap();
 
     let cursor = comp.into_inner();
-    let mut comp = CompoundFile::open_strict(cursor).expect("open");
+    let comp = CompoundFile::open_strict(cursor).expect("open");
     let mut stream = comp.open_stream("foobar").unwrap();
     let mut actual_data = Vec::new();
     stream.read_to_end(&mut actual_data).unwrap();
@@ -531,7 +559,7 @@ fn create_very_large_stream() {
     }
 
     let cursor = comp.into_inner();
-    let mut comp = CompoundFile::open_strict(cursor).expect("open");
+    let comp = CompoundFile::open_strict(cursor).expect("open");
     let mut stream = comp.open_stream("foobar").unwrap();
     assert_eq!(stream.len(), 1_000_000);
     assert_eq!(stream.seek(SeekFrom::End(0)).unwrap(), 1_000_000);
@@ -611,6 +639,33 @@ fn remove_small_stream() {
     comp.remove_stream("/foo").unwrap();
 }
 
+#[test]
+fn removed_stream_sectors_are_reused() {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create(cursor).expect("create");
+    comp.create_stream("/foo").unwrap().write_all(&[1; 5000]).unwrap();
+    comp.create_stream("/bar").unwrap().write_all(&[2; 5000]).unwrap();
+    let cursor = comp.into_inner();
+    let len = cursor.get_ref().len();
+
+    // The sectors freed by removing "/foo" aren't at the end of the file, so
+    // the file would grow if they weren't reused for "/baz".
+    let mut comp = CompoundFile::open(cursor).expect("open");
+    comp.remove_stream("/foo").unwrap();
+    comp.create_stream("/baz").unwrap().write_all(&[3; 5000]).unwrap();
+    let cursor = comp.into_inner();
+    assert_eq!(cursor.get_ref().len(), len);
+
+    let comp = CompoundFile::open_strict(cursor).expect("open");
+    assert_eq!(read_storage_to_vec(&comp, "/"), vec!["bar", "baz"]);
+    let mut data = Vec::new();
+    comp.open_stream("/bar").unwrap().read_to_end(&mut data).unwrap();
+    assert_eq!(data, vec![2; 5000]);
+    data.clear();
+    comp.open_stream("/baz").unwrap().read_to_end(&mut data).unwrap();
+    assert_eq!(data, vec![3; 5000]);
+}
+
 #[test]
 #[should_panic(expected = "No such stream: \\\"/foo\\\"")]
 fn remove_nonexistent_stream() {
@@ -628,6 +683,399 @@ fn remove_stream_on_storage() {
     comp.remove_stream("/foo").unwrap();
 }
 
+//===========================================================================//
+// Tests for copying:
+
+#[test]
+fn copy_streams() {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create(cursor).expect("create");
+    let small: Vec<u8> = (0..500).map(|i| i as u8).collect();
+    let large: Vec<u8> = (0..9000).map(|i| (i * 7) as u8).collect();
+    comp.create_stream("/small").unwrap().write_all(&small).unwrap();
+    comp.create_stream("/large").unwrap().write_all(&large).unwrap();
+    comp.set_state_bits("/small", 0xabcd).unwrap();
+    comp.create_storage("/foo").unwrap();
+    comp.copy_stream("/small", "/foo/small").unwrap();
+    comp.copy_stream("/large", "/foo/large").unwrap();
+    comp.create_stream("/foo/quux").unwrap().write_all(&large).unwrap();
+    comp.copy_stream("/small", "/foo/quux").unwrap();
+
+    let cursor = comp.into_inner();
+    let comp = CompoundFile::open_strict(cursor).expect("open");
+    assert_eq!(
+        read_storage_to_vec(&comp, "/foo"),
+        vec!["quux", "large", "small"]
+    );
+    assert_eq!(comp.entry("/foo/small").unwrap().state_bits(), 0xabcd);
+    for &(path, expected) in &[
+        ("/small", &small),
+        ("/large", &large),
+        ("/foo/small", &small),
+        ("/foo/large", &large),
+        ("/foo/quux", &small),
+    ] {
+        let mut data = Vec::new();
+        comp.open_stream(path).unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(&data, expected);
+    }
+}
+
+/// A reader/writer whose reads start failing once `reads_left` runs out.
+struct FailingReader {
+    inner: Cursor<Vec<u8>>,
+    reads_left: Rc<Cell<usize>>,
+}
+
+impl Read for FailingReader {
+    #[allow(clippy::io_other_error)]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.reads_left.get() == 0 {
+            return Err(io::Error::new(io::ErrorKind::Other, "read failed"));
+        }
+        self.reads_left.set(self.reads_left.get() - 1);
+        self.inner.read(buf)
+    }
+}
+
+impl Seek for FailingReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl Write for FailingReader {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[test]
+fn failed_copy_stream_keeps_existing_stream() {
+    let old: Vec<u8> = (0..700).map(|i| i as u8).collect();
+    let new: Vec<u8> = (0..9000).map(|i| (i * 7) as u8).collect();
+    // Let the copy fail at each read of the source stream in turn, until it
+    // succeeds.
+    for budget in 0.. {
+        let reads_left = Rc::new(Cell::new(usize::MAX));
+        let file = FailingReader {
+            inner: Cursor::new(Vec::new()),
+            reads_left: reads_left.clone(),
+        };
+        let mut comp = CompoundFile::create(file).expect("create");
+        comp.create_stream("/dst").unwrap().write_all(&old).unwrap();
+        comp.create_stream("/src").unwrap().write_all(&new).unwrap();
+        reads_left.set(budget);
+        let result = comp.copy_stream("/src", "/dst");
+        reads_left.set(usize::MAX);
+        assert_eq!(read_root_storage_to_vec(&comp), vec!["dst", "src"]);
+        let mut data = Vec::new();
+        comp.open_stream("/dst").unwrap().read_to_end(&mut data).unwrap();
+        if result.is_err() {
+            assert_eq!(data, old, "after failing at read {}", budget);
+            continue;
+        }
+        assert_eq!(data, new);
+        let cursor = comp.into_inner().inner;
+        CompoundFile::open_strict(cursor).expect("open");
+        break;
+    }
+}
+
+#[test]
+#[should_panic(
+    expected = "Cannot copy stream to \\\"/foo\\\" because a storage \
+                           already exists there"
+)]
+fn copy_stream_onto_storage() {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create(cursor).expect("create");
+    comp.create_stream("/bar").unwrap();
+    comp.create_storage("/foo").unwrap();
+    comp.copy_stream("/bar", "/foo").unwrap();
+}
+
+#[test]
+#[should_panic(expected = "Cannot copy \\\"/foo\\\" onto itself")]
+fn copy_stream_onto_itself() {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create(cursor).expect("create");
+    comp.create_stream("/foo").unwrap();
+    comp.copy_stream("/foo", "/FOO").unwrap();
+}
+
+#[test]
+fn copy_storage_recursively() {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create(cursor).expect("create");
+    comp.create_storage("/foo").unwrap();
+    comp.create_storage("/foo/bar").unwrap();
+    comp.create_stream("/foo/bar/baz").unwrap().write_all(&[3; 5000]).unwrap();
+    comp.create_stream("/foo/quux").unwrap().write_all(b"quux").unwrap();
+    let clsid =
+        Uuid::parse_str("3b90b4f8-1c92-4d0c-98e8-a4b32ef8b3c9").unwrap();
+    comp.set_storage_clsid("/foo/bar", clsid).unwrap();
+    comp.set_state_bits("/foo", 17).unwrap();
+    comp.copy_storage("/foo", "/copy").unwrap();
+
+    let cursor = comp.into_inner();
+    let comp = CompoundFile::open_strict(cursor).expect("open");
+    assert_eq!(read_storage_to_vec(&comp, "/"), vec!["foo", "copy"]);
+    let entries: Vec<Entry> = comp.walk_storage("/copy").unwrap().collect();
+    assert_eq!(
+        walk_to_vec(&entries),
+        vec![
+            Path::new("/copy"),
+            Path::new("/copy/bar"),
+            Path::new("/copy/bar/baz"),
+            Path::new("/copy/quux"),
+        ]
+    );
+    let original = comp.entry("/foo/bar").unwrap();
+    let copy = comp.entry("/copy/bar").unwrap();
+    assert_eq!(copy.clsid(), &clsid);
+    assert_eq!(copy.created(), original.created());
+    assert_eq!(copy.modified(), original.modified());
+    assert_eq!(comp.entry("/copy").unwrap().state_bits(), 17);
+    let mut data = Vec::new();
+    comp.open_stream("/copy/bar/baz").unwrap().read_to_end(&mut data).unwrap();
+    assert_eq!(data, vec![3; 5000]);
+}
+
+#[test]
+#[should_panic(expected = "Cannot copy \\\"/foo\\\" into itself")]
+fn copy_storage_into_itself() {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create(cursor).expect("create");
+    comp.create_storage("/foo").unwrap();
+    comp.create_storage("/foo/bar").unwrap();
+    comp.copy_storage("/foo", "/foo/bar/foo").unwrap();
+}
+
+#[test]
+#[should_panic(expected = "Cannot copy storage to \\\"/bar\\\" because an \
+                           object already exists there")]
+fn copy_storage_onto_existing_object() {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create(cursor).expect("create");
+    comp.create_storage("/foo").unwrap();
+    comp.create_stream("/bar").unwrap();
+    comp.copy_storage("/foo", "/bar").unwrap();
+}
+
+//===========================================================================//
+// Tests for importing from other compound files:
+
+#[test]
+fn import_storage_from_other_file() {
+    let cursor = Cursor::new(Vec::new());
+    let mut src = CompoundFile::create_with_version(Version::V3, cursor)
+        .expect("create");
+    src.create_storage("/foo").unwrap();
+    src.create_stream("/foo/bar").unwrap().write_all(&[1; 100]).unwrap();
+    src.create_storage("/foo/baz").unwrap();
+    src.create_stream("/foo/baz/quux").unwrap().write_all(&[2; 5000]).unwrap();
+    let clsid =
+        Uuid::parse_str("3b90b4f8-1c92-4d0c-98e8-a4b32ef8b3c9").unwrap();
+    src.set_storage_clsid("/foo/baz", clsid).unwrap();
+    src.set_state_bits("/foo/bar", 42).unwrap();
+    let data = src.into_inner().into_inner();
+    let src = CompoundFile::open(Cursor::new(data.as_slice())).expect("open");
+
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create(cursor).expect("create");
+    comp.create_storage("/dest").unwrap();
+    let report = comp.import_from(&src, "/foo", "/dest/imported").unwrap();
+    assert_eq!(report.num_entries(), 4);
+    assert_eq!(report.num_bytes(), 5100);
+
+    let cursor = comp.into_inner();
+    let comp = CompoundFile::open_strict(cursor).expect("open");
+    assert_eq!(comp.version(), Version::V4);
+    assert_eq!(
+        read_storage_to_vec(&comp, "/dest/imported"),
+        vec!["bar", "baz"]
+    );
+    assert_eq!(comp.entry("/dest/imported/baz").unwrap().clsid(), &clsid);
+    assert_eq!(comp.entry("/dest/imported/bar").unwrap().state_bits(), 42);
+    let mut data = Vec::new();
+    comp.open_stream("/dest/imported/baz/quux")
+        .unwrap()
+        .read_to_end(&mut data)
+        .unwrap();
+    assert_eq!(data, vec![2; 5000]);
+}
+
+#[test]
+fn import_stream_from_other_file() {
+    let cursor = Cursor::new(Vec::new());
+    let mut src = CompoundFile::create(cursor).expect("create");
+    src.create_stream("/foo").unwrap().write_all(b"foobar").unwrap();
+
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create(cursor).expect("create");
+    let report = comp.import_from(&src, "/foo", "/bar").unwrap();
+    assert_eq!(report.num_entries(), 1);
+    assert_eq!(report.num_bytes(), 6);
+    let mut data = Vec::new();
+    comp.open_stream("/bar").unwrap().read_to_end(&mut data).unwrap();
+    assert_eq!(data, b"foobar");
+}
+
+#[test]
+#[should_panic(expected = "Cannot import to \\\"/foo\\\" because an object \
+                           already exists there")]
+fn import_onto_existing_object() {
+    let cursor = Cursor::new(Vec::new());
+    let mut src = CompoundFile::create(cursor).expect("create");
+    src.create_stream("/foo").unwrap();
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create(cursor).expect("create");
+    comp.create_storage("/foo").unwrap();
+    comp.import_from(&src, "/foo", "/foo").unwrap();
+}
+
+//===========================================================================//
+// Tests for renaming:
+
+#[test]
+fn rename_stream_in_place() {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create(cursor).expect("create");
+    comp.create_stream("/foo").unwrap().write_all(b"foobar").unwrap();
+    comp.create_stream("/bar").unwrap();
+    comp.set_state_bits("/foo", 0x1234).unwrap();
+    comp.rename("/foo", "/quux").unwrap();
+
+    let cursor = comp.into_inner();
+    let comp = CompoundFile::open_strict(cursor).expect("open");
+    assert_eq!(read_storage_to_vec(&comp, "/"), vec!["bar", "quux"]);
+    assert_eq!(comp.entry("/quux").unwrap().state_bits(), 0x1234);
+    let mut data = Vec::new();
+    comp.open_stream("/quux").unwrap().read_to_end(&mut data).unwrap();
+    assert_eq!(data, b"foobar");
+}
+
+#[test]
+fn rename_changing_case() {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create(cursor).expect("create");
+    comp.create_storage("/foo").unwrap();
+    comp.create_stream("/foo/bar").unwrap();
+    comp.rename("/foo", "/FOO").unwrap();
+
+    let cursor = comp.into_inner();
+    let comp = CompoundFile::open_strict(cursor).expect("open");
+    assert_eq!(read_storage_to_vec(&comp, "/"), vec!["FOO"]);
+    assert_eq!(read_storage_to_vec(&comp, "/FOO"), vec!["bar"]);
+}
+
+#[test]
+fn move_storage_with_children() {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create(cursor).expect("create");
+    comp.create_storage("/foo").unwrap();
+    comp.create_storage("/foo/bar").unwrap();
+    comp.create_stream("/foo/bar/baz").unwrap().write_all(&[7; 5000]).unwrap();
+    comp.create_stream("/foo/quux").unwrap();
+    comp.create_storage("/stuff").unwrap();
+    let clsid =
+        Uuid::parse_str("3b90b4f8-1c92-4d0c-98e8-a4b32ef8b3c9").unwrap();
+    comp.set_storage_clsid("/foo/bar", clsid).unwrap();
+    let created = comp.entry("/foo/bar").unwrap().created();
+    comp.rename("/foo/bar", "/stuff/things").unwrap();
+
+    let cursor = comp.into_inner();
+    let comp = CompoundFile::open_strict(cursor).expect("open");
+    assert_eq!(read_storage_to_vec(&comp, "/foo"), vec!["quux"]);
+    assert_eq!(read_storage_to_vec(&comp, "/stuff"), vec!["things"]);
+    assert_eq!(read_storage_to_vec(&comp, "/stuff/things"), vec!["baz"]);
+    let entry = comp.entry("/stuff/things").unwrap();
+    assert_eq!(entry.clsid(), &clsid);
+    assert_eq!(entry.created(), created);
+    let mut data = Vec::new();
+    comp.open_stream("/stuff/things/baz")
+        .unwrap()
+        .read_to_end(&mut data)
+        .unwrap();
+    assert_eq!(data, vec![7; 5000]);
+}
+
+#[test]
+fn rename_many_siblings() {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create(cursor).expect("create");
+    comp.create_storage("/dest").unwrap();
+    let names = ["m", "f", "t", "b", "h", "q", "w", "a", "c", "g", "i"];
+    for name in names.iter() {
+        comp.create_stream(format!("/{}", name)).unwrap();
+    }
+    for name in ["m", "f", "h", "a"].iter() {
+        comp.rename(format!("/{}", name), format!("/dest/{}", name)).unwrap();
+    }
+
+    let cursor = comp.into_inner();
+    let comp = CompoundFile::open_strict(cursor).expect("open");
+    assert_eq!(
+        read_storage_to_vec(&comp, "/"),
+        vec!["b", "c", "g", "i", "q", "t", "w", "dest"]
+    );
+    assert_eq!(read_storage_to_vec(&comp, "/dest"), vec!["a", "f", "h", "m"]);
+}
+
+#[test]
+#[should_panic(
+    expected = "Cannot rename \\\"/foo\\\" to \\\"/BAR\\\" because an \
+                           object already exists there"
+)]
+fn rename_onto_existing_object() {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create(cursor).expect("create");
+    comp.create_stream("/foo").unwrap();
+    comp.create_storage("/bar").unwrap();
+    comp.rename("/foo", "/BAR").unwrap();
+}
+
+#[test]
+#[should_panic(expected = "No such object: \\\"/foo\\\"")]
+fn rename_nonexistent_object() {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create(cursor).expect("create");
+    comp.rename("/foo", "/bar").unwrap();
+}
+
+#[test]
+#[should_panic(expected = "Parent storage doesn't exist")]
+fn rename_into_nonexistent_storage() {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create(cursor).expect("create");
+    comp.create_stream("/foo").unwrap();
+    comp.rename("/foo", "/bar/foo").unwrap();
+}
+
+#[test]
+#[should_panic(expected = "Cannot rename the root storage object")]
+fn rename_root_storage() {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create(cursor).expect("create");
+    comp.create_storage("/foo").unwrap();
+    comp.rename("/", "/foo/bar").unwrap();
+}
+
+#[test]
+#[should_panic(expected = "Cannot move \\\"/foo\\\" into itself")]
+fn move_storage_into_itself() {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create(cursor).expect("create");
+    comp.create_storage("/foo").unwrap();
+    comp.create_storage("/foo/bar").unwrap();
+    comp.rename("/foo", "/foo/bar/foo").unwrap();
+}
+
 //===========================================================================//
 // Tests for navigating within streams:
 
@@ -651,7 +1099,7 @@ fn truncate_stream() {
     }
 
     let cursor = comp.into_inner();
-    let mut comp = CompoundFile::open_strict(cursor).expect("open");
+    let comp = CompoundFile::open_strict(cursor).expect("open");
     let mut stream = comp.open_stream("/foobar").unwrap();
     assert_eq!(stream.len(), 6000);
     let mut actual_data = Vec::new();
@@ -682,7 +1130,7 @@ fn extend_stream() {
     }
 
     let cursor = comp.into_inner();
-    let mut comp = CompoundFile::open_strict(cursor).expect("open");
+    let comp = CompoundFile::open_strict(cursor).expect("open");
     let mut stream = comp.open_stream("/foobar").unwrap();
     assert_eq!(stream.len(), 5000);
     let mut actual_data = Vec::new();
@@ -768,4 +1216,670 @@ fn drop_compound_file_with_stream_open() -> io::Result<()> {
     Ok(())
 }
 
+#[test]
+fn interleave_reads_through_buf_reader() -> io::Result<()> {
+    let small: Vec<u8> = (0..1000).map(|index| (index % 251) as u8).collect();
+    let large: Vec<u8> = (0..10000).map(|index| (index % 241) as u8).collect();
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create(cursor)?;
+    comp.create_stream("/small")?.write_all(&small)?;
+    comp.create_stream("/large")?.write_all(&large)?;
+    // A `BufReader` can only read by seeking, not with positional reads.
+    let cursor = BufReader::new(comp.into_inner());
+    let comp = CompoundFile::open_strict(cursor)?;
+    let mut small_stream = comp.open_stream("/small")?;
+    let mut large_stream = comp.open_stream("/large")?;
+    let mut small_data = Vec::new();
+    let mut large_data = Vec::new();
+    let mut buffer = [0u8; 100];
+    for _ in 0..10 {
+        small_stream.read_exact(&mut buffer)?;
+        small_data.extend_from_slice(&buffer);
+        large_stream.read_exact(&mut buffer)?;
+        large_data.extend_from_slice(&buffer);
+    }
+    large_stream.read_to_end(&mut large_data)?;
+    assert_eq!(small_data, small);
+    assert_eq!(large_data, large);
+    Ok(())
+}
+
+//===========================================================================//
+// Tests for releasing unused structural sectors:
+
+fn read_header_u32(data: &[u8], offset: usize) -> u32 {
+    LittleEndian::read_u32(&data[offset..])
+}
+
+#[test]
+fn truncate_directory_chain() -> io::Result<()> {
+    for version in [Version::V3, Version::V4] {
+        let cursor = Cursor::new(Vec::new());
+        let mut comp = CompoundFile::create_with_version(version, cursor)?;
+        let num_streams = 5 * version.dir_entries_per_sector();
+        for index in 0..num_streams {
+            comp.create_stream(format!("/stream{}", index))?;
+        }
+        comp.flush()?;
+        let data = comp.into_inner().into_inner();
+        if version == Version::V4 {
+            assert_eq!(read_header_u32(&data, 40), 6);
+        }
+
+        let mut comp = CompoundFile::open_strict(Cursor::new(data))?;
+        for index in 0..num_streams {
+            comp.remove_stream(format!("/stream{}", index))?;
+        }
+        comp.shrink_to_fit()?;
+        let data = comp.into_inner().into_inner();
+        if version == Version::V4 {
+            assert_eq!(read_header_u32(&data, 40), 1);
+        }
+        // Header, FAT sector, and directory sector:
+        assert_eq!(data.len(), 3 * version.sector_len());
+        let comp = CompoundFile::open_strict(Cursor::new(data))?;
+        assert!(read_root_storage_to_vec(&comp).is_empty());
+    }
+    Ok(())
+}
+
+#[test]
+fn truncate_minifat_chain() -> io::Result<()> {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create_with_version(Version::V3, cursor)?;
+    comp.create_stream("/foo")?.write_all(&[1; 3000])?;
+    comp.create_stream("/bar")?.write_all(&[2; 3000])?;
+    comp.create_stream("/baz")?.write_all(&[3; 3000])?;
+    comp.flush()?;
+    let data = comp.into_inner().into_inner();
+    assert_eq!(read_header_u32(&data, 64), 2);
+
+    let mut comp = CompoundFile::open_strict(Cursor::new(data))?;
+    comp.remove_stream("/baz")?;
+    comp.remove_stream("/bar")?;
+    comp.flush()?;
+    assert_eq!(comp.root_entry().len(), 3008);
+    let data = comp.into_inner().into_inner();
+    assert_eq!(read_header_u32(&data, 64), 1);
+
+    let mut comp = CompoundFile::open_strict(Cursor::new(data))?;
+
+    comp.remove_stream("/foo")?;
+    comp.shrink_to_fit()?;
+    let data = comp.into_inner().into_inner();
+    assert_eq!(read_header_u32(&data, 60), 0xfffffffe);
+    assert_eq!(read_header_u32(&data, 64), 0);
+    assert_eq!(data.len(), 3 * 512);
+    let mut comp = CompoundFile::open_strict(Cursor::new(data))?;
+    assert_eq!(comp.root_entry().len(), 0);
+
+    // The MiniFAT and mini stream should grow back as needed.
+    comp.create_stream("/foo")?.write_all(&[4; 100])?;
+    let comp = CompoundFile::open_strict(comp.into_inner())?;
+    let mut data = Vec::new();
+    comp.open_stream("/foo")?.read_to_end(&mut data)?;
+    assert_eq!(data, vec![4; 100]);
+    Ok(())
+}
+
+#[test]
+fn truncate_fat_and_difat_chains() -> io::Result<()> {
+    // Enough data to need more than 109 FAT sectors, and thus a DIFAT
+    // sector.
+    let len = 8 * 1024 * 1024;
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create_with_version(Version::V3, cursor)?;
+    comp.create_stream("/big")?.write_all(&vec![1; len])?;
+    comp.flush()?;
+    let data = comp.into_inner().into_inner();
+    assert!(read_header_u32(&data, 44) > 109);
+    assert_eq!(read_header_u32(&data, 72), 1);
+
+    let mut comp = CompoundFile::open_strict(Cursor::new(data))?;
+    comp.remove_stream("/big")?;
+    comp.shrink_to_fit()?;
+    let data = comp.into_inner().into_inner();
+    assert_eq!(read_header_u32(&data, 44), 1);
+    assert_eq!(read_header_u32(&data, 68), 0xfffffffe);
+    assert_eq!(read_header_u32(&data, 72), 0);
+    assert_eq!(data.len(), 3 * 512);
+    let mut comp = CompoundFile::open_strict(Cursor::new(data))?;
+
+    // The FAT should grow back as needed.
+    comp.create_stream("/big")?.write_all(&vec![2; len])?;
+    let comp = CompoundFile::open_strict(comp.into_inner())?;
+    let mut data = Vec::new();
+    comp.open_stream("/big")?.read_to_end(&mut data)?;
+    assert!(data == vec![2; len]);
+    Ok(())
+}
+
+#[test]
+fn shrink_to_fit_keeps_interior_free_sectors() -> io::Result<()> {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create_with_version(Version::V3, cursor)?;
+    comp.create_stream("/foo")?.write_all(&[1; 5000])?;
+    comp.create_stream("/bar")?.write_all(&[2; 5000])?;
+    comp.flush()?;
+    let old_len = comp.into_inner().into_inner().len();
+
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create_with_version(Version::V3, cursor)?;
+    comp.create_stream("/foo")?.write_all(&[1; 5000])?;
+    comp.create_stream("/bar")?.write_all(&[2; 5000])?;
+    comp.remove_stream("/foo")?;
+    comp.shrink_to_fit()?;
+    let data = comp.into_inner().into_inner();
+    assert_eq!(data.len(), old_len);
+
+    let mut comp = CompoundFile::open_strict(Cursor::new(data))?;
+    comp.remove_stream("/bar")?;
+    comp.shrink_to_fit()?;
+    assert_eq!(comp.into_inner().into_inner().len(), 3 * 512);
+    Ok(())
+}
+
+//===========================================================================//
+// Tests for compacting:
+
+#[allow(clippy::manual_is_multiple_of)]
+fn make_fragmented_compound_file(
+    version: Version,
+) -> io::Result<CompoundFile<Cursor<Vec<u8>>>> {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create_with_version(version, cursor)?;
+    comp.create_storage("/stuff")?;
+    // Interleave writes to several large and small streams, so that their
+    // chains end up tangled together.
+    let mut streams = Vec::new();
+    for index in 0..6 {
+        let path = format!("/stuff/stream{}", index);
+        streams.push(comp.create_stream(&path)?);
+    }
+    for round in 0..20 {
+        for (index, stream) in streams.iter_mut().enumerate() {
+            let len = if index % 2 == 0 { 700 } else { 10 };
+            stream.write_all(&vec![(round * 6 + index) as u8; len])?;
+            stream.flush()?;
+        }
+    }
+    drop(streams);
+    comp.create_stream("/empty")?;
+    comp.remove_stream("/stuff/stream2")?;
+    comp.remove_stream("/stuff/stream3")?;
+    comp.flush()?;
+    Ok(comp)
+}
+
+#[allow(clippy::manual_is_multiple_of)]
+fn expected_fragmented_stream_data(index: usize) -> Vec<u8> {
+    let len = if index % 2 == 0 { 700 } else { 10 };
+    let mut data = Vec::new();
+    for round in 0..20 {
+        data.extend(vec![(round * 6 + index) as u8; len]);
+    }
+    data
+}
+
+fn check_fragmented_compound_file<F: Read + Seek>(
+    comp: &mut CompoundFile<F>,
+) -> io::Result<()> {
+    assert_eq!(
+        read_storage_to_vec(comp, "/stuff"),
+        vec!["stream0", "stream1", "stream4", "stream5"]
+    );
+    for index in [0, 1, 4, 5] {
+        let path = format!("/stuff/stream{}", index);
+        let mut data = Vec::new();
+        comp.open_stream(&path)?.read_to_end(&mut data)?;
+        assert_eq!(data, expected_fragmented_stream_data(index));
+    }
+    assert_eq!(comp.entry("/empty")?.len(), 0);
+    Ok(())
+}
+
+#[test]
+fn compact_in_place() -> io::Result<()> {
+    for version in [Version::V3, Version::V4] {
+        let comp = make_fragmented_compound_file(version)?;
+        let old_len = comp.into_inner().into_inner().len();
+        let mut comp = make_fragmented_compound_file(version)?;
+        comp.compact()?;
+        check_fragmented_compound_file(&mut comp)?;
+        let data = comp.into_inner().into_inner();
+        assert!(data.len() < old_len);
+        let mut comp = CompoundFile::open_strict(Cursor::new(data))?;
+        check_fragmented_compound_file(&mut comp)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn compact_into_new_writer() -> io::Result<()> {
+    for version in [Version::V3, Version::V4] {
+        let mut comp = make_fragmented_compound_file(version)?;
+        let mut output = Cursor::new(Vec::new());
+        comp.compact_into(&mut output)?;
+        check_fragmented_compound_file(&mut comp)?;
+        let old_len = comp.into_inner().into_inner().len();
+        let data = output.into_inner();
+        assert!(data.len() < old_len);
+        let mut comp = CompoundFile::open_strict(Cursor::new(data))?;
+        assert_eq!(comp.version(), version);
+        check_fragmented_compound_file(&mut comp)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn compact_matches_compact_into() -> io::Result<()> {
+    let mut comp = make_fragmented_compound_file(Version::V3)?;
+    let mut output = Cursor::new(Vec::new());
+    comp.compact_into(&mut output)?;
+    comp.compact()?;
+    assert_eq!(comp.into_inner().into_inner(), output.into_inner());
+    Ok(())
+}
+
+#[test]
+fn compact_then_modify() -> io::Result<()> {
+    let mut comp = make_fragmented_compound_file(Version::V3)?;
+    let mut stream = comp.open_stream("/stuff/stream0")?;
+    comp.compact()?;
+    stream.seek(SeekFrom::End(0))?;
+    stream.write_all(&[1, 2, 3])?;
+    drop(stream);
+    comp.create_stream("/stuff/stream2")?.write_all(&[7; 5000])?;
+    comp.remove_stream("/stuff/stream1")?;
+    comp.flush()?;
+
+    let comp = CompoundFile::open_strict(comp.into_inner())?;
+    let mut expected = expected_fragmented_stream_data(0);
+    expected.extend_from_slice(&[1, 2, 3]);
+    let mut data = Vec::new();
+    comp.open_stream("/stuff/stream0")?.read_to_end(&mut data)?;
+    assert_eq!(data, expected);
+    data.clear();
+    comp.open_stream("/stuff/stream2")?.read_to_end(&mut data)?;
+    assert_eq!(data, vec![7; 5000]);
+    assert!(!comp.exists("/stuff/stream1"));
+    Ok(())
+}
+
+#[test]
+fn compact_empty_compound_file() -> io::Result<()> {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create(cursor)?;
+    comp.create_stream("/foo")?.write_all(&[1; 10000])?;
+    comp.remove_stream("/foo")?;
+    comp.compact()?;
+    let sector_len = comp.version().sector_len();
+    let data = comp.into_inner().into_inner();
+    // Header, FAT sector, and directory sector:
+    assert_eq!(data.len(), 3 * sector_len);
+    let comp = CompoundFile::open_strict(Cursor::new(data))?;
+    assert!(read_root_storage_to_vec(&comp).is_empty());
+    Ok(())
+}
+
+#[test]
+fn compact_file_with_difat() -> io::Result<()> {
+    // Enough data to need more than 109 FAT sectors, so that the compacted
+    // file needs a DIFAT sector.
+    let len = 8 * 1024 * 1024;
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create_with_version(Version::V3, cursor)?;
+    comp.create_stream("/junk")?.write_all(&vec![1; 4096])?;
+    let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+    comp.create_stream("/big")?.write_all(&data)?;
+    comp.remove_stream("/junk")?;
+    comp.compact()?;
+    let comp = CompoundFile::open_strict(comp.into_inner())?;
+    let mut actual = Vec::new();
+    comp.open_stream("/big")?.read_to_end(&mut actual)?;
+    assert!(actual == data);
+    Ok(())
+}
+
+//===========================================================================//
+// Tests for write-back mode:
+
+#[allow(clippy::manual_is_multiple_of)]
+fn make_compound_file_with_mode(
+    version: Version,
+    write_mode: WriteMode,
+) -> io::Result<Vec<u8>> {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create_with_version(version, cursor)?;
+    comp.set_write_mode(write_mode)?;
+    assert_eq!(comp.write_mode(), write_mode);
+    // Only streams are created here, since storages are timestamped.
+    for index in 0..40usize {
+        let path = format!("/stream{}", index);
+        let len = if index % 3 == 0 { 5000 } else { 100 };
+        comp.create_stream(&path)?.write_all(&vec![index as u8; len])?;
+    }
+    for index in (0..40).step_by(4) {
+        comp.remove_stream(format!("/stream{}", index))?;
+    }
+    comp.rename("/stream1", "/bar")?;
+    comp.flush()?;
+    Ok(comp.into_inner().into_inner())
+}
+
+#[test]
+fn write_back_matches_write_through() -> io::Result<()> {
+    for version in [Version::V3, Version::V4] {
+        let through =
+            make_compound_file_with_mode(version, WriteMode::WriteThrough)?;
+        let back =
+            make_compound_file_with_mode(version, WriteMode::WriteBack)?;
+        assert!(back == through);
+        let comp = CompoundFile::open_strict(Cursor::new(back))?;
+        assert!(comp.exists("/bar"));
+        assert!(!comp.exists("/stream1"));
+        assert!(!comp.exists("/stream4"));
+        assert_eq!(comp.entry("/stream3")?.len(), 5000);
+    }
+    Ok(())
+}
+
+#[test]
+fn write_back_changes_written_on_drop() -> io::Result<()> {
+    let mut data = Vec::new();
+    {
+        let cursor = Cursor::new(&mut data);
+        let mut comp = CompoundFile::create(cursor)?;
+        comp.set_write_mode(WriteMode::WriteBack)?;
+        comp.create_storage("/foo")?;
+        comp.create_stream("/foo/bar")?.write_all(&[7; 10000])?;
+        comp.create_stream("/baz")?.write_all(b"small")?;
+    }
+    let comp = CompoundFile::open_strict(Cursor::new(data))?;
+    let mut actual = Vec::new();
+    comp.open_stream("/foo/bar")?.read_to_end(&mut actual)?;
+    assert_eq!(actual, vec![7; 10000]);
+    actual.clear();
+    comp.open_stream("/baz")?.read_to_end(&mut actual)?;
+    assert_eq!(actual, b"small");
+    Ok(())
+}
+
+#[test]
+fn switch_from_write_back_to_write_through() -> io::Result<()> {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create(cursor)?;
+    comp.set_write_mode(WriteMode::WriteBack)?;
+    comp.create_stream("/foo")?.write_all(&[1; 3000])?;
+    comp.set_write_mode(WriteMode::WriteThrough)?;
+    assert_eq!(comp.write_mode(), WriteMode::WriteThrough);
+    comp.create_stream("/bar")?.write_all(&[2; 30])?;
+    let comp = CompoundFile::open_strict(comp.into_inner())?;
+    assert_eq!(comp.entry("/foo")?.len(), 3000);
+    let mut actual = Vec::new();
+    comp.open_stream("/bar")?.read_to_end(&mut actual)?;
+    assert_eq!(actual, vec![2; 30]);
+    Ok(())
+}
+
+#[test]
+fn compact_in_write_back_mode() -> io::Result<()> {
+    let mut comp = make_fragmented_compound_file(Version::V3)?;
+    comp.set_write_mode(WriteMode::WriteBack)?;
+    comp.create_stream("/stuff/stream2")?.write_all(&[1; 10])?;
+    comp.remove_stream("/stuff/stream2")?;
+    comp.compact()?;
+    comp.create_stream("/more")?.write_all(&[9; 2000])?;
+    comp.flush()?;
+    let mut comp = CompoundFile::open_strict(comp.into_inner())?;
+    check_fragmented_compound_file(&mut comp)?;
+    assert_eq!(comp.entry("/more")?.len(), 2000);
+    Ok(())
+}
+
+//===========================================================================//
+// Tests for transactions:
+
+fn make_compound_file_for_transaction() -> io::Result<Vec<u8>> {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create_with_version(Version::V3, cursor)?;
+    comp.create_storage("/foo")?;
+    comp.create_stream("/foo/large")?.write_all(&[1; 10000])?;
+    comp.create_stream("/foo/small")?.write_all(&[2; 100])?;
+    comp.create_stream("/doomed")?.write_all(&[3; 5000])?;
+    comp.flush()?;
+    Ok(comp.into_inner().into_inner())
+}
+
+fn check_compound_file_before_transaction<F: Read + Seek>(
+    comp: &mut CompoundFile<F>,
+) -> io::Result<()> {
+    assert_eq!(read_root_storage_to_vec(comp), vec!["foo", "doomed"]);
+    assert_eq!(read_storage_to_vec(comp, "/foo"), vec!["large", "small"]);
+    let mut data = Vec::new();
+    comp.open_stream("/foo/large")?.read_to_end(&mut data)?;
+    assert_eq!(data, vec![1; 10000]);
+    data.clear();
+    comp.open_stream("/foo/small")?.read_to_end(&mut data)?;
+    assert_eq!(data, vec![2; 100]);
+    Ok(())
+}
+
+fn make_changes_in_transaction<F: Read + Write + Seek>(
+    comp: &mut CompoundFile<F>,
+) -> io::Result<()> {
+    let mut stream = comp.open_stream("/foo/large")?;
+    stream.seek(SeekFrom::Start(100))?;
+    stream.write_all(&[4; 600])?;
+    drop(stream);
+    let mut stream = comp.open_stream("/foo/small")?;
+    stream.write_all(&[5; 10])?;
+    drop(stream);
+    comp.remove_stream("/doomed")?;
+    comp.create_storage("/bar")?;
+    comp.create_stream("/bar/new")?.write_all(&[6; 3000])?;
+    Ok(())
+}
+
+fn check_compound_file_after_transaction<F: Read + Seek>(
+    comp: &mut CompoundFile<F>,
+) -> io::Result<()> {
+    assert!(!comp.exists("/doomed"));
+    assert!(comp.is_storage("/bar"));
+    let mut expected = vec![1; 10000];
+    expected[100..700].copy_from_slice(&[4; 600]);
+    let mut data = Vec::new();
+    comp.open_stream("/foo/large")?.read_to_end(&mut data)?;
+    assert!(data == expected);
+    let mut expected = vec![2; 100];
+    expected[..10].copy_from_slice(&[5; 10]);
+    data.clear();
+    comp.open_stream("/foo/small")?.read_to_end(&mut data)?;
+    assert_eq!(data, expected);
+    data.clear();
+    comp.open_stream("/bar/new")?.read_to_end(&mut data)?;
+    assert_eq!(data, vec![6; 3000]);
+    Ok(())
+}
+
+#[test]
+fn commit_transaction() -> io::Result<()> {
+    let data = make_compound_file_for_transaction()?;
+    let mut comp = CompoundFile::open_strict(Cursor::new(data))?;
+    let mut transaction = comp.begin_transaction()?;
+    make_changes_in_transaction(&mut transaction)?;
+    check_compound_file_after_transaction(&mut transaction)?;
+    transaction.commit()?;
+    check_compound_file_after_transaction(&mut comp)?;
+    let mut comp = CompoundFile::open_strict(comp.into_inner())?;
+    check_compound_file_after_transaction(&mut comp)?;
+    Ok(())
+}
+
+#[test]
+fn uncommitted_transaction_leaves_file_untouched() -> io::Result<()> {
+    let old_data = make_compound_file_for_transaction()?;
+    let mut comp = CompoundFile::open_strict(Cursor::new(old_data.clone()))?;
+    let mut transaction = comp.begin_transaction()?;
+    make_changes_in_transaction(&mut transaction)?;
+    transaction.flush()?;
+    // Simulate a crash partway through the transaction.
+    std::mem::forget(transaction);
+    let new_data = comp.into_inner().into_inner();
+    assert!(new_data.len() > old_data.len());
+    assert!(new_data[..old_data.len()] == old_data[..]);
+    let mut comp = CompoundFile::open_strict(Cursor::new(new_data))?;
+    check_compound_file_before_transaction(&mut comp)?;
+    Ok(())
+}
+
+#[test]
+fn revert_transaction() -> io::Result<()> {
+    let data = make_compound_file_for_transaction()?;
+    let mut comp = CompoundFile::open_strict(Cursor::new(data))?;
+    let mut transaction = comp.begin_transaction()?;
+    make_changes_in_transaction(&mut transaction)?;
+    transaction.revert()?;
+    check_compound_file_before_transaction(&mut comp)?;
+    // The compound file should still be usable after reverting.
+    comp.create_stream("/after")?.write_all(&[7; 1000])?;
+    comp.flush()?;
+    let mut comp = CompoundFile::open_strict(comp.into_inner())?;
+    assert_eq!(comp.entry("/after")?.len(), 1000);
+    comp.remove_stream("/after")?;
+    check_compound_file_before_transaction(&mut comp)?;
+    Ok(())
+}
+
+#[test]
+fn dropping_transaction_reverts_it() -> io::Result<()> {
+    let data = make_compound_file_for_transaction()?;
+    let mut comp = CompoundFile::open_strict(Cursor::new(data))?;
+    {
+        let mut transaction = comp.begin_transaction()?;
+        make_changes_in_transaction(&mut transaction)?;
+    }
+    check_compound_file_before_transaction(&mut comp)?;
+    let mut comp = CompoundFile::open_strict(comp.into_inner())?;
+    check_compound_file_before_transaction(&mut comp)?;
+    Ok(())
+}
+
+#[test]
+fn transaction_in_write_back_mode() -> io::Result<()> {
+    let data = make_compound_file_for_transaction()?;
+    let mut comp = CompoundFile::open_strict(Cursor::new(data))?;
+    comp.set_write_mode(WriteMode::WriteBack)?;
+    comp.create_stream("/pending")?.write_all(&[8; 50])?;
+    let mut transaction = comp.begin_transaction()?;
+    make_changes_in_transaction(&mut transaction)?;
+    transaction.commit()?;
+    drop(comp.open_stream("/pending")?);
+    let mut comp = CompoundFile::open_strict(comp.into_inner())?;
+    check_compound_file_after_transaction(&mut comp)?;
+    assert_eq!(comp.entry("/pending")?.len(), 50);
+    Ok(())
+}
+
+#[test]
+fn transaction_that_grows_difat() -> io::Result<()> {
+    // Start with enough data that the file already needs a DIFAT sector, then
+    // add enough in a transaction to need another FAT sector.
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create_with_version(Version::V3, cursor)?;
+    let big: Vec<u8> = (0..7_500_000).map(|i| (i % 251) as u8).collect();
+    comp.create_stream("/big")?.write_all(&big)?;
+    let old_data = comp.into_inner().into_inner();
+    let mut comp = CompoundFile::open_strict(Cursor::new(old_data.clone()))?;
+    let mut transaction = comp.begin_transaction()?;
+    transaction.create_stream("/more")?.write_all(&[9; 200_000])?;
+    let mut stream = transaction.open_stream("/big")?;
+    stream.write_all(&[10; 1000])?;
+    drop(stream);
+    transaction.commit()?;
+    let new_data = comp.into_inner().into_inner();
+    assert_eq!(read_header_u32(&old_data, 72), 1);
+    assert_eq!(read_header_u32(&new_data, 72), 1);
+    assert_ne!(read_header_u32(&new_data, 68), read_header_u32(&old_data, 68));
+    let comp = CompoundFile::open_strict(Cursor::new(new_data))?;
+    let mut data = Vec::new();
+    comp.open_stream("/big")?.read_to_end(&mut data)?;
+    assert!(data[..1000] == [10; 1000][..]);
+    assert!(data[1000..] == big[1000..]);
+    assert_eq!(comp.entry("/more")?.len(), 200_000);
+    Ok(())
+}
+
+#[test]
+fn nested_transaction() -> io::Result<()> {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create(cursor)?;
+    let mut transaction = comp.begin_transaction()?;
+    let result = transaction.begin_transaction();
+    assert_eq!(
+        result.err().unwrap().to_string(),
+        "A transaction is already in progress"
+    );
+    Ok(())
+}
+
+#[test]
+fn stream_open_when_transaction_reverted() -> io::Result<()> {
+    let data = make_compound_file_for_transaction()?;
+    let mut comp = CompoundFile::open_strict(Cursor::new(data))?;
+    let mut stream = comp.open_stream("/foo/small")?;
+    let transaction = comp.begin_transaction()?;
+    stream.write_all(&[9; 10])?;
+    transaction.revert()?;
+    // The stream's buffered changes refer to state that no longer exists,
+    // so the stream can't be used any more, and its changes are discarded.
+    assert_eq!(stream.flush().unwrap_err().kind(), io::ErrorKind::Other);
+    assert_eq!(stream.seek(SeekFrom::Start(50))?, 50);
+    assert_eq!(
+        stream.read(&mut [0; 10]).unwrap_err().kind(),
+        io::ErrorKind::Other
+    );
+    drop(stream);
+    check_compound_file_before_transaction(&mut comp)?;
+    Ok(())
+}
+
+#[test]
+fn stream_open_when_transaction_committed() -> io::Result<()> {
+    let data = make_compound_file_for_transaction()?;
+    let mut comp = CompoundFile::open_strict(Cursor::new(data))?;
+    let mut stream = comp.open_stream("/foo/small")?;
+    let transaction = comp.begin_transaction()?;
+    stream.write_all(&[9; 10])?;
+    transaction.commit()?;
+    // Changes still buffered in the stream weren't part of the transaction,
+    // and aren't written afterwards either.
+    assert_eq!(stream.flush().unwrap_err().kind(), io::ErrorKind::Other);
+    drop(stream);
+    let mut comp = CompoundFile::open_strict(comp.into_inner())?;
+    check_compound_file_before_transaction(&mut comp)?;
+    // Streams opened after the transaction work as usual.
+    comp.open_stream("/foo/small")?.write_all(&[9; 10])?;
+    let mut data = Vec::new();
+    comp.open_stream("/foo/small")?.read_to_end(&mut data)?;
+    assert_eq!(data[..10], [9; 10]);
+    Ok(())
+}
+
+#[test]
+fn compact_during_transaction() -> io::Result<()> {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create(cursor)?;
+    let mut transaction = comp.begin_transaction()?;
+    assert_eq!(
+        transaction.compact().err().unwrap().kind(),
+        io::ErrorKind::InvalidInput
+    );
+    assert_eq!(
+        transaction.shrink_to_fit().err().unwrap().kind(),
+        io::ErrorKind::InvalidInput
+    );
+    Ok(())
+}
+
 //===========================================================================//
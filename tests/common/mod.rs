@@ -0,0 +1,88 @@
+//! Fixtures shared by the integration tests.
+
+#![allow(dead_code)]
+
+use cfb::io::{Cursor, Read, Seek, SeekFrom, Write};
+use cfb::{CompoundFile, Version};
+
+//===========================================================================//
+
+/// Returns `len` bytes of test data that depend on `seed`.  The pattern
+/// shifts every 256 bytes, so it doesn't repeat within a sector.
+pub fn make_data(seed: usize, len: usize) -> Vec<u8> {
+    (0..len).map(|offset| (offset * seed + offset / 256) as u8).collect()
+}
+
+/// Adds the objects that most tests read back to a compound file: a stream
+/// `/storage/small` in the mini stream, holding `make_data(1, 1000)`, and a
+/// regular stream `/large`, holding `make_data(2, 100000)`.
+pub fn add_small_and_large_streams<F: Read + Write + Seek>(
+    comp: &mut CompoundFile<F>,
+) {
+    comp.create_storage("/storage").unwrap();
+    let mut stream = comp.create_stream("/storage/small").unwrap();
+    stream.write_all(&make_data(1, 1000)).unwrap();
+    drop(stream);
+    let mut stream = comp.create_stream("/large").unwrap();
+    stream.write_all(&make_data(2, 100000)).unwrap();
+}
+
+/// Returns the bytes of a compound file holding the objects described in
+/// `add_small_and_large_streams`.
+pub fn make_compound_file(version: Version) -> Vec<u8> {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create_with_version(version, cursor).unwrap();
+    add_small_and_large_streams(&mut comp);
+    comp.into_inner().into_inner()
+}
+
+/// Creates a compound file holding the given objects, created in order.  A
+/// path ending in `/` becomes a storage (and its length is ignored); any
+/// other path becomes a stream of the given length, with the `n`th stream
+/// (counting from 1) holding `make_data(n, len)`.
+pub fn create_compound_file<S: AsRef<str>>(
+    version: Version,
+    objects: &[(S, usize)],
+) -> CompoundFile<Cursor<Vec<u8>>> {
+    create_compound_file_in_pieces(version, objects, 1)
+}
+
+/// Like `create_compound_file`, but writes the streams a quarter at a time,
+/// taking turns, so that their chains are interleaved with one another.
+pub fn create_fragmented_compound_file<S: AsRef<str>>(
+    version: Version,
+    objects: &[(S, usize)],
+) -> CompoundFile<Cursor<Vec<u8>>> {
+    create_compound_file_in_pieces(version, objects, 4)
+}
+
+fn create_compound_file_in_pieces<S: AsRef<str>>(
+    version: Version,
+    objects: &[(S, usize)],
+    num_pieces: usize,
+) -> CompoundFile<Cursor<Vec<u8>>> {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create_with_version(version, cursor).unwrap();
+    let mut streams = Vec::new();
+    for (path, len) in objects {
+        let path = path.as_ref();
+        if path.ends_with('/') {
+            comp.create_storage(path).unwrap();
+        } else {
+            comp.create_stream(path).unwrap();
+            streams.push((path, make_data(streams.len() + 1, *len)));
+        }
+    }
+    for piece in 0..num_pieces {
+        for &(path, ref data) in streams.iter() {
+            let start = data.len() * piece / num_pieces;
+            let end = data.len() * (piece + 1) / num_pieces;
+            let mut stream = comp.open_stream(path).unwrap();
+            stream.seek(SeekFrom::End(0)).unwrap();
+            stream.write_all(&data[start..end]).unwrap();
+        }
+    }
+    comp
+}
+
+//===========================================================================//
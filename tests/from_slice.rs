@@ -0,0 +1,124 @@
+use cfb::{CompoundFile, Version};
+use std::borrow::Cow;
+use std::io::Cursor;
+
+mod common;
+use common::{
+    create_compound_file, create_fragmented_compound_file, make_data,
+};
+
+//===========================================================================//
+
+/// Creates a compound file holding a small stream (in the mini stream) and a
+/// large contiguous stream.
+fn make_compound_file(version: Version) -> Vec<u8> {
+    let objects =
+        [("/storage/", 0), ("/storage/small", 1000), ("/contiguous", 50000)];
+    create_compound_file(version, &objects).into_inner().into_inner()
+}
+
+/// Creates a compound file holding two large streams, `/fragmented` and
+/// `/other`, whose chains are interleaved with each other.
+fn make_fragmented_compound_file(version: Version) -> Vec<u8> {
+    let objects = [("/fragmented", 50000), ("/other", 50000)];
+    let comp = create_fragmented_compound_file(version, &objects);
+    comp.into_inner().into_inner()
+}
+
+//===========================================================================//
+// Tests for reading compound files from in-memory slices:
+
+fn read_stream_bytes(version: Version) {
+    let data = make_compound_file(version);
+    let mut comp = CompoundFile::from_slice(&data).unwrap();
+    assert_eq!(comp.version(), version);
+    let small = comp.stream_bytes("/storage/small").unwrap();
+    assert_eq!(&*small, &make_data(1, 1000)[..]);
+    let contiguous = comp.stream_bytes("/contiguous").unwrap();
+    assert!(matches!(contiguous, Cow::Borrowed(_)));
+    assert_eq!(&*contiguous, &make_data(2, 50000)[..]);
+    let data = make_fragmented_compound_file(version);
+    let mut comp = CompoundFile::from_slice(&data).unwrap();
+    let fragmented = comp.stream_bytes("/fragmented").unwrap();
+    assert!(matches!(fragmented, Cow::Owned(_)));
+    assert_eq!(&*fragmented, &make_data(1, 50000)[..]);
+}
+
+#[test]
+fn read_stream_bytes_v3() {
+    read_stream_bytes(Version::V3);
+}
+
+#[test]
+fn read_stream_bytes_v4() {
+    read_stream_bytes(Version::V4);
+}
+
+#[test]
+fn read_stream_slices() {
+    let data = make_fragmented_compound_file(Version::V3);
+    let mut comp = CompoundFile::from_slice(&data).unwrap();
+    let slices = comp.stream_slices("/fragmented").unwrap();
+    assert_eq!(slices.len(), 98); // 50000 bytes in 512-byte sectors
+    let mut contents = Vec::new();
+    for slice in slices {
+        // Every slice is borrowed from the compound file's data.
+        let start = slice.as_ptr() as usize - data.as_ptr() as usize;
+        assert!(start + slice.len() <= data.len());
+        contents.extend_from_slice(slice);
+    }
+    assert_eq!(contents, make_data(1, 50000));
+    let data = make_compound_file(Version::V3);
+    let mut comp = CompoundFile::from_slice(&data).unwrap();
+    let slices: Vec<&[u8]> =
+        comp.stream_slices("/storage/small").unwrap().collect();
+    assert_eq!(slices.len(), 16); // 1000 bytes in 64-byte mini sectors
+    assert_eq!(slices.concat(), make_data(1, 1000));
+}
+
+#[test]
+fn borrowed_data_outlives_compound_file() {
+    let data = make_compound_file(Version::V4);
+    let contiguous = {
+        let mut comp = CompoundFile::from_slice(&data).unwrap();
+        comp.stream_bytes("/contiguous").unwrap()
+    };
+    assert_eq!(&*contiguous, &make_data(2, 50000)[..]);
+}
+
+#[test]
+fn empty_stream() {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create(cursor).unwrap();
+    comp.create_stream("/empty").unwrap();
+    let data = comp.into_inner().into_inner();
+    let mut comp = CompoundFile::from_slice(&data).unwrap();
+    assert!(comp.stream_bytes("/empty").unwrap().is_empty());
+    assert_eq!(comp.stream_slices("/empty").unwrap().count(), 0);
+}
+
+#[test]
+fn stream_bytes_errors() {
+    let data = make_compound_file(Version::V3);
+    let mut comp = CompoundFile::from_slice(&data).unwrap();
+    assert!(comp.stream_bytes("/nonexistent").is_err());
+    assert!(comp.stream_bytes("/storage").is_err());
+}
+
+#[test]
+fn truncated_file() {
+    let mut data = make_fragmented_compound_file(Version::V3);
+    // Truncate the file partway through the last slice of the "/other"
+    // stream.
+    let end = {
+        let mut comp = CompoundFile::from_slice(&data).unwrap();
+        let slice = comp.stream_slices("/other").unwrap().last().unwrap();
+        slice.as_ptr() as usize - data.as_ptr() as usize + slice.len()
+    };
+    data.truncate(end - 1);
+    let mut comp = CompoundFile::from_slice(&data).unwrap();
+    assert!(comp.stream_bytes("/other").is_err());
+    assert!(comp.stream_slices("/other").is_err());
+}
+
+//===========================================================================//
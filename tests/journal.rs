@@ -0,0 +1,389 @@
+use cfb::{CompoundFile, JournalFile, Version};
+use std::collections::BTreeMap;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+mod common;
+use common::make_data;
+
+//===========================================================================//
+
+/// An in-memory reader/writer that shares a budget of `write` calls with
+/// other `CrashingFile`s.  Once the budget runs out, every write fails, as
+/// though the process had died at that point (with the write that used up
+/// the budget torn after its first 64 bytes, which splits a header partway
+/// through its fields); the data can still be retrieved afterwards to see
+/// what a later process would find on disk.
+struct CrashingFile {
+    data: Arc<Mutex<Vec<u8>>>,
+    position: u64,
+    writes_left: Arc<AtomicUsize>,
+}
+
+impl CrashingFile {
+    fn new(
+        data: &Arc<Mutex<Vec<u8>>>,
+        writes_left: &Arc<AtomicUsize>,
+    ) -> Self {
+        CrashingFile {
+            data: Arc::clone(data),
+            position: 0,
+            writes_left: Arc::clone(writes_left),
+        }
+    }
+}
+
+fn take_data(data: &Arc<Mutex<Vec<u8>>>) -> Vec<u8> {
+    std::mem::take(&mut *data.lock().unwrap())
+}
+
+impl Read for CrashingFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self.data.lock().unwrap();
+        let mut cursor = Cursor::new(&data[..]);
+        cursor.set_position(self.position);
+        let bytes_read = cursor.read(buf)?;
+        self.position += bytes_read as u64;
+        Ok(bytes_read)
+    }
+}
+
+#[allow(clippy::io_other_error)]
+impl Write for CrashingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let writes_left = self.writes_left.load(Ordering::SeqCst);
+        if writes_left == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "simulated crash",
+            ));
+        }
+        self.writes_left.store(writes_left - 1, Ordering::SeqCst);
+        let mut data = self.data.lock().unwrap();
+        let mut cursor = Cursor::new(&mut *data);
+        cursor.set_position(self.position);
+        if writes_left == 1 {
+            cursor.write_all(&buf[..buf.len().min(64)])?;
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "simulated crash",
+            ));
+        }
+        let bytes_written = cursor.write(buf)?;
+        self.position += bytes_written as u64;
+        Ok(bytes_written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for CrashingFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let len = self.data.lock().unwrap().len() as i64;
+        let new_position = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(delta) => len + delta,
+            SeekFrom::Current(delta) => self.position as i64 + delta,
+        };
+        if new_position < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek to a negative position",
+            ));
+        }
+        self.position = new_position as u64;
+        Ok(self.position)
+    }
+}
+
+//===========================================================================//
+
+/// The paths of all objects in a compound file, with the contents of each
+/// stream (storages have no contents).
+type Contents = BTreeMap<String, Option<Vec<u8>>>;
+
+fn read_contents<F: Read + Seek>(comp: &mut CompoundFile<F>) -> Contents {
+    let entries: Vec<(String, bool)> = comp
+        .walk()
+        .map(|entry| (entry.path().display().to_string(), entry.is_stream()))
+        .collect();
+    let mut contents = Contents::new();
+    for (path, is_stream) in entries {
+        let data = if is_stream {
+            let mut data = Vec::new();
+            comp.open_stream(&path).unwrap().read_to_end(&mut data).unwrap();
+            Some(data)
+        } else {
+            None
+        };
+        contents.insert(path, data);
+    }
+    contents
+}
+
+fn make_base_file(version: Version) -> Vec<u8> {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create_with_version(version, cursor).unwrap();
+    comp.create_stream("/first")
+        .unwrap()
+        .write_all(&make_data(3, 700))
+        .unwrap();
+    comp.create_stream("/second")
+        .unwrap()
+        .write_all(&make_data(5, 5000))
+        .unwrap();
+    comp.into_inner().into_inner()
+}
+
+/// Makes one of a series of changes to a compound file, each of which is
+/// committed separately.
+fn make_change<F: Read + Write + Seek>(
+    comp: &mut CompoundFile<F>,
+    step: usize,
+) -> io::Result<()> {
+    match step {
+        0 => {
+            comp.create_storage("/dir")?;
+            comp.create_stream("/dir/big")?.write_all(&make_data(7, 6000))?;
+        }
+        1 => {
+            let mut stream = comp.open_stream("/second")?;
+            stream.seek(SeekFrom::Start(1000))?;
+            stream.write_all(&make_data(11, 2000))?;
+            comp.create_stream("/dir/small")?
+                .write_all(&make_data(13, 300))?;
+        }
+        2 => {
+            comp.remove_stream("/first")?;
+            comp.open_stream("/dir/small")?.set_len(9000)?;
+        }
+        3 => {
+            let mut transaction = comp.begin_transaction()?;
+            transaction.remove_stream("/dir/big")?;
+            transaction
+                .create_stream("/third")?
+                .write_all(&make_data(17, 4500))?;
+            transaction.commit()?;
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+const NUM_CHANGES: usize = 4;
+
+/// Opens the given compound file in journaling mode, and makes each change
+/// in turn, flushing after each one.  Returns the number of changes that
+/// were successfully flushed.
+fn make_changes<F: Read + Write + Seek, J: JournalFile>(
+    file: F,
+    journal: J,
+) -> usize {
+    let mut comp = match CompoundFile::open_with_journal(file, journal) {
+        Ok(comp) => comp,
+        Err(_) => return 0,
+    };
+    for step in 0..NUM_CHANGES {
+        if make_change(&mut comp, step).and_then(|_| comp.flush()).is_err() {
+            return step;
+        }
+    }
+    NUM_CHANGES
+}
+
+fn crash_after_every_write(version: Version) {
+    let base = make_base_file(version);
+    // Work out what the file should contain after each change.
+    let mut expected = Vec::<Contents>::new();
+    let mut comp = CompoundFile::open(Cursor::new(base.clone())).unwrap();
+    expected.push(read_contents(&mut comp));
+    for step in 0..NUM_CHANGES {
+        make_change(&mut comp, step).unwrap();
+        expected.push(read_contents(&mut comp));
+    }
+    drop(comp);
+
+    let mut num_writes = 0;
+    loop {
+        let writes_left = Arc::new(AtomicUsize::new(num_writes));
+        let data = Arc::new(Mutex::new(base.clone()));
+        let journal_data = Arc::new(Mutex::new(Vec::new()));
+        let num_flushed = make_changes(
+            CrashingFile::new(&data, &writes_left),
+            CrashingFile::new(&journal_data, &writes_left),
+        );
+        let crashed = writes_left.load(Ordering::SeqCst) == 0;
+
+        // Recover, then check that the file holds the state as of either the
+        // last completed flush or the one that was interrupted.
+        let data = take_data(&data);
+        let journal_data = take_data(&journal_data);
+        let mut comp = CompoundFile::open_with_journal(
+            Cursor::new(data),
+            Cursor::new(journal_data),
+        )
+        .unwrap_or_else(|error| {
+            panic!("crash after {} writes: {}", num_writes, error)
+        });
+        let contents = read_contents(&mut comp);
+        let matches_flushed = contents == expected[num_flushed];
+        let matches_next =
+            num_flushed < NUM_CHANGES && contents == expected[num_flushed + 1];
+        assert!(
+            matches_flushed || matches_next,
+            "crash after {} writes ({} changes flushed)",
+            num_writes,
+            num_flushed
+        );
+        let data = comp.into_inner().into_inner();
+        CompoundFile::open_strict(Cursor::new(data)).unwrap_or_else(|error| {
+            panic!("crash after {} writes: {}", num_writes, error)
+        });
+
+        if !crashed {
+            assert_eq!(num_flushed, NUM_CHANGES);
+            break;
+        }
+        num_writes += 1;
+    }
+    assert!(num_writes > 50);
+}
+
+//===========================================================================//
+// Tests for crash safety:
+
+#[test]
+fn crash_after_every_write_v3() {
+    crash_after_every_write(Version::V3);
+}
+
+#[test]
+fn crash_after_every_write_v4() {
+    crash_after_every_write(Version::V4);
+}
+
+//===========================================================================//
+// Tests for journaling mode:
+
+#[test]
+fn changes_committed_on_flush() {
+    let base = make_base_file(Version::V3);
+    let mut comp = CompoundFile::open_with_journal(
+        Cursor::new(base),
+        Cursor::new(Vec::new()),
+    )
+    .unwrap();
+    assert!(comp.is_journaling());
+    comp.create_stream("/foo").unwrap().write_all(b"foobar").unwrap();
+    comp.flush().unwrap();
+    let data = comp.into_inner().into_inner();
+    let comp = CompoundFile::open_strict(Cursor::new(data)).unwrap();
+    let mut contents = Vec::new();
+    comp.open_stream("/foo").unwrap().read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, b"foobar");
+}
+
+#[test]
+fn journal_of_a_different_type() {
+    let base = make_base_file(Version::V3);
+    let writes_left = Arc::new(AtomicUsize::new(usize::MAX));
+    let data = Arc::new(Mutex::new(base));
+    let mut comp = CompoundFile::open_with_journal(
+        CrashingFile::new(&data, &writes_left),
+        Cursor::new(Vec::new()),
+    )
+    .unwrap();
+    comp.create_stream("/foo").unwrap().write_all(b"foobar").unwrap();
+    comp.flush().unwrap();
+    // While the file is open in journaling mode, its header says so.
+    assert_ne!(comp.raw_header().unwrap().transaction_signature(), 0);
+    drop(comp);
+    let mut comp =
+        CompoundFile::open_strict(Cursor::new(take_data(&data))).unwrap();
+    assert_eq!(comp.raw_header().unwrap().transaction_signature(), 0);
+    assert!(comp.is_stream("/foo"));
+}
+
+#[test]
+fn unflushed_changes_discarded_after_crash() {
+    let base = make_base_file(Version::V3);
+    let expected = read_contents(
+        &mut CompoundFile::open(Cursor::new(base.clone())).unwrap(),
+    );
+    let writes_left = Arc::new(AtomicUsize::new(usize::MAX));
+    let data = Arc::new(Mutex::new(base));
+    let journal_data = Arc::new(Mutex::new(Vec::new()));
+    let mut comp = CompoundFile::open_with_journal(
+        CrashingFile::new(&data, &writes_left),
+        CrashingFile::new(&journal_data, &writes_left),
+    )
+    .unwrap();
+    comp.create_stream("/foo").unwrap().write_all(&[1; 9000]).unwrap();
+    comp.remove_stream("/first").unwrap();
+    // Simulate the process dying before the changes are flushed.
+    writes_left.store(0, Ordering::SeqCst);
+    drop(comp);
+    let data = take_data(&data);
+    let journal_data = take_data(&journal_data);
+    // Without its journal, the file can't be opened, since it might have
+    // been left partway through a commit.
+    let error = CompoundFile::open(Cursor::new(data.clone())).err().unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    match cfb::Error::from_io(&error) {
+        Some(cfb::Error::Journaled { .. }) => {}
+        other => panic!("expected a Journaled error, got {:?}", other),
+    }
+    let mut comp = CompoundFile::open_with_journal(
+        Cursor::new(data),
+        Cursor::new(journal_data),
+    )
+    .unwrap();
+    assert_eq!(read_contents(&mut comp), expected);
+    // Once the recovered file is closed, it can be opened normally again.
+    let data = comp.into_inner().into_inner();
+    let mut comp = CompoundFile::open_strict(Cursor::new(data)).unwrap();
+    assert_eq!(read_contents(&mut comp), expected);
+}
+
+#[test]
+fn revert_transaction_while_journaling() {
+    let base = make_base_file(Version::V4);
+    let mut comp = CompoundFile::open_with_journal(
+        Cursor::new(base),
+        Cursor::new(Vec::new()),
+    )
+    .unwrap();
+    comp.create_stream("/foo").unwrap();
+    let transaction = comp.begin_transaction().unwrap();
+    transaction.revert().unwrap();
+    // Beginning the transaction committed "/foo", so it survives the revert.
+    assert!(comp.is_stream("/foo"));
+    comp.create_stream("/bar").unwrap();
+    let data = comp.into_inner().into_inner();
+    let comp = CompoundFile::open_strict(Cursor::new(data)).unwrap();
+    assert!(comp.is_stream("/foo"));
+    assert!(comp.is_stream("/bar"));
+}
+
+#[test]
+fn cannot_compact_while_journaling() {
+    let base = make_base_file(Version::V3);
+    let mut comp = CompoundFile::open_with_journal(
+        Cursor::new(base),
+        Cursor::new(Vec::new()),
+    )
+    .unwrap();
+    assert_eq!(
+        comp.compact().unwrap_err().to_string(),
+        "Cannot compact a compound file while in journaling mode"
+    );
+    assert_eq!(
+        comp.shrink_to_fit().unwrap_err().to_string(),
+        "Cannot shrink a compound file while in journaling mode"
+    );
+}
+
+//===========================================================================//
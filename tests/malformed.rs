@@ -1,4 +1,4 @@
-use byteorder::{LittleEndian, WriteBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use cfb::CompoundFile;
 use std::{
     fs::read_dir,
@@ -34,7 +34,7 @@ fn can_read(path: &Path) {
     let data = std::fs::read(path).unwrap();
 
     let cursor = Cursor::new(data);
-    let mut cfb = match CompoundFile::open(cursor) {
+    let cfb = match CompoundFile::open(cursor) {
         Ok(cfb) => cfb,
         Err(_) => return,
     };
@@ -62,6 +62,7 @@ fn can_read(path: &Path) {
     expected = "Found reference to mini sector 123456789, but MiniFAT has \
                 only 2 entries"
 )]
+#[allow(clippy::identity_op)]
 fn invalid_mini_sector_issue_16() {
     // Create a CFB file with a mini stream.
     let cursor = Cursor::new(Vec::new());
@@ -78,7 +79,7 @@ fn invalid_mini_sector_issue_16() {
     cursor.write_u32::<LittleEndian>(123456789).unwrap();
 
     // Re-open the CFB file and try to read the mini stream.
-    let mut comp = CompoundFile::open(cursor).unwrap();
+    let comp = CompoundFile::open(cursor).unwrap();
     let mut data = Vec::new();
     comp.open_stream("foo").unwrap().read_to_end(&mut data).unwrap();
 }
@@ -198,7 +199,7 @@ fn sector_panic_pr_24() {
 #[test]
 #[should_panic(expected = "next_id (4294967293) is invalid")]
 fn alloc_panic_pr_24() {
-    let mut cfb = cfb::open("tests/panics_fuzzed/alloc_panic").unwrap();
+    let cfb = cfb::open("tests/panics_fuzzed/alloc_panic").unwrap();
     cfb.walk()
         .filter(|e| e.is_stream())
         .map(|e| e.path().to_path_buf())
@@ -216,7 +217,7 @@ fn alloc_panic_pr_24() {
 #[test]
 #[should_panic(expected = "next_id (4294967295) is invalid")]
 fn minialloc_panic_pr_24() {
-    let mut cfb = cfb::open("tests/panics_fuzzed/minialloc_panic").unwrap();
+    let cfb = cfb::open("tests/panics_fuzzed/minialloc_panic").unwrap();
     cfb.walk()
         .filter(|e| e.is_stream())
         .map(|e| e.path().to_path_buf())
@@ -242,3 +243,339 @@ fn check_for_infinite_loops() {
         panic_after(Duration::from_secs(1), move || can_read(&path))
     }
 }
+
+#[test]
+fn structured_error_for_bad_header() {
+    let cursor = Cursor::new(vec![0u8; 1024]);
+    let error = CompoundFile::open(cursor).err().unwrap();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    match cfb::Error::from_io(&error) {
+        Some(cfb::Error::Header { message }) => {
+            assert_eq!(message, "Invalid CFB file (wrong magic number)");
+        }
+        other => panic!("Unexpected error: {:?}", other),
+    }
+}
+
+#[test]
+fn structured_error_for_invalid_mini_sector() {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create(cursor).unwrap();
+    let version = comp.version();
+    comp.create_stream("foo").unwrap().write_all(&[0u8; 80]).unwrap();
+    let mut cursor = comp.into_inner();
+    // Corrupt the starting mini sector ID of the stream, as in
+    // invalid_mini_sector_issue_16 above.
+    let offset = 116 + 128 + (version.sector_len() as u64) * 2;
+    cursor.seek(SeekFrom::Start(offset)).unwrap();
+    cursor.write_u32::<LittleEndian>(123456789).unwrap();
+
+    let comp = CompoundFile::open(cursor).unwrap();
+    let mut data = Vec::new();
+    let error =
+        comp.open_stream("foo").unwrap().read_to_end(&mut data).unwrap_err();
+    assert!(matches!(
+        cfb::Error::from_io(&error),
+        Some(cfb::Error::MiniFat { sector_id: Some(123456789), .. })
+    ));
+}
+
+#[test]
+fn structured_error_for_invalid_name() {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create(cursor).unwrap();
+    let error = comp.create_storage("/foo:bar").unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    match cfb::Error::from_io(&error) {
+        Some(cfb::Error::Name { name, .. }) => assert_eq!(name, "foo:bar"),
+        other => panic!("Unexpected error: {:?}", other),
+    }
+}
+
+#[test]
+fn check_valid_file() {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create(cursor).unwrap();
+    comp.create_stream("small").unwrap().write_all(&[1u8; 80]).unwrap();
+    comp.create_stream("large").unwrap().write_all(&[2u8; 9000]).unwrap();
+    let report = cfb::check(comp.into_inner()).unwrap();
+    assert!(report.is_valid());
+    assert!(report.can_open_strict());
+}
+
+#[test]
+fn check_reports_every_violation() {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp =
+        CompoundFile::create_with_version(cfb::Version::V3, cursor).unwrap();
+    for name in ["a", "b", "c"] {
+        comp.create_stream(name).unwrap();
+    }
+    let mut data = comp.into_inner().into_inner();
+    // The directory is in the second sector of the file, after the header
+    // and the FAT.  Give entries 1 and 2 invalid colors, and give entry 3 a
+    // non-nil CLSID (which is only a warning).
+    let dir_offset = 2 * 512;
+    data[dir_offset + 128 + 67] = 7;
+    data[dir_offset + 256 + 67] = 9;
+    data[dir_offset + 384 + 80] = 1;
+
+    let report = cfb::check(Cursor::new(data)).unwrap();
+    assert!(!report.can_open_permissive());
+    let errors: Vec<String> =
+        report.errors().map(|violation| violation.to_string()).collect();
+    assert_eq!(
+        errors,
+        vec![
+            "error: Malformed directory entry (invalid color: 7)",
+            "error: Malformed directory entry (invalid color: 9)",
+        ]
+    );
+    let stream_ids: Vec<Option<u32>> = report
+        .violations()
+        .iter()
+        .map(|violation| match violation.error() {
+            cfb::Error::Directory { stream_id, .. } => *stream_id,
+            other => panic!("Unexpected error: {:?}", other),
+        })
+        .collect();
+    assert_eq!(stream_ids, vec![Some(1), Some(2), Some(3)]);
+    assert_eq!(report.warnings().count(), 1);
+}
+
+#[test]
+fn check_reports_unreadable_stream() {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create(cursor).unwrap();
+    let version = comp.version();
+    comp.create_stream("foo").unwrap().write_all(&[0u8; 80]).unwrap();
+    let mut cursor = comp.into_inner();
+    // Corrupt the starting mini sector ID of the stream, as in
+    // invalid_mini_sector_issue_16 above.
+    let offset = 116 + 128 + (version.sector_len() as u64) * 2;
+    cursor.seek(SeekFrom::Start(offset)).unwrap();
+    cursor.write_u32::<LittleEndian>(123456789).unwrap();
+
+    let report = cfb::check(cursor).unwrap();
+    // The file itself can still be opened, but the stream can't be read.
+    assert!(report.can_open_strict());
+    assert_eq!(report.violations().len(), 1);
+    let violation = &report.violations()[0];
+    assert_eq!(violation.severity(), cfb::Severity::Error);
+    assert!(matches!(
+        violation.error(),
+        cfb::Error::MiniFat { sector_id: Some(123456789), .. }
+    ));
+}
+
+#[test]
+fn check_reports_path_of_truncated_stream() {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp =
+        CompoundFile::create_with_version(cfb::Version::V3, cursor).unwrap();
+    comp.create_storage("dir").unwrap();
+    comp.create_stream("dir/foo").unwrap().write_all(&[1u8; 9000]).unwrap();
+    let mut cursor = comp.into_inner();
+    // Claim that the stream (directory entry 2, in the second sector) is
+    // longer than its chain.
+    cursor.seek(SeekFrom::Start(2 * 512 + 2 * 128 + 120)).unwrap();
+    cursor.write_u64::<LittleEndian>(12000).unwrap();
+
+    let report = cfb::check(cursor).unwrap();
+    assert_eq!(report.violations().len(), 1);
+    match report.violations()[0].error() {
+        cfb::Error::Directory { stream_id, path, .. } => {
+            assert_eq!(*stream_id, Some(2));
+            assert_eq!(path.as_deref(), Some(Path::new("/dir/foo")));
+        }
+        other => panic!("Unexpected error: {:?}", other),
+    }
+}
+
+#[test]
+fn check_agrees_with_open() {
+    for dir in ["tests/infinite_loops_fuzzed", "tests/panics_fuzzed"] {
+        for entry in read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let data = std::fs::read(&path).unwrap();
+            let report = panic_after(Duration::from_secs(1), {
+                let data = data.clone();
+                move || cfb::check(Cursor::new(data)).unwrap()
+            });
+            let opened = CompoundFile::open(Cursor::new(data.clone())).is_ok();
+            assert_eq!(report.can_open_permissive(), opened, "{:?}", path);
+            let opened = CompoundFile::open_strict(Cursor::new(data)).is_ok();
+            assert_eq!(report.can_open_strict(), opened, "{:?}", path);
+        }
+    }
+}
+
+#[test]
+fn warnings_list_tolerated_deviations() {
+    let cursor = Cursor::new(Vec::new());
+    let comp =
+        CompoundFile::create_with_version(cfb::Version::V3, cursor).unwrap();
+    let mut data = comp.into_inner().into_inner();
+    assert!(CompoundFile::open(Cursor::new(data.clone()))
+        .unwrap()
+        .warnings()
+        .is_empty());
+    // Set the (V3) number of directory sectors to 1, and end the DIFAT chain
+    // with FREE_SECTOR.
+    data[40..44].copy_from_slice(&1u32.to_le_bytes());
+    data[68..72].copy_from_slice(&0xFFFFFFFFu32.to_le_bytes());
+
+    let comp = CompoundFile::open(Cursor::new(data.clone())).unwrap();
+    let warnings: Vec<String> =
+        comp.warnings().iter().map(|warning| warning.to_string()).collect();
+    assert_eq!(
+        warnings,
+        vec![
+            "warning: Invalid number of directory sectors field (must be \
+             zero for CFB version 3, found 1)",
+            "warning: DIFAT chain in header ends with FREE_SECTOR instead of \
+             END_OF_CHAIN",
+        ]
+    );
+    assert!(CompoundFile::open_strict(Cursor::new(data)).is_err());
+}
+
+#[test]
+fn repair_invalid_colors() {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp =
+        CompoundFile::create_with_version(cfb::Version::V3, cursor).unwrap();
+    for (index, &name) in ["a", "b", "c"].iter().enumerate() {
+        let mut stream = comp.create_stream(name).unwrap();
+        stream.write_all(&vec![index as u8; 100 * index]).unwrap();
+    }
+    let mut data = comp.into_inner().into_inner();
+    // As in check_reports_every_violation above.
+    let dir_offset = 2 * 512;
+    data[dir_offset + 128 + 67] = 7;
+    data[dir_offset + 256 + 67] = 9;
+    data[dir_offset + 384 + 80] = 1;
+    assert!(CompoundFile::open(Cursor::new(data.clone())).is_err());
+
+    let mut output = Cursor::new(Vec::new());
+    let report = cfb::repair(Cursor::new(data), &mut output).unwrap();
+    assert!(report.is_lossless());
+    assert_eq!(report.num_entries(), 3);
+    assert_eq!(report.num_bytes(), 300);
+    let comp = CompoundFile::open_strict(output).unwrap();
+    assert_eq!(comp.version(), cfb::Version::V3);
+    for (index, &name) in ["a", "b", "c"].iter().enumerate() {
+        let mut buffer = Vec::new();
+        comp.open_stream(name).unwrap().read_to_end(&mut buffer).unwrap();
+        assert_eq!(buffer, vec![index as u8; 100 * index]);
+    }
+}
+
+#[test]
+fn repair_broken_chain() {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp =
+        CompoundFile::create_with_version(cfb::Version::V3, cursor).unwrap();
+    comp.create_storage("foo").unwrap();
+    let content: Vec<u8> = (0..9000).map(|index| index as u8).collect();
+    comp.create_stream("foo/bar").unwrap().write_all(&content).unwrap();
+    let mut data = comp.into_inner().into_inner();
+    // Point the FAT entry for the stream's third sector past the end of the
+    // file.
+    let dir_offset = 2 * 512;
+    let start_offset = dir_offset + 2 * 128 + 116;
+    let start_sector =
+        (&data[start_offset..]).read_u32::<LittleEndian>().unwrap();
+    let fat_offset = 512 + 4 * (start_sector as usize + 2);
+    data[fat_offset..fat_offset + 4].copy_from_slice(&123456u32.to_le_bytes());
+
+    let mut output = Cursor::new(Vec::new());
+    let report = cfb::repair(Cursor::new(data), &mut output).unwrap();
+    assert_eq!(report.num_entries(), 2);
+    assert_eq!(report.num_bytes(), 1536);
+    assert_eq!(report.losses().len(), 1);
+    let loss = &report.losses()[0];
+    assert_eq!(loss.path(), Some(Path::new("/foo/bar")));
+    assert_eq!(loss.bytes_lost(), 9000 - 1536);
+    assert!(matches!(
+        loss.error(),
+        cfb::Error::SectorOutOfRange { sector_id: 123456, .. }
+    ));
+    let comp = CompoundFile::open_strict(output).unwrap();
+    let mut buffer = Vec::new();
+    comp.open_stream("foo/bar").unwrap().read_to_end(&mut buffer).unwrap();
+    assert_eq!(buffer, &content[..1536]);
+}
+
+#[test]
+fn repair_unreachable_entries() {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create(cursor).unwrap();
+    let version = comp.version();
+    comp.create_stream("a").unwrap().write_all(&[1u8; 50]).unwrap();
+    comp.create_storage("b").unwrap();
+    let mut data = comp.into_inner().into_inner();
+    // Cut the root entry off from its children.
+    let child_offset = 2 * version.sector_len() + 76;
+    data[child_offset..child_offset + 4].copy_from_slice(&[0xff; 4]);
+
+    let mut output = Cursor::new(Vec::new());
+    let report = cfb::repair(Cursor::new(data), &mut output).unwrap();
+    assert_eq!(report.num_entries(), 0);
+    let losses: Vec<(Option<&Path>, u64, String)> = report
+        .losses()
+        .iter()
+        .map(|loss| (loss.path(), loss.bytes_lost(), loss.error().to_string()))
+        .collect();
+    assert_eq!(
+        losses,
+        vec![
+            (
+                None,
+                50,
+                "Directory entry 1 (\"a\") is not reachable from the root"
+                    .to_string()
+            ),
+            (
+                None,
+                0,
+                "Directory entry 2 (\"b\") is not reachable from the root"
+                    .to_string()
+            ),
+        ]
+    );
+    let comp = CompoundFile::open_strict(output).unwrap();
+    assert_eq!(comp.read_root_storage().count(), 0);
+}
+
+#[test]
+fn repair_invalid_header() {
+    let input = Cursor::new(vec![0u8; 1024]);
+    let error = cfb::repair(input, Cursor::new(Vec::new())).err().unwrap();
+    assert!(matches!(
+        cfb::Error::from_io(&error),
+        Some(cfb::Error::Header { .. })
+    ));
+}
+
+#[test]
+fn repair_fuzzed_files() {
+    for dir in ["tests/infinite_loops_fuzzed", "tests/panics_fuzzed"] {
+        for entry in read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let data = std::fs::read(&path).unwrap();
+            let output = panic_after(Duration::from_secs(1), move || {
+                let mut output = Cursor::new(Vec::new());
+                cfb::repair(Cursor::new(data), &mut output).ok()?;
+                Some(output)
+            });
+            if let Some(output) = output {
+                assert!(
+                    CompoundFile::open_strict(output).is_ok(),
+                    "{:?}",
+                    path
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,136 @@
+#![cfg(feature = "mmap")]
+
+use cfb::{CompoundFile, MmapFile, MmapFileMut, ReadAt, Version};
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+mod common;
+use common::{add_small_and_large_streams, make_data};
+
+//===========================================================================//
+
+/// A temporary file that is deleted when dropped.
+struct TempPath(PathBuf);
+
+impl TempPath {
+    fn new(name: &str) -> TempPath {
+        let file_name = format!("cfb-test-{}-{}", std::process::id(), name);
+        TempPath(std::env::temp_dir().join(file_name))
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+fn create_compound_file(path: &TempPath, version: Version) {
+    let file = fs::File::create(&path.0).unwrap();
+    let mut comp = CompoundFile::create_with_version(version, file).unwrap();
+    add_small_and_large_streams(&mut comp);
+    comp.flush().unwrap();
+}
+
+fn read_stream<F: Read + Seek>(
+    comp: &mut CompoundFile<F>,
+    path: &str,
+) -> Vec<u8> {
+    let mut data = Vec::new();
+    comp.open_stream(path).unwrap().read_to_end(&mut data).unwrap();
+    data
+}
+
+//===========================================================================//
+// Tests for memory-mapped compound files:
+
+#[test]
+fn read_through_mmap() {
+    let path = TempPath::new("read_through_mmap");
+    create_compound_file(&path, Version::V4);
+    let mut comp = cfb::open_mmap(&path.0).unwrap();
+    assert_eq!(comp.version(), Version::V4);
+    assert_eq!(read_stream(&mut comp, "/storage/small"), make_data(1, 1000));
+    assert_eq!(read_stream(&mut comp, "/large"), make_data(2, 100000));
+}
+
+#[test]
+fn stream_readers_share_mmap() {
+    let path = TempPath::new("stream_readers_share_mmap");
+    create_compound_file(&path, Version::V3);
+    let comp = CompoundFile::open(MmapFile::open(&path.0).unwrap()).unwrap();
+    let reader = comp.open_stream_reader("/large").unwrap();
+    let mut buffer = vec![0u8; 1000];
+    reader.read_exact_at(&mut buffer, 50000).unwrap();
+    assert_eq!(buffer, &make_data(2, 100000)[50000..51000]);
+}
+
+#[test]
+fn write_through_mmap() {
+    let path = TempPath::new("write_through_mmap");
+    create_compound_file(&path, Version::V3);
+    let old_len = fs::metadata(&path.0).unwrap().len();
+    {
+        let mut comp = cfb::open_rw_mmap(&path.0).unwrap();
+        // Grow the file, so that the map has to grow too.
+        let mut stream = comp.create_stream("/new").unwrap();
+        stream.write_all(&make_data(3, 200000)).unwrap();
+        drop(stream);
+        let mut stream = comp.open_stream("/storage/small").unwrap();
+        stream.seek(SeekFrom::Start(500)).unwrap();
+        stream.write_all(&make_data(4, 500)).unwrap();
+        drop(stream);
+        comp.flush().unwrap();
+        assert_eq!(read_stream(&mut comp, "/new"), make_data(3, 200000));
+    }
+    // The file is truncated back to its real length, a whole number of
+    // sectors, rather than left at the capacity of the map.
+    let new_len = fs::metadata(&path.0).unwrap().len();
+    assert!(new_len > old_len + 200000);
+    assert_eq!(new_len % 512, 0);
+    let mut comp = cfb::open(&path.0).unwrap();
+    let mut expected = make_data(1, 1000);
+    expected[500..].copy_from_slice(&make_data(4, 500));
+    assert_eq!(read_stream(&mut comp, "/storage/small"), expected);
+    assert_eq!(read_stream(&mut comp, "/large"), make_data(2, 100000));
+    assert_eq!(read_stream(&mut comp, "/new"), make_data(3, 200000));
+    CompoundFile::open_strict(fs::File::open(&path.0).unwrap()).unwrap();
+}
+
+#[test]
+fn shrink_through_mmap() {
+    let path = TempPath::new("shrink_through_mmap");
+    create_compound_file(&path, Version::V3);
+    let old_len = fs::metadata(&path.0).unwrap().len();
+    {
+        let mut comp = cfb::open_rw_mmap(&path.0).unwrap();
+        comp.remove_stream("/large").unwrap();
+        comp.shrink_to_fit().unwrap();
+        comp.flush().unwrap();
+    }
+    assert!(fs::metadata(&path.0).unwrap().len() < old_len - 100000);
+    let mut comp = cfb::open_mmap(&path.0).unwrap();
+    assert_eq!(read_stream(&mut comp, "/storage/small"), make_data(1, 1000));
+    assert!(!comp.exists("/large"));
+}
+
+#[test]
+fn flush_leaves_capacity_until_sync_all() {
+    let path = TempPath::new("flush_leaves_capacity_until_sync_all");
+    fs::write(&path.0, make_data(1, 1000)).unwrap();
+    let mut file = MmapFileMut::open(&path.0).unwrap();
+    file.seek(SeekFrom::End(0)).unwrap();
+    file.write_all(&make_data(2, 100)).unwrap();
+    // Flushing doesn't shrink the file, so that the next write needn't grow
+    // it again.
+    file.flush().unwrap();
+    assert_eq!(fs::metadata(&path.0).unwrap().len(), 2000);
+    file.sync_all().unwrap();
+    assert_eq!(fs::metadata(&path.0).unwrap().len(), 1100);
+    file.write_all(&make_data(3, 100)).unwrap();
+    drop(file);
+    assert_eq!(fs::metadata(&path.0).unwrap().len(), 1200);
+}
+
+//===========================================================================//
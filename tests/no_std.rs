@@ -0,0 +1,63 @@
+#![cfg(not(feature = "std"))]
+
+use cfb::io::{Cursor, Read, Seek, SeekFrom};
+use cfb::{CompoundFile, Version};
+
+mod common;
+use common::{make_compound_file, make_data};
+
+//===========================================================================//
+// Tests for compound files without the standard library:
+
+#[test]
+fn read_with_name_chains() {
+    let data = make_compound_file(Version::V4);
+    let comp = CompoundFile::open_strict(Cursor::new(data)).unwrap();
+    assert_eq!(comp.version(), Version::V4);
+    assert!(comp.is_storage(["storage"]));
+    assert!(comp.is_stream(["storage", "small"]));
+    assert!(!comp.exists(["storage", "large"]));
+    assert!(comp.is_storage::<[&str; 0]>([]));
+
+    let mut buffer = Vec::new();
+    let mut stream = comp.open_stream(vec!["storage", "small"]).unwrap();
+    stream.read_to_end(&mut buffer).unwrap();
+    assert_eq!(buffer, make_data(1, 1000));
+
+    let mut stream = comp.open_stream(["large"]).unwrap();
+    stream.seek(SeekFrom::Start(30000)).unwrap();
+    let mut buffer = vec![0u8; 1000];
+    stream.read_exact(&mut buffer).unwrap();
+    assert_eq!(buffer, &make_data(2, 100000)[30000..31000]);
+}
+
+#[test]
+fn entry_paths_and_timestamps() {
+    let data = make_compound_file(Version::V3);
+    let mut comp = CompoundFile::open(Cursor::new(data)).unwrap();
+    let paths: Vec<String> =
+        comp.walk().map(|entry| entry.path().to_string()).collect();
+    assert_eq!(paths, vec!["/", "/large", "/storage", "/storage/small"]);
+    // There is no clock to read, so new objects get zero timestamps.
+    let entry = comp.entry(["storage", "small"]).unwrap();
+    assert_eq!(entry.path(), "/storage/small");
+    assert_eq!(entry.created_filetime(), 0);
+    assert_eq!(entry.modified_filetime(), 0);
+    // Entry paths can be passed back in to refer to the same object.
+    comp.remove_stream(entry.path()).unwrap();
+    assert!(!comp.exists(["storage", "small"]));
+}
+
+#[test]
+fn errors_have_kinds_and_messages() {
+    let data = make_compound_file(Version::V3);
+    let mut comp = CompoundFile::open(Cursor::new(data)).unwrap();
+    let error = comp.open_stream(["storage", "missing"]).err().unwrap();
+    assert_eq!(error.kind(), cfb::io::ErrorKind::NotFound);
+    assert_eq!(error.to_string(), "No such stream: \"/storage/missing\"");
+    let error = comp.create_stream(["bad/name"]).err().unwrap();
+    assert_eq!(error.kind(), cfb::io::ErrorKind::InvalidInput);
+    assert!(CompoundFile::open(Cursor::new(vec![0u8; 1024])).is_err());
+}
+
+//===========================================================================//
@@ -0,0 +1,147 @@
+use cfb::{CompoundFile, Version};
+use std::io::{BufReader, Cursor, Read};
+
+mod common;
+use common::{create_compound_file, make_data};
+
+//===========================================================================//
+
+/// Creates a V3 compound file whose directory starts in the second sector
+/// after the header, at offset 1024.
+fn make_compound_file(objects: &[(&str, usize)]) -> Vec<u8> {
+    create_compound_file(Version::V3, objects).into_inner().into_inner()
+}
+
+const DIR_OFFSET: usize = 1024;
+
+fn set_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+//===========================================================================//
+// Tests for finding and reading orphaned entries and chains:
+
+#[test]
+fn no_orphans_in_valid_file() {
+    let mut names = vec![("small", 100), ("large", 9000), ("storage/", 0)];
+    let nested: Vec<String> =
+        (0..20).map(|index| format!("storage/{}", index)).collect();
+    for (index, name) in nested.iter().enumerate() {
+        names.push((name, 4000 + 200 * index));
+    }
+    let data = make_compound_file(&names);
+    let mut comp = CompoundFile::open_strict(Cursor::new(data)).unwrap();
+    assert!(comp.orphaned_entries().is_empty());
+    assert!(comp.orphaned_chains().unwrap().is_empty());
+}
+
+#[test]
+fn read_orphaned_entries() {
+    let mut data =
+        make_compound_file(&[("small", 100), ("large", 9000), ("dir/", 0)]);
+    // Cut the root entry off from its children.
+    set_u32(&mut data, DIR_OFFSET + 76, 0xffffffff);
+
+    let mut comp = CompoundFile::open(Cursor::new(&data[..])).unwrap();
+    assert_eq!(comp.read_root_storage().count(), 0);
+    let orphans = comp.orphaned_entries();
+    let summary: Vec<(u32, &str, bool, u64)> = orphans
+        .iter()
+        .map(|orphan| {
+            (
+                orphan.stream_id(),
+                orphan.name(),
+                orphan.is_stream(),
+                orphan.len(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            (1, "small", true, 100),
+            (2, "large", true, 9000),
+            (3, "dir", false, 0),
+        ]
+    );
+    // The chain of the large stream belongs to its orphaned entry, so it
+    // isn't reported separately.
+    assert!(comp.orphaned_chains().unwrap().is_empty());
+
+    for (index, orphan) in orphans[..2].iter().enumerate() {
+        let mut reader = comp.open_orphaned_entry(orphan).unwrap();
+        let mut buffer = Vec::new();
+        reader.read_to_end(&mut buffer).unwrap();
+        assert_eq!(buffer, make_data(index + 1, orphan.len() as usize));
+    }
+    let error = comp.open_orphaned_entry(&orphans[2]).err().unwrap();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn read_orphaned_chain() {
+    let mut data = make_compound_file(&[("large", 9000)]);
+    // Remove the stream's directory entry without freeing its chain.
+    set_u32(&mut data, DIR_OFFSET + 76, 0xffffffff);
+    let entry = DIR_OFFSET + 128;
+    data[entry..entry + 128].fill(0);
+    data[entry + 68..entry + 80].fill(0xff);
+
+    let mut comp = CompoundFile::open(Cursor::new(&data[..])).unwrap();
+    assert!(comp.orphaned_entries().is_empty());
+    let chains = comp.orphaned_chains().unwrap();
+    assert_eq!(chains.len(), 1);
+    let chain = &chains[0];
+    assert_eq!(chain.sector_ids().len(), 18);
+    assert_eq!(chain.len(), 18 * 512);
+    let mut reader = comp.open_orphaned_chain(chain).unwrap();
+    assert_eq!(reader.len(), 18 * 512);
+    let mut buffer = Vec::new();
+    reader.read_to_end(&mut buffer).unwrap();
+    assert_eq!(&buffer[..9000], &make_data(1, 9000)[..]);
+}
+
+#[test]
+fn read_orphaned_chain_from_file() {
+    let mut data = make_compound_file(&[("large", 9000)]);
+    set_u32(&mut data, DIR_OFFSET + 76, 0xffffffff);
+    let entry = DIR_OFFSET + 128;
+    data[entry..entry + 128].fill(0);
+    data[entry + 68..entry + 80].fill(0xff);
+    let path = std::env::temp_dir()
+        .join(format!("cfb-test-{}-orphaned-chain", std::process::id()));
+    std::fs::write(&path, &data).unwrap();
+
+    // Orphans can be read from a compound file opened with `cfb::open`,
+    // whose underlying `File` can't be cloned.
+    let mut comp = cfb::open(&path).unwrap();
+    let chains = comp.orphaned_chains().unwrap();
+    let mut reader = comp.open_orphaned_chain(&chains[0]).unwrap();
+    let mut buffer = Vec::new();
+    let result = reader.read_to_end(&mut buffer);
+    drop((reader, comp));
+    std::fs::remove_file(&path).unwrap();
+    result.unwrap();
+    assert_eq!(&buffer[..9000], &make_data(1, 9000)[..]);
+}
+
+#[test]
+fn read_orphans_through_buf_reader() {
+    let mut data = make_compound_file(&[("small", 100), ("large", 9000)]);
+    set_u32(&mut data, DIR_OFFSET + 76, 0xffffffff);
+
+    // Orphans can be read from any `Read + Seek` file, even one that doesn't
+    // support positional reads.
+    let cursor = BufReader::new(Cursor::new(data));
+    let mut comp = CompoundFile::open(cursor).unwrap();
+    let orphans = comp.orphaned_entries();
+    assert_eq!(orphans.len(), 2);
+    for (index, orphan) in orphans.iter().enumerate() {
+        let mut reader = comp.open_orphaned_entry(orphan).unwrap();
+        let mut buffer = Vec::new();
+        reader.read_to_end(&mut buffer).unwrap();
+        assert_eq!(buffer, make_data(index + 1, orphan.len() as usize));
+    }
+}
+
+//===========================================================================//
@@ -0,0 +1,127 @@
+use cfb::{raw, CompoundFile, Version};
+use std::io::Cursor;
+
+mod common;
+use common::create_compound_file;
+
+//===========================================================================//
+
+fn make_compound_file(version: Version) -> Vec<u8> {
+    let objects = [("small", 100), ("dir/", 0), ("dir/large", 9000)];
+    create_compound_file(version, &objects).into_inner().into_inner()
+}
+
+/// Follows a chain in the given FAT (or MiniFAT), returning its sector IDs.
+fn follow_chain(fat: &[u32], start_sector: u32) -> Vec<u32> {
+    let mut sector_ids = Vec::new();
+    let mut sector_id = start_sector;
+    while sector_id != raw::END_OF_CHAIN {
+        sector_ids.push(sector_id);
+        sector_id = fat[sector_id as usize];
+    }
+    sector_ids
+}
+
+//===========================================================================//
+// Tests for reading the header, allocation tables, and directory entries:
+
+#[test]
+fn raw_header() {
+    for &version in &[Version::V3, Version::V4] {
+        let data = make_compound_file(version);
+        let mut comp = CompoundFile::open_strict(Cursor::new(data)).unwrap();
+        let header = comp.raw_header().unwrap();
+        assert!(header.clsid().is_nil());
+        assert_eq!(header.minor_version(), 0x3e);
+        assert_eq!(header.major_version(), version.number());
+        assert_eq!(header.byte_order(), 0xfffe);
+        assert_eq!(header.sector_shift(), version.sector_shift());
+        assert_eq!(header.mini_sector_shift(), 6);
+        assert_eq!(header.transaction_signature(), 0);
+        assert_eq!(header.mini_stream_cutoff(), 4096);
+        assert_eq!(header.first_difat_sector(), raw::END_OF_CHAIN);
+        assert_eq!(header.num_difat_sectors(), 0);
+        assert!(comp.raw_difat_sector_ids().is_empty());
+
+        let difat = comp.raw_difat();
+        assert_eq!(header.num_fat_sectors() as usize, difat.len());
+        let initial = header.initial_difat_entries();
+        assert_eq!(initial.len(), 109);
+        assert_eq!(&initial[..difat.len()], &difat[..]);
+        assert!(initial[difat.len()..]
+            .iter()
+            .all(|&entry| entry == raw::FREE_SECTOR));
+    }
+}
+
+#[test]
+fn raw_header_fields_not_used_by_crate() {
+    let mut data = make_compound_file(Version::V3);
+    data[24..26].copy_from_slice(&0x21u16.to_le_bytes());
+    data[52..56].copy_from_slice(&7u32.to_le_bytes());
+    let mut comp = CompoundFile::open_strict(Cursor::new(data)).unwrap();
+    let header = comp.raw_header().unwrap();
+    assert_eq!(header.minor_version(), 0x21);
+    assert_eq!(header.transaction_signature(), 7);
+}
+
+#[test]
+fn raw_fat_and_minifat() {
+    let data = make_compound_file(Version::V3);
+    let comp = CompoundFile::open_strict(Cursor::new(data)).unwrap();
+    let fat = comp.raw_fat();
+    for &sector_id in comp.raw_difat().iter() {
+        assert_eq!(fat[sector_id as usize], raw::FAT_SECTOR);
+    }
+
+    let entries = comp.raw_dir_entries();
+    let root = &entries[raw::ROOT_STREAM_ID as usize];
+    let large = entries.iter().find(|entry| entry.name() == "large").unwrap();
+    assert_eq!(follow_chain(&fat, large.start_sector()).len(), 18);
+    assert_eq!(follow_chain(&fat, root.start_sector()).len(), 1);
+
+    let minifat = comp.raw_minifat();
+    let small = entries.iter().find(|entry| entry.name() == "small").unwrap();
+    assert_eq!(follow_chain(&minifat, small.start_sector()).len(), 2);
+}
+
+#[test]
+fn raw_dir_entries() {
+    let data = make_compound_file(Version::V3);
+    let comp = CompoundFile::open_strict(Cursor::new(data)).unwrap();
+    let entries = comp.raw_dir_entries();
+    // Version 3 directory sectors hold four entries each.
+    assert_eq!(entries.len(), 4);
+    for (index, entry) in entries.iter().enumerate() {
+        assert_eq!(entry.stream_id(), index as u32);
+    }
+
+    let root = &entries[0];
+    assert_eq!(root.name(), "Root Entry");
+    assert_eq!(root.obj_type(), raw::OBJ_TYPE_ROOT);
+    assert_eq!(root.color(), raw::COLOR_BLACK);
+    assert_eq!(root.stream_len(), 128);
+    let dir = &entries[2];
+    assert_eq!(dir.name(), "dir");
+    assert_eq!(dir.obj_type(), raw::OBJ_TYPE_STORAGE);
+    let large = &entries[dir.child() as usize];
+    assert_eq!(large.name(), "large");
+    assert_eq!(large.obj_type(), raw::OBJ_TYPE_STREAM);
+    assert_eq!(large.stream_len(), 9000);
+    assert_eq!(large.left_sibling(), raw::NO_STREAM);
+    assert_eq!(large.right_sibling(), raw::NO_STREAM);
+    assert_eq!(large.child(), raw::NO_STREAM);
+
+    // The root's children ("small" and "dir") form a tree.
+    let child = &entries[root.child() as usize];
+    let sibling = if child.left_sibling() != raw::NO_STREAM {
+        child.left_sibling()
+    } else {
+        child.right_sibling()
+    };
+    let mut names = vec![child.name(), entries[sibling as usize].name()];
+    names.sort_unstable();
+    assert_eq!(names, vec!["dir", "small"]);
+}
+
+//===========================================================================//
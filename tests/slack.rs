@@ -0,0 +1,110 @@
+use cfb::{CompoundFile, SlackKind, Version};
+use std::io::{Cursor, Read, Seek, SeekFrom};
+
+mod common;
+use common::{create_compound_file, make_data};
+
+//===========================================================================//
+
+/// Creates a V3 compound file with a mini stream, a regular stream that has
+/// been truncated, and a removed stream whose sectors and directory entry
+/// were freed.
+fn make_compound_file() -> CompoundFile<Cursor<Vec<u8>>> {
+    let objects = [("small", 100), ("large", 9000), ("secret", 5000)];
+    let mut comp = create_compound_file(Version::V3, &objects);
+    comp.remove_stream("secret").unwrap();
+    comp.open_stream("large").unwrap().set_len(8900).unwrap();
+    comp.flush().unwrap();
+    comp
+}
+
+//===========================================================================//
+// Tests for finding and reading slack regions:
+
+#[test]
+fn slack_regions_of_file() {
+    let mut comp = make_compound_file();
+    let regions = comp.slack_regions().unwrap();
+    let summary: Vec<(SlackKind, u64)> =
+        regions.iter().map(|region| (region.kind(), region.len())).collect();
+    let mut expected = vec![
+        // The removed stream's directory entry.
+        (SlackKind::UnallocatedDirEntry, 128),
+        // The end of the last mini sector of "small".
+        (SlackKind::StreamTail, 28),
+        // The end of the mini stream's only sector.
+        (SlackKind::StreamTail, 384),
+        // The truncated part of "large", and the rest of its last sector.
+        (SlackKind::StreamTail, 316),
+    ];
+    // The removed stream's sectors.
+    expected.extend((0..10).map(|_| (SlackKind::FreeSector, 512)));
+    assert_eq!(summary, expected);
+
+    assert!(regions
+        .windows(2)
+        .all(|pair| pair[0].offset() + pair[0].len() <= pair[1].offset()));
+    let data = comp.into_inner().into_inner();
+    for region in regions.iter() {
+        assert!(region.offset() + region.len() <= data.len() as u64);
+    }
+}
+
+#[test]
+fn read_slack_regions() {
+    let mut comp = make_compound_file();
+    let regions = comp.slack_regions().unwrap();
+    let mut reader = comp.open_slack(regions.clone());
+    assert_eq!(reader.len(), regions.iter().map(|r| r.len()).sum::<u64>());
+    let mut slack = Vec::new();
+    reader.read_to_end(&mut slack).unwrap();
+    assert_eq!(slack.len() as u64, reader.len());
+    // Neither removing nor truncating a stream overwrites its data.
+    let secret = make_data(3, 5000);
+    assert!(slack.windows(5000).any(|window| window == &secret[..]));
+    let truncated = &make_data(2, 9000)[8900..];
+    assert!(slack.windows(100).any(|window| window == truncated));
+
+    drop(reader);
+    let data = comp.into_inner().into_inner();
+    let mut expected = Vec::new();
+    for region in regions.iter() {
+        let start = region.offset() as usize;
+        expected
+            .extend_from_slice(&data[start..start + region.len() as usize]);
+    }
+    assert_eq!(slack, expected);
+}
+
+#[test]
+fn seek_within_slack() {
+    let mut comp = make_compound_file();
+    let regions: Vec<_> = comp
+        .slack_regions()
+        .unwrap()
+        .into_iter()
+        .filter(|region| region.kind() == SlackKind::FreeSector)
+        .collect();
+    let mut reader = comp.open_slack(regions);
+    assert_eq!(reader.len(), 5120);
+    assert_eq!(reader.seek(SeekFrom::Start(4990)).unwrap(), 4990);
+    let mut buf = [0u8; 20];
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf[..10], &make_data(3, 5000)[4990..]);
+    assert_eq!(&buf[10..], &[0; 10]);
+    assert_eq!(reader.seek(SeekFrom::End(-10)).unwrap(), 5110);
+    assert_eq!(reader.seek(SeekFrom::Current(-5110)).unwrap(), 0);
+    assert!(reader.seek(SeekFrom::Current(-1)).is_err());
+    assert!(reader.seek(SeekFrom::End(1)).is_err());
+}
+
+#[test]
+fn no_slack_regions_to_read() {
+    let comp = make_compound_file();
+    let mut reader = comp.open_slack(Vec::new());
+    assert!(reader.is_empty());
+    let mut buf = Vec::new();
+    assert_eq!(reader.read_to_end(&mut buf).unwrap(), 0);
+}
+
+//===========================================================================//
@@ -0,0 +1,122 @@
+use cfb::{CompoundFile, ReadAt, Version};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+mod common;
+use common::{create_fragmented_compound_file, make_data};
+
+//===========================================================================//
+
+const NUM_STREAMS: usize = 8;
+
+fn stream_path(index: usize) -> String {
+    format!("/stream{}", index)
+}
+
+#[allow(clippy::manual_is_multiple_of)]
+fn stream_len(index: usize) -> usize {
+    // Alternate between streams in the mini stream and regular streams.
+    if index % 2 == 0 {
+        100 + 700 * index
+    } else {
+        5000 + 9000 * index
+    }
+}
+
+fn make_compound_file(version: Version) -> Vec<u8> {
+    let mut objects = vec![("/storage/".to_string(), 0)];
+    for index in 0..NUM_STREAMS {
+        objects.push((stream_path(index), stream_len(index)));
+    }
+    let comp = create_fragmented_compound_file(version, &objects);
+    comp.into_inner().into_inner()
+}
+
+//===========================================================================//
+// Tests for reading streams with positional reads:
+
+fn read_streams(version: Version) {
+    let data = make_compound_file(version);
+    let comp = CompoundFile::open_strict(Cursor::new(&data[..])).unwrap();
+    for index in 0..NUM_STREAMS {
+        let expected = make_data(index + 1, stream_len(index));
+        let mut reader = comp.open_stream_reader(stream_path(index)).unwrap();
+        assert_eq!(reader.len(), expected.len() as u64);
+        let mut actual = Vec::new();
+        reader.read_to_end(&mut actual).unwrap();
+        assert_eq!(actual, expected);
+        // Positional reads don't depend on (or change) the reader's cursor.
+        let mut buffer = vec![0u8; 300];
+        let offset = expected.len() / 2;
+        let num_bytes = reader.read_at(&mut buffer, offset as u64).unwrap();
+        assert_eq!(num_bytes, buffer.len().min(expected.len() - offset));
+        assert_eq!(&buffer[..num_bytes], &expected[offset..][..num_bytes]);
+        assert_eq!(reader.stream_position().unwrap(), expected.len() as u64);
+    }
+}
+
+#[test]
+fn read_streams_v3() {
+    read_streams(Version::V3);
+}
+
+#[test]
+fn read_streams_v4() {
+    read_streams(Version::V4);
+}
+
+#[test]
+fn seek_within_stream_reader() {
+    let data = make_compound_file(Version::V3);
+    let comp = CompoundFile::open(Cursor::new(&data[..])).unwrap();
+    let expected = make_data(2, stream_len(1));
+    let mut reader = comp.open_stream_reader(stream_path(1)).unwrap();
+    reader.seek(SeekFrom::Start(1000)).unwrap();
+    let mut buffer = vec![0u8; 1000];
+    reader.read_exact(&mut buffer).unwrap();
+    assert_eq!(buffer, &expected[1000..2000]);
+    reader.seek(SeekFrom::Current(-1500)).unwrap();
+    reader.read_exact(&mut buffer).unwrap();
+    assert_eq!(buffer, &expected[500..1500]);
+    assert!(reader.seek(SeekFrom::End(1)).is_err());
+    assert!(reader.seek(SeekFrom::Current(-1501)).is_err());
+}
+
+#[test]
+fn open_stream_reader_errors() {
+    let data = make_compound_file(Version::V3);
+    let comp = CompoundFile::open(Cursor::new(&data[..])).unwrap();
+    assert!(comp.open_stream_reader("/nonexistent").is_err());
+    assert!(comp.open_stream_reader("/storage").is_err());
+}
+
+#[test]
+fn reader_sees_flushed_writes() {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp = CompoundFile::create(cursor).unwrap();
+    let mut stream = comp.create_stream("/foo").unwrap();
+    stream.write_all(&make_data(1, 10000)).unwrap();
+    stream.flush().unwrap();
+    let cursor = comp.into_inner();
+    let comp = CompoundFile::open(Cursor::new(cursor.get_ref())).unwrap();
+    let mut reader = comp.open_stream_reader("/foo").unwrap();
+    let mut actual = Vec::new();
+    reader.read_to_end(&mut actual).unwrap();
+    assert_eq!(actual, make_data(1, 10000));
+}
+
+#[test]
+fn read_file_opened_from_path() {
+    let path = std::env::temp_dir()
+        .join(format!("cfb-test-{}-stream-reader", std::process::id()));
+    std::fs::write(&path, make_compound_file(Version::V3)).unwrap();
+    let comp = cfb::open(&path).unwrap();
+    let expected = make_data(2, stream_len(1));
+    let reader = comp.open_stream_reader(stream_path(1)).unwrap();
+    let mut buffer = vec![0u8; 1000];
+    reader.read_exact_at(&mut buffer, 3000).unwrap();
+    drop((reader, comp));
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(buffer, &expected[3000..4000]);
+}
+
+//===========================================================================//
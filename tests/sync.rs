@@ -0,0 +1,138 @@
+#![cfg(feature = "sync")]
+
+use cfb::{CompoundFile, ReadAt, Stream, StreamReader, Version};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+use std::thread;
+
+mod common;
+use common::{create_compound_file, make_data};
+
+//===========================================================================//
+
+const NUM_STREAMS: usize = 8;
+
+fn stream_path(index: usize) -> String {
+    format!("/stream{}", index)
+}
+
+#[allow(clippy::manual_is_multiple_of)]
+fn stream_len(index: usize) -> usize {
+    // Alternate between streams in the mini stream and regular streams.
+    if index % 2 == 0 {
+        3000
+    } else {
+        30000
+    }
+}
+
+fn make_compound_file() -> CompoundFile<Cursor<Vec<u8>>> {
+    let objects: Vec<(String, usize)> = (0..NUM_STREAMS)
+        .map(|index| (stream_path(index), stream_len(index)))
+        .collect();
+    create_compound_file(Version::V3, &objects)
+}
+
+//===========================================================================//
+// Tests for thread safety:
+
+#[test]
+fn compound_file_and_stream_are_send_and_sync() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<CompoundFile<Cursor<Vec<u8>>>>();
+    assert_send_sync::<Stream<Cursor<Vec<u8>>>>();
+    assert_send_sync::<StreamReader<Cursor<Vec<u8>>>>();
+}
+
+#[test]
+fn read_streams_from_multiple_threads() {
+    let comp = Arc::new(make_compound_file());
+    let handles: Vec<_> = (0..NUM_STREAMS)
+        .map(|index| {
+            let comp = Arc::clone(&comp);
+            thread::spawn(move || {
+                let mut stream = comp.open_stream(stream_path(index)).unwrap();
+                // Read in small pieces, so that the threads interleave.
+                let mut data = Vec::new();
+                let mut buffer = [0u8; 100];
+                loop {
+                    let num_bytes = stream.read(&mut buffer).unwrap();
+                    if num_bytes == 0 {
+                        break;
+                    }
+                    data.extend_from_slice(&buffer[..num_bytes]);
+                }
+                (index, data)
+            })
+        })
+        .collect();
+    for handle in handles {
+        let (index, data) = handle.join().unwrap();
+        let len = stream_len(index);
+        assert_eq!(data, make_data(index + 1, len));
+    }
+}
+
+#[test]
+fn share_stream_readers_between_threads() {
+    let comp = make_compound_file();
+    let readers: Vec<_> = (0..NUM_STREAMS)
+        .map(|index| comp.open_stream_reader(stream_path(index)).unwrap())
+        .collect();
+    thread::scope(|scope| {
+        for (index, reader) in readers.iter().enumerate() {
+            // Several threads read each stream at once, in small pieces at
+            // different offsets, so that the reads interleave.
+            for start in 0..4 {
+                scope.spawn(move || {
+                    let len = stream_len(index);
+                    let expected = make_data(index + 1, len);
+                    let mut buffer = [0u8; 100];
+                    let mut offset = start * 100;
+                    while offset < expected.len() {
+                        let num_bytes = reader
+                            .read_at(&mut buffer, offset as u64)
+                            .unwrap();
+                        assert_eq!(
+                            &buffer[..num_bytes],
+                            &expected[offset..offset + num_bytes]
+                        );
+                        offset += 400;
+                    }
+                });
+            }
+        }
+    });
+}
+
+#[test]
+fn write_streams_from_multiple_threads() {
+    let comp = make_compound_file();
+    thread::scope(|scope| {
+        for index in 0..NUM_STREAMS {
+            let mut stream = comp.open_stream(stream_path(index)).unwrap();
+            scope.spawn(move || {
+                stream.seek(SeekFrom::End(0)).unwrap();
+                for _ in 0..10 {
+                    stream.write_all(&make_data(index + 1, 1000)).unwrap();
+                    stream.flush().unwrap();
+                }
+            });
+        }
+    });
+    let cursor = comp.into_inner();
+    let comp = CompoundFile::open_strict(cursor).unwrap();
+    for index in 0..NUM_STREAMS {
+        let len = stream_len(index);
+        let mut expected = make_data(index + 1, len);
+        for _ in 0..10 {
+            expected.extend_from_slice(&make_data(index + 1, 1000));
+        }
+        let mut data = Vec::new();
+        let mut stream = comp.open_stream(stream_path(index)).unwrap();
+        stream.read_to_end(&mut data).unwrap();
+        assert_eq!(data, expected);
+    }
+}
+
+//===========================================================================//
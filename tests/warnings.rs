@@ -0,0 +1,256 @@
+use cfb::{CompoundFile, ValidationPolicy, Version};
+use std::convert::TryInto;
+use std::io::Cursor;
+
+mod common;
+use common::create_compound_file;
+
+//===========================================================================//
+
+/// Creates a V3 compound file holding a storage and a small stream, whose
+/// four sectors are the FAT, the directory, the MiniFAT, and the mini stream.
+fn make_compound_file() -> Vec<u8> {
+    let objects = [("storage/", 0), ("small", 100)];
+    create_compound_file(Version::V3, &objects).into_inner().into_inner()
+}
+
+fn sector_offset(sector_id: u32) -> usize {
+    (sector_id as usize + 1) * 512
+}
+
+fn set_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn get_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// Returns the offset within `data` of the directory entry with the given
+/// name, which must be in the directory's first sector.
+fn dir_entry_offset(data: &[u8], name: &str) -> usize {
+    let comp = CompoundFile::open_strict(Cursor::new(data)).unwrap();
+    let stream_id = comp
+        .raw_dir_entries()
+        .iter()
+        .find(|entry| entry.name() == name)
+        .unwrap()
+        .stream_id() as usize;
+    assert!(stream_id < 4);
+    sector_offset(get_u32(data, 48)) + stream_id * 128
+}
+
+/// Checks that the strict policy rejects the given file, and returns the
+/// warnings recorded when opening it with the given policy.
+fn warnings_with_policy(data: &[u8], policy: ValidationPolicy) -> Vec<String> {
+    assert!(CompoundFile::open_strict(Cursor::new(data)).is_err());
+    let comp = CompoundFile::open_with_policy(Cursor::new(data), policy)
+        .expect("open");
+    comp.warnings().iter().map(|warning| warning.to_string()).collect()
+}
+
+fn warnings(data: &[u8]) -> Vec<String> {
+    warnings_with_policy(data, ValidationPolicy::permissive())
+}
+
+//===========================================================================//
+// Tests for the deviations from the spec recorded when opening a file:
+
+#[test]
+fn no_warnings_for_valid_file() {
+    let data = make_compound_file();
+    let comp = CompoundFile::open(Cursor::new(&data)).unwrap();
+    assert!(comp.warnings().is_empty());
+    let comp = CompoundFile::open_strict(Cursor::new(&data)).unwrap();
+    assert!(comp.warnings().is_empty());
+}
+
+#[test]
+fn no_warnings_for_created_file() {
+    let cursor = Cursor::new(Vec::new());
+    let comp = CompoundFile::create(cursor).unwrap();
+    assert!(comp.warnings().is_empty());
+}
+
+#[test]
+fn warn_about_nonzero_reserved_field() {
+    let mut data = make_compound_file();
+    data[34] = 1;
+    assert_eq!(
+        warnings(&data),
+        vec!["warning: Invalid reserved field in header (must be all zeros)"]
+    );
+}
+
+#[test]
+fn warn_about_v3_num_dir_sectors() {
+    let mut data = make_compound_file();
+    set_u32(&mut data, 40, 1);
+    assert_eq!(
+        warnings(&data),
+        vec![
+            "warning: Invalid number of directory sectors field (must be \
+             zero for CFB version 3, found 1)"
+        ]
+    );
+}
+
+#[test]
+fn warn_about_free_sector_as_end_of_chain() {
+    let mut data = make_compound_file();
+    set_u32(&mut data, 68, 0xffffffff);
+    assert_eq!(
+        warnings(&data),
+        vec![
+            "warning: DIFAT chain in header ends with FREE_SECTOR instead of \
+             END_OF_CHAIN"
+        ]
+    );
+}
+
+#[test]
+fn warn_about_wrong_sector_count() {
+    let mut data = make_compound_file();
+    set_u32(&mut data, 44, 2);
+    assert_eq!(
+        warnings(&data),
+        vec![
+            "warning: Incorrect number of FAT sectors (header says 2, DIFAT \
+             says 1)"
+        ]
+    );
+}
+
+#[test]
+fn warn_about_unmarked_fat_sector() {
+    let mut data = make_compound_file();
+    let fat_sector = get_u32(&data, 76);
+    let entry = sector_offset(fat_sector) + 4 * fat_sector as usize;
+    set_u32(&mut data, entry, 0xfffffffe);
+    assert_eq!(warnings(&data).len(), 1);
+    assert!(warnings(&data)[0].contains("is not marked as such in the FAT"));
+}
+
+#[test]
+fn warn_about_zero_padded_fat() {
+    let mut data = make_compound_file();
+    let fat_sector = get_u32(&data, 76);
+    let fat = sector_offset(fat_sector);
+    data[fat + 4 * 4..fat + 512].fill(0);
+    assert_eq!(
+        warnings(&data),
+        vec!["warning: FAT is padded with zeros past the last sector of the file"]
+    );
+}
+
+#[test]
+fn warn_about_long_minifat() {
+    let mut data = make_compound_file();
+    // The mini stream holds two mini sectors, so a third MiniFAT entry is
+    // one too many.
+    let minifat = sector_offset(get_u32(&data, 60));
+    set_u32(&mut data, minifat + 8, 0xfffffffe);
+    assert_eq!(warnings(&data).len(), 1);
+    assert!(warnings(&data)[0].contains("MiniFAT has 3 entries"));
+}
+
+#[test]
+fn warn_about_unterminated_name() {
+    let mut data = make_compound_file();
+    let entry = dir_entry_offset(&data, "small");
+    data[entry + 10] = b'x';
+    assert_eq!(warnings(&data).len(), 1);
+    assert!(warnings(&data)[0].contains("name not null-terminated"));
+}
+
+#[test]
+fn warn_about_odd_name_length() {
+    let mut data = make_compound_file();
+    let entry = dir_entry_offset(&data, "small");
+    data[entry + 64] = 11;
+    // Even the permissive policy rejects odd name lengths.
+    assert!(CompoundFile::open(Cursor::new(&data)).is_err());
+    let mut policy = ValidationPolicy::strict();
+    policy.allow_odd_name_lengths = true;
+    let warnings = warnings_with_policy(&data, policy);
+    assert_eq!(warnings.len(), 1);
+    assert!(warnings[0].contains("odd name length: 11"));
+}
+
+#[test]
+fn warn_about_wrong_root_name() {
+    let mut data = make_compound_file();
+    let entry = dir_entry_offset(&data, "Root Entry");
+    data[entry] = b'r';
+    assert_eq!(warnings(&data).len(), 1);
+    assert!(warnings(&data)[0].contains("root entry name is \"root Entry\""));
+}
+
+#[test]
+fn warn_about_stream_clsid() {
+    let mut data = make_compound_file();
+    let entry = dir_entry_offset(&data, "small");
+    data[entry + 80] = 1;
+    assert_eq!(warnings(&data).len(), 1);
+    assert!(warnings(&data)[0].contains("non-null stream CLSID"));
+}
+
+#[test]
+fn warn_about_stream_timestamp() {
+    let mut data = make_compound_file();
+    let entry = dir_entry_offset(&data, "small");
+    data[entry + 100] = 1;
+    assert_eq!(warnings(&data).len(), 1);
+    assert!(warnings(&data)[0].contains("non-zero stream creation time: 1"));
+}
+
+#[test]
+fn warn_about_storage_stream_length() {
+    let mut data = make_compound_file();
+    let entry = dir_entry_offset(&data, "storage");
+    data[entry + 120] = 1;
+    assert_eq!(warnings(&data).len(), 1);
+    assert!(warnings(&data)[0].contains("non-zero storage stream length: 1"));
+}
+
+#[test]
+fn warn_about_adjacent_red_nodes() {
+    let cursor = Cursor::new(Vec::new());
+    let mut comp =
+        CompoundFile::create_with_version(Version::V3, cursor).unwrap();
+    for name in ["a", "b", "c"] {
+        comp.create_stream(name).unwrap();
+    }
+    let mut data = comp.into_inner().into_inner();
+    let entries: Vec<usize> = ["a", "b", "c"]
+        .iter()
+        .map(|name| dir_entry_offset(&data, name))
+        .collect();
+    for entry in entries {
+        data[entry + 67] = 0;
+    }
+    let warnings = warnings(&data);
+    assert!(!warnings.is_empty());
+    for warning in warnings {
+        assert!(warning.contains("RB tree has adjacent red nodes"));
+    }
+}
+
+#[test]
+fn warnings_follow_policy() {
+    let mut data = make_compound_file();
+    let entry = dir_entry_offset(&data, "small");
+    data[34] = 1;
+    data[entry + 80] = 1;
+    // A policy that allows only one of the two deviations rejects the file,
+    // and one that allows both records both.
+    let mut policy = ValidationPolicy::strict();
+    policy.allow_stream_clsids = true;
+    assert!(
+        CompoundFile::open_with_policy(Cursor::new(&data), policy).is_err()
+    );
+    policy.allow_nonzero_reserved_fields = true;
+    assert_eq!(warnings_with_policy(&data, policy).len(), 2);
+}
+
+//===========================================================================//